@@ -0,0 +1,73 @@
+// memory description of the board
+//
+// this file is shared by build.rs, which generates the linker script from it, and
+// the crate (as `board::layout`), so the flash partitioning and the linked memory
+// layout can not diverge. build.rs checks the description and fails the build on
+// overlapping or misaligned regions.
+
+pub struct BoardLayout {
+	pub flash_base: usize,
+	pub flash_size: usize,
+	// sram including the part used as tcm
+	pub sram_base: usize,
+	pub sram_size: usize,
+	// size of each of itcm and dtcm, 0, 32, 64 or 128 KiB (gpnvm bits 7 and 8)
+	pub tcm_size: usize,
+	pub itcm_base: usize,
+	pub dtcm_base: usize,
+	// put the main stack at the end of the dtcm instead of the end of the ram
+	pub stack_in_dtcm: bool,
+	// flash partitioning, see src/image.rs. the chip boots from the start of the
+	// flash, so the bootloader offset is 0 unless something else is placed there
+	pub bootloader_offset: usize,
+	pub bootloader_size: usize,
+	pub slot_size: usize,
+	// slot images start with a header page, the application is linked behind it
+	pub image_header_size: usize,
+	pub settings_size: usize,
+	// chip selects 0 to 3 of the static memory controller (ethernet, lcd, ...)
+	pub smc_base: usize,
+	pub smc_size: usize,
+	pub sdram_base: usize,
+	pub sdram_size: usize
+}
+
+pub const BOARD: BoardLayout = BoardLayout {
+	flash_base: 0x0040_0000,
+	flash_size: 0x0020_0000,
+	sram_base: 0x2040_0000,
+	sram_size: 0x0006_0000,
+	tcm_size: 0,
+	itcm_base: 0x0000_0000,
+	dtcm_base: 0x2000_0000,
+	stack_in_dtcm: false,
+	bootloader_offset: 0,
+	bootloader_size: 0x0002_0000,
+	slot_size: 0x000E_0000,
+	image_header_size: 0x200,
+	settings_size: 0x0002_0000,
+	smc_base: 0x6000_0000,
+	smc_size: 0x0400_0000,
+	sdram_base: 0x7000_0000,
+	sdram_size: 0x0200_0000
+};
+
+impl BoardLayout {
+	pub const fn slot_a_offset(&self) -> usize {
+		self.bootloader_offset + self.bootloader_size
+	}
+
+	pub const fn slot_b_offset(&self) -> usize {
+		self.slot_a_offset() + self.slot_size
+	}
+
+	// the settings use the end of the flash
+	pub const fn settings_offset(&self) -> usize {
+		self.flash_size - self.settings_size
+	}
+
+	// sram left for the system after the tcm has been taken off
+	pub const fn ram_size(&self) -> usize {
+		self.sram_size - 2 * self.tcm_size
+	}
+}
@@ -3,16 +3,228 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+#[allow(dead_code)]
+mod board;
+
+use board::{BoardLayout, BOARD};
+
+// smallest flash area that can be erased on its own (16 pages)
+const ERASE_BLOCK: usize = 0x2000;
+// the image header takes exactly one flash page, see src/image.rs
+const PAGE_SIZE: usize = 0x200;
+
+struct Region {
+    name: &'static str,
+    origin: usize,
+    length: usize,
+}
+
+fn check_aligned(what: &str, value: usize, alignment: usize) {
+    if value % alignment != 0 {
+        panic!(
+            "board.rs: {} (0x{:08X}) has to be aligned to 0x{:X} bytes",
+            what, value, alignment
+        );
+    }
+}
+
+// fail the build if two regions of `regions` overlap
+fn check_overlap(regions: &[Region]) {
+    for (i, a) in regions.iter().enumerate() {
+        for b in regions[i + 1..].iter() {
+            if a.origin < b.origin + b.length && b.origin < a.origin + a.length {
+                panic!(
+                    "board.rs: {} (0x{:08X} - 0x{:08X}) overlaps {} (0x{:08X} - 0x{:08X})",
+                    a.name,
+                    a.origin,
+                    a.origin + a.length,
+                    b.name,
+                    b.origin,
+                    b.origin + b.length
+                );
+            }
+        }
+    }
+}
+
+fn check_layout(board: &BoardLayout) {
+    check_aligned("bootloader offset", board.bootloader_offset, ERASE_BLOCK);
+    check_aligned("bootloader size", board.bootloader_size, ERASE_BLOCK);
+    check_aligned("slot size", board.slot_size, ERASE_BLOCK);
+    check_aligned("settings size", board.settings_size, ERASE_BLOCK);
+    // the header page is written on its own and the vector table of the application
+    // behind it has to be aligned for vtor
+    if board.image_header_size != PAGE_SIZE {
+        panic!(
+            "board.rs: image header size 0x{:X} has to be one flash page (0x{:X} bytes)",
+            board.image_header_size, PAGE_SIZE
+        );
+    }
+    match board.tcm_size {
+        0 | 0x8000 | 0x1_0000 | 0x2_0000 => (),
+        size => panic!(
+            "board.rs: tcm size 0x{:X} is not one of 0, 32, 64 or 128 KiB",
+            size
+        ),
+    }
+    if board.stack_in_dtcm && board.tcm_size == 0 {
+        panic!("board.rs: the stack can not be placed in the dtcm, the tcm size is 0");
+    }
+    if 2 * board.tcm_size >= board.sram_size {
+        panic!("board.rs: itcm and dtcm leave no sram for the system");
+    }
+    if board.image_header_size >= board.slot_size {
+        panic!("board.rs: the image header does not fit into a slot");
+    }
+    if board.settings_size > board.flash_size {
+        panic!("board.rs: the settings do not fit into the flash");
+    }
+
+    let flash = |name, offset, length| Region {
+        name: name,
+        origin: board.flash_base + offset,
+        length: length,
+    };
+    let partitions = [
+        flash("bootloader", board.bootloader_offset, board.bootloader_size),
+        flash("slot A", board.slot_a_offset(), board.slot_size),
+        flash("slot B", board.slot_b_offset(), board.slot_size),
+        flash("settings", board.settings_offset(), board.settings_size),
+    ];
+    check_overlap(&partitions);
+    if board.slot_b_offset() + board.slot_size > board.flash_size {
+        panic!("board.rs: the bootloader and both slots do not fit into the flash");
+    }
+
+    check_overlap(&[
+        Region { name: "flash", origin: board.flash_base, length: board.flash_size },
+        Region { name: "sram", origin: board.sram_base, length: board.sram_size },
+        Region { name: "itcm", origin: board.itcm_base, length: board.tcm_size },
+        Region { name: "dtcm", origin: board.dtcm_base, length: board.tcm_size },
+        Region { name: "smc", origin: board.smc_base, length: board.smc_size },
+        Region { name: "sdram", origin: board.sdram_base, length: board.sdram_size },
+    ]);
+}
+
+// flash region the binary is linked to for the selected features
+fn flash_region(board: &BoardLayout) -> (&'static str, usize, usize) {
+    let bootloader = env::var_os("CARGO_FEATURE_BOOTLOADER").is_some();
+    let slot_a = env::var_os("CARGO_FEATURE_SLOT_A").is_some();
+    let slot_b = env::var_os("CARGO_FEATURE_SLOT_B").is_some();
+    let header = board.image_header_size;
+    match (bootloader, slot_a, slot_b) {
+        (false, false, false) => (
+            "standalone application using the whole flash",
+            0,
+            board.settings_offset(),
+        ),
+        (true, false, false) => ("bootloader", board.bootloader_offset, board.bootloader_size),
+        (false, true, false) => (
+            "application in slot A, the first page of the slot holds the image header",
+            board.slot_a_offset() + header,
+            board.slot_size - header,
+        ),
+        (false, false, true) => (
+            "application in slot B, the first page of the slot holds the image header",
+            board.slot_b_offset() + header,
+            board.slot_size - header,
+        ),
+        _ => panic!("the features `bootloader`, `slot-a` and `slot-b` are mutually exclusive"),
+    }
+}
+
+fn memory_x(board: &BoardLayout) -> String {
+    let (description, offset, length) = flash_region(board);
+    let mut out = String::new();
+    out.push_str("/* Generated by build.rs from board.rs, do not edit */\n");
+    out.push_str("MEMORY\n{\n");
+    out.push_str(&format!("  /* {} */\n", description));
+    out.push_str(&format!(
+        "  FLASH : ORIGIN = 0x{:08X}, LENGTH = 0x{:08X}\n",
+        board.flash_base + offset,
+        length
+    ));
+    out.push_str(&format!(
+        "  RAM : ORIGIN = 0x{:08X}, LENGTH = 0x{:08X}\n",
+        board.sram_base,
+        board.ram_size()
+    ));
+    out.push_str(&format!(
+        "  SDRAM : ORIGIN = 0x{:08X}, LENGTH = 0x{:08X}\n",
+        board.sdram_base, board.sdram_size
+    ));
+    if board.tcm_size != 0 {
+        out.push_str(&format!(
+            "  ITCM : ORIGIN = 0x{:08X}, LENGTH = 0x{:08X}\n",
+            board.itcm_base, board.tcm_size
+        ));
+        out.push_str(&format!(
+            "  DTCM : ORIGIN = 0x{:08X}, LENGTH = 0x{:08X}\n",
+            board.dtcm_base, board.tcm_size
+        ));
+    }
+    out.push_str("}\n\n");
+
+    // without tcm the tcm sections end up in the ram, so the code still works
+    if board.tcm_size == 0 {
+        out.push_str("REGION_ALIAS(\"ITCM\", RAM);\n");
+        out.push_str("REGION_ALIAS(\"DTCM\", RAM);\n\n");
+    }
+    // the stack grows down to the end of the statics in the same memory, src/stack.rs
+    // puts its guard region there
+    if board.stack_in_dtcm {
+        out.push_str("_stack_start = ORIGIN(DTCM) + LENGTH(DTCM);\n");
+        out.push_str("_stack_limit = _edtcm_bss;\n\n");
+    } else {
+        out.push_str("_stack_limit = __sheap;\n\n");
+    }
+
+    // the layout for the rust code, read them with `extern "C" { static _name: u8; }`
+    let symbols = [
+        ("_board_flash_start", board.flash_base),
+        ("_board_flash_size", board.flash_size),
+        ("_board_bootloader_start", board.flash_base + board.bootloader_offset),
+        ("_board_bootloader_size", board.bootloader_size),
+        ("_board_slot_a_start", board.flash_base + board.slot_a_offset()),
+        ("_board_slot_b_start", board.flash_base + board.slot_b_offset()),
+        ("_board_slot_size", board.slot_size),
+        ("_board_settings_start", board.flash_base + board.settings_offset()),
+        ("_board_settings_size", board.settings_size),
+        ("_board_tcm_size", board.tcm_size),
+        ("_board_sdram_start", board.sdram_base),
+        ("_board_sdram_size", board.sdram_size),
+    ];
+    for (name, value) in symbols.iter() {
+        out.push_str(&format!("{} = 0x{:08X};\n", name, value));
+    }
+    out.push_str("\nINCLUDE sdram.x\n");
+    out.push_str("INCLUDE tcm.x\n");
+    out
+}
+
 fn main() {
-    // Put the linker script somewhere the linker can find it
+    check_layout(&BOARD);
+
+    // Put the linker scripts somewhere the linker can find them
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x(&BOARD).as_bytes())
+        .unwrap();
+    File::create(out.join("sdram.x"))
+        .unwrap()
+        .write_all(include_bytes!("sdram.x"))
+        .unwrap();
+    File::create(out.join("tcm.x"))
+        .unwrap()
+        .write_all(include_bytes!("tcm.x"))
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
-    // Only re-run the build script when memory.x is changed,
-    // instead of when any part of the source code changes.
-    println!("cargo:rerun-if-changed=memory.x");
+    // Only re-run the build script when the board description or a linker
+    // script is changed, instead of when any part of the source code changes.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=board.rs");
+    println!("cargo:rerun-if-changed=sdram.x");
+    println!("cargo:rerun-if-changed=tcm.x");
 }
@@ -26,16 +26,19 @@ extern crate embedded_systems_board_uni_hd as board;
 use cortex_m_rt::entry;
 use atsamx7x_hal::target_device;
 use atsamx7x_hal::gpio::*;
-use atsamx7x_hal::clock_gen::{Clocks, MasterClockConfig, SlckConfig, MainckConfig, PllackConfig, UpllckConfig, SystemClockConfig, MasterDivider, MasterPrescale};
+use atsamx7x_hal::clock_gen::Clocks;
 use atsamx7x_hal::serial::{config, Serial};
-use atsamx7x_hal::time::{MegaHertz, *};
+use atsamx7x_hal::time::*;
 use atsamx7x_hal::delay::Delay;
 use atsamx7x_hal::ebi::{ExternalBusInterface};
 use embedded_hal::blocking::delay::{DelayMs};
 
 use core::fmt::Write;
 
-use board::mem::init_sdram;
+use board::boot;
+use board::bus::BusTimings;
+use board::clocks::ClockPreset;
+use board::mem::{init_sdram, sdram_heap};
 use board::mem::{EbiPins};
 
 #[entry]
@@ -54,19 +57,7 @@ fn main() -> ! {
 	scb.enable_icache();
 	scb.disable_dcache(&mut cpuid);
 
-	peripherals.EFC.eefc_fmr.write( |w| {
-		unsafe {w.fws().bits(5);}
-		w.cloe().set_bit();
-		w.scod().clear_bit()
-	});
-
-	let clocks:Clocks = SystemClockConfig{
-		slck_conf : SlckConfig::default(),
-		mainck_conf : MainckConfig::default().use_crystal(MegaHertz(12).into()).disable_rc(),
-		plla_conf : PllackConfig::default().from_divider(1, 49).startup_cycles(100),
-		upll_conf : UpllckConfig::default().enable(),
-		mck_conf :MasterClockConfig::default().src_pllack().from_divider(MasterPrescale::Pres2, MasterDivider::Div2)
-	}.freeze(&mut pmc, &mut supc);
+	let clocks:Clocks = ClockPreset::Max300MHz.freeze(&BusTimings::new(), &mut pmc, &mut supc, &peripherals.EFC);
 
 	let pioa = peripherals.PIOA.split(&mut pmc);
 	let tx = pioa.p10.into_peripheral_a();
@@ -113,7 +104,8 @@ fn main() -> ! {
 
 	//setup allocator
 	unsafe {
-		HEAP_ALLOCATOR.lock().init(sdram.start_address() as usize, sdram.start_address() as usize + sdram.size() as usize);
+		let (heap_start, heap_size) = sdram_heap(&sdram);
+		HEAP_ALLOCATOR.lock().init(heap_start, heap_size);
 	}
 
 	writeln!(serial, "-----------------------------\r").ok();
@@ -150,6 +142,9 @@ fn main() -> ! {
 	}
 	writeln!(serial, "-----------------------------\r").ok();
 
+	// up and running, the bootloader keeps starting this image
+	boot::mark_booted();
+
 	//enter infinite loop at end
 	loop {
 	}
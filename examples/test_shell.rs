@@ -0,0 +1,162 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
+
+use core::alloc::Layout;
+use cortex_m::asm;
+use linked_list_allocator::LockedHeap;
+
+#[alloc_error_handler]
+fn on_oom(_layout: Layout) -> ! {
+	asm::bkpt();
+
+	loop {}
+}
+
+#[global_allocator]
+static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+extern crate panic_halt;
+extern crate embedded_systems_board_uni_hd as board;
+
+use cortex_m_rt::{entry, exception, ExceptionFrame};
+use atsamx7x_hal::target_device;
+use atsamx7x_hal::target_device::interrupt;
+use atsamx7x_hal::gpio::*;
+use atsamx7x_hal::clock_gen::Clocks;
+use atsamx7x_hal::ebi::{ExternalBusInterface};
+use atsamx7x_hal::smc::Smc;
+use embedded_hal::serial::Read;
+
+use core::fmt::Write;
+
+use board::boot;
+use board::bus::BusTimings;
+use board::clocks::ClockPreset;
+use board::mem::{init_sdram, sdram_heap};
+use board::mem::{EbiPins};
+use board::lcd::setup_lcd;
+use board::shell::Shell;
+use board::leds::Leds;
+use board::stack;
+use board::tcm;
+use board::bootinfo::BootInfo;
+use board::flash::Flash;
+use board::uart::{self, BufferedSerial};
+use board::ident::BoardId;
+use board::shell::commands::{MemCommand, ClocksCommand, HeapCommand, LedCommand, LedAction, LcdCommand, StackCommand, ResetCommand};
+
+#[exception]
+fn MemoryManagement() {
+	stack::memory_fault()
+}
+
+#[exception]
+fn HardFault(ef: &ExceptionFrame) -> ! {
+	stack::hard_fault(ef)
+}
+
+#[interrupt]
+fn UART0() {
+	uart::handle_interrupt();
+}
+
+#[entry]
+fn main() -> ! {
+	// the lcd pixel loops run from the itcm
+	unsafe { tcm::init() };
+	stack::paint();
+
+	let cortex_p = cortex_m::Peripherals::take().unwrap();
+	let peripherals = target_device::Peripherals::take().unwrap();
+
+	let wdt = &peripherals.WDT;
+	wdt.wdt_mr.write( |w| w.wddis().set_bit() );
+
+	let mut pmc = peripherals.PMC;
+	let mut supc = peripherals.SUPC;
+
+	let mut scb = cortex_p.SCB;
+	let mut cpuid = cortex_p.CPUID;
+	scb.enable_icache();
+	scb.disable_dcache(&mut cpuid);
+
+	let mut mpu = cortex_p.MPU;
+	stack::enable_guard(&mut mpu).ok();
+
+	let boot_info = BootInfo::read(&peripherals.RSTC, &supc);
+
+	let clocks:Clocks = ClockPreset::Max300MHz.freeze(&BusTimings::new(), &mut pmc, &mut supc, &peripherals.EFC);
+
+	let pioa = peripherals.PIOA.split(&mut pmc);
+	let tx = pioa.p10.into_peripheral_a();
+	let rx = pioa.p9.into_peripheral_a();
+
+	let mut serial = BufferedSerial::new(
+		peripherals.UART0,
+		(tx, rx),
+		115_200,
+		ClockPreset::Max300MHz.mck_hz(),
+		&mut pmc
+	);
+	writeln!(serial, "{}\r", boot_info).ok();
+
+	let mut flash = Flash::new(peripherals.EFC);
+	match BoardId::read(&mut flash) {
+		Ok(id) => writeln!(serial, "{}\r", id).ok(),
+		Err(e) => writeln!(serial, "identification failed: {:?}\r", e).ok()
+	};
+
+	let pins = EbiPins::default();
+	let ebi = ExternalBusInterface::new(&pins);
+
+	let sdramc = peripherals.SDRAMC;
+	let sdram = init_sdram(&mut pmc, sdramc, &clocks, &ebi);
+	unsafe {
+		let (heap_start, heap_size) = sdram_heap(&sdram);
+		HEAP_ALLOCATOR.lock().init(heap_start, heap_size);
+	}
+
+	let smc = peripherals.SMC;
+	let mut smc = Smc::setup(smc, &ebi, &clocks, &mut pmc);
+	let backlight_pin = pioa.p1.into_peripheral_b();
+	let mut lcd = setup_lcd(&mut smc, backlight_pin);
+
+	let pioc = peripherals.PIOC.split(&mut pmc);
+	let mut leds = Leds::new(pioc.p19.into_open_drain_output(), pioc.p10.into_open_drain_output());
+
+	let mut mem = MemCommand::default();
+	let mut clocks_cmd = ClocksCommand::new(&clocks);
+	let mut heap = HeapCommand::new(&HEAP_ALLOCATOR);
+	let mut lcd_cmd = LcdCommand::new(&mut lcd);
+	let mut stack_cmd = StackCommand;
+	let mut reset = ResetCommand;
+	let mut led = LedCommand::new(|index, action| {
+		match (index, action) {
+			(0, LedAction::On) => leds.led0.on(),
+			(0, LedAction::Off) => leds.led0.off(),
+			(0, LedAction::Toggle) => leds.led0.toggle(),
+			(1, LedAction::On) => leds.led1.on(),
+			(1, LedAction::Off) => leds.led1.off(),
+			(1, LedAction::Toggle) => leds.led1.toggle(),
+			_ => return false
+		}
+		true
+	});
+
+	let mut shell = Shell::new("board> ");
+	writeln!(serial, "Board initialized!\r").ok();
+	shell.prompt(&mut serial);
+
+	// up and running, the bootloader keeps starting this image
+	boot::mark_booted();
+
+	loop {
+		if let Ok(byte) = serial.read() {
+			shell.feed(byte, &mut [&mut mem, &mut clocks_cmd, &mut heap, &mut led, &mut lcd_cmd, &mut stack_cmd, &mut reset], &mut serial);
+		}
+	}
+}
@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate embedded_systems_board_uni_hd as board;
+extern crate panic_halt;
+
+use cortex_m_rt::entry;
+use cortex_m::peripheral::DWT;
+
+use atsamx7x_hal::target_device;
+use atsamx7x_hal::gpio::*;
+use atsamx7x_hal::clock_gen::Clocks;
+use atsamx7x_hal::serial::{config, Serial};
+use atsamx7x_hal::time::*;
+
+use core::fmt::Write;
+
+use board::boot;
+use board::bus::BusTimings;
+use board::clocks::ClockPreset;
+use board::flash::Flash;
+use board::tcm;
+
+const ROUNDS: u32 = 100;
+
+#[link_section = ".dtcm.bss"]
+static mut DTCM_BUFFER: [u32; 1024] = [0; 1024];
+static mut RAM_BUFFER: [u32; 1024] = [0; 1024];
+static mut SINK: u32 = 0;
+
+// the same loop once in flash and once in the itcm
+#[inline(never)]
+fn checksum_flash(data: &mut [u32]) -> u32 {
+	let mut sum = 0u32;
+	for (i, d) in data.iter_mut().enumerate() {
+		*d = d.wrapping_mul(31).wrapping_add(i as u32);
+		sum = sum.rotate_left(3) ^ *d;
+	}
+	sum
+}
+
+itcm! {
+	fn checksum_itcm(data: &mut [u32]) -> u32 {
+		let mut sum = 0u32;
+		for (i, d) in data.iter_mut().enumerate() {
+			*d = d.wrapping_mul(31).wrapping_add(i as u32);
+			sum = sum.rotate_left(3) ^ *d;
+		}
+		sum
+	}
+}
+
+// core clock cycles per call of `f`
+fn measure<F: FnMut() -> u32>(mut f: F) -> u32 {
+	let start = DWT::get_cycle_count();
+	let mut result = 0;
+	for _ in 0..ROUNDS {
+		result ^= f();
+	}
+	cortex_m::asm::dsb();
+	let cycles = DWT::get_cycle_count().wrapping_sub(start) / ROUNDS;
+	// keep the result alive
+	unsafe { core::ptr::write_volatile(&mut SINK, result) };
+	cycles
+}
+
+#[entry]
+fn main() -> ! {
+	unsafe { tcm::init() };
+
+	let mut cortex_p = cortex_m::Peripherals::take().unwrap();
+	let peripherals = target_device::Peripherals::take().unwrap();
+
+	let wdt = &peripherals.WDT;
+	wdt.wdt_mr.write( |w| w.wddis().set_bit() );
+
+	let mut pmc = peripherals.PMC;
+	let mut supc = peripherals.SUPC;
+
+	cortex_p.SCB.enable_icache();
+
+	let clocks:Clocks = ClockPreset::Max300MHz.freeze(&BusTimings::new(), &mut pmc, &mut supc, &peripherals.EFC);
+
+	let pioa = peripherals.PIOA.split(&mut pmc);
+	let tx = pioa.p10.into_peripheral_a();
+	let rx = pioa.p9.into_peripheral_a();
+
+	let mut serial = Serial::uart0(
+		peripherals.UART0,
+		(tx, rx),
+		config::UartConfig::default().baudrate(115_200.bps()),
+		&clocks,
+		&mut pmc
+	).unwrap();
+
+	let mut flash = Flash::new(peripherals.EFC);
+	match tcm::configured_size(&mut flash) {
+		Ok(size) if size == tcm::linked_size() => writeln!(serial, "TCM: {:?}\r", size).unwrap(),
+		Ok(size) => writeln!(serial, "TCM: configured {:?}, linked for {:?}, see board.rs\r", size, tcm::linked_size()).unwrap(),
+		Err(e) => writeln!(serial, "TCM: reading GPNVM failed: {:?}\r", e).unwrap()
+	}
+
+	cortex_p.DCB.enable_trace();
+	cortex_p.DWT.enable_cycle_counter();
+
+	let (ram, dtcm) = unsafe { (&mut RAM_BUFFER, &mut DTCM_BUFFER) };
+	writeln!(serial, "flash + icache, ram data:  {} cycles\r", measure(|| checksum_flash(ram))).unwrap();
+	writeln!(serial, "flash + icache, dtcm data: {} cycles\r", measure(|| checksum_flash(dtcm))).unwrap();
+	writeln!(serial, "itcm, ram data:            {} cycles\r", measure(|| checksum_itcm(ram))).unwrap();
+	writeln!(serial, "itcm, dtcm data:           {} cycles\r", measure(|| checksum_itcm(dtcm))).unwrap();
+
+	// up and running, the bootloader keeps starting this image
+	boot::mark_booted();
+
+	loop {
+	}
+}
@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+extern crate panic_halt;
+extern crate embedded_systems_board_uni_hd as board;
+
+use cortex_m_rt::entry;
+use atsamx7x_hal::target_device;
+use atsamx7x_hal::gpio::*;
+use atsamx7x_hal::clock_gen::Clocks;
+use atsamx7x_hal::serial::{config, Serial};
+use atsamx7x_hal::time::*;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+use core::fmt::Write;
+
+use board::boot;
+use board::bus::BusTimings;
+use board::clocks::ClockPreset;
+use board::flash::Flash;
+use board::image::ImageHeader;
+use board::usb::{UsbBus, DfuClass, USB_VID, USB_PID_DFU};
+use board::usb::dfu::{DfuMode, DfuState};
+use board::usb::dfu_class::SlotTarget;
+use board::update::active_slot;
+
+#[entry]
+fn main() -> ! {
+	let cortex_p = cortex_m::Peripherals::take().unwrap();
+	let peripherals = target_device::Peripherals::take().unwrap();
+
+	let wdt = &peripherals.WDT;
+	wdt.wdt_mr.write( |w| w.wddis().set_bit() );
+
+	let mut pmc = peripherals.PMC;
+	let mut supc = peripherals.SUPC;
+
+	let mut scb = cortex_p.SCB;
+	scb.enable_icache();
+
+	// the preset keeps the upll running
+	let clocks:Clocks = ClockPreset::Max300MHz.freeze(&BusTimings::new(), &mut pmc, &mut supc, &peripherals.EFC);
+
+	let pioa = peripherals.PIOA.split(&mut pmc);
+	let tx = pioa.p10.into_peripheral_a();
+	let rx = pioa.p9.into_peripheral_a();
+
+	let mut serial = Serial::uart0(
+		peripherals.UART0,
+		(tx, rx),
+		config::UartConfig::default().baudrate(115_200.bps()),
+		&clocks,
+		&mut pmc
+	).unwrap();
+
+	let mut flash = Flash::new(peripherals.EFC);
+	let target = SlotTarget::new(&mut flash);
+	writeln!(serial, "running from {:?}, downloads go to slot {:?}\r", active_slot(), target.slot()).ok();
+
+	let bus: &'static UsbBusAllocator<UsbBus> = cortex_m::singleton!(: UsbBusAllocator<UsbBus> =
+		UsbBusAllocator::new(UsbBus::new(peripherals.USBHS, &mut pmc))).unwrap();
+	// start in dfu mode, dfu-util does not have to detach the device first
+	let mut dfu = DfuClass::new(bus, target, DfuMode::Dfu);
+	let mut device = UsbDeviceBuilder::new(bus, UsbVidPid(USB_VID, USB_PID_DFU))
+		.manufacturer("HD Embedded")
+		.product("Board firmware update")
+		.serial_number("0")
+		.max_packet_size_0(64)
+		.build();
+
+	let mut state = dfu.dfu().state();
+
+	// up and running, the bootloader keeps starting this image
+	boot::mark_booted();
+
+	loop {
+		device.poll(&mut [&mut dfu]);
+
+		if dfu.dfu().state() != state {
+			match (state, dfu.dfu().state()) {
+				(DfuState::Idle, DfuState::DownloadSync) => { writeln!(serial, "download started\r").ok(); },
+				(_, DfuState::ManifestWaitReset) => { writeln!(serial, "download complete\r").ok(); },
+				(_, DfuState::Error) => { writeln!(serial, "dfu error {:?}\r", dfu.dfu().status()).ok(); },
+				_ => {}
+			}
+			state = dfu.dfu().state();
+		}
+
+		if dfu.reset_requested() {
+			let slot = dfu.dfu().target().slot();
+			if let Ok(header) = ImageHeader::parse(slot.contents()) {
+				writeln!(serial, "installed version {} in slot {:?}, resetting\r", header.version, slot).ok();
+			}
+			cortex_m::peripheral::SCB::sys_reset();
+		}
+	}
+}
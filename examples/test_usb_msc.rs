@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+extern crate panic_halt;
+extern crate embedded_systems_board_uni_hd as board;
+
+use cortex_m_rt::entry;
+use atsamx7x_hal::target_device;
+use atsamx7x_hal::gpio::*;
+use atsamx7x_hal::clock_gen::Clocks;
+use atsamx7x_hal::serial::{config, Serial};
+use atsamx7x_hal::time::*;
+use atsamx7x_hal::ebi::{ExternalBusInterface};
+use embedded_sdmmc::Controller;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+use core::fmt::Write;
+
+use board::boot;
+use board::bus::BusTimings;
+use board::clocks::ClockPreset;
+use board::mem::{init_sdram, EbiPins};
+use board::ramdisk::{sdram_disk, write_file, FixedTime};
+use board::usb::{UsbBus, MassStorage, USB_VID, USB_PID_MASS_STORAGE};
+use board::usb::scsi::Scsi;
+
+const DISK_SIZE: usize = 8 * 1024 * 1024;
+
+#[entry]
+fn main() -> ! {
+	let cortex_p = cortex_m::Peripherals::take().unwrap();
+	let peripherals = target_device::Peripherals::take().unwrap();
+
+	let wdt = &peripherals.WDT;
+	wdt.wdt_mr.write( |w| w.wddis().set_bit() );
+
+	let mut pmc = peripherals.PMC;
+	let mut supc = peripherals.SUPC;
+
+	let mut scb = cortex_p.SCB;
+	let mut cpuid = cortex_p.CPUID;
+	scb.enable_icache();
+	scb.disable_dcache(&mut cpuid);
+
+	let clocks:Clocks = ClockPreset::Max300MHz.freeze(&BusTimings::new(), &mut pmc, &mut supc, &peripherals.EFC);
+
+	let pioa = peripherals.PIOA.split(&mut pmc);
+	let tx = pioa.p10.into_peripheral_a();
+	let rx = pioa.p9.into_peripheral_a();
+
+	let mut serial = Serial::uart0(
+		peripherals.UART0,
+		(tx, rx),
+		config::UartConfig::default().baudrate(115_200.bps()),
+		&clocks,
+		&mut pmc
+	).unwrap();
+
+	let pins = EbiPins::default();
+	let ebi = ExternalBusInterface::new(&pins);
+	let sdram = init_sdram(&mut pmc, peripherals.SDRAMC, &clocks, &ebi);
+
+	// the disk takes the end of the sdram, a file tells the host where it comes from
+	let mut disk = unsafe { sdram_disk(&sdram, sdram.size() as usize - DISK_SIZE, DISK_SIZE) };
+	disk.format("BOARD").unwrap();
+	let mut fs = Controller::new(disk, FixedTime::default());
+	write_file(&mut fs, "README.TXT", b"RAM disk in the SDRAM of the board, its contents are lost on reset.\r\n").unwrap();
+	let (disk, _) = fs.free();
+
+	let bus: &'static UsbBusAllocator<UsbBus> = cortex_m::singleton!(: UsbBusAllocator<UsbBus> =
+		UsbBusAllocator::new(UsbBus::new(peripherals.USBHS, &mut pmc))).unwrap();
+	let mut msc = MassStorage::new(bus, Scsi::new(disk).identity("HD", "SDRAM disk"), 64);
+	let mut device = UsbDeviceBuilder::new(bus, UsbVidPid(USB_VID, USB_PID_MASS_STORAGE))
+		.manufacturer("HD Embedded")
+		.product("Board RAM disk")
+		.serial_number("0")
+		.max_packet_size_0(64)
+		.build();
+
+	writeln!(serial, "RAM disk of {} KiB on usb\r", DISK_SIZE / 1024).ok();
+	let mut ejected = false;
+
+	// up and running, the bootloader keeps starting this image
+	boot::mark_booted();
+
+	loop {
+		device.poll(&mut [&mut msc]);
+
+		// the host has unmounted the disk, the board could use it now
+		if msc.scsi().is_ejected() != ejected {
+			ejected = msc.scsi().is_ejected();
+			writeln!(serial, "disk {}\r", if ejected { "ejected" } else { "inserted" }).ok();
+		}
+	}
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+extern crate panic_halt;
+extern crate embedded_systems_board_uni_hd as board;
+
+use cortex_m_rt::entry;
+use atsamx7x_hal::target_device;
+use atsamx7x_hal::clock_gen::Clocks;
+use embedded_hal::serial::Read;
+use usb_device::bus::UsbBusAllocator;
+
+use core::fmt::Write;
+
+use board::boot;
+use board::bus::BusTimings;
+use board::clocks::ClockPreset;
+use board::flash::Flash;
+use board::ident::{BoardId, SerialNumber};
+use board::shell::Shell;
+use board::shell::commands::{MemCommand, ClocksCommand, StackCommand, ResetCommand};
+use board::stack;
+use board::usb::{UsbBus, UsbSerial};
+
+#[entry]
+fn main() -> ! {
+	stack::paint();
+
+	let cortex_p = cortex_m::Peripherals::take().unwrap();
+	let peripherals = target_device::Peripherals::take().unwrap();
+
+	let wdt = &peripherals.WDT;
+	wdt.wdt_mr.write( |w| w.wddis().set_bit() );
+
+	let mut pmc = peripherals.PMC;
+	let mut supc = peripherals.SUPC;
+
+	let mut scb = cortex_p.SCB;
+	scb.enable_icache();
+
+	// the preset keeps the upll running
+	let clocks:Clocks = ClockPreset::Max300MHz.freeze(&BusTimings::new(), &mut pmc, &mut supc, &peripherals.EFC);
+
+	let mut flash = Flash::new(peripherals.EFC);
+	let id = BoardId::read(&mut flash).ok();
+	// the usb device keeps the serial number
+	let serial_number: &'static str = match id {
+		Some(id) => {
+			let serial: &'static SerialNumber = cortex_m::singleton!(: SerialNumber = id.serial()).unwrap();
+			serial.as_str()
+		},
+		None => "unknown"
+	};
+
+	let bus: &'static UsbBusAllocator<UsbBus> = cortex_m::singleton!(: UsbBusAllocator<UsbBus> =
+		UsbBusAllocator::new(UsbBus::new(peripherals.USBHS, &mut pmc))).unwrap();
+	let mut console = UsbSerial::new(bus, serial_number);
+
+	let mut mem = MemCommand::default();
+	let mut clocks_cmd = ClocksCommand::new(&clocks);
+	let mut stack_cmd = StackCommand;
+	let mut reset = ResetCommand;
+
+	let mut shell = Shell::new("usb> ");
+	let mut connected = false;
+
+	// up and running, the bootloader keeps starting this image
+	boot::mark_booted();
+
+	loop {
+		if let Ok(byte) = console.read() {
+			shell.feed(byte, &mut [&mut mem, &mut clocks_cmd, &mut stack_cmd, &mut reset], &mut console);
+		}
+
+		// greet every terminal that opens the port
+		if console.is_connected() != connected {
+			connected = console.is_connected();
+			if connected {
+				if let Some(id) = id {
+					writeln!(console, "{}\r", id).ok();
+				}
+				shell.prompt(&mut console);
+			}
+		}
+	}
+}
@@ -0,0 +1,27 @@
+/* Statics in the external sdram, declared with
+   #[link_section = ".sdram.bss"] (zeroed) or #[link_section = ".sdram.data"]
+   (initialised from flash). The sdram is not accessible before the controller is
+   set up, so these sections are initialised by `mem::init_sdram` instead of the
+   runtime. Everything behind _esdram is free, e.g. for the heap. */
+SECTIONS
+{
+  .sdram.data : ALIGN(4)
+  {
+    _ssdram_data = .;
+    *(.sdram.data .sdram.data.*);
+    . = ALIGN(4);
+    _esdram_data = .;
+  } > SDRAM AT > FLASH
+
+  _sisdram_data = LOADADDR(.sdram.data);
+
+  .sdram.bss (NOLOAD) : ALIGN(4)
+  {
+    _ssdram_bss = .;
+    *(.sdram.bss .sdram.bss.*);
+    . = ALIGN(4);
+    _esdram_bss = .;
+  } > SDRAM
+
+  _esdram = .;
+} INSERT AFTER .bss;
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+extern crate panic_halt;
+extern crate embedded_systems_board_uni_hd as board;
+
+use cortex_m_rt::entry;
+use atsamx7x_hal::target_device;
+
+use board::boot::{self, BootDecision, BootState};
+use board::flash::{Flash, PAGE_SIZE};
+use board::image::Slot;
+
+#[entry]
+fn main() -> ! {
+	let peripherals = target_device::Peripherals::take().unwrap();
+	// the watchdog mode register can only be written once after reset,
+	// so it is left untouched for the application to configure
+
+	let mut flash = Flash::new(peripherals.EFC);
+	let mut state = BootState::load();
+
+	let mut images = [
+		Slot::A.check(Slot::A.contents()).ok(),
+		Slot::B.check(Slot::B.contents()).ok()
+	];
+
+	loop {
+		match boot::select(images, state) {
+			BootDecision::Boot(slot, new_state) => {
+				new_state.store();
+				cortex_m::interrupt::disable();
+				unsafe { boot::jump(slot.entry()) }
+			},
+			BootDecision::Invalidate(slot) => {
+				flash.erase(slot.offset(), 8 * PAGE_SIZE).ok();
+				images[slot as usize] = None;
+				state = BootState::default();
+			},
+			BootDecision::NoImage => {
+				// nothing to start, wait for the debugger
+				loop {
+					cortex_m::asm::wfi();
+				}
+			}
+		}
+	}
+}
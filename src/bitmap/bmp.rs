@@ -0,0 +1,365 @@
+// windows bitmaps: 1, 4, 8 bit palette, 16 and 32 bit with bitfields, 24 bit and
+// rle4/rle8 compression
+
+use crate::lcd::rgb565;
+
+use super::{emit_row, read_u16_le, read_u32_le, DecodeError, PixelTarget, Placement, MAX_WIDTH};
+
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+
+struct Header {
+	width: u32,
+	height: u32,
+	top_down: bool,
+	bpp: u16,
+	compression: u32,
+	data: usize,
+	masks: [u32; 3]
+}
+
+fn parse_header(data: &[u8]) -> Result<Header, DecodeError> {
+	if data.get(0..2) != Some(&b"BM"[..]) {
+		return Err(DecodeError::Format);
+	}
+	let offset = read_u32_le(data, 10)? as usize;
+	let dib = read_u32_le(data, 14)?;
+	// os/2 core headers are not supported
+	if dib < 40 {
+		return Err(DecodeError::Unsupported);
+	}
+	let width = read_u32_le(data, 18)? as i32;
+	let height = read_u32_le(data, 22)? as i32;
+	let bpp = read_u16_le(data, 28)?;
+	let compression = read_u32_le(data, 30)?;
+	if width <= 0 || height == 0 {
+		return Err(DecodeError::Format);
+	}
+	if width as usize > MAX_WIDTH {
+		return Err(DecodeError::TooWide);
+	}
+
+	let masks = match (compression, bpp) {
+		(BI_BITFIELDS, 16) | (BI_BITFIELDS, 32) => [read_u32_le(data, 54)?, read_u32_le(data, 58)?, read_u32_le(data, 62)?],
+		(BI_RGB, 16) => [0x7C00, 0x03E0, 0x001F],
+		(BI_RGB, 32) => [0x00FF_0000, 0x0000_FF00, 0x0000_00FF],
+		(BI_RGB, 1) | (BI_RGB, 4) | (BI_RGB, 8) | (BI_RGB, 24) | (BI_RLE8, 8) | (BI_RLE4, 4) => [0; 3],
+		_ => return Err(DecodeError::Unsupported)
+	};
+
+	Ok(Header {
+		width: width as u32,
+		height: (height as i64).abs() as u32,
+		top_down: height < 0,
+		bpp: bpp,
+		compression: compression,
+		data: offset,
+		masks: masks
+	})
+}
+
+// width and height in pixels
+pub fn bmp_size(data: &[u8]) -> Result<(u32, u32), DecodeError> {
+	parse_header(data).map(|h| (h.width, h.height))
+}
+
+// scale the bits selected by `mask` to 8 bits
+fn channel(value: u32, mask: u32) -> u8 {
+	if mask == 0 {
+		return 0;
+	}
+	let bits = mask.count_ones();
+	let v = (value & mask) >> mask.trailing_zeros();
+	if bits >= 8 {
+		(v >> (bits - 8)) as u8
+	} else {
+		// replicate the top bits into the missing low ones
+		let v = v << (8 - bits);
+		(v | (v >> bits)) as u8
+	}
+}
+
+fn palette(data: &[u8], header: &Header) -> Result<[u16; 256], DecodeError> {
+	let mut palette = [0u16; 256];
+	if header.bpp > 8 {
+		return Ok(palette);
+	}
+	let used = read_u32_le(data, 46)? as usize;
+	let count = if used == 0 || used > 1 << header.bpp { 1 << header.bpp } else { used };
+	let start = 14 + read_u32_le(data, 14)? as usize;
+	let entries = data.get(start..start + count * 4).ok_or(DecodeError::Truncated)?;
+	for (p, e) in palette.iter_mut().zip(entries.chunks(4)) {
+		*p = rgb565(e[2], e[1], e[0]);
+	}
+	Ok(palette)
+}
+
+// draw a bmp image, returns its size
+pub fn draw_bmp<T: PixelTarget>(data: &[u8], target: &mut T, placement: Placement) -> Result<(u32, u32), DecodeError> {
+	let header = parse_header(data)?;
+	let palette = palette(data, &header)?;
+	match header.compression {
+		BI_RLE8 | BI_RLE4 => draw_rle(data, &header, &palette, target, &placement)?,
+		_ => draw_uncompressed(data, &header, &palette, target, &placement)?
+	}
+	Ok((header.width, header.height))
+}
+
+fn draw_uncompressed<T: PixelTarget>(data: &[u8], header: &Header, palette: &[u16; 256], target: &mut T, placement: &Placement) -> Result<(), DecodeError> {
+	let width = header.width as usize;
+	let stride = (width * header.bpp as usize + 31) / 32 * 4;
+	let mut line = [0u16; MAX_WIDTH];
+
+	for row in 0..header.height {
+		let start = header.data + row as usize * stride;
+		let src = data.get(start..start + stride).ok_or(DecodeError::Truncated)?;
+		for (x, p) in line[..width].iter_mut().enumerate() {
+			*p = match header.bpp {
+				1 => palette[((src[x / 8] >> (7 - x % 8)) & 0x1) as usize],
+				4 => palette[((src[x / 2] >> (4 - x % 2 * 4)) & 0xF) as usize],
+				8 => palette[src[x] as usize],
+				16 => {
+					let v = u16::from_le_bytes([src[x * 2], src[x * 2 + 1]]) as u32;
+					rgb565(channel(v, header.masks[0]), channel(v, header.masks[1]), channel(v, header.masks[2]))
+				},
+				24 => rgb565(src[x * 3 + 2], src[x * 3 + 1], src[x * 3]),
+				_ => {
+					let v = u32::from_le_bytes([src[x * 4], src[x * 4 + 1], src[x * 4 + 2], src[x * 4 + 3]]);
+					rgb565(channel(v, header.masks[0]), channel(v, header.masks[1]), channel(v, header.masks[2]))
+				}
+			};
+		}
+		let y = if header.top_down { row } else { header.height - 1 - row };
+		emit_row(target, placement, y, &line[..width]);
+	}
+	Ok(())
+}
+
+// pixels skipped by a delta or the end of line/bitmap escapes get palette entry 0
+fn draw_rle<T: PixelTarget>(data: &[u8], header: &Header, palette: &[u16; 256], target: &mut T, placement: &Placement) -> Result<(), DecodeError> {
+	let width = header.width as usize;
+	let rle4 = header.compression == BI_RLE4;
+	let mut line = [palette[0]; MAX_WIDTH];
+	let mut row = 0u32;
+	let mut x = 0usize;
+	let mut pos = header.data;
+
+	let next = |pos: &mut usize| -> Result<u8, DecodeError> {
+		let b = *data.get(*pos).ok_or(DecodeError::Truncated)?;
+		*pos += 1;
+		Ok(b)
+	};
+
+	// rle bitmaps are always stored bottom up
+	let mut finish_row = |row: &mut u32, x: &mut usize, line: &mut [u16; MAX_WIDTH]| {
+		if *row < header.height {
+			emit_row(target, placement, header.height - 1 - *row, &line[..width]);
+		}
+		*row += 1;
+		*x = 0;
+		for p in line.iter_mut() {
+			*p = palette[0];
+		}
+	};
+
+	while row < header.height {
+		let count = next(&mut pos)? as usize;
+		let value = next(&mut pos)?;
+		if count > 0 {
+			// encoded run, rle4 alternates between the two nibbles
+			for i in 0..count {
+				let index = if rle4 { if i % 2 == 0 { value >> 4 } else { value & 0xF } } else { value };
+				if x < width {
+					line[x] = palette[index as usize];
+				}
+				x += 1;
+			}
+			continue;
+		}
+		match value {
+			0 => finish_row(&mut row, &mut x, &mut line),
+			1 => {
+				while row < header.height {
+					finish_row(&mut row, &mut x, &mut line);
+				}
+			},
+			2 => {
+				let dx = next(&mut pos)? as usize;
+				let dy = next(&mut pos)?;
+				let column = x + dx;
+				for _ in 0..dy {
+					finish_row(&mut row, &mut x, &mut line);
+				}
+				x = column;
+			},
+			count => {
+				// absolute run, padded to 16 bits
+				let count = count as usize;
+				let bytes = if rle4 { (count + 1) / 2 } else { count };
+				let src = data.get(pos..pos + bytes).ok_or(DecodeError::Truncated)?;
+				for i in 0..count {
+					let index = if rle4 { (src[i / 2] >> (4 - i % 2 * 4)) & 0xF } else { src[i] };
+					if x < width {
+						line[x] = palette[index as usize];
+					}
+					x += 1;
+				}
+				pos += bytes + bytes % 2;
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::bitmap::Framebuffer;
+
+	// 3x2, 24 bit, bottom up: red green blue / white black #123456
+	const RGB24: &[u8] = &[
+		b'B', b'M', 0x4E, 0, 0, 0, 0, 0, 0, 0, 0x36, 0, 0, 0,
+		0x28, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 1, 0, 24, 0,
+		0, 0, 0, 0, 0x18, 0, 0, 0, 0x13, 0x0B, 0, 0, 0x13, 0x0B, 0, 0,
+		0, 0, 0, 0, 0, 0, 0, 0,
+		0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x56, 0x34, 0x12, 0, 0, 0,
+		0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0x00, 0, 0, 0
+	];
+
+	// header for the other fixtures, the palette follows the 40 byte info header
+	// and the masks, a negative height is stored top down
+	fn bmp(width: i32, height: i32, bpp: u16, compression: u32, masks: &[u32], palette: &[[u8; 3]], pixels: &[u8]) -> Vec<u8> {
+		let offset = 54 + masks.len() * 4 + palette.len() * 4;
+		let mut data = Vec::new();
+		data.extend_from_slice(b"BM");
+		data.extend_from_slice(&((offset + pixels.len()) as u32).to_le_bytes());
+		data.extend_from_slice(&[0; 4]);
+		data.extend_from_slice(&(offset as u32).to_le_bytes());
+		data.extend_from_slice(&40u32.to_le_bytes());
+		data.extend_from_slice(&width.to_le_bytes());
+		data.extend_from_slice(&height.to_le_bytes());
+		data.extend_from_slice(&1u16.to_le_bytes());
+		data.extend_from_slice(&bpp.to_le_bytes());
+		data.extend_from_slice(&compression.to_le_bytes());
+		data.extend_from_slice(&(pixels.len() as u32).to_le_bytes());
+		data.extend_from_slice(&[0; 8]);
+		data.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+		data.extend_from_slice(&[0; 4]);
+		for m in masks {
+			data.extend_from_slice(&m.to_le_bytes());
+		}
+		for [r, g, b] in palette {
+			data.extend_from_slice(&[*b, *g, *r, 0]);
+		}
+		data.extend_from_slice(pixels);
+		data
+	}
+
+	fn decode(data: &[u8], width: u16, height: u16) -> Vec<u16> {
+		let mut pixels = vec![0xDEAD; width as usize * height as usize];
+		let mut fb = Framebuffer::new(&mut pixels, width, height);
+		assert_eq!(draw_bmp(data, &mut fb, Placement::default()), Ok((width as u32, height as u32)));
+		pixels
+	}
+
+	const RED: u16 = rgb565(0xFF, 0, 0);
+	const GREEN: u16 = rgb565(0, 0xFF, 0);
+	const BLUE: u16 = rgb565(0, 0, 0xFF);
+	const WHITE: u16 = 0xFFFF;
+	const BLACK: u16 = 0;
+
+	#[test]
+	fn rgb24() {
+		assert_eq!(bmp_size(RGB24), Ok((3, 2)));
+		assert_eq!(decode(RGB24, 3, 2), vec![RED, GREEN, BLUE, WHITE, BLACK, rgb565(0x12, 0x34, 0x56)]);
+	}
+
+	#[test]
+	fn palette_8_top_down() {
+		let palette = [[0, 0, 0], [0xFF, 0, 0], [0, 0xFF, 0], [0, 0, 0xFF]];
+		let data = bmp(2, -2, 8, BI_RGB, &[], &palette, &[1, 2, 0, 0, 3, 0, 0, 0]);
+		assert_eq!(decode(&data, 2, 2), vec![RED, GREEN, BLUE, BLACK]);
+	}
+
+	#[test]
+	fn palette_1_and_4() {
+		let palette = [[0, 0, 0], [0xFF, 0xFF, 0xFF]];
+		let data = bmp(10, 1, 1, BI_RGB, &[], &palette, &[0b1010_0000, 0b0100_0000, 0, 0]);
+		let mut expected = vec![BLACK; 10];
+		expected[0] = WHITE;
+		expected[2] = WHITE;
+		expected[9] = WHITE;
+		assert_eq!(decode(&data, 10, 1), expected);
+
+		let palette = [[0, 0, 0], [0xFF, 0, 0], [0, 0xFF, 0]];
+		let data = bmp(3, 1, 4, BI_RGB, &[], &palette, &[0x21, 0x00, 0, 0]);
+		assert_eq!(decode(&data, 3, 1), vec![GREEN, RED, BLACK]);
+	}
+
+	#[test]
+	fn bitfields_16_and_32() {
+		let pixels = [0x00, 0xF8, 0xE0, 0x07, 0x1F, 0x00, 0x00, 0x00];
+		let data = bmp(4, 1, 16, BI_BITFIELDS, &[0xF800, 0x07E0, 0x001F], &[], &pixels);
+		assert_eq!(decode(&data, 4, 1), vec![RED, GREEN, BLUE, BLACK]);
+
+		// 5-5-5 without bitfields
+		let data = bmp(2, 1, 16, BI_RGB, &[], &[], &[0x00, 0x7C, 0xFF, 0x7F]);
+		assert_eq!(decode(&data, 2, 1), vec![RED, WHITE]);
+
+		let data = bmp(2, 1, 32, BI_RGB, &[], &[], &[0x56, 0x34, 0x12, 0xFF, 0xFF, 0x00, 0x00, 0x00]);
+		assert_eq!(decode(&data, 2, 1), vec![rgb565(0x12, 0x34, 0x56), BLUE]);
+	}
+
+	#[test]
+	fn rle8_delta_and_absolute() {
+		let palette = [[0, 0, 0], [0xFF, 0, 0], [0, 0xFF, 0], [0, 0, 0xFF]];
+		let pixels = [
+			// bottom row: a run of two, then one row up at the same column
+			2, 1, 0, 2, 0, 1,
+			// middle row: absolute run of 3 (padded), the last one is cut off, end of line
+			0, 3, 3, 2, 1, 0, 0, 0,
+			// top row: end of bitmap
+			0, 1
+		];
+		let data = bmp(4, 3, 8, BI_RLE8, &[], &palette, &pixels);
+		assert_eq!(decode(&data, 4, 3), vec![
+			BLACK, BLACK, BLACK, BLACK,
+			BLACK, BLACK, BLUE, GREEN,
+			RED, RED, BLACK, BLACK
+		]);
+	}
+
+	#[test]
+	fn rle4() {
+		let palette = [[0, 0, 0], [0xFF, 0, 0], [0, 0xFF, 0], [0, 0, 0xFF]];
+		let pixels = [
+			// bottom row: run of 4 alternating nibbles
+			4, 0x12, 0, 0,
+			// top row: absolute run of 3 nibbles in 2 bytes
+			0, 3, 0x31, 0x20, 0, 1
+		];
+		let data = bmp(4, 2, 4, BI_RLE4, &[], &palette, &pixels);
+		assert_eq!(decode(&data, 4, 2), vec![BLUE, RED, GREEN, BLACK, RED, GREEN, RED, GREEN]);
+	}
+
+	#[test]
+	fn errors() {
+		let mut pixels = vec![0u16; 16];
+		let mut fb = Framebuffer::new(&mut pixels, 4, 4);
+		let placement = Placement::default();
+		assert_eq!(draw_bmp(b"PNG", &mut fb, placement), Err(DecodeError::Format));
+		assert_eq!(draw_bmp(&RGB24[..60], &mut fb, placement), Err(DecodeError::Truncated));
+		assert_eq!(draw_bmp(&bmp(0, 1, 24, BI_RGB, &[], &[], &[]), &mut fb, placement), Err(DecodeError::Format));
+		assert_eq!(draw_bmp(&bmp(MAX_WIDTH as i32 + 1, 1, 24, BI_RGB, &[], &[], &[]), &mut fb, placement), Err(DecodeError::TooWide));
+		assert_eq!(draw_bmp(&bmp(1, 1, 2, BI_RGB, &[], &[], &[0; 4]), &mut fb, placement), Err(DecodeError::Unsupported));
+		assert_eq!(draw_bmp(&bmp(1, 1, 24, BI_RLE8, &[], &[], &[0; 4]), &mut fb, placement), Err(DecodeError::Unsupported));
+		let mut core = RGB24.to_vec();
+		core[14] = 12;
+		assert_eq!(draw_bmp(&core, &mut fb, placement), Err(DecodeError::Unsupported));
+		// rle data ending before the end of bitmap escape
+		let data = bmp(2, 2, 8, BI_RLE8, &[], &[[0, 0, 0]], &[2, 0, 0, 0]);
+		assert_eq!(draw_bmp(&data, &mut fb, placement), Err(DecodeError::Truncated));
+	}
+}
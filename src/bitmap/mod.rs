@@ -0,0 +1,235 @@
+// decoders for bmp and qoi images
+//
+// the decoders produce one rgb565 scanline at a time and hand it to a `PixelTarget`,
+// so an image never has to fit into memory decoded. scanlines are scaled by an
+// integer factor and clipped against the target before they are written.
+
+use crate::lcd::{LCD, LCD_WIDTH, LCD_HEIGHT};
+
+mod bmp;
+mod qoi;
+
+pub use self::bmp::{bmp_size, draw_bmp};
+pub use self::qoi::{qoi_size, draw_qoi};
+
+// widest image the decoders accept, one scanline is kept on the stack
+pub const MAX_WIDTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+	// not an image of the expected format
+	Format,
+	// valid image using a feature the decoder does not implement
+	Unsupported,
+	Truncated,
+	TooWide
+}
+
+// something rgb565 scanlines can be drawn to
+pub trait PixelTarget {
+	fn width(&self) -> u16;
+
+	fn height(&self) -> u16;
+
+	// `pixels` lie completely inside the target
+	fn write_row(&mut self, x: u16, y: u16, pixels: &[u16]);
+}
+
+impl PixelTarget for LCD {
+	fn width(&self) -> u16 {
+		LCD_WIDTH
+	}
+
+	fn height(&self) -> u16 {
+		LCD_HEIGHT
+	}
+
+	fn write_row(&mut self, x: u16, y: u16, pixels: &[u16]) {
+		self.set_window(x, y, x + pixels.len() as u16 - 1, y);
+		self.write_pixel_slice(pixels);
+	}
+}
+
+// rgb565 frame buffer, e.g. in the sdram, rows are stored without padding
+pub struct Framebuffer<'a> {
+	pixels: &'a mut [u16],
+	width: u16,
+	height: u16
+}
+
+impl<'a> Framebuffer<'a> {
+	pub fn new(pixels: &'a mut [u16], width: u16, height: u16) -> Framebuffer<'a> {
+		assert!(pixels.len() >= width as usize * height as usize);
+		Framebuffer {
+			pixels: pixels,
+			width: width,
+			height: height
+		}
+	}
+
+	pub fn pixels(&self) -> &[u16] {
+		&self.pixels[..self.width as usize * self.height as usize]
+	}
+
+	pub fn pixel(&self, x: u16, y: u16) -> u16 {
+		self.pixels[y as usize * self.width as usize + x as usize]
+	}
+
+	pub fn fill(&mut self, color: u16) {
+		for p in self.pixels.iter_mut() {
+			*p = color;
+		}
+	}
+
+	// copy the frame buffer to the lcd, starting at its top left corner
+	pub fn flush(&self, lcd: &mut LCD) {
+		let width = core::cmp::min(self.width, LCD_WIDTH);
+		let height = core::cmp::min(self.height, LCD_HEIGHT);
+		if width == 0 || height == 0 {
+			return;
+		}
+		lcd.set_window(0, 0, width - 1, height - 1);
+		for row in self.pixels.chunks(self.width as usize).take(height as usize) {
+			lcd.write_pixel_slice(&row[..width as usize]);
+		}
+	}
+}
+
+impl<'a> PixelTarget for Framebuffer<'a> {
+	fn width(&self) -> u16 {
+		self.width
+	}
+
+	fn height(&self) -> u16 {
+		self.height
+	}
+
+	fn write_row(&mut self, x: u16, y: u16, pixels: &[u16]) {
+		let start = y as usize * self.width as usize + x as usize;
+		self.pixels[start..start + pixels.len()].copy_from_slice(pixels);
+	}
+}
+
+// where and how large an image is drawn, the position may lie outside the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+	pub x: i32,
+	pub y: i32,
+	pub scale: u8
+}
+
+impl Placement {
+	pub fn at(x: i32, y: i32) -> Placement {
+		Placement {
+			x: x,
+			y: y,
+			scale: 1
+		}
+	}
+
+	pub fn scale(mut self, scale: u8) -> Self {
+		assert!(scale > 0);
+		self.scale = scale;
+		self
+	}
+}
+
+impl Default for Placement {
+	fn default() -> Placement {
+		Placement::at(0, 0)
+	}
+}
+
+// scale and clip the decoded scanline `src_y` of an image and write it to `target`
+fn emit_row<T: PixelTarget>(target: &mut T, placement: &Placement, src_y: u32, row: &[u16]) {
+	let scale = placement.scale as i32;
+	let width = target.width() as i32;
+	let height = target.height() as i32;
+
+	// visible part of the scaled row, in target coordinates
+	let x0 = core::cmp::max(placement.x, 0);
+	let x1 = core::cmp::min(placement.x + row.len() as i32 * scale, width);
+	if x0 >= x1 {
+		return;
+	}
+
+	let mut line = [0u16; 64];
+	for r in 0..scale {
+		let y = placement.y + src_y as i32 * scale + r;
+		if y < 0 || y >= height {
+			continue;
+		}
+		let mut x = x0;
+		while x < x1 {
+			let count = core::cmp::min((x1 - x) as usize, line.len());
+			for (i, p) in line[..count].iter_mut().enumerate() {
+				*p = row[((x + i as i32 - placement.x) / scale) as usize];
+			}
+			target.write_row(x as u16, y as u16, &line[..count]);
+			x += count as i32;
+		}
+	}
+}
+
+fn read_u16_le(data: &[u8], offset: usize) -> Result<u16, DecodeError> {
+	data.get(offset..offset + 2)
+		.map(|b| u16::from_le_bytes([b[0], b[1]]))
+		.ok_or(DecodeError::Truncated)
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> Result<u32, DecodeError> {
+	data.get(offset..offset + 4)
+		.map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+		.ok_or(DecodeError::Truncated)
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Result<u32, DecodeError> {
+	data.get(offset..offset + 4)
+		.map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+		.ok_or(DecodeError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rows(fb: &Framebuffer) -> Vec<Vec<u16>> {
+		fb.pixels().chunks(fb.width() as usize).map(|r| r.to_vec()).collect()
+	}
+
+	#[test]
+	fn scale_and_clip() {
+		let mut pixels = vec![0u16; 5 * 4];
+		let mut fb = Framebuffer::new(&mut pixels, 5, 4);
+		let placement = Placement::at(-1, 1).scale(2);
+		emit_row(&mut fb, &placement, 0, &[1, 2, 3]);
+		emit_row(&mut fb, &placement, 1, &[4, 5, 6]);
+		assert_eq!(rows(&fb), vec![
+			vec![0, 0, 0, 0, 0],
+			vec![1, 2, 2, 3, 3],
+			vec![1, 2, 2, 3, 3],
+			vec![4, 5, 5, 6, 6]
+		]);
+	}
+
+	#[test]
+	fn outside_the_target() {
+		let mut pixels = vec![0u16; 4];
+		let mut fb = Framebuffer::new(&mut pixels, 2, 2);
+		emit_row(&mut fb, &Placement::at(2, 0), 0, &[1]);
+		emit_row(&mut fb, &Placement::at(-1, 0), 0, &[1]);
+		emit_row(&mut fb, &Placement::at(0, -1), 0, &[1]);
+		emit_row(&mut fb, &Placement::at(0, 2), 0, &[1]);
+		assert!(fb.pixels().iter().all(|p| *p == 0));
+	}
+
+	#[test]
+	fn long_rows_are_split() {
+		let row: Vec<u16> = (0..100).collect();
+		let mut pixels = vec![0u16; 300];
+		let mut fb = Framebuffer::new(&mut pixels, 150, 2);
+		emit_row(&mut fb, &Placement::at(70, 1), 0, &row);
+		assert!(fb.pixels()[..220].iter().all(|p| *p == 0));
+		assert_eq!(&fb.pixels()[220..], &row[..80]);
+	}
+}
@@ -0,0 +1,174 @@
+// "quite ok image" format, see https://qoiformat.org/qoi-specification.pdf
+// the alpha channel is decoded but ignored, images are drawn opaque
+
+use crate::lcd::rgb565;
+
+use super::{emit_row, read_u32_be, DecodeError, PixelTarget, Placement, MAX_WIDTH};
+
+const HEADER_SIZE: usize = 14;
+
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+const OP_MASK: u8 = 0xC0;
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Rgba {
+	r: u8,
+	g: u8,
+	b: u8,
+	a: u8
+}
+
+impl Rgba {
+	fn hash(&self) -> usize {
+		(self.r as usize * 3 + self.g as usize * 5 + self.b as usize * 7 + self.a as usize * 11) % 64
+	}
+}
+
+// width and height in pixels
+pub fn qoi_size(data: &[u8]) -> Result<(u32, u32), DecodeError> {
+	if data.get(0..4) != Some(&b"qoif"[..]) {
+		return Err(DecodeError::Format);
+	}
+	let width = read_u32_be(data, 4)?;
+	let height = read_u32_be(data, 8)?;
+	if data.len() < HEADER_SIZE {
+		return Err(DecodeError::Truncated);
+	}
+	if width == 0 || height == 0 {
+		return Err(DecodeError::Format);
+	}
+	Ok((width, height))
+}
+
+// draw a qoi image, returns its size
+pub fn draw_qoi<T: PixelTarget>(data: &[u8], target: &mut T, placement: Placement) -> Result<(u32, u32), DecodeError> {
+	let (width, height) = qoi_size(data)?;
+	if width as usize > MAX_WIDTH {
+		return Err(DecodeError::TooWide);
+	}
+
+	let mut line = [0u16; MAX_WIDTH];
+	let mut index = [Rgba { r: 0, g: 0, b: 0, a: 0 }; 64];
+	let mut px = Rgba { r: 0, g: 0, b: 0, a: 255 };
+	let mut run = 0u8;
+	let mut pos = HEADER_SIZE;
+
+	let mut next = || -> Result<u8, DecodeError> {
+		let b = *data.get(pos).ok_or(DecodeError::Truncated)?;
+		pos += 1;
+		Ok(b)
+	};
+
+	for y in 0..height {
+		for p in line[..width as usize].iter_mut() {
+			if run > 0 {
+				run -= 1;
+			} else {
+				let op = next()?;
+				match op {
+					OP_RGB => {
+						px.r = next()?;
+						px.g = next()?;
+						px.b = next()?;
+					},
+					OP_RGBA => {
+						px.r = next()?;
+						px.g = next()?;
+						px.b = next()?;
+						px.a = next()?;
+					},
+					_ => match op & OP_MASK {
+						OP_INDEX => px = index[(op & 0x3F) as usize],
+						OP_DIFF => {
+							px.r = px.r.wrapping_add((op >> 4) & 0x3).wrapping_sub(2);
+							px.g = px.g.wrapping_add((op >> 2) & 0x3).wrapping_sub(2);
+							px.b = px.b.wrapping_add(op & 0x3).wrapping_sub(2);
+						},
+						OP_LUMA => {
+							let dg = (op & 0x3F).wrapping_sub(32);
+							let b = next()?;
+							px.r = px.r.wrapping_add(dg).wrapping_add(b >> 4).wrapping_sub(8);
+							px.g = px.g.wrapping_add(dg);
+							px.b = px.b.wrapping_add(dg).wrapping_add(b & 0xF).wrapping_sub(8);
+						},
+						// run, the current pixel is repeated once more than encoded
+						_ => run = op & 0x3F
+					}
+				}
+				index[px.hash()] = px;
+			}
+			*p = rgb565(px.r, px.g, px.b);
+		}
+		emit_row(target, &placement, y, &line[..width as usize]);
+	}
+	Ok((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::bitmap::Framebuffer;
+
+	// 3x2, every op once: rgb, run, diff, luma, index, rgba
+	const IMAGE: &[u8] = &[
+		b'q', b'o', b'i', b'f', 0, 0, 0, 3, 0, 0, 0, 2, 4, 0,
+		// red
+		OP_RGB, 0xFF, 0x00, 0x00,
+		// red once more
+		0xC0,
+		// dr -1, dg +1, db 0: 0xFE 0x01 0x00
+		OP_DIFF | 1 << 4 | 3 << 2 | 2,
+		// dg +10, dr - dg = -2, db - dg = +3: 0x06 0x0B 0x0D
+		OP_LUMA | 42, 6 << 4 | 11,
+		// red from the index, (255 * 3 + 255 * 11) % 64 = 50
+		OP_INDEX | 50,
+		// blue, half transparent
+		OP_RGBA, 0x00, 0x00, 0xFF, 0x80,
+		0, 0, 0, 0, 0, 0, 0, 1
+	];
+
+	#[test]
+	fn all_ops() {
+		assert_eq!(qoi_size(IMAGE), Ok((3, 2)));
+		let mut pixels = vec![0u16; 6];
+		let mut fb = Framebuffer::new(&mut pixels, 3, 2);
+		assert_eq!(draw_qoi(IMAGE, &mut fb, Placement::default()), Ok((3, 2)));
+		let red = rgb565(0xFF, 0, 0);
+		assert_eq!(pixels, vec![
+			red, red, rgb565(0xFE, 0x01, 0x00),
+			rgb565(0x06, 0x0B, 0x0D), red, rgb565(0, 0, 0xFF)
+		]);
+	}
+
+	#[test]
+	fn long_run() {
+		// one rgb pixel and a run of 62 more, the longest a single op encodes
+		let mut data = IMAGE[..HEADER_SIZE].to_vec();
+		data[4..12].copy_from_slice(&[0, 0, 0, 9, 0, 0, 0, 7]);
+		data.extend_from_slice(&[OP_RGB, 0x10, 0x20, 0x30, 0xC0 | 61]);
+		let mut pixels = vec![0u16; 63];
+		let mut fb = Framebuffer::new(&mut pixels, 9, 7);
+		assert_eq!(draw_qoi(&data, &mut fb, Placement::default()), Ok((9, 7)));
+		assert!(pixels.iter().all(|p| *p == rgb565(0x10, 0x20, 0x30)));
+	}
+
+	#[test]
+	fn errors() {
+		let mut pixels = vec![0u16; 6];
+		let mut fb = Framebuffer::new(&mut pixels, 3, 2);
+		let placement = Placement::default();
+		assert_eq!(draw_qoi(b"qoix", &mut fb, placement), Err(DecodeError::Format));
+		assert_eq!(qoi_size(&IMAGE[..10]), Err(DecodeError::Truncated));
+		assert_eq!(draw_qoi(&IMAGE[..20], &mut fb, placement), Err(DecodeError::Truncated));
+		let mut zero = IMAGE.to_vec();
+		zero[7] = 0;
+		assert_eq!(qoi_size(&zero), Err(DecodeError::Format));
+		let mut wide = IMAGE.to_vec();
+		wide[4..8].copy_from_slice(&(MAX_WIDTH as u32 + 1).to_be_bytes());
+		assert_eq!(draw_qoi(&wide, &mut fb, placement), Err(DecodeError::TooWide));
+	}
+}
@@ -0,0 +1,193 @@
+// slot selection for the bootloader
+//
+// the bootloader starts the newest valid image. before jumping it counts the attempt
+// in a backup register (survives resets, but not power loss), the application clears
+// the counter with `mark_booted` once it is up and running. an image that fails to do
+// so MAX_BOOT_ATTEMPTS times in a row gets invalidated and the other slot is used.
+
+use core::ptr;
+use crate::gpbr;
+use crate::image::{ImageHeader, Slot};
+
+pub const MAX_BOOT_ATTEMPTS: u8 = 3;
+
+const BOOT_STATE_MAGIC: u32 = 0xB007_0000;
+
+const NVIC_ICER: *mut u32 = 0xE000_E180 as *mut u32;
+const NVIC_ICPR: *mut u32 = 0xE000_E280 as *mut u32;
+const NVIC_REGISTERS: usize = 8;
+const SYST_CSR: *mut u32 = 0xE000_E010 as *mut u32;
+const SCB_ICSR: *mut u32 = 0xE000_ED04 as *mut u32;
+const SCB_ICSR_PENDSTCLR: u32 = 1 << 25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootState {
+	// slot started last without being confirmed by the application
+	pub slot: Option<Slot>,
+	pub attempts: u8
+}
+
+impl Default for BootState {
+	fn default() -> BootState {
+		BootState {
+			slot: None,
+			attempts: 0
+		}
+	}
+}
+
+impl BootState {
+	pub fn decode(raw: u32) -> BootState {
+		if raw & 0xFFFF_0000 != BOOT_STATE_MAGIC {
+			return BootState::default();
+		}
+		let slot = match (raw >> 8) & 0xFF {
+			1 => Some(Slot::A),
+			2 => Some(Slot::B),
+			_ => None
+		};
+		BootState {
+			slot: slot,
+			attempts: raw as u8
+		}
+	}
+
+	pub fn encode(&self) -> u32 {
+		let slot = match self.slot {
+			None => 0,
+			Some(Slot::A) => 1,
+			Some(Slot::B) => 2
+		};
+		BOOT_STATE_MAGIC | (slot << 8) | self.attempts as u32
+	}
+
+	pub fn load() -> BootState {
+		BootState::decode(gpbr::read(gpbr::BOOT_STATE))
+	}
+
+	pub fn store(&self) {
+		gpbr::write(gpbr::BOOT_STATE, self.encode());
+	}
+}
+
+// to be called by the application once it considers itself healthy
+pub fn mark_booted() {
+	BootState::default().store();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootDecision {
+	// jump into the slot after storing the new state
+	Boot(Slot, BootState),
+	// the slot failed too often, erase its header and decide again
+	Invalidate(Slot),
+	NoImage
+}
+
+// `images` holds the valid header of slot a and b
+pub fn select(images: [Option<ImageHeader>; 2], state: BootState) -> BootDecision {
+	let newest = match (images[0], images[1]) {
+		(None, None) => return BootDecision::NoImage,
+		(Some(_), None) => Slot::A,
+		(None, Some(_)) => Slot::B,
+		(Some(a), Some(b)) => if b.version > a.version { Slot::B } else { Slot::A }
+	};
+
+	if state.slot == Some(newest) {
+		if state.attempts >= MAX_BOOT_ATTEMPTS {
+			return BootDecision::Invalidate(newest);
+		}
+		BootDecision::Boot(newest, BootState { slot: Some(newest), attempts: state.attempts + 1 })
+	} else {
+		BootDecision::Boot(newest, BootState { slot: Some(newest), attempts: 1 })
+	}
+}
+
+// relocate the vector table to `vector_table`, load its stack pointer and jump to its
+// reset handler. interrupts have to be disabled and no peripheral should be left in a
+// state the application does not expect. all interrupt lines are masked and cleared
+// and the systick is stopped, then interrupts are enabled again, so the application
+// starts with the interrupt state it would have after a reset.
+pub unsafe fn jump(vector_table: u32) -> ! {
+	for i in 0..NVIC_REGISTERS {
+		ptr::write_volatile(NVIC_ICER.add(i), 0xFFFF_FFFF);
+		ptr::write_volatile(NVIC_ICPR.add(i), 0xFFFF_FFFF);
+	}
+	ptr::write_volatile(SYST_CSR, 0);
+	ptr::write_volatile(SCB_ICSR, SCB_ICSR_PENDSTCLR);
+
+	let scb = &*cortex_m::peripheral::SCB::ptr();
+	scb.vtor.write(vector_table);
+	cortex_m::asm::dsb();
+	cortex_m::asm::isb();
+
+	let table = vector_table as *const u32;
+	let reset: extern "C" fn() -> ! = core::mem::transmute(ptr::read_volatile(table.add(1)) as usize);
+	cortex_m::register::msp::write(ptr::read_volatile(table));
+	cortex_m::interrupt::enable();
+	reset()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn header(version: u32) -> Option<ImageHeader> {
+		Some(ImageHeader { version: version, length: 0, crc: 0, entry: 0 })
+	}
+
+	#[test]
+	fn state_round_trip() {
+		let state = BootState { slot: Some(Slot::B), attempts: 2 };
+		assert_eq!(BootState::decode(state.encode()), state);
+		// anything without the magic, e.g. after power loss
+		assert_eq!(BootState::decode(0), BootState::default());
+		assert_eq!(BootState::decode(0x1234_0102), BootState::default());
+	}
+
+	#[test]
+	fn fresh_boot_takes_newest() {
+		assert_eq!(select([None, None], BootState::default()), BootDecision::NoImage);
+		assert_eq!(select([header(1), None], BootState::default()),
+			BootDecision::Boot(Slot::A, BootState { slot: Some(Slot::A), attempts: 1 }));
+		assert_eq!(select([header(1), header(2)], BootState::default()),
+			BootDecision::Boot(Slot::B, BootState { slot: Some(Slot::B), attempts: 1 }));
+		// equal versions prefer slot a
+		assert_eq!(select([header(2), header(2)], BootState::default()),
+			BootDecision::Boot(Slot::A, BootState { slot: Some(Slot::A), attempts: 1 }));
+	}
+
+	#[test]
+	fn retry_counts_attempts() {
+		let state = BootState { slot: Some(Slot::B), attempts: 1 };
+		assert_eq!(select([header(1), header(2)], state),
+			BootDecision::Boot(Slot::B, BootState { slot: Some(Slot::B), attempts: 2 }));
+
+		// an unconfirmed attempt of the other slot does not count for this one
+		let state = BootState { slot: Some(Slot::A), attempts: 2 };
+		assert_eq!(select([header(1), header(2)], state),
+			BootDecision::Boot(Slot::B, BootState { slot: Some(Slot::B), attempts: 1 }));
+	}
+
+	#[test]
+	fn exhausted_slot_is_invalidated() {
+		let state = BootState { slot: Some(Slot::B), attempts: MAX_BOOT_ATTEMPTS };
+		assert_eq!(select([header(1), header(2)], state), BootDecision::Invalidate(Slot::B));
+
+		let state = BootState { slot: Some(Slot::A), attempts: MAX_BOOT_ATTEMPTS };
+		assert_eq!(select([header(1), None], state), BootDecision::Invalidate(Slot::A));
+	}
+
+	#[test]
+	fn falls_back_to_other_slot() {
+		// as the bootloader does it: drop the invalidated image and start over
+		let mut images = [header(1), header(2)];
+		let state = BootState { slot: Some(Slot::B), attempts: MAX_BOOT_ATTEMPTS };
+		match select(images, state) {
+			BootDecision::Invalidate(slot) => images[slot as usize] = None,
+			d => panic!("{:?}", d)
+		}
+		assert_eq!(select(images, BootState::default()),
+			BootDecision::Boot(Slot::A, BootState { slot: Some(Slot::A), attempts: 1 }));
+	}
+}
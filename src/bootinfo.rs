@@ -0,0 +1,178 @@
+// reset cause and boot information
+//
+// `BootInfo::read` should run early after reset: it evaluates the reset controller
+// and supply controller status, counts the boot in a backup register and picks up
+// the crash record left by the previous run. its `Display` output is meant to be
+// the first line logged on uart0.
+
+use core::fmt;
+
+use atsame70q21::{RSTC, SUPC};
+
+use crate::gpbr;
+use crate::watchdog;
+
+const RSTC_SR_RSTTYP_SHIFT: u32 = 8;
+const SUPC_SR_BODRSTS: u32 = 1 << 3;
+const SUPC_SR_SMRSTS: u32 = 1 << 4;
+
+// kind register: magic in the top byte, kind in bits 23:16, code in bits 15:0
+const CRASH_MAGIC: u32 = 0xC7 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+	PowerOn,
+	// wake up from backup mode
+	Backup,
+	Watchdog,
+	Software,
+	// nrst pin, e.g. the reset button or the debugger
+	User,
+	BrownOut,
+	// supply monitor
+	SupplyMonitor,
+	Unknown(u8)
+}
+
+impl fmt::Display for ResetCause {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ResetCause::PowerOn => write!(f, "power-on"),
+			ResetCause::Backup => write!(f, "backup wake-up"),
+			ResetCause::Watchdog => write!(f, "watchdog"),
+			ResetCause::Software => write!(f, "software"),
+			ResetCause::User => write!(f, "user (nrst)"),
+			ResetCause::BrownOut => write!(f, "brown-out"),
+			ResetCause::SupplyMonitor => write!(f, "supply monitor"),
+			ResetCause::Unknown(t) => write!(f, "unknown ({})", t)
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashKind {
+	Panic,
+	HardFault,
+	// the stack ran into its guard region, see stack.rs
+	StackOverflow,
+	// mask of the supervised tasks that did not check in
+	Watchdog(u16),
+	Other(u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrashRecord {
+	pub kind: CrashKind,
+	pub pc: u32,
+	// e.g. lr or the faulting address
+	pub info: u32
+}
+
+impl CrashRecord {
+	// store the record for the next boot, to be called from panic or fault handlers
+	pub fn store(&self) {
+		let (kind, code) = match self.kind {
+			CrashKind::Panic => (1, 0),
+			CrashKind::HardFault => (2, 0),
+			CrashKind::Watchdog(mask) => (3, mask),
+			CrashKind::Other(code) => (4, code),
+			CrashKind::StackOverflow => (5, 0)
+		};
+		gpbr::write(gpbr::CRASH_PC, self.pc);
+		gpbr::write(gpbr::CRASH_INFO, self.info);
+		gpbr::write(gpbr::CRASH_KIND, CRASH_MAGIC | (kind << 16) | code as u32);
+	}
+
+	// read and clear the stored record
+	pub fn take() -> Option<CrashRecord> {
+		let raw = gpbr::read(gpbr::CRASH_KIND);
+		gpbr::write(gpbr::CRASH_KIND, 0);
+		if raw & 0xFF00_0000 != CRASH_MAGIC {
+			return None;
+		}
+		let code = raw as u16;
+		let kind = match (raw >> 16) & 0xFF {
+			0x1 => CrashKind::Panic,
+			0x2 => CrashKind::HardFault,
+			0x3 => CrashKind::Watchdog(code),
+			0x4 => CrashKind::Other(code),
+			0x5 => CrashKind::StackOverflow,
+			_ => return None
+		};
+		Some(CrashRecord {
+			kind: kind,
+			pc: gpbr::read(gpbr::CRASH_PC),
+			info: gpbr::read(gpbr::CRASH_INFO)
+		})
+	}
+}
+
+impl fmt::Display for CrashRecord {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.kind {
+			CrashKind::Panic => write!(f, "panic")?,
+			CrashKind::HardFault => write!(f, "hard fault")?,
+			CrashKind::StackOverflow => write!(f, "stack overflow")?,
+			CrashKind::Watchdog(mask) => write!(f, "watchdog (tasks {:#06x} missing)", mask)?,
+			CrashKind::Other(code) => write!(f, "error {}", code)?
+		}
+		write!(f, " at {:#010x} ({:#010x})", self.pc, self.info)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfo {
+	pub cause: ResetCause,
+	// boots since the backup registers were last powered up
+	pub boot_count: u32,
+	pub last_crash: Option<CrashRecord>
+}
+
+impl BootInfo {
+	// call only once per boot, it counts the boot and consumes the crash record
+	pub fn read(rstc: &RSTC, supc: &SUPC) -> BootInfo {
+		let rsttyp = ((rstc.rstc_sr.read().bits() >> RSTC_SR_RSTTYP_SHIFT) & 0x7) as u8;
+		let supc_sr = supc.supc_sr.read().bits();
+		let watchdog = watchdog::caused_last_reset(rstc);
+
+		let cause = if watchdog {
+			ResetCause::Watchdog
+		} else if supc_sr & SUPC_SR_BODRSTS != 0 {
+			ResetCause::BrownOut
+		} else if supc_sr & SUPC_SR_SMRSTS != 0 {
+			ResetCause::SupplyMonitor
+		} else {
+			match rsttyp {
+				0 => ResetCause::PowerOn,
+				1 => ResetCause::Backup,
+				3 => ResetCause::Software,
+				4 => ResetCause::User,
+				t => ResetCause::Unknown(t)
+			}
+		};
+
+		// the backup registers are cleared on power-on
+		let boot_count = if cause == ResetCause::PowerOn {
+			1
+		} else {
+			gpbr::read(gpbr::BOOT_COUNTER).wrapping_add(1)
+		};
+		gpbr::write(gpbr::BOOT_COUNTER, boot_count);
+
+		BootInfo {
+			cause: cause,
+			boot_count: boot_count,
+			last_crash: CrashRecord::take()
+		}
+	}
+}
+
+impl fmt::Display for BootInfo {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "boot #{}, reset cause: {}", self.boot_count, self.cause)?;
+		if let Some(crash) = self.last_crash {
+			write!(f, ", last crash: {}", crash)?;
+		}
+		Ok(())
+	}
+}
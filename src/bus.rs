@@ -0,0 +1,197 @@
+// reprogramming of the external bus timings after master clock changes
+//
+// `init_sdram` and `setup_lcd` convert their nanosecond timings into master clock
+// cycles once. `BusTimings` keeps the nanosecond specs of everything attached to
+// the ebi, so `switch_clocks` can put the sdram into self-refresh, change the clocks
+// and recompute all cycle counts for the new master clock before resuming.
+//
+// the sdram must not be accessed while the switch is in progress, so neither the
+// code nor the stack or any data used by the caller may live there.
+
+use core::ptr;
+
+use atsame70q21::{EFC, PMC, SUPC};
+use atsamx7x_hal::clock_gen::Clocks;
+
+use crate::clocks::ClockSettings;
+use crate::lcd::{LCD_SMC_DEVICE, LCD_TIMING};
+use crate::mem::{SdramTimingSpec, SDRAM_TIMING};
+
+const SDRAMC_BASE: usize = 0x4008_4000;
+const SDRAMC_TR: *mut u32 = (SDRAMC_BASE + 0x04) as *mut u32;
+const SDRAMC_CR: *mut u32 = (SDRAMC_BASE + 0x08) as *mut u32;
+const SDRAMC_LPR: *mut u32 = (SDRAMC_BASE + 0x10) as *mut u32;
+
+const SDRAMC_LPR_LPCB_MASK: u32 = 0x3;
+const SDRAMC_LPR_LPCB_SELF_REFRESH: u32 = 0x1;
+
+const SMC_BASE: usize = 0x4008_0000;
+const SMC_CS_STRIDE: usize = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmcTimingSpec {
+	pub setup_read_ns: u32,
+	pub setup_read_cs_ns: u32,
+	pub setup_write_ns: u32,
+	pub setup_write_cs_ns: u32,
+	pub pulse_read_ns: u32,
+	pub pulse_read_cs_ns: u32,
+	pub pulse_write_ns: u32,
+	pub pulse_write_cs_ns: u32,
+	pub cycle_read_ns: u32,
+	pub cycle_write_ns: u32
+}
+
+// number of clock cycles covering at least `ps` picoseconds
+pub fn ps_to_cycles(ps: u32, hz: u32) -> u32 {
+	((ps as u64 * hz as u64 + 999_999_999_999) / 1_000_000_000_000) as u32
+}
+
+pub fn ns_to_cycles(ns: u32, hz: u32) -> u32 {
+	ps_to_cycles(ns.saturating_mul(1000), hz)
+}
+
+// smc setup fields encode 128 * bit5 + bits[4:0]
+fn encode_setup(cycles: u32) -> u32 {
+	match cycles {
+		0..=31 => cycles,
+		32..=128 => 0x20,
+		_ => 0x20 | core::cmp::min(cycles - 128, 31)
+	}
+}
+
+// smc pulse fields encode 256 * bit6 + bits[5:0]
+fn encode_pulse(cycles: u32) -> u32 {
+	match cycles {
+		0..=63 => cycles,
+		64..=256 => 0x40,
+		_ => 0x40 | core::cmp::min(cycles - 256, 63)
+	}
+}
+
+// smc cycle fields encode 256 * bits[8:7] + bits[6:0]
+fn encode_cycle(cycles: u32) -> u32 {
+	for hi in 0..4 {
+		if cycles <= hi * 256 + 127 {
+			return (hi << 7) | cycles.saturating_sub(hi * 256);
+		}
+	}
+	0x1FF
+}
+
+pub struct BusTimings {
+	sdram: Option<SdramTimingSpec>,
+	smc: [Option<SmcTimingSpec>; 4]
+}
+
+impl BusTimings {
+	pub fn new() -> BusTimings {
+		BusTimings {
+			sdram: None,
+			smc: [None; 4]
+		}
+	}
+
+	// everything set up by `init_sdram` and `setup_lcd`
+	pub fn board() -> BusTimings {
+		BusTimings::new()
+			.sdram(SDRAM_TIMING)
+			.smc_device(LCD_SMC_DEVICE, LCD_TIMING)
+	}
+
+	pub fn sdram(mut self, spec: SdramTimingSpec) -> Self {
+		self.sdram = Some(spec);
+		self
+	}
+
+	pub fn smc_device(mut self, chip_select: u8, spec: SmcTimingSpec) -> Self {
+		assert!((chip_select as usize) < self.smc.len(), "the smc has no chip select {}", chip_select);
+		self.smc[chip_select as usize] = Some(spec);
+		self
+	}
+
+	// recompute all recorded timings for a master clock of `mck_hz`
+	pub fn apply(&self, mck_hz: u32) {
+		if let Some(spec) = self.sdram {
+			let c = |ns| core::cmp::min(ns_to_cycles(ns, mck_hz), 0xF);
+			let timing = (c(spec.twr_ns) << 8)
+				| (c(spec.trc_ns) << 12)
+				| (c(spec.trp_ns) << 16)
+				| (c(spec.trcd_ns) << 20)
+				| (c(spec.tras_ns) << 24)
+				| (core::cmp::min(ps_to_cycles(spec.txsr_ps, mck_hz), 0xF) << 28);
+			let refresh = core::cmp::min(mck_hz as u64 * spec.refresh_ns as u64 / 1_000_000_000, 0xFFF) as u32;
+			unsafe {
+				let cr = ptr::read_volatile(SDRAMC_CR);
+				ptr::write_volatile(SDRAMC_CR, (cr & 0xFF) | timing);
+				ptr::write_volatile(SDRAMC_TR, refresh);
+			}
+		}
+
+		for (cs, spec) in self.smc.iter().enumerate() {
+			let spec = match spec {
+				Some(s) => s,
+				None => continue
+			};
+			let c = |ns| ns_to_cycles(ns, mck_hz);
+			let setup = encode_setup(c(spec.setup_write_ns))
+				| (encode_setup(c(spec.setup_write_cs_ns)) << 8)
+				| (encode_setup(c(spec.setup_read_ns)) << 16)
+				| (encode_setup(c(spec.setup_read_cs_ns)) << 24);
+			let pulse = encode_pulse(c(spec.pulse_write_ns))
+				| (encode_pulse(c(spec.pulse_write_cs_ns)) << 8)
+				| (encode_pulse(c(spec.pulse_read_ns)) << 16)
+				| (encode_pulse(c(spec.pulse_read_cs_ns)) << 24);
+			let cycle = encode_cycle(c(spec.cycle_write_ns))
+				| (encode_cycle(c(spec.cycle_read_ns)) << 16);
+
+			let base = (SMC_BASE + cs * SMC_CS_STRIDE) as *mut u32;
+			unsafe {
+				ptr::write_volatile(base, setup);
+				ptr::write_volatile(base.add(1), pulse);
+				ptr::write_volatile(base.add(2), cycle);
+			}
+		}
+	}
+
+	// change the system clocks and retime the external bus, the sdram keeps its
+	// contents by running in self-refresh while the clock is switched
+	pub fn switch_clocks(&self, settings: &ClockSettings, pmc: &mut PMC, supc: &mut SUPC, efc: &EFC) -> Clocks {
+		cortex_m::interrupt::free(|_| {
+			if self.sdram.is_some() {
+				enter_self_refresh();
+			}
+			let clocks = settings.freeze(pmc, supc, efc);
+			self.apply(settings.mck_hz());
+			if self.sdram.is_some() {
+				exit_self_refresh();
+			}
+			clocks
+		})
+	}
+}
+
+impl Default for BusTimings {
+	fn default() -> BusTimings {
+		BusTimings::board()
+	}
+}
+
+// the controller enters self-refresh as soon as no access is pending
+pub fn enter_self_refresh() {
+	cortex_m::asm::dsb();
+	unsafe {
+		let lpr = ptr::read_volatile(SDRAMC_LPR);
+		ptr::write_volatile(SDRAMC_LPR, (lpr & !SDRAMC_LPR_LPCB_MASK) | SDRAMC_LPR_LPCB_SELF_REFRESH);
+	}
+	cortex_m::asm::dsb();
+}
+
+// the next access wakes the device up, disabling the low power mode keeps it awake
+pub fn exit_self_refresh() {
+	unsafe {
+		let lpr = ptr::read_volatile(SDRAMC_LPR);
+		ptr::write_volatile(SDRAMC_LPR, lpr & !SDRAMC_LPR_LPCB_MASK);
+	}
+	cortex_m::asm::dsb();
+}
@@ -0,0 +1,305 @@
+// clock configuration presets for the board's 12 MHz crystal
+//
+// the frequencies of a configuration are computed in const fns, so presets and
+// custom settings can be checked at compile time with `check_clock_settings!`.
+// switching also programs the matching number of flash wait states. clocks are
+// always changed through `bus::BusTimings::switch_clocks`, so the timings of the
+// sdram and smc devices that are set up follow the new master clock. at startup,
+// before the external bus is used, `BusTimings::new()` has nothing to retime.
+
+use atsame70q21::{EFC, PMC, SUPC};
+use atsamx7x_hal::clock_gen::{Clocks, MasterClockConfig, SlckConfig, MainckConfig, PllackConfig, UpllckConfig, SystemClockConfig, MasterDivider, MasterPrescale};
+use atsamx7x_hal::time::MegaHertz;
+
+use crate::bus::BusTimings;
+
+pub const CRYSTAL_HZ: u32 = 12_000_000;
+
+pub const MAX_CORE_HZ: u32 = 300_000_000;
+pub const MAX_MCK_HZ: u32 = 150_000_000;
+// the datasheet specifies up to 500 MHz, the board is known to work at 600 MHz
+pub const PLLA_MIN_HZ: u32 = 160_000_000;
+pub const PLLA_MAX_HZ: u32 = 600_000_000;
+
+// maximum master clock per flash wait state (vddio 3.0 V to 3.6 V)
+const FLASH_HZ_PER_WAIT_STATE: u32 = 23_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MasterSource {
+	Mainck,
+	Pllack
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSettings {
+	pub source: MasterSource,
+	// plla = crystal / plla_div * (plla_mul + 1), same arguments as `PllackConfig::from_divider`
+	pub plla_div: u8,
+	pub plla_mul: u8,
+	// core clock = source / prescale, one of 1, 2, 3, 4, 8, 16, 32, 64
+	pub prescale: u8,
+	// master clock = core clock / divider, one of 1, 2, 3, 4
+	pub divider: u8,
+	pub usb: bool
+}
+
+impl ClockSettings {
+	pub const fn plla_hz(&self) -> u32 {
+		if self.plla_div == 0 {
+			return 0;
+		}
+		CRYSTAL_HZ / self.plla_div as u32 * (self.plla_mul as u32 + 1)
+	}
+
+	pub const fn source_hz(&self) -> u32 {
+		match self.source {
+			MasterSource::Mainck => CRYSTAL_HZ,
+			MasterSource::Pllack => self.plla_hz()
+		}
+	}
+
+	pub const fn core_hz(&self) -> u32 {
+		if self.prescale == 0 {
+			return 0;
+		}
+		self.source_hz() / self.prescale as u32
+	}
+
+	pub const fn mck_hz(&self) -> u32 {
+		if self.divider == 0 {
+			return 0;
+		}
+		self.core_hz() / self.divider as u32
+	}
+
+	pub const fn flash_wait_states(&self) -> u8 {
+		let mck = self.mck_hz();
+		if mck <= FLASH_HZ_PER_WAIT_STATE {
+			return 0;
+		}
+		((mck + FLASH_HZ_PER_WAIT_STATE - 1) / FLASH_HZ_PER_WAIT_STATE - 1) as u8
+	}
+
+	pub const fn is_valid(&self) -> bool {
+		let prescale_ok = match self.prescale {
+			1 | 2 | 3 | 4 | 8 | 16 | 32 | 64 => true,
+			_ => false
+		};
+		let divider_ok = match self.divider {
+			1 | 2 | 3 | 4 => true,
+			_ => false
+		};
+		let plla_ok = match self.source {
+			MasterSource::Mainck => true,
+			MasterSource::Pllack => self.plla_div != 0 && self.plla_mul != 0
+				&& self.plla_hz() >= PLLA_MIN_HZ && self.plla_hz() <= PLLA_MAX_HZ
+		};
+		prescale_ok && divider_ok && plla_ok
+			&& self.core_hz() <= MAX_CORE_HZ
+			&& self.mck_hz() <= MAX_MCK_HZ
+	}
+
+	pub fn system_config(&self) -> SystemClockConfig {
+		let prescale = match self.prescale {
+			1 => MasterPrescale::Pres1,
+			2 => MasterPrescale::Pres2,
+			3 => MasterPrescale::Pres3,
+			4 => MasterPrescale::Pres4,
+			8 => MasterPrescale::Pres8,
+			16 => MasterPrescale::Pres16,
+			32 => MasterPrescale::Pres32,
+			_ => MasterPrescale::Pres64
+		};
+		let divider = match self.divider {
+			1 => MasterDivider::Div1,
+			2 => MasterDivider::Div2,
+			3 => MasterDivider::Div3,
+			_ => MasterDivider::Div4
+		};
+
+		let (plla_conf, mck_conf) = match self.source {
+			MasterSource::Pllack => (
+				PllackConfig::default().from_divider(self.plla_div, self.plla_mul).startup_cycles(100),
+				MasterClockConfig::default().src_pllack().from_divider(prescale, divider)
+			),
+			MasterSource::Mainck => (
+				PllackConfig::default(),
+				MasterClockConfig::default().src_mainck().from_divider(prescale, divider)
+			)
+		};
+		let upll_conf = if self.usb {
+			UpllckConfig::default().enable()
+		} else {
+			UpllckConfig::default()
+		};
+
+		SystemClockConfig {
+			slck_conf: SlckConfig::default(),
+			mainck_conf: MainckConfig::default().use_crystal(MegaHertz(12).into()).disable_rc(),
+			plla_conf: plla_conf,
+			upll_conf: upll_conf,
+			mck_conf: mck_conf
+		}
+	}
+
+	// apply the settings, flash wait states are raised before and lowered after the switch.
+	// the external bus is not retimed, see `BusTimings::switch_clocks`
+	pub(crate) fn freeze(&self, pmc: &mut PMC, supc: &mut SUPC, efc: &EFC) -> Clocks {
+		assert!(self.is_valid());
+		let current = efc.eefc_fmr.read().fws().bits();
+		let target = self.flash_wait_states();
+
+		set_flash_wait_states(efc, core::cmp::max(current, target));
+		let clocks = self.system_config().freeze(pmc, supc);
+		set_flash_wait_states(efc, target);
+		clocks
+	}
+}
+
+fn set_flash_wait_states(efc: &EFC, fws: u8) {
+	efc.eefc_fmr.write( |w| {
+		unsafe {w.fws().bits(fws);}
+		w.cloe().set_bit();
+		w.scod().clear_bit()
+	});
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockPreset {
+	// 300 MHz core, 150 MHz master clock, usb pll running
+	Max300MHz,
+	// 150 MHz core, 75 MHz master clock, usb pll running
+	Balanced150MHz,
+	// crystal only, 12 MHz core and master clock, no plls
+	LowPower12MHz
+}
+
+impl ClockPreset {
+	pub const fn settings(self) -> ClockSettings {
+		match self {
+			ClockPreset::Max300MHz => ClockSettings {
+				source: MasterSource::Pllack,
+				plla_div: 1,
+				plla_mul: 49,
+				prescale: 2,
+				divider: 2,
+				usb: true
+			},
+			ClockPreset::Balanced150MHz => ClockSettings {
+				source: MasterSource::Pllack,
+				plla_div: 1,
+				plla_mul: 24,
+				prescale: 2,
+				divider: 2,
+				usb: true
+			},
+			ClockPreset::LowPower12MHz => ClockSettings {
+				source: MasterSource::Mainck,
+				plla_div: 0,
+				plla_mul: 0,
+				prescale: 1,
+				divider: 1,
+				usb: false
+			}
+		}
+	}
+
+	pub const fn core_hz(self) -> u32 {
+		self.settings().core_hz()
+	}
+
+	pub const fn mck_hz(self) -> u32 {
+		self.settings().mck_hz()
+	}
+
+	pub fn freeze(self, timings: &BusTimings, pmc: &mut PMC, supc: &mut SUPC, efc: &EFC) -> Clocks {
+		timings.switch_clocks(&self.settings(), pmc, supc, efc)
+	}
+}
+
+// fails to compile if the given `ClockSettings` are out of spec
+#[macro_export]
+macro_rules! check_clock_settings {
+	($settings:expr) => {
+		const _: () = [()][!$crate::clocks::ClockSettings::is_valid(&$settings) as usize];
+	};
+}
+
+check_clock_settings!(ClockPreset::Max300MHz.settings());
+check_clock_settings!(ClockPreset::Balanced150MHz.settings());
+check_clock_settings!(ClockPreset::LowPower12MHz.settings());
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pll(plla_mul: u8, prescale: u8, divider: u8) -> ClockSettings {
+		ClockSettings {
+			source: MasterSource::Pllack,
+			plla_div: 1,
+			plla_mul: plla_mul,
+			prescale: prescale,
+			divider: divider,
+			usb: false
+		}
+	}
+
+	#[test]
+	fn presets() {
+		let max = ClockPreset::Max300MHz.settings();
+		assert_eq!(max.plla_hz(), 600_000_000);
+		assert_eq!(ClockPreset::Max300MHz.core_hz(), 300_000_000);
+		assert_eq!(ClockPreset::Max300MHz.mck_hz(), 150_000_000);
+		assert_eq!(max.flash_wait_states(), 6);
+
+		let balanced = ClockPreset::Balanced150MHz.settings();
+		assert_eq!(balanced.plla_hz(), 300_000_000);
+		assert_eq!(ClockPreset::Balanced150MHz.core_hz(), 150_000_000);
+		assert_eq!(ClockPreset::Balanced150MHz.mck_hz(), 75_000_000);
+		assert_eq!(balanced.flash_wait_states(), 3);
+
+		let low = ClockPreset::LowPower12MHz.settings();
+		assert_eq!(low.plla_hz(), 0);
+		assert_eq!(ClockPreset::LowPower12MHz.core_hz(), 12_000_000);
+		assert_eq!(ClockPreset::LowPower12MHz.mck_hz(), 12_000_000);
+		assert_eq!(low.flash_wait_states(), 0);
+
+		assert!(max.is_valid() && balanced.is_valid() && low.is_valid());
+		assert!(max.usb && balanced.usb && !low.usb);
+	}
+
+	#[test]
+	fn flash_wait_states() {
+		// datasheet: up to 23, 46, 69, 92, 115, 138 and 150 MHz for 0 to 6 wait states
+		let table = [
+			(pll(15, 8, 1), 24_000_000, 1),
+			(pll(23, 8, 1), 36_000_000, 1),
+			(pll(15, 4, 1), 48_000_000, 2),
+			(pll(23, 4, 1), 72_000_000, 3),
+			(pll(15, 2, 1), 96_000_000, 4),
+			(pll(19, 2, 1), 120_000_000, 5),
+			(pll(23, 2, 1), 144_000_000, 6)
+		];
+		for (settings, mck, fws) in table.iter() {
+			assert!(settings.is_valid());
+			assert_eq!(settings.mck_hz(), *mck);
+			assert_eq!(settings.flash_wait_states(), *fws, "{} Hz", mck);
+		}
+	}
+
+	#[test]
+	fn out_of_spec() {
+		// master clock above 150 MHz
+		assert!(!pll(49, 2, 1).is_valid());
+		// core clock above 300 MHz
+		assert!(!pll(49, 1, 4).is_valid());
+		// plla outside of 160 to 600 MHz
+		assert!(!pll(12, 1, 2).is_valid());
+		assert!(!pll(50, 4, 2).is_valid());
+		assert!(!pll(24, 5, 1).is_valid());
+		assert!(!pll(24, 2, 0).is_valid());
+		assert!(!ClockSettings { plla_mul: 0, ..pll(24, 2, 2) }.is_valid());
+		assert_eq!(pll(24, 0, 1).core_hz(), 0);
+		assert_eq!(ClockSettings { plla_div: 0, ..pll(24, 2, 2) }.plla_hz(), 0);
+	}
+}
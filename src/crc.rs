@@ -0,0 +1,56 @@
+// crc32 as used by ethernet, zip and png (reflected, polynomial 0xEDB88320)
+pub struct Crc32 {
+	state: u32
+}
+
+impl Crc32 {
+	pub fn new() -> Crc32 {
+		Crc32 {
+			state: 0xFFFF_FFFF
+		}
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		let mut crc = self.state;
+		for byte in data {
+			crc ^= *byte as u32;
+			for _ in 0..8 {
+				let mask = (!(crc & 1)).wrapping_add(1);
+				crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+			}
+		}
+		self.state = crc;
+	}
+
+	pub fn finish(&self) -> u32 {
+		!self.state
+	}
+}
+
+impl Default for Crc32 {
+	fn default() -> Crc32 {
+		Crc32::new()
+	}
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+	let mut crc = Crc32::new();
+	crc.update(data);
+	crc.finish()
+}
+
+// crc16 ccitt as used by xmodem (polynomial 0x1021, initial value 0)
+pub fn crc16_xmodem(data: &[u8]) -> u16 {
+	let mut crc: u16 = 0;
+	for byte in data {
+		crc ^= (*byte as u16) << 8;
+		for _ in 0..8 {
+			if crc & 0x8000 != 0 {
+				crc = (crc << 1) ^ 0x1021;
+			} else {
+				crc <<= 1;
+			}
+		}
+	}
+	crc
+}
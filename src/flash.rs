@@ -0,0 +1,384 @@
+// internal flash programming through the enhanced embedded flash controller (eefc)
+//
+// the flash can not be read while a command is executing, so the routine that issues
+// the command and waits for completion is placed into ram (.data is copied there by
+// cortex-m-rt) and interrupts are disabled while it runs
+//
+// `Flash` implements `ReadNorFlash` and `NorFlash` of embedded-storage with offsets
+// relative to the flash start, for generic storage code. writes there are done in
+// 16 byte units, the ecc granularity of the flash.
+
+use core::ptr;
+use atsame70q21::EFC;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::layout::BOARD;
+
+pub const FLASH_BASE: usize = BOARD.flash_base;
+pub const FLASH_SIZE: usize = BOARD.flash_size;
+pub const PAGE_SIZE: usize = 512;
+pub const PAGE_COUNT: usize = FLASH_SIZE / PAGE_SIZE;
+// sector 0 is split into two small sectors of 8 KiB and one of 112 KiB, the others
+// all have this size
+pub const SECTOR_SIZE: usize = 0x2_0000;
+pub const LOCK_REGION_SIZE: usize = 0x4000;
+pub const LOCK_REGION_COUNT: usize = FLASH_SIZE / LOCK_REGION_SIZE;
+// the two 8 KiB small sectors at the start can not be erased in 32 page units
+const SMALL_SECTORS_END: usize = 0x4000;
+// smallest unit the flash can be programmed in
+pub const WRITE_SIZE: usize = 16;
+// smallest unit `erase` accepts, 8 pages
+pub const ERASE_SIZE: usize = 8 * PAGE_SIZE;
+
+const EEFC_FCR: *mut u32 = 0x400E_0C04 as *mut u32;
+const EEFC_FSR: *const u32 = 0x400E_0C08 as *const u32;
+const EEFC_FRR: *const u32 = 0x400E_0C0C as *const u32;
+// data cache invalidate by address
+const SCB_DCIMVAC: *mut u32 = 0xE000_EF5C as *mut u32;
+
+const FCR_FKEY: u32 = 0x5A << 24;
+
+const FSR_FRDY: u32 = 1 << 0;
+const FSR_FCMDE: u32 = 1 << 1;
+const FSR_FLOCKE: u32 = 1 << 2;
+const FSR_FLERR: u32 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FlashCommand {
+	GetDescriptor = 0x00,
+	WritePage = 0x01,
+	EraseAll = 0x05,
+	ErasePages = 0x07,
+	SetLockBit = 0x08,
+	ClearLockBit = 0x09,
+	GetLockBit = 0x0A,
+	SetGpnvm = 0x0B,
+	ClearGpnvm = 0x0C,
+	GetGpnvm = 0x0D,
+	StartReadUniqueId = 0x0E,
+	StopReadUniqueId = 0x0F,
+	EraseSector = 0x11
+}
+
+// general purpose nvm bits, they take effect after the next reset
+pub const GPNVM_SECURITY: u8 = 0;
+// boot from flash instead of the rom (sam-ba)
+pub const GPNVM_BOOT_FLASH: u8 = 1;
+// tcm size, two bits: 0, 32, 64 or 128 KiB each for itcm and dtcm
+pub const GPNVM_TCM_LOW: u8 = 7;
+pub const GPNVM_TCM_HIGH: u8 = 8;
+const GPNVM_COUNT: u8 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashError {
+	OutOfBounds,
+	Unaligned,
+	// the controller rejected the command (bad keyword or argument)
+	Command,
+	// the region is locked
+	Locked,
+	// programming or erase failed
+	Flash
+}
+
+#[inline(never)]
+#[link_section = ".data.ramfunc"]
+unsafe fn eefc_command_ram(fcr: u32) -> u32 {
+	ptr::write_volatile(EEFC_FCR, fcr);
+	loop {
+		let fsr = ptr::read_volatile(EEFC_FSR);
+		if fsr & FSR_FRDY != 0 {
+			return fsr;
+		}
+	}
+}
+
+// while the unique identifier is read the flash returns it instead of its contents,
+// so this has to run from ram as well. the cache line is invalidated before and
+// after, neither the identifier nor the flash contents may be served from the cache.
+#[inline(never)]
+#[link_section = ".data.ramfunc"]
+unsafe fn eefc_unique_id_ram(id: &mut [u32; 4]) -> u32 {
+	let flash = FLASH_BASE as *const u32;
+	ptr::write_volatile(SCB_DCIMVAC, FLASH_BASE as u32);
+	cortex_m::asm::dsb();
+	ptr::write_volatile(EEFC_FCR, FCR_FKEY | FlashCommand::StartReadUniqueId as u32);
+	while ptr::read_volatile(EEFC_FSR) & FSR_FRDY != 0 {}
+	id[0] = ptr::read_volatile(flash);
+	id[1] = ptr::read_volatile(flash.add(1));
+	id[2] = ptr::read_volatile(flash.add(2));
+	id[3] = ptr::read_volatile(flash.add(3));
+	ptr::write_volatile(EEFC_FCR, FCR_FKEY | FlashCommand::StopReadUniqueId as u32);
+	let fsr = loop {
+		let fsr = ptr::read_volatile(EEFC_FSR);
+		if fsr & FSR_FRDY != 0 {
+			break fsr;
+		}
+	};
+	ptr::write_volatile(SCB_DCIMVAC, FLASH_BASE as u32);
+	cortex_m::asm::dsb();
+	cortex_m::asm::isb();
+	fsr
+}
+
+fn check_status(fsr: u32) -> Result<(), FlashError> {
+	if fsr & FSR_FCMDE != 0 {
+		Err(FlashError::Command)
+	} else if fsr & FSR_FLOCKE != 0 {
+		Err(FlashError::Locked)
+	} else if fsr & FSR_FLERR != 0 {
+		Err(FlashError::Flash)
+	} else {
+		Ok(())
+	}
+}
+
+pub struct Flash {
+	efc: EFC
+}
+
+impl Flash {
+	pub fn new(efc: EFC) -> Flash {
+		Flash {
+			efc: efc
+		}
+	}
+
+	pub fn free(self) -> EFC {
+		self.efc
+	}
+
+	pub fn command(&mut self, cmd: FlashCommand, arg: u16) -> Result<(), FlashError> {
+		let fcr = FCR_FKEY | ((arg as u32) << 8) | cmd as u32;
+		let fsr = cortex_m::interrupt::free(|_| {
+			cortex_m::asm::dsb();
+			unsafe { eefc_command_ram(fcr) }
+		});
+		check_status(fsr)
+	}
+
+	// erase `count` pages starting at `page`, count has to be 8, 16 or 32 (at most 16
+	// in the small sectors) and the first page a multiple of it
+	pub fn erase_pages(&mut self, page: usize, count: usize) -> Result<(), FlashError> {
+		let size = match count {
+			8 => 1,
+			16 => 2,
+			32 => 3,
+			_ => return Err(FlashError::Unaligned)
+		};
+		if page % count != 0 || (count == 32 && page * PAGE_SIZE < SMALL_SECTORS_END) {
+			return Err(FlashError::Unaligned);
+		}
+		if page + count > PAGE_COUNT {
+			return Err(FlashError::OutOfBounds);
+		}
+		self.command(FlashCommand::ErasePages, (page as u16) | size)
+	}
+
+	// erase a region given as offset from the flash start, it has to be aligned to 8 pages
+	pub fn erase(&mut self, offset: usize, len: usize) -> Result<(), FlashError> {
+		if offset + len > FLASH_SIZE {
+			return Err(FlashError::OutOfBounds);
+		}
+		let mut page = offset / PAGE_SIZE;
+		let end = (offset + len + PAGE_SIZE - 1) / PAGE_SIZE;
+		if offset % (8 * PAGE_SIZE) != 0 || (end - page) % 8 != 0 {
+			return Err(FlashError::Unaligned);
+		}
+
+		while page < end {
+			let count = [32, 16, 8].iter()
+				.cloned()
+				.find(|c| page % c == 0 && page + c <= end && (*c < 32 || page * PAGE_SIZE >= SMALL_SECTORS_END))
+				.unwrap();
+			self.erase_pages(page, count)?;
+			page += count;
+		}
+		Ok(())
+	}
+
+	// erase the sector containing `page`
+	pub fn erase_sector(&mut self, page: usize) -> Result<(), FlashError> {
+		if page >= PAGE_COUNT {
+			return Err(FlashError::OutOfBounds);
+		}
+		self.command(FlashCommand::EraseSector, page as u16)
+	}
+
+	// program one (previously erased) page
+	pub fn write_page(&mut self, page: usize, data: &[u8]) -> Result<(), FlashError> {
+		if page >= PAGE_COUNT {
+			return Err(FlashError::OutOfBounds);
+		}
+		if data.len() > PAGE_SIZE {
+			return Err(FlashError::OutOfBounds);
+		}
+		self.program(page, 0, data)
+	}
+
+	// program `data` at byte `start` of a page, the rest of the page is left unchanged
+	fn program(&mut self, page: usize, start: usize, data: &[u8]) -> Result<(), FlashError> {
+		// fill the latch buffer by writing to the page address, missing bytes stay erased
+		let latch = (FLASH_BASE + page * PAGE_SIZE) as *mut u32;
+		for i in 0..PAGE_SIZE / 4 {
+			let mut word = [0xFFu8; 4];
+			for (j, b) in word.iter_mut().enumerate() {
+				if let Some(d) = (i * 4 + j).checked_sub(start).and_then(|k| data.get(k)) {
+					*b = *d;
+				}
+			}
+			unsafe { ptr::write_volatile(latch.add(i), u32::from_le_bytes(word)); }
+		}
+		self.command(FlashCommand::WritePage, page as u16)
+	}
+
+	// program a page aligned region (previously erased) given as offset from the flash start
+	pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+		if offset % PAGE_SIZE != 0 {
+			return Err(FlashError::Unaligned);
+		}
+		if offset + data.len() > FLASH_SIZE {
+			return Err(FlashError::OutOfBounds);
+		}
+		for (i, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+			self.write_page(offset / PAGE_SIZE + i, chunk)?;
+		}
+		Ok(())
+	}
+
+	pub fn read(&self, offset: usize, len: usize) -> &'static [u8] {
+		assert!(offset + len <= FLASH_SIZE);
+		unsafe { core::slice::from_raw_parts((FLASH_BASE + offset) as *const u8, len) }
+	}
+
+	// all gpnvm bits, bit n is gpnvm n
+	pub fn gpnvm(&mut self) -> Result<u32, FlashError> {
+		self.command(FlashCommand::GetGpnvm, 0)?;
+		Ok(unsafe { ptr::read_volatile(EEFC_FRR) })
+	}
+
+	// set or clear a gpnvm bit, the bit is only written if it differs, as every
+	// write costs a flash cycle
+	pub fn set_gpnvm(&mut self, bit: u8, value: bool) -> Result<(), FlashError> {
+		if bit >= GPNVM_COUNT {
+			return Err(FlashError::OutOfBounds);
+		}
+		if (self.gpnvm()? >> bit) & 1 == value as u32 {
+			return Ok(());
+		}
+		let cmd = if value { FlashCommand::SetGpnvm } else { FlashCommand::ClearGpnvm };
+		self.command(cmd, bit as u16)
+	}
+
+	// lock or unlock all lock regions touched by a region given as offset from the
+	// flash start. locked regions can not be written or erased.
+	pub fn set_locked(&mut self, offset: usize, len: usize, locked: bool) -> Result<(), FlashError> {
+		if offset + len > FLASH_SIZE {
+			return Err(FlashError::OutOfBounds);
+		}
+		let cmd = if locked { FlashCommand::SetLockBit } else { FlashCommand::ClearLockBit };
+		let first = offset / LOCK_REGION_SIZE;
+		let end = (offset + len + LOCK_REGION_SIZE - 1) / LOCK_REGION_SIZE;
+		for region in first..end {
+			self.command(cmd, (region * LOCK_REGION_SIZE / PAGE_SIZE) as u16)?;
+		}
+		Ok(())
+	}
+
+	pub fn lock(&mut self, offset: usize, len: usize) -> Result<(), FlashError> {
+		self.set_locked(offset, len, true)
+	}
+
+	pub fn unlock(&mut self, offset: usize, len: usize) -> Result<(), FlashError> {
+		self.set_locked(offset, len, false)
+	}
+
+	// whether the lock region containing `offset` is locked
+	pub fn is_locked(&mut self, offset: usize) -> Result<bool, FlashError> {
+		if offset >= FLASH_SIZE {
+			return Err(FlashError::OutOfBounds);
+		}
+		let region = offset / LOCK_REGION_SIZE;
+		self.command(FlashCommand::GetLockBit, 0)?;
+		// every read of the result register returns the next 32 lock bits
+		let mut bits = 0;
+		for _ in 0..=region / 32 {
+			bits = unsafe { ptr::read_volatile(EEFC_FRR) };
+		}
+		Ok((bits >> (region % 32)) & 1 != 0)
+	}
+
+	// 128 bit identifier unique to every device
+	pub fn unique_id(&mut self) -> Result<[u8; 16], FlashError> {
+		let mut words = [0u32; 4];
+		let fsr = cortex_m::interrupt::free(|_| {
+			cortex_m::asm::dsb();
+			unsafe { eefc_unique_id_ram(&mut words) }
+		});
+		check_status(fsr)?;
+
+		let mut id = [0u8; 16];
+		for (chunk, word) in id.chunks_mut(4).zip(words.iter()) {
+			chunk.copy_from_slice(&word.to_le_bytes());
+		}
+		Ok(id)
+	}
+}
+
+impl ReadNorFlash for Flash {
+	type Error = FlashError;
+
+	const READ_SIZE: usize = 1;
+
+	fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+		let offset = offset as usize;
+		if offset + bytes.len() > FLASH_SIZE {
+			return Err(FlashError::OutOfBounds);
+		}
+		bytes.copy_from_slice(Flash::read(self, offset, bytes.len()));
+		Ok(())
+	}
+
+	fn capacity(&self) -> usize {
+		FLASH_SIZE
+	}
+}
+
+impl NorFlash for Flash {
+	const WRITE_SIZE: usize = WRITE_SIZE;
+
+	const ERASE_SIZE: usize = ERASE_SIZE;
+
+	fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+		let (from, to) = (from as usize, to as usize);
+		if to < from || to > FLASH_SIZE {
+			return Err(FlashError::OutOfBounds);
+		}
+		if from % ERASE_SIZE != 0 || to % ERASE_SIZE != 0 {
+			return Err(FlashError::Unaligned);
+		}
+		Flash::erase(self, from, to - from)
+	}
+
+	fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+		let mut offset = offset as usize;
+		if offset % WRITE_SIZE != 0 || bytes.len() % WRITE_SIZE != 0 {
+			return Err(FlashError::Unaligned);
+		}
+		if offset + bytes.len() > FLASH_SIZE {
+			return Err(FlashError::OutOfBounds);
+		}
+
+		// split the data at page boundaries
+		let mut bytes = bytes;
+		while !bytes.is_empty() {
+			let start = offset % PAGE_SIZE;
+			let len = core::cmp::min(PAGE_SIZE - start, bytes.len());
+			self.program(offset / PAGE_SIZE, start, &bytes[..len])?;
+			offset += len;
+			bytes = &bytes[len..];
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,32 @@
+// general purpose backup registers, they keep their value across resets and in
+// backup mode as long as vddio is present. every user gets a fixed index here.
+
+use core::ptr;
+
+const SYS_GPBR: *mut u32 = 0x400E_1890 as *mut u32;
+
+pub const GPBR_COUNT: usize = 8;
+
+// slot selection state of the bootloader (`boot`)
+pub const BOOT_STATE: usize = 0;
+// rtt value when backup mode was entered (`power`)
+pub const BACKUP_ENTRY: usize = 1;
+pub const BACKUP_MARKER: usize = 2;
+// set by the watchdog early warning before it resets the chip (`watchdog`)
+pub const WATCHDOG_MARKER: usize = 3;
+// number of boots since power on (`bootinfo`)
+pub const BOOT_COUNTER: usize = 4;
+// crash record of the previous run (`bootinfo`)
+pub const CRASH_KIND: usize = 5;
+pub const CRASH_PC: usize = 6;
+pub const CRASH_INFO: usize = 7;
+
+pub fn read(index: usize) -> u32 {
+	assert!(index < GPBR_COUNT);
+	unsafe { ptr::read_volatile(SYS_GPBR.add(index)) }
+}
+
+pub fn write(index: usize, value: u32) {
+	assert!(index < GPBR_COUNT);
+	unsafe { ptr::write_volatile(SYS_GPBR.add(index), value) }
+}
@@ -0,0 +1,289 @@
+// chip identification and per board identifiers
+//
+// `BoardId::read` checks that the firmware runs on the chip it is built for (the
+// atsame70q21 also configured in openocd.cfg) and reads the 128 bit unique identifier
+// of the flash. the serial number and the mac address are derived from that
+// identifier, so they stay the same over reflashing and erasing the settings.
+
+use core::fmt;
+use core::ptr;
+
+use crate::flash::{Flash, FlashError};
+use crate::settings::{Settings, SettingsStorage, KEY_MAC_ADDRESS};
+
+const CHIPID_CIDR: *const u32 = 0x400E_0940 as *const u32;
+const CHIPID_EXID: *const u32 = 0x400E_0944 as *const u32;
+
+// atsame70q21, the version bits are masked out
+pub const EXPECTED_CIDR: u32 = 0xA102_0E00;
+pub const EXPECTED_EXID: u32 = 0x0000_0002;
+const CIDR_VERSION_MASK: u32 = 0x1F;
+
+// the contents of the chip identifier registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipId {
+	pub cidr: u32,
+	pub exid: u32
+}
+
+impl ChipId {
+	pub fn read() -> ChipId {
+		unsafe {
+			ChipId {
+				cidr: ptr::read_volatile(CHIPID_CIDR),
+				exid: ptr::read_volatile(CHIPID_EXID)
+			}
+		}
+	}
+
+	pub fn version(&self) -> u8 {
+		(self.cidr & CIDR_VERSION_MASK) as u8
+	}
+
+	// silicon revision, 'A' for version 0
+	pub fn revision(&self) -> char {
+		(b'A' + self.version()) as char
+	}
+
+	// 0 is the cortex-m7
+	pub fn processor(&self) -> u8 {
+		((self.cidr >> 5) & 0x7) as u8
+	}
+
+	// 0x10 for the same70
+	pub fn architecture(&self) -> u8 {
+		(self.cidr >> 20) as u8
+	}
+
+	// size of the first flash in bytes, None for reserved values
+	pub fn flash_size(&self) -> Option<usize> {
+		let kib = match (self.cidr >> 8) & 0xF {
+			0x0 => 0,
+			0x1 => 8,
+			0x2 => 16,
+			0x3 => 32,
+			0x5 => 64,
+			0x7 => 128,
+			0x8 => 160,
+			0x9 => 256,
+			0xA => 512,
+			0xC => 1024,
+			0xE => 2048,
+			_ => return None
+		};
+		Some(kib * 1024)
+	}
+
+	// size of the internal sram in bytes, including the part used as tcm
+	pub fn sram_size(&self) -> usize {
+		let kib = match (self.cidr >> 16) & 0xF {
+			0x0 => 48,
+			0x1 => 192,
+			0x2 => 384,
+			0x3 => 6,
+			0x4 => 24,
+			0x5 => 4,
+			0x6 => 80,
+			0x7 => 160,
+			0x8 => 8,
+			0x9 => 16,
+			0xA => 32,
+			0xB => 64,
+			0xC => 128,
+			0xD => 256,
+			0xE => 96,
+			_ => 512
+		};
+		kib * 1024
+	}
+
+	pub fn is_expected(&self) -> bool {
+		self.cidr & !CIDR_VERSION_MASK == EXPECTED_CIDR && self.exid == EXPECTED_EXID
+	}
+}
+
+impl fmt::Display for ChipId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.is_expected() {
+			write!(f, "ATSAME70Q21 rev {}", self.revision())?;
+		} else {
+			write!(f, "unknown chip (arch {:#04x}, rev {})", self.architecture(), self.revision())?;
+		}
+		if let Some(flash) = self.flash_size() {
+			write!(f, ", {} KiB flash", flash / 1024)?;
+		}
+		write!(f, ", {} KiB sram", self.sram_size() / 1024)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentError {
+	Flash(FlashError),
+	// the firmware is running on another chip than the one it is built for
+	UnexpectedChip(ChipId)
+}
+
+impl From<FlashError> for IdentError {
+	fn from(e: FlashError) -> IdentError {
+		IdentError::Flash(e)
+	}
+}
+
+pub const SERIAL_LEN: usize = 32;
+
+// the unique identifier as upper case hex digits
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SerialNumber([u8; SERIAL_LEN]);
+
+impl SerialNumber {
+	pub fn as_str(&self) -> &str {
+		core::str::from_utf8(&self.0).unwrap()
+	}
+}
+
+impl fmt::Display for SerialNumber {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+impl fmt::Debug for SerialNumber {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardId {
+	pub chip: ChipId,
+	pub unique_id: [u8; 16]
+}
+
+impl BoardId {
+	// read the chip id and the unique identifier, fails if the chip is not the expected one
+	pub fn read(flash: &mut Flash) -> Result<BoardId, IdentError> {
+		let chip = ChipId::read();
+		if !chip.is_expected() {
+			return Err(IdentError::UnexpectedChip(chip));
+		}
+		Ok(BoardId {
+			chip: chip,
+			unique_id: flash.unique_id()?
+		})
+	}
+
+	// serial number for the inventory, also reported as the usb serial number
+	pub fn serial(&self) -> SerialNumber {
+		const HEX: &[u8; 16] = b"0123456789ABCDEF";
+		let mut serial = [0u8; SERIAL_LEN];
+		for (i, b) in self.unique_id.iter().enumerate() {
+			serial[2 * i] = HEX[(b >> 4) as usize];
+			serial[2 * i + 1] = HEX[(b & 0xF) as usize];
+		}
+		SerialNumber(serial)
+	}
+
+	// locally administered unicast mac address, all bits of the unique identifier
+	// are folded into it
+	pub fn mac_address(&self) -> [u8; 6] {
+		let mut mac = [0u8; 6];
+		for (i, b) in self.unique_id.iter().enumerate() {
+			mac[i % 6] ^= *b;
+		}
+		mac[0] = (mac[0] & !0x01) | 0x02;
+		mac
+	}
+
+	// the mac address stored in the settings, if there is none the derived one
+	pub fn configured_mac_address<S: SettingsStorage>(&self, settings: &Settings<S>) -> [u8; 6] {
+		let mut mac = [0u8; 6];
+		match settings.get(KEY_MAC_ADDRESS, &mut mac) {
+			Some(6) => mac,
+			_ => self.mac_address()
+		}
+	}
+}
+
+impl fmt::Display for BoardId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mac = self.mac_address();
+		write!(f, "{}, serial {}, mac {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+			self.chip, self.serial(), mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn chip(cidr: u32) -> ChipId {
+		ChipId {
+			cidr: cidr,
+			exid: EXPECTED_EXID
+		}
+	}
+
+	fn board(unique_id: [u8; 16]) -> BoardId {
+		BoardId {
+			chip: chip(EXPECTED_CIDR),
+			unique_id: unique_id
+		}
+	}
+
+	#[test]
+	fn same70q21() {
+		for (cidr, revision) in [(0xA102_0E00, 'A'), (0xA102_0E01, 'B')].iter() {
+			let id = chip(*cidr);
+			assert!(id.is_expected());
+			assert_eq!(id.revision(), *revision);
+			assert_eq!(id.processor(), 0);
+			assert_eq!(id.architecture(), 0x10);
+			assert_eq!(id.flash_size(), Some(2048 * 1024));
+			assert_eq!(id.sram_size(), 384 * 1024);
+		}
+		assert_eq!(format!("{}", chip(0xA102_0E01)), "ATSAME70Q21 rev B, 2048 KiB flash, 384 KiB sram");
+	}
+
+	#[test]
+	fn other_chips() {
+		// same70q20: 1 MiB flash
+		assert!(!chip(0xA102_0C00).is_expected());
+		assert!(!ChipId { exid: 0, ..chip(EXPECTED_CIDR) }.is_expected());
+		assert_eq!(chip(0xA102_0800).flash_size(), Some(160 * 1024));
+		assert_eq!(chip(0xA102_0700).flash_size(), Some(128 * 1024));
+		assert_eq!(chip(0xA102_0000).flash_size(), Some(0));
+		assert_eq!(chip(0xA102_0400).flash_size(), None);
+		assert_eq!(chip(0xA102_0F00).flash_size(), None);
+		assert_eq!(chip(0xA10D_0A00).sram_size(), 256 * 1024);
+		assert_eq!(chip(0xA10F_0A00).sram_size(), 512 * 1024);
+		assert_eq!(format!("{}", chip(0xA10D_0400)), "unknown chip (arch 0x10, rev A), 256 KiB sram");
+	}
+
+	#[test]
+	fn serial() {
+		let id = board([0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0x00, 0xFF, 0x10, 0x20, 0x30, 0x40, 0x50, 0x6A]);
+		assert_eq!(id.serial().as_str(), "0123456789ABCDEF00FF10203040506A");
+		assert_eq!(id.serial().as_str().len(), SERIAL_LEN);
+	}
+
+	#[test]
+	fn mac_address() {
+		let ids = [[0u8; 16], [0xFF; 16], [0x01; 16], [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]];
+		for unique_id in ids.iter() {
+			let mac = board(*unique_id).mac_address();
+			// locally administered, not multicast
+			assert_eq!(mac[0] & 0x03, 0x02, "{:02x?}", mac);
+		}
+		// every byte of the identifier changes the address
+		let mut seen = Vec::new();
+		for i in 0..16 {
+			let mut unique_id = [0u8; 16];
+			unique_id[i] = 0x40;
+			let mac = board(unique_id).mac_address();
+			assert_ne!(mac, board([0; 16]).mac_address());
+			seen.push(mac);
+		}
+		assert_eq!(board([0; 16]).mac_address(), [0x02, 0, 0, 0, 0, 0]);
+		assert_eq!(seen[7], [0x02, 0x40, 0, 0, 0, 0]);
+	}
+}
@@ -0,0 +1,173 @@
+// firmware image format and flash slot layout
+//
+// an image starts with a header that fills one flash page, followed by the binary
+// linked to run at `slot base + HEADER_SIZE`. keeping the header one page long keeps
+// the vector table behind it aligned for vtor and lets the updater write the header
+// last, so a slot with an interrupted update never carries a valid header.
+//
+// header layout (all fields little endian u32):
+//   0x00 magic
+//   0x04 header format version
+//   0x08 image version, the bootloader prefers the highest valid one
+//   0x0C payload length in bytes
+//   0x10 crc32 of the payload
+//   0x14 entry point, address of the vector table of the payload
+//   0x18 crc32 of the bytes 0x00..0x18
+
+use crate::crc::crc32;
+use crate::flash::{FLASH_BASE, PAGE_SIZE};
+use crate::layout::BOARD;
+
+pub const IMAGE_MAGIC: u32 = 0x5746_4448; // "HDFW"
+pub const HEADER_FORMAT: u32 = 1;
+pub const HEADER_SIZE: usize = PAGE_SIZE;
+
+const HEADER_FIELDS: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+	TooShort,
+	BadMagic,
+	UnsupportedFormat(u32),
+	HeaderCrc,
+	Length,
+	Crc { expected: u32, actual: u32 },
+	// the entry point does not lie inside the slot the image is checked against
+	WrongSlot
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+	pub version: u32,
+	pub length: u32,
+	pub crc: u32,
+	pub entry: u32
+}
+
+impl ImageHeader {
+	pub fn parse(bytes: &[u8]) -> Result<ImageHeader, ImageError> {
+		if bytes.len() < HEADER_FIELDS * 4 {
+			return Err(ImageError::TooShort);
+		}
+		let mut fields = [0u32; HEADER_FIELDS];
+		for (i, f) in fields.iter_mut().enumerate() {
+			*f = u32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+		}
+
+		if fields[0] != IMAGE_MAGIC {
+			return Err(ImageError::BadMagic);
+		}
+		if fields[1] != HEADER_FORMAT {
+			return Err(ImageError::UnsupportedFormat(fields[1]));
+		}
+		if fields[6] != crc32(&bytes[..24]) {
+			return Err(ImageError::HeaderCrc);
+		}
+
+		Ok(ImageHeader {
+			version: fields[2],
+			length: fields[3],
+			crc: fields[4],
+			entry: fields[5]
+		})
+	}
+
+	// serialize into `buf`, which has to be at least HEADER_SIZE long
+	pub fn write_to(&self, buf: &mut [u8]) {
+		let fields = [IMAGE_MAGIC, HEADER_FORMAT, self.version, self.length, self.crc, self.entry];
+		for b in buf[..HEADER_SIZE].iter_mut() {
+			*b = 0xFF;
+		}
+		for (i, f) in fields.iter().enumerate() {
+			buf[i * 4..i * 4 + 4].copy_from_slice(&f.to_le_bytes());
+		}
+		let crc = crc32(&buf[..24]);
+		buf[24..28].copy_from_slice(&crc.to_le_bytes());
+	}
+
+	pub fn for_payload(version: u32, payload: &[u8], entry: u32) -> ImageHeader {
+		ImageHeader {
+			version: version,
+			length: payload.len() as u32,
+			crc: crc32(payload),
+			entry: entry
+		}
+	}
+}
+
+// check header and payload crc of a complete image (header page + payload)
+pub fn verify_image(image: &[u8]) -> Result<ImageHeader, ImageError> {
+	let header = ImageHeader::parse(image)?;
+	let end = HEADER_SIZE + header.length as usize;
+	if image.len() < end {
+		return Err(ImageError::Length);
+	}
+	let actual = crc32(&image[HEADER_SIZE..end]);
+	if actual != header.crc {
+		return Err(ImageError::Crc { expected: header.crc, actual: actual });
+	}
+	Ok(header)
+}
+
+// flash partitioning as described in board.rs, the first sector (128 KiB) is
+// reserved for the bootloader and the last sector for board settings
+pub const BOOTLOADER_OFFSET: usize = BOARD.bootloader_offset;
+pub const BOOTLOADER_SIZE: usize = BOARD.bootloader_size;
+pub const SLOT_SIZE: usize = BOARD.slot_size;
+pub const SETTINGS_OFFSET: usize = BOARD.settings_offset();
+pub const SETTINGS_SIZE: usize = BOARD.settings_size;
+
+// the linker places slot applications behind the header
+const _: () = [()][(HEADER_SIZE != BOARD.image_header_size) as usize];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+	A,
+	B
+}
+
+impl Slot {
+	// offset from the flash start
+	pub fn offset(self) -> usize {
+		match self {
+			Slot::A => BOOTLOADER_OFFSET + BOOTLOADER_SIZE,
+			Slot::B => BOOTLOADER_OFFSET + BOOTLOADER_SIZE + SLOT_SIZE
+		}
+	}
+
+	pub fn address(self) -> usize {
+		FLASH_BASE + self.offset()
+	}
+
+	// address the payload of this slot has to be linked to
+	pub fn entry(self) -> u32 {
+		(self.address() + HEADER_SIZE) as u32
+	}
+
+	pub fn other(self) -> Slot {
+		match self {
+			Slot::A => Slot::B,
+			Slot::B => Slot::A
+		}
+	}
+
+	pub fn containing(address: usize) -> Option<Slot> {
+		[Slot::A, Slot::B].iter()
+			.cloned()
+			.find(|s| address >= s.address() && address < s.address() + SLOT_SIZE)
+	}
+
+	// verify an image as it would be placed in this slot
+	pub fn check(self, image: &[u8]) -> Result<ImageHeader, ImageError> {
+		let header = verify_image(image)?;
+		if header.entry != self.entry() || HEADER_SIZE + header.length as usize > SLOT_SIZE {
+			return Err(ImageError::WrongSlot);
+		}
+		Ok(header)
+	}
+
+	// contents of the slot as currently programmed in flash
+	pub fn contents(self) -> &'static [u8] {
+		unsafe { core::slice::from_raw_parts(self.address() as *const u8, SLOT_SIZE) }
+	}
+}
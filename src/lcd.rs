@@ -2,37 +2,150 @@ use atsamx7x_hal::smc::{Smc, *};
 use atsamx7x_hal::time::{NanoSeconds};
 use atsamx7x_hal::gpio::*;
 
+use core::ptr;
+
+use crate::bus::SmcTimingSpec;
+
+// the display controller sits on smc chip select 2, its register select line is
+// wired to a1 so commands and data are two consecutive 16 bit addresses
+const LCD_BASE: usize = 0x6200_0000;
+const LCD_COMMAND: *mut u16 = LCD_BASE as *mut u16;
+const LCD_DATA: *mut u16 = (LCD_BASE + 2) as *mut u16;
+
+pub const LCD_WIDTH: u16 = 320;
+pub const LCD_HEIGHT: u16 = 240;
+
+// display command set (mipi dcs)
+const DCS_SET_COLUMN: u16 = 0x2A;
+const DCS_SET_PAGE: u16 = 0x2B;
+const DCS_WRITE_MEMORY: u16 = 0x2C;
+
+pub const fn rgb565(r: u8, g: u8, b: u8) -> u16 {
+	((r as u16 & 0xF8) << 8) | ((g as u16 & 0xFC) << 3) | (b as u16 >> 3)
+}
+
+// the pixel loops run from the itcm (see `tcm`), so pushing pixels does not compete
+// with other code for the instruction cache
+itcm! {
+	fn push_color(color: u16, count: u32) {
+		for _ in 0..count {
+			unsafe { ptr::write_volatile(LCD_DATA, color) }
+		}
+	}
+}
+
+itcm! {
+	fn push_pixels(pixels: &[u16]) {
+		for p in pixels {
+			unsafe { ptr::write_volatile(LCD_DATA, *p) }
+		}
+	}
+}
 
 pub struct LCD {
 	backlight_pwm_pin: pioa::PA1<PeripheralCntr<PeriphB>>
 }
 
 impl LCD {
+	pub fn width(&self) -> u16 {
+		LCD_WIDTH
+	}
 
+	pub fn height(&self) -> u16 {
+		LCD_HEIGHT
+	}
+
+	pub fn write_command(&mut self, cmd: u16) {
+		unsafe { ptr::write_volatile(LCD_COMMAND, cmd) }
+	}
+
+	pub fn write_data(&mut self, data: u16) {
+		unsafe { ptr::write_volatile(LCD_DATA, data) }
+	}
+
+	// select the (inclusive) rectangle following pixel writes go to
+	pub fn set_window(&mut self, x0: u16, y0: u16, x1: u16, y1: u16) {
+		self.write_command(DCS_SET_COLUMN);
+		self.write_data(x0 >> 8);
+		self.write_data(x0 & 0xFF);
+		self.write_data(x1 >> 8);
+		self.write_data(x1 & 0xFF);
+		self.write_command(DCS_SET_PAGE);
+		self.write_data(y0 >> 8);
+		self.write_data(y0 & 0xFF);
+		self.write_data(y1 >> 8);
+		self.write_data(y1 & 0xFF);
+		self.write_command(DCS_WRITE_MEMORY);
+	}
+
+	// rgb565 pixels into the current window
+	pub fn write_pixels<I: IntoIterator<Item = u16>>(&mut self, pixels: I) {
+		for p in pixels {
+			self.write_data(p);
+		}
+	}
+
+	// same as `write_pixels` for pixels already in memory, runs from the itcm
+	pub fn write_pixel_slice(&mut self, pixels: &[u16]) {
+		push_pixels(pixels);
+	}
+
+	pub fn fill_rect(&mut self, x: u16, y: u16, width: u16, height: u16, color: u16) {
+		if width == 0 || height == 0 || x >= LCD_WIDTH || y >= LCD_HEIGHT {
+			return;
+		}
+		let x1 = core::cmp::min(x as u32 + width as u32, LCD_WIDTH as u32) as u16 - 1;
+		let y1 = core::cmp::min(y as u32 + height as u32, LCD_HEIGHT as u32) as u16 - 1;
+		self.set_window(x, y, x1, y1);
+		let count = (x1 - x + 1) as u32 * (y1 - y + 1) as u32;
+		push_color(color, count);
+	}
+
+	pub fn fill(&mut self, color: u16) {
+		self.fill_rect(0, 0, LCD_WIDTH, LCD_HEIGHT, color);
+	}
 }
 
+pub const LCD_SMC_DEVICE: u8 = 2;
+
+// kept around so the smc can be reprogrammed when the master clock changes (see `bus`),
+// setup time for signals is negligable, when setting to 0 at least 1 cycle is used
+pub const LCD_TIMING: SmcTimingSpec = SmcTimingSpec {
+	setup_read_ns: 0,
+	setup_read_cs_ns: 0,
+	setup_write_ns: 5,
+	setup_write_cs_ns: 5,
+	pulse_read_ns: 500,
+	pulse_read_cs_ns: 500,
+	pulse_write_ns: 50,
+	pulse_write_cs_ns: 50,
+	cycle_read_ns: 1000,
+	cycle_write_ns: 100
+};
+
 pub fn setup_lcd(smc: &mut Smc, lcd_pin: pioa::PA1<PeripheralCntr<PeriphB>>) -> LCD{
+	// the pixel loops live in the itcm, which the application loads at startup
+	assert!(crate::tcm::is_initialised(), "the lcd needs the itcm, call tcm::init first");
 	let conf = SmcDeviceConfig{
 		mode: SmcDeviceMode::default()
 			.bus_width_16_bit()
 			.read_mode_rd()
 			.write_mode_we(),
-		// setup time for signals is negligable, when setting to 0 at least 1 cycle is used
 		setup: SmcDeviceSetupTimings {
-			read: NanoSeconds(0).into(),
-			read_cs: NanoSeconds(0).into(),
-			write: NanoSeconds(5).into(),
-			write_cs: NanoSeconds(5).into()
+			read: NanoSeconds(LCD_TIMING.setup_read_ns).into(),
+			read_cs: NanoSeconds(LCD_TIMING.setup_read_cs_ns).into(),
+			write: NanoSeconds(LCD_TIMING.setup_write_ns).into(),
+			write_cs: NanoSeconds(LCD_TIMING.setup_write_cs_ns).into()
 		},
 		pulse: SmcDevicePulseTimings {
-			read: NanoSeconds(500).into(),
-			read_cs: NanoSeconds(500).into(),
-			write: NanoSeconds(50).into(),
-			write_cs: NanoSeconds(50).into()
+			read: NanoSeconds(LCD_TIMING.pulse_read_ns).into(),
+			read_cs: NanoSeconds(LCD_TIMING.pulse_read_cs_ns).into(),
+			write: NanoSeconds(LCD_TIMING.pulse_write_ns).into(),
+			write_cs: NanoSeconds(LCD_TIMING.pulse_write_cs_ns).into()
 		},
 		cycle: SmcDeviceCycleTimings {
-			read: NanoSeconds(1000).into(),
-			write: NanoSeconds(100).into()
+			read: NanoSeconds(LCD_TIMING.cycle_read_ns).into(),
+			write: NanoSeconds(LCD_TIMING.cycle_write_ns).into()
 		}
 	};
 
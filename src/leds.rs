@@ -0,0 +1,285 @@
+// the two board leds on pc19 and pc10
+//
+// both are driven by open drain outputs and light up when the pin is pulled low.
+// besides switching them directly, `Leds` can run patterns that are advanced by
+// calling `tick` once every millisecond (e.g. from a timer interrupt or a delay loop).
+
+use embedded_hal::digital::v2::OutputPin;
+
+// ticks per software pwm period, also the number of brightness steps
+pub const PWM_STEPS: u8 = 10;
+
+// blink codes for errors during board bring up
+pub const ERROR_CLOCKS: u8 = 1;
+pub const ERROR_SDRAM_TEST_FAILED: u8 = 2;
+pub const ERROR_LCD: u8 = 3;
+pub const ERROR_NETWORK: u8 = 4;
+pub const ERROR_UPDATE: u8 = 5;
+
+pub struct Led<P> {
+	pin: P,
+	active_low: bool,
+	on: bool
+}
+
+impl<P: OutputPin> Led<P> {
+	pub fn new(pin: P, active_low: bool) -> Led<P> {
+		let mut led = Led {
+			pin: pin,
+			active_low: active_low,
+			on: true
+		};
+		led.off();
+		led
+	}
+
+	// open drain led connected to the supply
+	pub fn active_low(pin: P) -> Led<P> {
+		Led::new(pin, true)
+	}
+
+	pub fn set(&mut self, on: bool) {
+		if on == self.on {
+			return;
+		}
+		// switching a gpio can not fail on this chip
+		if on != self.active_low {
+			self.pin.set_high().ok();
+		} else {
+			self.pin.set_low().ok();
+		}
+		self.on = on;
+	}
+
+	pub fn on(&mut self) {
+		self.set(true);
+	}
+
+	pub fn off(&mut self) {
+		self.set(false);
+	}
+
+	pub fn toggle(&mut self) {
+		let on = !self.on;
+		self.set(on);
+	}
+
+	pub fn is_on(&self) -> bool {
+		self.on
+	}
+
+	pub fn free(self) -> P {
+		self.pin
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+	Off,
+	On,
+	// half the period on, half off
+	Blink { period_ms: u32 },
+	// double flash once a second
+	Heartbeat,
+	// `code` short flashes followed by a pause
+	ErrorCode(u8),
+	// fade in and out using software pwm
+	Breathe { period_ms: u32 }
+}
+
+impl Pattern {
+	// length of one repetition
+	pub fn period_ms(&self) -> u32 {
+		match *self {
+			Pattern::Off | Pattern::On => 1,
+			Pattern::Blink { period_ms } | Pattern::Breathe { period_ms } => core::cmp::max(period_ms, 2),
+			Pattern::Heartbeat => 1000,
+			Pattern::ErrorCode(code) => code as u32 * 500 + 1500
+		}
+	}
+
+	// brightness in 0..=PWM_STEPS at `t_ms` into the period
+	pub fn level(&self, t_ms: u32) -> u8 {
+		let on = |b: bool| if b { PWM_STEPS } else { 0 };
+		match *self {
+			Pattern::Off => 0,
+			Pattern::On => PWM_STEPS,
+			Pattern::Blink { .. } => on(t_ms < self.period_ms() / 2),
+			Pattern::Heartbeat => on(t_ms < 100 || (t_ms >= 250 && t_ms < 350)),
+			Pattern::ErrorCode(code) => on(t_ms < code as u32 * 500 && t_ms % 500 < 200),
+			Pattern::Breathe { .. } => {
+				let half = self.period_ms() / 2;
+				let t = if t_ms < half { t_ms } else { self.period_ms() - t_ms };
+				(t * PWM_STEPS as u32 / half) as u8
+			}
+		}
+	}
+}
+
+struct Animation {
+	pattern: Pattern,
+	t_ms: u32
+}
+
+impl Animation {
+	fn new(pattern: Pattern) -> Animation {
+		Animation {
+			pattern: pattern,
+			t_ms: 0
+		}
+	}
+
+	fn tick(&mut self, pwm_phase: u8) -> bool {
+		let level = self.pattern.level(self.t_ms);
+		self.t_ms = (self.t_ms + 1) % self.pattern.period_ms();
+		pwm_phase < level
+	}
+}
+
+pub struct Leds<A, B> {
+	pub led0: Led<A>,
+	pub led1: Led<B>,
+	animations: [Animation; 2],
+	pwm_phase: u8
+}
+
+impl<A: OutputPin, B: OutputPin> Leds<A, B> {
+	// `led0` is the open drain output on pc19, `led1` the one on pc10
+	pub fn new(led0: A, led1: B) -> Leds<A, B> {
+		Leds {
+			led0: Led::active_low(led0),
+			led1: Led::active_low(led1),
+			animations: [Animation::new(Pattern::Off), Animation::new(Pattern::Off)],
+			pwm_phase: 0
+		}
+	}
+
+	// returns false if there is no led with that index
+	pub fn set_pattern(&mut self, index: usize, pattern: Pattern) -> bool {
+		match self.animations.get_mut(index) {
+			Some(a) => {
+				*a = Animation::new(pattern);
+				true
+			},
+			None => false
+		}
+	}
+
+	pub fn pattern(&self, index: usize) -> Option<Pattern> {
+		self.animations.get(index).map(|a| a.pattern)
+	}
+
+	// show an error code on both leds
+	pub fn error(&mut self, code: u8) {
+		self.set_pattern(0, Pattern::ErrorCode(code));
+		self.set_pattern(1, Pattern::ErrorCode(code));
+	}
+
+	// advance the patterns by one millisecond
+	pub fn tick(&mut self) {
+		let phase = self.pwm_phase;
+		self.pwm_phase = (self.pwm_phase + 1) % PWM_STEPS;
+
+		let on = self.animations[0].tick(phase);
+		self.led0.set(on);
+		let on = self.animations[1].tick(phase);
+		self.led1.set(on);
+	}
+
+	pub fn free(self) -> (A, B) {
+		(self.led0.free(), self.led1.free())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct Pin {
+		high: bool
+	}
+
+	impl OutputPin for Pin {
+		type Error = ();
+
+		fn set_low(&mut self) -> Result<(), ()> {
+			self.high = false;
+			Ok(())
+		}
+
+		fn set_high(&mut self) -> Result<(), ()> {
+			self.high = true;
+			Ok(())
+		}
+	}
+
+	fn levels(pattern: Pattern, times: &[u32]) -> Vec<u8> {
+		times.iter().map(|t| pattern.level(*t)).collect()
+	}
+
+	#[test]
+	fn blink() {
+		let blink = Pattern::Blink { period_ms: 100 };
+		assert_eq!(levels(blink, &[0, 49, 50, 99]), vec![PWM_STEPS, PWM_STEPS, 0, 0]);
+		// too short periods still blink
+		let fast = Pattern::Blink { period_ms: 0 };
+		assert_eq!(fast.period_ms(), 2);
+		assert_eq!(levels(fast, &[0, 1]), vec![PWM_STEPS, 0]);
+	}
+
+	#[test]
+	fn heartbeat() {
+		let on = PWM_STEPS;
+		assert_eq!(Pattern::Heartbeat.period_ms(), 1000);
+		assert_eq!(levels(Pattern::Heartbeat, &[0, 99, 100, 249, 250, 349, 350, 999]), vec![on, on, 0, 0, on, on, 0, 0]);
+	}
+
+	#[test]
+	fn error_code() {
+		let on = PWM_STEPS;
+		let code = Pattern::ErrorCode(3);
+		assert_eq!(code.period_ms(), 3000);
+		assert_eq!(levels(code, &[0, 199, 200, 499, 500, 1199, 1200, 1499, 1500, 2999]),
+			vec![on, on, 0, 0, on, on, 0, 0, 0, 0]);
+		// three flashes in one period
+		let flashes = (1..code.period_ms()).filter(|t| code.level(*t) > 0 && code.level(t - 1) == 0).count();
+		assert_eq!(flashes + (code.level(0) > 0) as usize, 3);
+		assert!((0..1500).all(|t| Pattern::ErrorCode(0).level(t) == 0));
+	}
+
+	#[test]
+	fn breathe() {
+		let breathe = Pattern::Breathe { period_ms: 200 };
+		assert_eq!(levels(breathe, &[0, 50, 99, 100, 150, 199]), vec![0, 5, 9, PWM_STEPS, 5, 0]);
+		assert_eq!(levels(Pattern::Off, &[0]), vec![0]);
+		assert_eq!(levels(Pattern::On, &[0]), vec![PWM_STEPS]);
+	}
+
+	#[test]
+	fn ticks_wrap_around() {
+		let mut leds = Leds::new(Pin { high: false }, Pin { high: false });
+		// off after construction, the leds are active low
+		assert!(!leds.led0.is_on());
+		assert!(leds.pattern(2).is_none());
+		assert!(!leds.set_pattern(2, Pattern::On));
+		assert!(leds.set_pattern(0, Pattern::Blink { period_ms: 4 }));
+		leds.set_pattern(1, Pattern::Breathe { period_ms: 40 });
+
+		let mut led0 = Vec::new();
+		let mut led1 = 0;
+		for _ in 0..3 * 40 {
+			leds.tick();
+			led0.push(leds.led0.is_on());
+			led1 += leds.led1.is_on() as u32;
+		}
+		assert_eq!(led0[..8], [true, true, false, false, true, true, false, false]);
+		assert!(led0.chunks(4).all(|c| c == [true, true, false, false]));
+		// the software pwm averages to half brightness over a breathing period
+		assert_eq!(led1 % 3, 0);
+		assert!(led1 / 3 > 15 && led1 / 3 < 25, "{}", led1);
+
+		// the period ended with the led off, which releases the open drain output
+		let (pin0, _) = leds.free();
+		assert!(pin0.high);
+	}
+}
@@ -1,4 +1,35 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+// memory layout shared with build.rs
+#[path = "../board.rs"]
+pub mod layout;
+
+#[macro_use]
+pub mod tcm;
 pub mod mem;
 pub mod lcd;
+pub mod crc;
+pub mod net;
+pub mod tftp;
+pub mod flash;
+pub mod image;
+pub mod update;
+pub mod boot;
+pub mod xmodem;
+pub mod shell;
+pub mod leds;
+pub mod clocks;
+pub mod bus;
+pub mod gpbr;
+pub mod rtt;
+pub mod power;
+pub mod watchdog;
+pub mod bootinfo;
+pub mod stack;
+pub mod ident;
+pub mod settings;
+pub mod ramdisk;
+pub mod bitmap;
+pub mod widgets;
+pub mod usb;
+pub mod uart;
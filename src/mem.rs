@@ -129,6 +129,29 @@ impl Default for EbiPins {
 
 impl atsamx7x_hal::ebi::EBIPins for EbiPins{}
 
+// sdram timings from the datasheet, kept around so the controller can be
+// reprogrammed when the master clock changes (see `bus`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdramTimingSpec {
+	pub twr_ns: u32,
+	pub trc_ns: u32,
+	pub trp_ns: u32,
+	pub trcd_ns: u32,
+	pub tras_ns: u32,
+	pub txsr_ps: u32,
+	pub refresh_ns: u32
+}
+
+pub const SDRAM_TIMING: SdramTimingSpec = SdramTimingSpec {
+	twr_ns: 12,
+	trc_ns: 60,
+	trp_ns: 18,
+	trcd_ns: 18,
+	tras_ns: 42,
+	txsr_ps: 61500,
+	refresh_ns: 7812
+};
+
 pub fn init_sdram(pmc: &mut PMC, sdramc: SDRAMC, clocks: &Clocks, ebi: &ExternalBusInterface) -> Sdram{
 
 	let conf = SdramConfig {
@@ -138,16 +161,53 @@ pub fn init_sdram(pmc: &mut PMC, sdramc: SDRAMC, clocks: &Clocks, ebi: &External
 		alignment: SdramAlignment::Unaligned,
 		latency: SdramCasLatency::Latency3,
 		timing : SdramTiming {
-			twr : NanoSeconds(12).into(),
-			trc : NanoSeconds(60).into(),
-			trp : NanoSeconds(18).into(),
-			trcd: NanoSeconds(18).into(),
-			tras: NanoSeconds(42).into(),
-			txsr: PicoSeconds(61500),
-			refresh : NanoSeconds(7812).into()
+			twr : NanoSeconds(SDRAM_TIMING.twr_ns).into(),
+			trc : NanoSeconds(SDRAM_TIMING.trc_ns).into(),
+			trp : NanoSeconds(SDRAM_TIMING.trp_ns).into(),
+			trcd: NanoSeconds(SDRAM_TIMING.trcd_ns).into(),
+			tras: NanoSeconds(SDRAM_TIMING.tras_ns).into(),
+			txsr: PicoSeconds(SDRAM_TIMING.txsr_ps),
+			refresh : NanoSeconds(SDRAM_TIMING.refresh_ns).into()
 		},
 	};
 
 	let sdram = Sdram::setup(sdramc, &ebi, conf, clocks, pmc).unwrap();
+	unsafe { init_sdram_sections() };
 	sdram
 }
+
+// bounds of the `.sdram.data` and `.sdram.bss` sections, see sdram.x
+extern "C" {
+	static mut _ssdram_data: u32;
+	static mut _esdram_data: u32;
+	static _sisdram_data: u32;
+	static mut _ssdram_bss: u32;
+	static mut _esdram_bss: u32;
+	static _esdram: u32;
+}
+
+// copy the initial values of `.sdram.data` from flash and zero `.sdram.bss`
+unsafe fn init_sdram_sections() {
+	r0::init_data(&mut _ssdram_data, &mut _esdram_data, &_sisdram_data);
+	r0::zero_bss(&mut _ssdram_bss, &mut _esdram_bss);
+}
+
+// bytes at the start of the sdram taken by statics
+pub fn sdram_statics_size(sdram: &Sdram) -> usize {
+	unsafe { &_esdram as *const u32 as usize - sdram.start_address() as usize }
+}
+
+// start and size of the sdram not used by statics, e.g. for the heap
+pub fn sdram_heap(sdram: &Sdram) -> (usize, usize) {
+	let used = sdram_statics_size(sdram);
+	(sdram.start_address() as usize + used, sdram.size() as usize - used)
+}
+
+// hand out a region of the sdram as byte slice, e.g. as download buffer
+// the caller has to make sure that handed out regions do not overlap (or with the heap)
+// offsets are relative to the start of the sdram, the statics in it are never handed out
+pub unsafe fn sdram_region(sdram: &Sdram, offset: usize, len: usize) -> &'static mut [u8] {
+	let end = offset.checked_add(len).expect("sdram region overflows");
+	assert!(offset >= sdram_statics_size(sdram) && end <= sdram.size() as usize);
+	core::slice::from_raw_parts_mut((sdram.start_address() as *mut u8).add(offset), len)
+}
@@ -0,0 +1,29 @@
+// minimal network abstractions, the actual stack (ethernet controller on the ebi
+// chip select or the gmac) has to provide an implementation of these traits
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+	pub addr: Ipv4Addr,
+	pub port: u16
+}
+
+impl Endpoint {
+	pub fn new(addr: Ipv4Addr, port: u16) -> Endpoint {
+		Endpoint {
+			addr: addr,
+			port: port
+		}
+	}
+}
+
+pub trait UdpSocket {
+	type Error;
+
+	fn send_to(&mut self, data: &[u8], remote: Endpoint) -> Result<(), Self::Error>;
+
+	// returns `Ok(None)` if no datagram arrived within `timeout_ms`
+	fn recv_from(&mut self, buf: &mut [u8], timeout_ms: u32) -> Result<Option<(usize, Endpoint)>, Self::Error>;
+}
@@ -0,0 +1,266 @@
+// low power modes
+//
+// sleep: the core stops until the next interrupt, everything else keeps running.
+// wait:  all clocks stop, the chip wakes up within microseconds on a fast startup
+//        source. the sdram is put into self-refresh before the master clock stops
+//        and the clocks and bus timings are restored afterwards, so its contents
+//        survive.
+// backup: the core is powered off, waking up resets the chip. the sdram is left in
+//        self-refresh, `init_sdram` has to be called again after the reset.
+//
+// the time spent in each mode is measured with the rtt.
+
+use core::ptr;
+
+use atsame70q21::{EFC, PMC, SUPC};
+use atsamx7x_hal::clock_gen::Clocks;
+
+use crate::bus::{self, BusTimings};
+use crate::clocks::ClockSettings;
+use crate::gpbr;
+use crate::rtt;
+
+const PMC_BASE: usize = 0x400E_0600;
+const CKGR_MOR: *mut u32 = (PMC_BASE + 0x20) as *mut u32;
+const PMC_MCKR: *mut u32 = (PMC_BASE + 0x30) as *mut u32;
+const PMC_SR: *const u32 = (PMC_BASE + 0x68) as *const u32;
+const PMC_FSMR: *mut u32 = (PMC_BASE + 0x70) as *mut u32;
+const PMC_FSPR: *mut u32 = (PMC_BASE + 0x74) as *mut u32;
+
+const CKGR_MOR_KEY: u32 = 0x37 << 16;
+const CKGR_MOR_WAITMODE: u32 = 1 << 2;
+const CKGR_MOR_MOSCRCEN: u32 = 1 << 3;
+const CKGR_MOR_MOSCRCF_12MHZ: u32 = 2 << 4;
+const CKGR_MOR_MOSCSEL: u32 = 1 << 24;
+const CKGR_MOR_KEEP: u32 = 0x0300_FF03;
+
+const PMC_MCKR_CSS_MASK: u32 = 0x3;
+const PMC_MCKR_CSS_MAIN: u32 = 0x1;
+const PMC_MCKR_PRES_MDIV_MASK: u32 = 0x370;
+
+const PMC_SR_MCKRDY: u32 = 1 << 3;
+const PMC_SR_MOSCSELS: u32 = 1 << 16;
+const PMC_SR_MOSCRCS: u32 = 1 << 17;
+
+const PMC_FSMR_RTTAL: u32 = 1 << 16;
+const PMC_FSMR_LPM: u32 = 1 << 20;
+// keep the flash in deep power down while waiting
+const PMC_FSMR_FLPM_DEEP_POWER_DOWN: u32 = 1 << 21;
+
+const SUPC_BASE: usize = 0x400E_1810;
+const SUPC_CR: *mut u32 = SUPC_BASE as *mut u32;
+const SUPC_WUMR: *mut u32 = (SUPC_BASE + 0x0C) as *mut u32;
+const SUPC_WUIR: *mut u32 = (SUPC_BASE + 0x10) as *mut u32;
+
+const SUPC_CR_KEY: u32 = 0xA5 << 24;
+const SUPC_CR_VROFF: u32 = 1 << 2;
+const SUPC_WUMR_RTTEN: u32 = 1 << 2;
+
+const BACKUP_MARKER: u32 = 0xBAC0_0001;
+
+// uart0 rx (pa9) doubles as wake-up input 6
+pub const WKUP_UART0_RX: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WakeSources {
+	pins: u16,
+	active_high: u16,
+	rtt_alarm_ms: Option<u32>
+}
+
+impl WakeSources {
+	pub fn new() -> WakeSources {
+		WakeSources::default()
+	}
+
+	// any falling edge (start bit) on the uart0 receive line
+	pub fn uart_rx(self) -> Self {
+		self.pin(WKUP_UART0_RX, false)
+	}
+
+	// one of the wake-up inputs WKUP0 .. WKUP13
+	pub fn pin(mut self, wkup: u8, active_high: bool) -> Self {
+		assert!(wkup < 14);
+		self.pins |= 1 << wkup;
+		if active_high {
+			self.active_high |= 1 << wkup;
+		} else {
+			self.active_high &= !(1 << wkup);
+		}
+		self
+	}
+
+	pub fn rtt_alarm_ms(mut self, ms: u32) -> Self {
+		self.rtt_alarm_ms = Some(ms);
+		self
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PowerStats {
+	pub run_ms: u32,
+	pub sleep_ms: u32,
+	pub wait_ms: u32,
+	pub sleep_count: u32,
+	pub wait_count: u32
+}
+
+// `ticks` summed up over many intervals in ms, converting each interval on its own
+// would drop everything shorter than a millisecond
+fn total_ms(ticks: u64) -> u32 {
+	core::cmp::min(ticks * 1000 / rtt::RTT_HZ as u64, u32::MAX as u64) as u32
+}
+
+pub struct Power {
+	// time in each mode in rtt ticks
+	run_ticks: u64,
+	sleep_ticks: u64,
+	wait_ticks: u64,
+	sleep_count: u32,
+	wait_count: u32,
+	last: u32,
+	last_backup_ms: Option<u32>
+}
+
+impl Power {
+	pub fn new() -> Power {
+		rtt::init();
+		let now = rtt::now();
+
+		// a valid marker means we woke up from backup mode
+		let last_backup_ms = if gpbr::read(gpbr::BACKUP_MARKER) == BACKUP_MARKER {
+			gpbr::write(gpbr::BACKUP_MARKER, 0);
+			Some(rtt::ticks_to_ms(now.wrapping_sub(gpbr::read(gpbr::BACKUP_ENTRY))))
+		} else {
+			None
+		};
+
+		Power {
+			run_ticks: 0,
+			sleep_ticks: 0,
+			wait_ticks: 0,
+			sleep_count: 0,
+			wait_count: 0,
+			last: now,
+			last_backup_ms: last_backup_ms
+		}
+	}
+
+	pub fn stats(&self) -> PowerStats {
+		let running = rtt::now().wrapping_sub(self.last) as u64;
+		PowerStats {
+			run_ms: total_ms(self.run_ticks + running),
+			sleep_ms: total_ms(self.sleep_ticks),
+			wait_ms: total_ms(self.wait_ticks),
+			sleep_count: self.sleep_count,
+			wait_count: self.wait_count
+		}
+	}
+
+	// time spent in backup mode before the last reset
+	pub fn last_backup_ms(&self) -> Option<u32> {
+		self.last_backup_ms
+	}
+
+	// rtt ticks since the last call
+	fn elapsed(&mut self) -> u64 {
+		let now = rtt::now();
+		let ticks = now.wrapping_sub(self.last);
+		self.last = now;
+		ticks as u64
+	}
+
+	// stop the core until an interrupt occurs
+	pub fn sleep(&mut self) {
+		self.run_ticks += self.elapsed();
+		unsafe {
+			let fsmr = ptr::read_volatile(PMC_FSMR);
+			ptr::write_volatile(PMC_FSMR, fsmr & !PMC_FSMR_LPM);
+			(*cortex_m::peripheral::SCB::ptr()).scr.modify(|scr| scr & !(1 << 2));
+		}
+		cortex_m::asm::dsb();
+		cortex_m::asm::wfi();
+		self.sleep_ticks += self.elapsed();
+		self.sleep_count += 1;
+	}
+
+	// enter wait mode until one of `wake` triggers, afterwards the clocks are
+	// configured with `settings` again and the bus retimed by `bus`
+	pub fn wait(&mut self, wake: &WakeSources, timings: &BusTimings, settings: &ClockSettings, pmc: &mut PMC, supc: &mut SUPC, efc: &EFC) -> Clocks {
+		self.run_ticks += self.elapsed();
+
+		cortex_m::interrupt::free(|_| unsafe {
+			bus::enter_self_refresh();
+
+			// wait mode has to be entered from the main rc oscillator
+			let mor = ptr::read_volatile(CKGR_MOR) & CKGR_MOR_KEEP;
+			ptr::write_volatile(CKGR_MOR, mor | CKGR_MOR_KEY | CKGR_MOR_MOSCRCEN | CKGR_MOR_MOSCRCF_12MHZ);
+			while ptr::read_volatile(PMC_SR) & PMC_SR_MOSCRCS == 0 {}
+
+			let mckr = ptr::read_volatile(PMC_MCKR);
+			ptr::write_volatile(PMC_MCKR, (mckr & !PMC_MCKR_CSS_MASK) | PMC_MCKR_CSS_MAIN);
+			while ptr::read_volatile(PMC_SR) & PMC_SR_MCKRDY == 0 {}
+			let mckr = ptr::read_volatile(PMC_MCKR);
+			ptr::write_volatile(PMC_MCKR, mckr & !PMC_MCKR_PRES_MDIV_MASK);
+			while ptr::read_volatile(PMC_SR) & PMC_SR_MCKRDY == 0 {}
+
+			let mor = ptr::read_volatile(CKGR_MOR) & CKGR_MOR_KEEP;
+			ptr::write_volatile(CKGR_MOR, (mor & !CKGR_MOR_MOSCSEL) | CKGR_MOR_KEY | CKGR_MOR_MOSCRCEN | CKGR_MOR_MOSCRCF_12MHZ);
+			while ptr::read_volatile(PMC_SR) & PMC_SR_MOSCSELS == 0 {}
+
+			efc.eefc_fmr.modify(|_, w| w.fws().bits(0));
+
+			let mut fsmr = wake.pins as u32 | PMC_FSMR_LPM | PMC_FSMR_FLPM_DEEP_POWER_DOWN;
+			if let Some(ms) = wake.rtt_alarm_ms {
+				rtt::set_alarm_ms(ms);
+				fsmr |= PMC_FSMR_RTTAL;
+			}
+			ptr::write_volatile(PMC_FSPR, wake.active_high as u32);
+			ptr::write_volatile(PMC_FSMR, fsmr);
+
+			let mor = ptr::read_volatile(CKGR_MOR) & CKGR_MOR_KEEP;
+			ptr::write_volatile(CKGR_MOR, mor | CKGR_MOR_KEY | CKGR_MOR_MOSCRCEN | CKGR_MOR_MOSCRCF_12MHZ | CKGR_MOR_WAITMODE);
+			while ptr::read_volatile(PMC_SR) & PMC_SR_MCKRDY == 0 {}
+
+			ptr::write_volatile(PMC_FSMR, 0);
+			rtt::alarm_pending();
+		});
+
+		self.wait_ticks += self.elapsed();
+		self.wait_count += 1;
+
+		// restarts the crystal and plls, retimes the bus and leaves self-refresh
+		timings.switch_clocks(settings, pmc, supc, efc)
+	}
+
+	// enter backup mode, the chip resets when one of `wake` triggers
+	pub fn backup(&mut self, wake: &WakeSources) -> ! {
+		cortex_m::interrupt::disable();
+		bus::enter_self_refresh();
+
+		let mut wumr = 0;
+		if let Some(ms) = wake.rtt_alarm_ms {
+			rtt::set_alarm_ms(ms);
+			wumr |= SUPC_WUMR_RTTEN;
+		}
+
+		gpbr::write(gpbr::BACKUP_ENTRY, rtt::now());
+		gpbr::write(gpbr::BACKUP_MARKER, BACKUP_MARKER);
+
+		unsafe {
+			ptr::write_volatile(SUPC_WUIR, wake.pins as u32 | ((wake.active_high as u32) << 16));
+			ptr::write_volatile(SUPC_WUMR, wumr);
+			(*cortex_m::peripheral::SCB::ptr()).scr.modify(|scr| scr | (1 << 2));
+			ptr::write_volatile(SUPC_CR, SUPC_CR_KEY | SUPC_CR_VROFF);
+		}
+		loop {
+			cortex_m::asm::wfe();
+		}
+	}
+}
+
+impl Default for Power {
+	fn default() -> Power {
+		Power::new()
+	}
+}
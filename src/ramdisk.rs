@@ -0,0 +1,410 @@
+// fat formatted ram disk, e.g. in the sdram
+//
+// `RamDisk` implements the `BlockDevice` trait of embedded-sdmmc, so files are
+// accessed through its `Controller` the same way as on an sd card, and `BlockStorage`
+// to export it over usb (see `usb::msc`). `format` writes a partition table with a
+// single fat16 partition spanning the disk, which is what the controller expects to
+// find. fat16 needs at least 4085 clusters, so the disk has to be 2 MiB or larger.
+
+use core::cell::UnsafeCell;
+
+use atsamx7x_hal::sdram::Sdram;
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx, Controller, Mode, TimeSource, Timestamp, VolumeIdx};
+
+use crate::mem::sdram_region;
+use crate::usb::scsi::BlockStorage;
+
+pub const BLOCK_SIZE: usize = Block::LEN;
+
+// first block of the partition, block 0 holds the partition table
+const PARTITION_START: u32 = 1;
+const PARTITION_TYPE_FAT16_LBA: u8 = 0x0E;
+
+const RESERVED_SECTORS: u32 = 1;
+const FAT_COUNT: u32 = 2;
+const ROOT_ENTRIES: u32 = 512;
+const ROOT_SECTORS: u32 = ROOT_ENTRIES * 32 / BLOCK_SIZE as u32;
+const MEDIA_FIXED: u8 = 0xF8;
+
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamDiskError {
+	OutOfRange,
+	// too small to hold a fat16 volume
+	TooSmall
+}
+
+fn le16(b: &[u8], at: usize) -> u16 {
+	u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+pub struct RamDisk<'a> {
+	mem: UnsafeCell<&'a mut [u8]>
+}
+
+impl<'a> RamDisk<'a> {
+	// the buffer length has to be a multiple of BLOCK_SIZE
+	pub fn new(mem: &'a mut [u8]) -> RamDisk<'a> {
+		assert!(mem.len() % BLOCK_SIZE == 0);
+		RamDisk {
+			mem: UnsafeCell::new(mem)
+		}
+	}
+
+	pub fn free(self) -> &'a mut [u8] {
+		self.mem.into_inner()
+	}
+
+	pub fn blocks(&self) -> u32 {
+		(self.mem().len() / BLOCK_SIZE) as u32
+	}
+
+	fn mem(&self) -> &[u8] {
+		unsafe { &*self.mem.get() }
+	}
+
+	fn mem_mut(&mut self) -> &mut [u8] {
+		self.mem.get_mut()
+	}
+
+	fn range(&self, start: BlockIdx, count: usize) -> Result<core::ops::Range<usize>, RamDiskError> {
+		let start = (start.0 as usize).checked_mul(BLOCK_SIZE).ok_or(RamDiskError::OutOfRange)?;
+		let end = count.checked_mul(BLOCK_SIZE)
+			.and_then(|len| start.checked_add(len))
+			.ok_or(RamDiskError::OutOfRange)?;
+		if end > self.mem().len() {
+			return Err(RamDiskError::OutOfRange);
+		}
+		Ok(start..end)
+	}
+
+	// mark the directory entry at `offset` in `block` as deleted and free its clusters
+	// in all fats, only for the fat16 layout written by `format`
+	fn delete_entry(&mut self, block: BlockIdx, offset: usize) -> Result<(), RamDiskError> {
+		let entry = self.range(block, 1)?.start + offset;
+		let boot = PARTITION_START as usize * BLOCK_SIZE;
+		let mem = self.mem_mut();
+		let fat_start = boot + le16(mem, boot + 14) as usize * BLOCK_SIZE;
+		let fat_len = le16(mem, boot + 22) as usize * BLOCK_SIZE;
+		let fat_count = mem[boot + 16] as usize;
+		let mut cluster = le16(mem, entry + 26) as usize;
+		mem[entry] = 0xE5;
+
+		// at most one step per fat entry, a damaged chain can not loop forever
+		for _ in 0..fat_len / 2 {
+			if cluster < 2 || cluster >= 0xFFF0 || cluster >= fat_len / 2 {
+				break;
+			}
+			let next = le16(mem, fat_start + cluster * 2) as usize;
+			for copy in 0..fat_count {
+				let at = fat_start + copy * fat_len + cluster * 2;
+				mem[at..at + 2].copy_from_slice(&[0, 0]);
+			}
+			cluster = next;
+		}
+		Ok(())
+	}
+
+	// create an empty fat16 file system, `label` is cut or padded to 11 characters
+	pub fn format(&mut self, label: &str) -> Result<(), RamDiskError> {
+		let total = self.blocks().saturating_sub(PARTITION_START);
+
+		// smallest cluster size that keeps the cluster count within fat16 limits
+		let mut layout = None;
+		for shift in 0..7 {
+			let cluster = 1 << shift;
+			// the fat size is estimated from all sectors, which slightly overestimates it
+			let estimate = total.saturating_sub(RESERVED_SECTORS + ROOT_SECTORS) / cluster + 2;
+			let fat = (estimate * 2 + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
+			let data = total.saturating_sub(RESERVED_SECTORS + ROOT_SECTORS + FAT_COUNT * fat);
+			let clusters = data / cluster;
+			if clusters < FAT16_MIN_CLUSTERS {
+				break;
+			}
+			if clusters <= FAT16_MAX_CLUSTERS {
+				layout = Some((cluster, fat));
+				break;
+			}
+		}
+		let (cluster, fat) = layout.ok_or(RamDiskError::TooSmall)?;
+
+		let meta = PARTITION_START + RESERVED_SECTORS + FAT_COUNT * fat + ROOT_SECTORS;
+		let mem = self.mem_mut();
+		for b in mem[..meta as usize * BLOCK_SIZE].iter_mut() {
+			*b = 0;
+		}
+
+		// partition table
+		let entry = &mut mem[0x1BE..0x1CE];
+		entry[4] = PARTITION_TYPE_FAT16_LBA;
+		entry[8..12].copy_from_slice(&PARTITION_START.to_le_bytes());
+		entry[12..16].copy_from_slice(&total.to_le_bytes());
+		mem[510] = 0x55;
+		mem[511] = 0xAA;
+
+		// boot sector with bios parameter block
+		let boot = &mut mem[PARTITION_START as usize * BLOCK_SIZE..][..BLOCK_SIZE];
+		boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+		boot[3..11].copy_from_slice(b"MSDOS5.0");
+		boot[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+		boot[13] = cluster as u8;
+		boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+		boot[16] = FAT_COUNT as u8;
+		boot[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+		if total < 0x1_0000 {
+			boot[19..21].copy_from_slice(&(total as u16).to_le_bytes());
+		} else {
+			boot[32..36].copy_from_slice(&total.to_le_bytes());
+		}
+		boot[21] = MEDIA_FIXED;
+		boot[22..24].copy_from_slice(&(fat as u16).to_le_bytes());
+		boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+		boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+		boot[28..32].copy_from_slice(&PARTITION_START.to_le_bytes());
+		boot[36] = 0x80;
+		boot[38] = 0x29;
+		boot[39..43].copy_from_slice(&0x5241_4D44u32.to_le_bytes());
+		for (i, b) in boot[43..54].iter_mut().enumerate() {
+			*b = label.as_bytes().get(i).map(|c| c.to_ascii_uppercase()).unwrap_or(b' ');
+		}
+		boot[54..62].copy_from_slice(b"FAT16   ");
+		boot[510] = 0x55;
+		boot[511] = 0xAA;
+
+		// the first two fat entries hold the media type and the clean shutdown flag
+		for copy in 0..FAT_COUNT {
+			let start = (PARTITION_START + RESERVED_SECTORS + copy * fat) as usize * BLOCK_SIZE;
+			mem[start..start + 4].copy_from_slice(&[MEDIA_FIXED, 0xFF, 0xFF, 0xFF]);
+		}
+		Ok(())
+	}
+}
+
+impl<'a> BlockDevice for RamDisk<'a> {
+	type Error = RamDiskError;
+
+	fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx, _reason: &str) -> Result<(), RamDiskError> {
+		let range = self.range(start_block_idx, blocks.len())?;
+		for (block, data) in blocks.iter_mut().zip(self.mem()[range].chunks(BLOCK_SIZE)) {
+			block.contents.copy_from_slice(data);
+		}
+		Ok(())
+	}
+
+	fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), RamDiskError> {
+		let range = self.range(start_block_idx, blocks.len())?;
+		// the trait writes through a shared reference. the disk is not Sync and this is
+		// the only reference into the buffer until the call returns
+		let mem = unsafe { &mut *self.mem.get() };
+		for (block, data) in blocks.iter().zip(mem[range].chunks_mut(BLOCK_SIZE)) {
+			data.copy_from_slice(&block.contents);
+		}
+		Ok(())
+	}
+
+	fn num_blocks(&self) -> Result<BlockCount, RamDiskError> {
+		Ok(BlockCount(self.blocks()))
+	}
+}
+
+// blocks of the ram disk are the blocks of the mass storage device
+impl<'a> BlockStorage for RamDisk<'a> {
+	type Error = RamDiskError;
+
+	fn block_count(&self) -> u32 {
+		self.blocks()
+	}
+
+	fn read_block(&mut self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), RamDiskError> {
+		let range = self.range(BlockIdx(lba), 1)?;
+		buf.copy_from_slice(&self.mem()[range]);
+		Ok(())
+	}
+
+	fn write_block(&mut self, lba: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), RamDiskError> {
+		let range = self.range(BlockIdx(lba), 1)?;
+		self.mem_mut()[range].copy_from_slice(data);
+		Ok(())
+	}
+}
+
+// ram disk in the sdram region `offset..offset + len`, see `mem::sdram_region`
+pub unsafe fn sdram_disk(sdram: &Sdram, offset: usize, len: usize) -> RamDisk<'static> {
+	RamDisk::new(sdram_region(sdram, offset, len))
+}
+
+// the board has no calendar clock, files get a fixed timestamp
+pub struct FixedTime(pub Timestamp);
+
+impl Default for FixedTime {
+	fn default() -> FixedTime {
+		// 2020-01-01 00:00:00
+		FixedTime(Timestamp {
+			year_since_1970: 50,
+			zero_indexed_month: 0,
+			zero_indexed_day: 0,
+			hours: 0,
+			minutes: 0,
+			seconds: 0
+		})
+	}
+}
+
+impl TimeSource for FixedTime {
+	fn get_timestamp(&self) -> Timestamp {
+		self.0
+	}
+}
+
+pub type RamFsError = embedded_sdmmc::Error<RamDiskError>;
+
+// store `data` as `name` (8.3) in the root directory, replacing an existing file
+pub fn write_file<T: TimeSource>(fs: &mut Controller<RamDisk, T>, name: &str, data: &[u8]) -> Result<(), RamFsError> {
+	let mut volume = fs.get_volume(VolumeIdx(0))?;
+	let root = fs.open_root_dir(&volume)?;
+	let result = fs.open_file_in_dir(&mut volume, &root, name, Mode::ReadWriteCreateOrTruncate)
+		.and_then(|mut file| {
+			let written = fs.write(&mut volume, &mut file, data).map(|_| ());
+			fs.close_file(&volume, file).and(written)
+		});
+	fs.close_dir(&volume, root);
+	result
+}
+
+// read the file `name` from the root directory into `buf`, returns the number of
+// bytes read, which is less than the file size if `buf` is too short
+pub fn read_file<T: TimeSource>(fs: &mut Controller<RamDisk, T>, name: &str, buf: &mut [u8]) -> Result<usize, RamFsError> {
+	let mut volume = fs.get_volume(VolumeIdx(0))?;
+	let root = fs.open_root_dir(&volume)?;
+	let result = fs.open_file_in_dir(&mut volume, &root, name, Mode::ReadOnly)
+		.and_then(|mut file| {
+			let read = fs.read(&volume, &mut file, buf);
+			fs.close_file(&volume, file).and(read)
+		});
+	fs.close_dir(&volume, root);
+	result
+}
+
+// embedded-sdmmc 0.3 can not delete files, the directory entry is marked as deleted
+// and the cluster chain freed directly on the disk
+pub fn remove_file<T: TimeSource>(fs: &mut Controller<RamDisk, T>, name: &str) -> Result<(), RamFsError> {
+	let volume = fs.get_volume(VolumeIdx(0))?;
+	let root = fs.open_root_dir(&volume)?;
+	let entry = fs.find_directory_entry(&volume, &root, name);
+	fs.close_dir(&volume, root);
+	let entry = entry?;
+	fs.device().delete_entry(entry.entry_block, entry.entry_offset as usize).map_err(embedded_sdmmc::Error::DeviceError)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DISK_SIZE: usize = 4 * 1024 * 1024;
+
+	fn le32(b: &[u8], at: usize) -> u32 {
+		u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+	}
+
+	#[test]
+	fn format_layout() {
+		let mut mem = vec![0xA5u8; DISK_SIZE];
+		let mut disk = RamDisk::new(&mut mem);
+		disk.format("board disk").unwrap();
+		let mem = disk.free();
+
+		let total = (DISK_SIZE / BLOCK_SIZE) as u32 - PARTITION_START;
+		assert_eq!(mem[0x1BE + 4], PARTITION_TYPE_FAT16_LBA);
+		assert_eq!(le32(mem, 0x1BE + 8), PARTITION_START);
+		assert_eq!(le32(mem, 0x1BE + 12), total);
+		assert_eq!(&mem[510..512], &[0x55, 0xAA]);
+
+		let boot = &mem[BLOCK_SIZE..2 * BLOCK_SIZE];
+		assert_eq!(le16(boot, 11) as usize, BLOCK_SIZE);
+		// 8191 sectors stay below the fat16 maximum with one sector per cluster
+		assert_eq!(boot[13], 1);
+		assert_eq!(le16(boot, 19) as u32, total);
+		assert_eq!(&boot[43..54], b"BOARD DISK ");
+		assert_eq!(&boot[54..62], b"FAT16   ");
+		let fat = le16(boot, 22) as usize;
+		let data = total - RESERVED_SECTORS - ROOT_SECTORS - 2 * fat as u32;
+		assert!(data >= FAT16_MIN_CLUSTERS && data <= FAT16_MAX_CLUSTERS);
+		assert!(fat * BLOCK_SIZE / 2 >= data as usize + 2);
+
+		// both fats start with the media entries, the root directory is empty
+		for copy in 0..2 {
+			let start = (2 + copy * fat) * BLOCK_SIZE;
+			assert_eq!(&mem[start..start + 6], &[MEDIA_FIXED, 0xFF, 0xFF, 0xFF, 0, 0]);
+		}
+		let root = (2 + 2 * fat) * BLOCK_SIZE;
+		assert!(mem[root..root + ROOT_SECTORS as usize * BLOCK_SIZE].iter().all(|b| *b == 0));
+	}
+
+	#[test]
+	fn too_small() {
+		let mut mem = vec![0u8; 1024 * 1024];
+		assert_eq!(RamDisk::new(&mut mem).format("small"), Err(RamDiskError::TooSmall));
+	}
+
+	#[test]
+	fn files() {
+		let mut mem = vec![0u8; DISK_SIZE];
+		let mut disk = RamDisk::new(&mut mem);
+		disk.format("files").unwrap();
+		let mut fs = Controller::new(disk, FixedTime::default());
+
+		let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+		write_file(&mut fs, "DATA.BIN", &data).unwrap();
+		write_file(&mut fs, "HELLO.TXT", b"hello").unwrap();
+		let mut buf = vec![0u8; 8192];
+		assert_eq!(read_file(&mut fs, "DATA.BIN", &mut buf).unwrap(), 5000);
+		assert_eq!(&buf[..5000], &data[..]);
+
+		// replacing truncates
+		write_file(&mut fs, "DATA.BIN", b"short").unwrap();
+		assert_eq!(read_file(&mut fs, "DATA.BIN", &mut buf).unwrap(), 5);
+		assert_eq!(&buf[..5], b"short");
+		// a short buffer gets the start of the file
+		assert_eq!(read_file(&mut fs, "HELLO.TXT", &mut buf[..3]).unwrap(), 3);
+		assert_eq!(&buf[..3], b"hel");
+
+		remove_file(&mut fs, "HELLO.TXT").unwrap();
+		assert!(read_file(&mut fs, "HELLO.TXT", &mut buf).is_err());
+		assert_eq!(read_file(&mut fs, "DATA.BIN", &mut buf).unwrap(), 5);
+		remove_file(&mut fs, "DATA.BIN").unwrap();
+		assert!(remove_file(&mut fs, "DATA.BIN").is_err());
+
+		// all clusters are free again
+		let mem = fs.device().mem();
+		let fat = le16(mem, BLOCK_SIZE + 22) as usize * BLOCK_SIZE;
+		for copy in 0..2 {
+			let start = 2 * BLOCK_SIZE + copy * fat;
+			assert!(mem[start + 4..start + fat].iter().all(|b| *b == 0));
+		}
+	}
+
+	#[test]
+	fn blocks() {
+		let mut mem = vec![0u8; 8 * BLOCK_SIZE];
+		let mut disk = RamDisk::new(&mut mem);
+		assert_eq!(BlockStorage::block_count(&disk), 8);
+
+		let block = [0x3Cu8; BLOCK_SIZE];
+		disk.write_block(7, &block).unwrap();
+		let mut buf = [0u8; BLOCK_SIZE];
+		disk.read_block(7, &mut buf).unwrap();
+		assert_eq!(&buf[..], &block[..]);
+		let mut blocks = [Block::new(), Block::new()];
+		BlockDevice::read(&disk, &mut blocks, BlockIdx(6), "test").unwrap();
+		assert!(blocks[0].contents.iter().all(|b| *b == 0));
+		assert!(blocks[1].contents.iter().all(|b| *b == 0x3C));
+
+		assert_eq!(disk.read_block(8, &mut buf), Err(RamDiskError::OutOfRange));
+		assert_eq!(disk.write_block(u32::MAX, &block), Err(RamDiskError::OutOfRange));
+		assert_eq!(BlockDevice::write(&disk, &blocks, BlockIdx(7)), Err(RamDiskError::OutOfRange));
+		assert_eq!(disk.range(BlockIdx(1), usize::MAX / 2), Err(RamDiskError::OutOfRange));
+		assert_eq!(disk.range(BlockIdx(u32::MAX), usize::MAX / BLOCK_SIZE), Err(RamDiskError::OutOfRange));
+	}
+}
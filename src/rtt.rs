@@ -0,0 +1,65 @@
+// real-time timer running from the slow clock, used as timebase that keeps
+// counting in all low power modes (including backup mode)
+
+use core::ptr;
+
+const RTT_BASE: usize = 0x400E_1830;
+const RTT_MR: *mut u32 = RTT_BASE as *mut u32;
+const RTT_AR: *mut u32 = (RTT_BASE + 0x04) as *mut u32;
+const RTT_VR: *const u32 = (RTT_BASE + 0x08) as *const u32;
+const RTT_SR: *const u32 = (RTT_BASE + 0x0C) as *const u32;
+
+const RTT_MR_ALMIEN: u32 = 1 << 16;
+const RTT_MR_RTTRST: u32 = 1 << 18;
+const RTT_SR_ALMS: u32 = 1 << 0;
+
+// 32768 Hz slow clock divided by 32
+pub const RTT_PRESCALER: u32 = 32;
+pub const RTT_HZ: u32 = 32768 / RTT_PRESCALER;
+
+pub fn is_running() -> bool {
+	unsafe { ptr::read_volatile(RTT_MR) & 0xFFFF == RTT_PRESCALER }
+}
+
+// start counting from zero, unless the timer is already set up (e.g. after backup mode)
+pub fn init() {
+	if !is_running() {
+		unsafe { ptr::write_volatile(RTT_MR, RTT_PRESCALER | RTT_MR_RTTRST) }
+	}
+}
+
+// the counter runs asynchronously to the core, read until two reads match
+pub fn now() -> u32 {
+	loop {
+		let a = unsafe { ptr::read_volatile(RTT_VR) };
+		let b = unsafe { ptr::read_volatile(RTT_VR) };
+		if a == b {
+			return a;
+		}
+	}
+}
+
+pub fn ticks_to_ms(ticks: u32) -> u32 {
+	(ticks as u64 * 1000 / RTT_HZ as u64) as u32
+}
+
+pub fn ms_to_ticks(ms: u32) -> u32 {
+	(ms as u64 * RTT_HZ as u64 / 1000) as u32
+}
+
+// raise the alarm `ms` from now, it can wake the chip from every low power mode
+pub fn set_alarm_ms(ms: u32) {
+	let ticks = core::cmp::max(ms_to_ticks(ms), 1);
+	unsafe {
+		let mr = ptr::read_volatile(RTT_MR);
+		ptr::write_volatile(RTT_MR, mr & !RTT_MR_ALMIEN);
+		// the alarm fires when the counter reaches ALMV + 1
+		ptr::write_volatile(RTT_AR, now().wrapping_add(ticks - 1));
+		ptr::write_volatile(RTT_MR, mr | RTT_MR_ALMIEN);
+	}
+}
+
+// reading the status clears the alarm flag
+pub fn alarm_pending() -> bool {
+	unsafe { ptr::read_volatile(RTT_SR) & RTT_SR_ALMS != 0 }
+}
@@ -0,0 +1,500 @@
+// persistent key/value settings in the reserved internal flash sector
+//
+// the region is split into two banks. records are appended to the active bank,
+// one flash page each, so a write that is interrupted by a power loss only leaves a
+// page with a bad crc behind, which is skipped. when the active bank is full the
+// latest value of every key is copied into the other bank, whose header is written
+// last: until then the old bank stays the valid one. this also spreads the erase
+// cycles over both banks.
+//
+// record page layout:
+//   0x00 magic "SET1"
+//   0x04 key length, flags (bit 0: deleted), value length (u16)
+//   0x08 key, followed by the value
+//   PAGE_SIZE - 4: crc32 of everything before
+//
+// bank header page (first page of a bank):
+//   0x00 magic "BANK", 0x04 generation, 0x08 crc32 of the first 8 bytes
+
+use crate::crc::crc32;
+use crate::flash::{Flash, FlashError, PAGE_SIZE};
+use crate::image::{SETTINGS_OFFSET, SETTINGS_SIZE};
+
+const RECORD_MAGIC: u32 = 0x3154_4553; // "SET1"
+const BANK_MAGIC: u32 = 0x4B4E_4142; // "BANK"
+const FLAG_DELETED: u8 = 1 << 0;
+
+const RECORD_HEADER: usize = 8;
+pub const MAX_KEY_LEN: usize = 32;
+pub const MAX_VALUE_LEN: usize = PAGE_SIZE - RECORD_HEADER - MAX_KEY_LEN - 4;
+
+// keys used by the board crate
+pub const KEY_MAC_ADDRESS: &str = "mac";
+pub const KEY_STATIC_IP: &str = "ip";
+pub const KEY_LCD_CALIBRATION: &str = "lcd.cal";
+pub const KEY_BACKLIGHT: &str = "lcd.backlight";
+pub const KEY_CLOCK_PRESET: &str = "clock.preset";
+
+// page based storage the settings live in, offsets are relative to the region
+pub trait SettingsStorage {
+	type Error;
+
+	fn size(&self) -> usize;
+
+	fn read(&self, offset: usize, buf: &mut [u8]);
+
+	// program one erased page
+	fn write_page(&mut self, offset: usize, data: &[u8; PAGE_SIZE]) -> Result<(), Self::Error>;
+
+	fn erase(&mut self, offset: usize, len: usize) -> Result<(), Self::Error>;
+}
+
+// the reserved sector of the internal flash
+pub struct FlashSettingsStorage<'a> {
+	flash: &'a mut Flash
+}
+
+impl<'a> FlashSettingsStorage<'a> {
+	pub fn new(flash: &'a mut Flash) -> FlashSettingsStorage<'a> {
+		FlashSettingsStorage {
+			flash: flash
+		}
+	}
+}
+
+impl<'a> SettingsStorage for FlashSettingsStorage<'a> {
+	type Error = FlashError;
+
+	fn size(&self) -> usize {
+		SETTINGS_SIZE
+	}
+
+	fn read(&self, offset: usize, buf: &mut [u8]) {
+		buf.copy_from_slice(self.flash.read(SETTINGS_OFFSET + offset, buf.len()));
+	}
+
+	fn write_page(&mut self, offset: usize, data: &[u8; PAGE_SIZE]) -> Result<(), FlashError> {
+		self.flash.write_page((SETTINGS_OFFSET + offset) / PAGE_SIZE, data)
+	}
+
+	fn erase(&mut self, offset: usize, len: usize) -> Result<(), FlashError> {
+		self.flash.erase(SETTINGS_OFFSET + offset, len)
+	}
+}
+
+// storage in a plain buffer behaving like flash (erase sets to 0xFF, programming
+// can only clear bits). on a host this simulates the flash on top of a `Vec<u8>`.
+pub struct RamSettingsStorage<'a> {
+	mem: &'a mut [u8]
+}
+
+impl<'a> RamSettingsStorage<'a> {
+	// the buffer length has to be a multiple of two pages
+	pub fn new(mem: &'a mut [u8]) -> RamSettingsStorage<'a> {
+		assert!(mem.len() % (2 * PAGE_SIZE) == 0);
+		RamSettingsStorage {
+			mem: mem
+		}
+	}
+}
+
+impl<'a> SettingsStorage for RamSettingsStorage<'a> {
+	type Error = ();
+
+	fn size(&self) -> usize {
+		self.mem.len()
+	}
+
+	fn read(&self, offset: usize, buf: &mut [u8]) {
+		buf.copy_from_slice(&self.mem[offset..offset + buf.len()]);
+	}
+
+	fn write_page(&mut self, offset: usize, data: &[u8; PAGE_SIZE]) -> Result<(), ()> {
+		for (m, d) in self.mem[offset..offset + PAGE_SIZE].iter_mut().zip(data.iter()) {
+			*m &= *d;
+		}
+		Ok(())
+	}
+
+	fn erase(&mut self, offset: usize, len: usize) -> Result<(), ()> {
+		for b in self.mem[offset..offset + len].iter_mut() {
+			*b = 0xFF;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingsError<E> {
+	Storage(E),
+	KeyTooLong,
+	ValueTooLong,
+	// the live settings do not fit into one bank
+	Full
+}
+
+struct Record {
+	key_len: usize,
+	value_len: usize,
+	deleted: bool
+}
+
+fn parse_record(page: &[u8; PAGE_SIZE]) -> Option<Record> {
+	let magic = u32::from_le_bytes([page[0], page[1], page[2], page[3]]);
+	if magic != RECORD_MAGIC {
+		return None;
+	}
+	let crc = u32::from_le_bytes([page[PAGE_SIZE - 4], page[PAGE_SIZE - 3], page[PAGE_SIZE - 2], page[PAGE_SIZE - 1]]);
+	if crc != crc32(&page[..PAGE_SIZE - 4]) {
+		return None;
+	}
+	let key_len = page[4] as usize;
+	let value_len = u16::from_le_bytes([page[6], page[7]]) as usize;
+	if key_len > MAX_KEY_LEN || value_len > MAX_VALUE_LEN {
+		return None;
+	}
+	Some(Record {
+		key_len: key_len,
+		value_len: value_len,
+		deleted: page[5] & FLAG_DELETED != 0
+	})
+}
+
+pub struct Settings<S> {
+	storage: S,
+	bank_size: usize,
+	active: usize,
+	generation: u32,
+	// next free page in the active bank
+	next: usize
+}
+
+impl<S: SettingsStorage> Settings<S> {
+	// find the active bank, formats the storage if there is none
+	pub fn mount(storage: S) -> Result<Settings<S>, SettingsError<S::Error>> {
+		let bank_size = storage.size() / 2;
+		let mut settings = Settings {
+			storage: storage,
+			bank_size: bank_size,
+			active: 0,
+			generation: 0,
+			next: 1
+		};
+
+		match (settings.bank_generation(0), settings.bank_generation(1)) {
+			(None, None) => {
+				settings.storage.erase(0, bank_size).map_err(SettingsError::Storage)?;
+				settings.write_bank_header(0, 1)?;
+				settings.generation = 1;
+				return Ok(settings);
+			},
+			(Some(a), Some(b)) if b > a => { settings.active = 1; settings.generation = b; },
+			(Some(a), _) => { settings.active = 0; settings.generation = a; },
+			(None, Some(b)) => { settings.active = 1; settings.generation = b; }
+		}
+
+		// the first erased page marks the end of the log
+		let mut page = [0u8; PAGE_SIZE];
+		while settings.next < settings.pages() {
+			settings.read_page(settings.active, settings.next, &mut page);
+			if page.iter().all(|b| *b == 0xFF) {
+				break;
+			}
+			settings.next += 1;
+		}
+		Ok(settings)
+	}
+
+	pub fn free(self) -> S {
+		self.storage
+	}
+
+	fn pages(&self) -> usize {
+		self.bank_size / PAGE_SIZE
+	}
+
+	fn read_page(&self, bank: usize, page: usize, buf: &mut [u8; PAGE_SIZE]) {
+		self.storage.read(bank * self.bank_size + page * PAGE_SIZE, buf);
+	}
+
+	fn bank_generation(&self, bank: usize) -> Option<u32> {
+		let mut header = [0u8; 12];
+		self.storage.read(bank * self.bank_size, &mut header);
+		let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+		let generation = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+		let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+		if magic == BANK_MAGIC && crc == crc32(&header[..8]) {
+			Some(generation)
+		} else {
+			None
+		}
+	}
+
+	fn write_bank_header(&mut self, bank: usize, generation: u32) -> Result<(), SettingsError<S::Error>> {
+		let mut page = [0xFFu8; PAGE_SIZE];
+		page[..4].copy_from_slice(&BANK_MAGIC.to_le_bytes());
+		page[4..8].copy_from_slice(&generation.to_le_bytes());
+		let crc = crc32(&page[..8]);
+		page[8..12].copy_from_slice(&crc.to_le_bytes());
+		self.storage.write_page(bank * self.bank_size, &page).map_err(SettingsError::Storage)
+	}
+
+	// latest valid record for `key` in the active bank, read into `page`
+	fn find(&self, key: &[u8], page: &mut [u8; PAGE_SIZE]) -> Option<(usize, Record)> {
+		for index in (1..self.next).rev() {
+			self.read_page(self.active, index, page);
+			if let Some(record) = parse_record(page) {
+				if &page[RECORD_HEADER..RECORD_HEADER + record.key_len] == key {
+					return Some((index, record));
+				}
+			}
+		}
+		None
+	}
+
+	// copy the value of `key` into `buf`, returns its length
+	pub fn get(&self, key: &str, buf: &mut [u8]) -> Option<usize> {
+		let mut page = [0u8; PAGE_SIZE];
+		let (_, record) = self.find(key.as_bytes(), &mut page)?;
+		if record.deleted || record.value_len > buf.len() {
+			return None;
+		}
+		let start = RECORD_HEADER + record.key_len;
+		buf[..record.value_len].copy_from_slice(&page[start..start + record.value_len]);
+		Some(record.value_len)
+	}
+
+	pub fn get_u32(&self, key: &str) -> Option<u32> {
+		let mut buf = [0u8; 4];
+		match self.get(key, &mut buf) {
+			Some(4) => Some(u32::from_le_bytes(buf)),
+			_ => None
+		}
+	}
+
+	pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), SettingsError<S::Error>> {
+		self.append(key.as_bytes(), value, false)
+	}
+
+	pub fn set_u32(&mut self, key: &str, value: u32) -> Result<(), SettingsError<S::Error>> {
+		self.set(key, &value.to_le_bytes())
+	}
+
+	pub fn remove(&mut self, key: &str) -> Result<(), SettingsError<S::Error>> {
+		self.append(key.as_bytes(), &[], true)
+	}
+
+	fn append(&mut self, key: &[u8], value: &[u8], deleted: bool) -> Result<(), SettingsError<S::Error>> {
+		if key.is_empty() || key.len() > MAX_KEY_LEN {
+			return Err(SettingsError::KeyTooLong);
+		}
+		if value.len() > MAX_VALUE_LEN {
+			return Err(SettingsError::ValueTooLong);
+		}
+		if self.next == self.pages() {
+			self.compact()?;
+			if self.next == self.pages() {
+				return Err(SettingsError::Full);
+			}
+		}
+
+		let mut page = [0xFFu8; PAGE_SIZE];
+		page[..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+		page[4] = key.len() as u8;
+		page[5] = if deleted { FLAG_DELETED } else { 0 };
+		page[6..8].copy_from_slice(&(value.len() as u16).to_le_bytes());
+		page[RECORD_HEADER..RECORD_HEADER + key.len()].copy_from_slice(key);
+		page[RECORD_HEADER + key.len()..RECORD_HEADER + key.len() + value.len()].copy_from_slice(value);
+		let crc = crc32(&page[..PAGE_SIZE - 4]);
+		page[PAGE_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+
+		let offset = self.active * self.bank_size + self.next * PAGE_SIZE;
+		// the page counts as used even if programming fails half way
+		self.next += 1;
+		self.storage.write_page(offset, &page).map_err(SettingsError::Storage)
+	}
+
+	// move the latest value of every key into the other bank
+	fn compact(&mut self) -> Result<(), SettingsError<S::Error>> {
+		let target = 1 - self.active;
+		self.storage.erase(target * self.bank_size, self.bank_size).map_err(SettingsError::Storage)?;
+
+		let mut page = [0u8; PAGE_SIZE];
+		let mut latest = [0u8; PAGE_SIZE];
+		let mut written = 1;
+		for index in 1..self.next {
+			self.read_page(self.active, index, &mut page);
+			let record = match parse_record(&page) {
+				Some(r) => r,
+				None => continue
+			};
+			if record.deleted {
+				continue;
+			}
+			// skip if a newer record for the key follows
+			let key = &page[RECORD_HEADER..RECORD_HEADER + record.key_len];
+			if self.find(key, &mut latest).map(|(latest, _)| latest != index).unwrap_or(false) {
+				continue;
+			}
+			self.storage.write_page(target * self.bank_size + written * PAGE_SIZE, &page).map_err(SettingsError::Storage)?;
+			written += 1;
+		}
+
+		self.write_bank_header(target, self.generation.wrapping_add(1))?;
+		self.active = target;
+		self.generation = self.generation.wrapping_add(1);
+		self.next = written;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// two banks of a header and three records
+	const SIZE: usize = 8 * PAGE_SIZE;
+
+	// programs only the first half of the page after `writes_left` writes, as a
+	// power loss in the middle of programming would
+	struct TornStorage<'a> {
+		ram: RamSettingsStorage<'a>,
+		writes_left: usize
+	}
+
+	impl<'a> SettingsStorage for TornStorage<'a> {
+		type Error = ();
+
+		fn size(&self) -> usize {
+			self.ram.size()
+		}
+
+		fn read(&self, offset: usize, buf: &mut [u8]) {
+			self.ram.read(offset, buf)
+		}
+
+		fn write_page(&mut self, offset: usize, data: &[u8; PAGE_SIZE]) -> Result<(), ()> {
+			if self.writes_left == 0 {
+				let mut torn = *data;
+				for b in torn[PAGE_SIZE / 2..].iter_mut() {
+					*b = 0xFF;
+				}
+				self.ram.write_page(offset, &torn)?;
+				return Err(());
+			}
+			self.writes_left -= 1;
+			self.ram.write_page(offset, data)
+		}
+
+		fn erase(&mut self, offset: usize, len: usize) -> Result<(), ()> {
+			self.ram.erase(offset, len)
+		}
+	}
+
+	fn value(settings: &Settings<impl SettingsStorage>, key: &str) -> Option<Vec<u8>> {
+		let mut buf = [0u8; MAX_VALUE_LEN];
+		settings.get(key, &mut buf).map(|len| buf[..len].to_vec())
+	}
+
+	#[test]
+	fn set_get_overwrite() {
+		let mut mem = vec![0u8; SIZE];
+		let mut settings = Settings::mount(RamSettingsStorage::new(&mut mem)).unwrap();
+		assert_eq!(value(&settings, KEY_MAC_ADDRESS), None);
+		settings.set(KEY_MAC_ADDRESS, &[2, 0, 0, 0, 0, 1]).unwrap();
+		settings.set_u32(KEY_BACKLIGHT, 80).unwrap();
+		settings.set(KEY_MAC_ADDRESS, &[2, 0, 0, 0, 0, 2]).unwrap();
+		assert_eq!(value(&settings, KEY_MAC_ADDRESS), Some(vec![2, 0, 0, 0, 0, 2]));
+		assert_eq!(settings.get_u32(KEY_BACKLIGHT), Some(80));
+		// a buffer too small for the value
+		assert_eq!(settings.get(KEY_MAC_ADDRESS, &mut [0u8; 4]), None);
+		assert_eq!(settings.get_u32(KEY_MAC_ADDRESS), None);
+
+		settings.free();
+		let mut settings = Settings::mount(RamSettingsStorage::new(&mut mem)).unwrap();
+		assert_eq!(value(&settings, KEY_MAC_ADDRESS), Some(vec![2, 0, 0, 0, 0, 2]));
+		assert_eq!(settings.get_u32(KEY_BACKLIGHT), Some(80));
+		settings.remove(KEY_BACKLIGHT).unwrap();
+		assert_eq!(settings.get_u32(KEY_BACKLIGHT), None);
+	}
+
+	#[test]
+	fn limits() {
+		let mut mem = vec![0u8; SIZE];
+		let mut settings = Settings::mount(RamSettingsStorage::new(&mut mem)).unwrap();
+		assert_eq!(settings.set("", &[1]), Err(SettingsError::KeyTooLong));
+		assert_eq!(settings.set(&"k".repeat(MAX_KEY_LEN + 1), &[1]), Err(SettingsError::KeyTooLong));
+		assert_eq!(settings.set("k", &[0; MAX_VALUE_LEN + 1]), Err(SettingsError::ValueTooLong));
+		let key = "k".repeat(MAX_KEY_LEN);
+		settings.set(&key, &[0x5A; MAX_VALUE_LEN]).unwrap();
+		assert_eq!(value(&settings, &key), Some(vec![0x5A; MAX_VALUE_LEN]));
+	}
+
+	#[test]
+	fn compaction() {
+		let mut mem = vec![0u8; SIZE];
+		let mut settings = Settings::mount(RamSettingsStorage::new(&mut mem)).unwrap();
+		settings.set_u32("a", 1).unwrap();
+		settings.set_u32("b", 1).unwrap();
+		settings.set_u32("a", 2).unwrap();
+		// the bank is full, a and b move to the other one
+		settings.set_u32("b", 2).unwrap();
+		assert_eq!((settings.active, settings.generation, settings.next), (1, 2, 4));
+
+		settings.free();
+		let mut settings = Settings::mount(RamSettingsStorage::new(&mut mem)).unwrap();
+		assert_eq!((settings.active, settings.generation, settings.next), (1, 2, 4));
+		assert_eq!(settings.get_u32("a"), Some(2));
+		assert_eq!(settings.get_u32("b"), Some(2));
+
+		settings.remove("b").unwrap();
+		assert_eq!((settings.active, settings.generation), (0, 3));
+		assert_eq!(settings.get_u32("b"), None);
+		// removed keys are dropped by the next compaction
+		settings.set_u32("c", 1).unwrap();
+		assert_eq!((settings.active, settings.generation, settings.next), (1, 4, 3));
+		settings.set_u32("d", 1).unwrap();
+		// three live keys fill a bank
+		assert_eq!(settings.set_u32("e", 1), Err(SettingsError::Full));
+		assert_eq!(settings.get_u32("a"), Some(2));
+		assert_eq!(settings.get_u32("b"), None);
+		assert_eq!(settings.get_u32("c"), Some(1));
+		assert_eq!(settings.get_u32("d"), Some(1));
+	}
+
+	#[test]
+	fn torn_record() {
+		let mut mem = vec![0u8; SIZE];
+		let mut settings = Settings::mount(TornStorage { ram: RamSettingsStorage::new(&mut mem), writes_left: 2 }).unwrap();
+		settings.set_u32("a", 1).unwrap();
+		assert_eq!(settings.set_u32("a", 2), Err(SettingsError::Storage(())));
+
+		settings.free();
+		let mut settings = Settings::mount(RamSettingsStorage::new(&mut mem)).unwrap();
+		// the torn page is skipped and stays used
+		assert_eq!(settings.get_u32("a"), Some(1));
+		assert_eq!(settings.next, 3);
+		settings.set_u32("a", 3).unwrap();
+		assert_eq!(settings.get_u32("a"), Some(3));
+	}
+
+	#[test]
+	fn torn_compaction() {
+		let mut mem = vec![0u8; SIZE];
+		{
+			let mut settings = Settings::mount(RamSettingsStorage::new(&mut mem)).unwrap();
+			settings.set_u32("a", 1).unwrap();
+			settings.set_u32("b", 1).unwrap();
+			settings.set_u32("c", 1).unwrap();
+		}
+		// power is lost while the records are copied, before the new bank header is written
+		let mut settings = Settings::mount(TornStorage { ram: RamSettingsStorage::new(&mut mem), writes_left: 1 }).unwrap();
+		assert_eq!(settings.set_u32("a", 2), Err(SettingsError::Storage(())));
+
+		settings.free();
+		let settings = Settings::mount(RamSettingsStorage::new(&mut mem)).unwrap();
+		assert_eq!((settings.active, settings.generation), (0, 1));
+		assert_eq!(settings.get_u32("a"), Some(1));
+		assert_eq!(settings.get_u32("c"), Some(1));
+	}
+}
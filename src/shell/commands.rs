@@ -0,0 +1,404 @@
+// generic commands for the board shell
+
+use core::fmt::Write;
+use core::ptr;
+
+use atsamx7x_hal::clock_gen::Clocks;
+use linked_list_allocator::LockedHeap;
+
+use super::{parse_number, Command, CommandError};
+use crate::layout::BOARD;
+use crate::lcd::LCD;
+use crate::stack;
+
+// memory windows the mem command may access: flash, internal ram, the tcm (empty
+// without one), the smc chip selects (ethernet, lcd, ...) and the sdram
+pub const BOARD_MEMORY_WINDOWS: &[(usize, usize)] = &[
+	(BOARD.flash_base, BOARD.flash_size),
+	(BOARD.sram_base, BOARD.ram_size()),
+	(BOARD.itcm_base, BOARD.tcm_size),
+	(BOARD.dtcm_base, BOARD.tcm_size),
+	(BOARD.smc_base, BOARD.smc_size),
+	(BOARD.sdram_base, BOARD.sdram_size)
+];
+
+pub struct MemCommand {
+	windows: &'static [(usize, usize)]
+}
+
+impl MemCommand {
+	pub fn new(windows: &'static [(usize, usize)]) -> MemCommand {
+		MemCommand {
+			windows: windows
+		}
+	}
+
+	// returns the end of the range
+	fn check(&self, addr: usize, len: usize) -> Result<usize, CommandError> {
+		let end = addr.checked_add(len).ok_or(CommandError::Failed("address range overflows"))?;
+		let inside = |(start, size): &(usize, usize)| {
+			addr >= *start && start.checked_add(*size).map_or(false, |window_end| end <= window_end)
+		};
+		if self.windows.iter().any(inside) {
+			Ok(end)
+		} else {
+			Err(CommandError::Failed("address outside of the accessible memory"))
+		}
+	}
+}
+
+impl Default for MemCommand {
+	fn default() -> MemCommand {
+		MemCommand::new(BOARD_MEMORY_WINDOWS)
+	}
+}
+
+fn access_width(arg: Option<&&str>) -> Result<usize, CommandError> {
+	match arg.cloned() {
+		None | Some("w") => Ok(4),
+		Some("h") => Ok(2),
+		Some("b") => Ok(1),
+		_ => Err(CommandError::BadArgument)
+	}
+}
+
+impl Command for MemCommand {
+	fn name(&self) -> &'static str {
+		"mem"
+	}
+
+	fn help(&self) -> &'static str {
+		"mem read <addr> [b|h|w] | mem write <addr> <value> [b|h|w] | mem dump <addr> [len]"
+	}
+
+	fn run(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+		match args {
+			["read", addr, width @ ..] if width.len() <= 1 => {
+				let addr = parse_number(addr)? as usize;
+				let width = access_width(width.first())?;
+				if addr % width != 0 {
+					return Err(CommandError::Failed("unaligned address"));
+				}
+				self.check(addr, width)?;
+				let value = unsafe {
+					match width {
+						1 => ptr::read_volatile(addr as *const u8) as u32,
+						2 => ptr::read_volatile(addr as *const u16) as u32,
+						_ => ptr::read_volatile(addr as *const u32)
+					}
+				};
+				writeln!(out, "{:#010x}: {:#0w$x}\r", addr, value, w = 2 + 2 * width).ok();
+				Ok(())
+			},
+			["write", addr, value, width @ ..] if width.len() <= 1 => {
+				let addr = parse_number(addr)? as usize;
+				let value = parse_number(value)?;
+				let width = access_width(width.first())?;
+				if width < 4 && value >> (8 * width) != 0 {
+					return Err(CommandError::Failed("value does not fit the access width"));
+				}
+				if addr % width != 0 {
+					return Err(CommandError::Failed("unaligned address"));
+				}
+				self.check(addr, width)?;
+				unsafe {
+					match width {
+						1 => ptr::write_volatile(addr as *mut u8, value as u8),
+						2 => ptr::write_volatile(addr as *mut u16, value as u16),
+						_ => ptr::write_volatile(addr as *mut u32, value)
+					}
+				}
+				Ok(())
+			},
+			["dump", addr, len @ ..] if len.len() <= 1 => {
+				let addr = parse_number(addr)? as usize;
+				let len = match len.first() {
+					Some(l) => parse_number(l)? as usize,
+					None => 64
+				};
+				let end = self.check(addr, len)?;
+				for line in (addr..end).step_by(16) {
+					let line_end = core::cmp::min(line.saturating_add(16), end);
+					let mut bytes = [0u8; 16];
+					for (i, a) in (line..line_end).enumerate() {
+						bytes[i] = unsafe { ptr::read_volatile(a as *const u8) };
+					}
+					let bytes = &bytes[..line_end - line];
+
+					write!(out, "{:#010x}: ", line).ok();
+					for b in bytes {
+						write!(out, "{:02x} ", b).ok();
+					}
+					for _ in bytes.len()..16 {
+						out.write_str("   ").ok();
+					}
+					for b in bytes {
+						let c = if (0x20..0x7F).contains(b) { *b as char } else { '.' };
+						out.write_char(c).ok();
+					}
+					out.write_str("\r\n").ok();
+				}
+				Ok(())
+			},
+			_ => Err(CommandError::Usage)
+		}
+	}
+}
+
+pub struct ClocksCommand<'a> {
+	clocks: &'a Clocks
+}
+
+impl<'a> ClocksCommand<'a> {
+	pub fn new(clocks: &'a Clocks) -> ClocksCommand<'a> {
+		ClocksCommand {
+			clocks: clocks
+		}
+	}
+}
+
+impl<'a> Command for ClocksCommand<'a> {
+	fn name(&self) -> &'static str {
+		"clocks"
+	}
+
+	fn help(&self) -> &'static str {
+		"clocks"
+	}
+
+	fn run(&mut self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+		writeln!(out, "Master Clk: {:?}\r", self.clocks.mck()).ok();
+		Ok(())
+	}
+}
+
+pub struct HeapCommand<'a> {
+	heap: &'a LockedHeap
+}
+
+impl<'a> HeapCommand<'a> {
+	pub fn new(heap: &'a LockedHeap) -> HeapCommand<'a> {
+		HeapCommand {
+			heap: heap
+		}
+	}
+}
+
+impl<'a> Command for HeapCommand<'a> {
+	fn name(&self) -> &'static str {
+		"heap"
+	}
+
+	fn help(&self) -> &'static str {
+		"heap"
+	}
+
+	fn run(&mut self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+		let heap = self.heap.lock();
+		writeln!(out, "start: {:#010x}\r", heap.bottom()).ok();
+		writeln!(out, "size:  {}\r", heap.size()).ok();
+		writeln!(out, "used:  {}\r", heap.used()).ok();
+		writeln!(out, "free:  {}\r", heap.free()).ok();
+		Ok(())
+	}
+}
+
+// stack size and the most used since `stack::paint`
+pub struct StackCommand;
+
+impl Command for StackCommand {
+	fn name(&self) -> &'static str {
+		"stack"
+	}
+
+	fn help(&self) -> &'static str {
+		"stack"
+	}
+
+	fn run(&mut self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+		writeln!(out, "guard: {:#010x}\r", stack::guard_start()).ok();
+		writeln!(out, "size:  {}\r", stack::size()).ok();
+		writeln!(out, "used:  {}\r", stack::high_water_mark()).ok();
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedAction {
+	On,
+	Off,
+	Toggle
+}
+
+// `set` gets the led index and returns false if there is no such led
+pub struct LedCommand<F> {
+	set: F
+}
+
+impl<F: FnMut(usize, LedAction) -> bool> LedCommand<F> {
+	pub fn new(set: F) -> LedCommand<F> {
+		LedCommand {
+			set: set
+		}
+	}
+}
+
+impl<F: FnMut(usize, LedAction) -> bool> Command for LedCommand<F> {
+	fn name(&self) -> &'static str {
+		"led"
+	}
+
+	fn help(&self) -> &'static str {
+		"led <index> on|off|toggle"
+	}
+
+	fn run(&mut self, args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+		let (index, action) = match args {
+			[index, action] => (parse_number(index)? as usize, *action),
+			_ => return Err(CommandError::Usage)
+		};
+		let action = match action {
+			"on" => LedAction::On,
+			"off" => LedAction::Off,
+			"toggle" => LedAction::Toggle,
+			_ => return Err(CommandError::Usage)
+		};
+		if (self.set)(index, action) {
+			Ok(())
+		} else {
+			Err(CommandError::Failed("no such led"))
+		}
+	}
+}
+
+pub struct LcdCommand<'a> {
+	lcd: &'a mut LCD
+}
+
+impl<'a> LcdCommand<'a> {
+	pub fn new(lcd: &'a mut LCD) -> LcdCommand<'a> {
+		LcdCommand {
+			lcd: lcd
+		}
+	}
+}
+
+impl<'a> Command for LcdCommand<'a> {
+	fn name(&self) -> &'static str {
+		"lcd"
+	}
+
+	fn help(&self) -> &'static str {
+		"lcd fill <rgb565>"
+	}
+
+	fn run(&mut self, args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+		match args {
+			["fill", color] => {
+				let color = parse_number(color)?;
+				if color > 0xFFFF {
+					return Err(CommandError::BadArgument);
+				}
+				self.lcd.fill(color as u16);
+				Ok(())
+			},
+			_ => Err(CommandError::Usage)
+		}
+	}
+}
+
+pub struct ResetCommand;
+
+impl Command for ResetCommand {
+	fn name(&self) -> &'static str {
+		"reset"
+	}
+
+	fn help(&self) -> &'static str {
+		"reset"
+	}
+
+	fn run(&mut self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+		writeln!(out, "resetting …\r").ok();
+		cortex_m::peripheral::SCB::sys_reset()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const WINDOWS: &[(usize, usize)] = &[(0x2040_0000, 0x100), (0xFFFF_FF00, 0xF0)];
+
+	fn run(command: &mut dyn Command, line: &str) -> Result<(), CommandError> {
+		let args: Vec<&str> = line.split_whitespace().collect();
+		command.run(&args, &mut String::new())
+	}
+
+	#[test]
+	fn board_windows() {
+		let mem = MemCommand::default();
+		assert_eq!(mem.check(0x0040_0000, 0x0020_0000), Ok(0x0060_0000));
+		assert_eq!(mem.check(0x2045_FFFC, 4), Ok(0x2046_0000));
+		assert_eq!(mem.check(0x6000_0000, 2), Ok(0x6000_0002));
+		assert_eq!(mem.check(0x71FF_FFFF, 1), Ok(0x7200_0000));
+		assert!(mem.check(0x0060_0000, 1).is_err());
+		assert!(mem.check(0x2046_0000, 1).is_err());
+		// the layout has no tcm
+		assert!(mem.check(0x0000_0000, 4).is_err());
+		assert!(mem.check(0x2000_0000, 4).is_err());
+	}
+
+	#[test]
+	fn mem_windows() {
+		let mem = MemCommand::new(WINDOWS);
+		assert_eq!(mem.check(0x2040_0000, 0x100), Ok(0x2040_0100));
+		assert_eq!(mem.check(0x2040_00FC, 4), Ok(0x2040_0100));
+		assert_eq!(mem.check(0xFFFF_FFE0, 0x10), Ok(0xFFFF_FFF0));
+	}
+
+	#[test]
+	fn mem_rejects_ranges_outside_the_windows() {
+		let mut mem = MemCommand::new(WINDOWS);
+		let outside = CommandError::Failed("address outside of the accessible memory");
+		assert_eq!(mem.check(0x2040_0000, 0x101), Err(outside));
+		assert_eq!(mem.check(0x203F_FFFF, 2), Err(outside));
+		assert_eq!(mem.check(usize::MAX - 0xF, 0x100), Err(CommandError::Failed("address range overflows")));
+		// nothing is accessed when the range is rejected
+		assert_eq!(run(&mut mem, "dump 0xFFFFFFF0 0x100"), Err(outside));
+		assert_eq!(run(&mut mem, "dump 0x20400080 0x81"), Err(outside));
+		assert_eq!(run(&mut mem, "read 0x20400100"), Err(outside));
+		assert_eq!(run(&mut mem, "write 0x1000 1 b"), Err(outside));
+	}
+
+	#[test]
+	fn mem_arguments() {
+		let mut mem = MemCommand::new(WINDOWS);
+		assert_eq!(run(&mut mem, "read 0x20400002"), Err(CommandError::Failed("unaligned address")));
+		assert_eq!(run(&mut mem, "write 0x20400001 1 h"), Err(CommandError::Failed("unaligned address")));
+		assert_eq!(run(&mut mem, "read 0x20400000 q"), Err(CommandError::BadArgument));
+		let too_wide = Err(CommandError::Failed("value does not fit the access width"));
+		assert_eq!(run(&mut mem, "write 0x20400000 0x1234 b"), too_wide);
+		assert_eq!(run(&mut mem, "write 0x20400000 0x100 b"), too_wide);
+		assert_eq!(run(&mut mem, "write 0x20400000 0x10000 h"), too_wide);
+		assert_eq!(run(&mut mem, "dump 0x20400000 many"), Err(CommandError::BadArgument));
+		assert_eq!(run(&mut mem, "read"), Err(CommandError::Usage));
+		assert_eq!(run(&mut mem, "dump 0x20400000 4 4"), Err(CommandError::Usage));
+	}
+
+	#[test]
+	fn led() {
+		let mut calls = Vec::new();
+		{
+			let mut led = LedCommand::new(|index, action| {
+				calls.push((index, action));
+				index < 2
+			});
+			assert_eq!(run(&mut led, "1 toggle"), Ok(()));
+			assert_eq!(run(&mut led, "2 on"), Err(CommandError::Failed("no such led")));
+			assert_eq!(run(&mut led, "1 blink"), Err(CommandError::Usage));
+			assert_eq!(run(&mut led, "x on"), Err(CommandError::BadArgument));
+		}
+		assert_eq!(calls, vec![(1, LedAction::Toggle), (2, LedAction::On)]);
+	}
+}
@@ -0,0 +1,279 @@
+// line editor for serial terminals: printable characters, backspace, ctrl-c,
+// history on the arrow keys; tab is reported to the shell for completion
+
+use core::fmt::Write;
+
+pub const LINE_LEN: usize = 80;
+pub const HISTORY_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+	Pending,
+	Line,
+	Interrupt,
+	Complete
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+	None,
+	Esc,
+	Csi
+}
+
+pub struct LineEditor {
+	buf: [u8; LINE_LEN],
+	len: usize,
+	history: [[u8; LINE_LEN]; HISTORY_LEN],
+	history_lens: [usize; HISTORY_LEN],
+	history_count: usize,
+	history_next: usize,
+	// 0 while editing a new line, n while showing the nth most recent entry
+	browse: usize,
+	escape: Escape,
+	last_cr: bool
+}
+
+impl LineEditor {
+	pub fn new() -> LineEditor {
+		LineEditor {
+			buf: [0; LINE_LEN],
+			len: 0,
+			history: [[0; LINE_LEN]; HISTORY_LEN],
+			history_lens: [0; HISTORY_LEN],
+			history_count: 0,
+			history_next: 0,
+			browse: 0,
+			escape: Escape::None,
+			last_cr: false
+		}
+	}
+
+	pub fn line(&self) -> &str {
+		// only printable ascii ends up in the buffer
+		core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+	}
+
+	pub fn clear(&mut self) {
+		self.len = 0;
+		self.browse = 0;
+	}
+
+	pub fn feed(&mut self, byte: u8, out: &mut dyn Write) -> Input {
+		let last_cr = self.last_cr;
+		self.last_cr = byte == b'\r';
+
+		match self.escape {
+			Escape::Esc => {
+				self.escape = if byte == b'[' { Escape::Csi } else { Escape::None };
+				return Input::Pending;
+			},
+			Escape::Csi => {
+				self.escape = Escape::None;
+				match byte {
+					b'A' => self.history_older(out),
+					b'B' => self.history_newer(out),
+					_ => {}
+				}
+				return Input::Pending;
+			},
+			Escape::None => {}
+		}
+
+		match byte {
+			0x1B => self.escape = Escape::Esc,
+			// terminals send either \r, \n or \r\n on enter
+			b'\n' if last_cr => {},
+			b'\r' | b'\n' => {
+				out.write_str("\r\n").ok();
+				self.push_history();
+				return Input::Line;
+			},
+			0x03 => {
+				out.write_str("^C\r\n").ok();
+				self.clear();
+				return Input::Interrupt;
+			},
+			0x08 | 0x7F => {
+				if self.len > 0 {
+					self.len -= 1;
+					out.write_str("\x08 \x08").ok();
+				}
+			},
+			b'\t' => return Input::Complete,
+			0x20..=0x7E => {
+				if self.len < LINE_LEN {
+					self.buf[self.len] = byte;
+					self.len += 1;
+					out.write_char(byte as char).ok();
+				}
+			},
+			_ => {}
+		}
+		Input::Pending
+	}
+
+	// append to the current line, used by tab completion
+	pub fn insert(&mut self, s: &str, out: &mut dyn Write) {
+		for b in s.bytes().filter(|b| (0x20..=0x7E).contains(b)) {
+			if self.len == LINE_LEN {
+				break;
+			}
+			self.buf[self.len] = b;
+			self.len += 1;
+			out.write_char(b as char).ok();
+		}
+	}
+
+	fn push_history(&mut self) {
+		if self.len == 0 {
+			return;
+		}
+		if self.history_count > 0 && self.history_entry(1) == &self.buf[..self.len] {
+			return;
+		}
+		self.history[self.history_next] = self.buf;
+		self.history_lens[self.history_next] = self.len;
+		self.history_next = (self.history_next + 1) % HISTORY_LEN;
+		if self.history_count < HISTORY_LEN {
+			self.history_count += 1;
+		}
+	}
+
+	// nth most recent entry, starting at 1
+	fn history_entry(&self, n: usize) -> &[u8] {
+		let i = (self.history_next + HISTORY_LEN - n) % HISTORY_LEN;
+		&self.history[i][..self.history_lens[i]]
+	}
+
+	fn history_older(&mut self, out: &mut dyn Write) {
+		if self.browse < self.history_count {
+			self.browse += 1;
+			self.show_history(out);
+		}
+	}
+
+	fn history_newer(&mut self, out: &mut dyn Write) {
+		if self.browse > 0 {
+			self.browse -= 1;
+			self.show_history(out);
+		}
+	}
+
+	fn show_history(&mut self, out: &mut dyn Write) {
+		for _ in 0..self.len {
+			out.write_char('\x08').ok();
+		}
+		out.write_str("\x1b[K").ok();
+
+		let mut line = [0u8; LINE_LEN];
+		let len = if self.browse == 0 {
+			0
+		} else {
+			let entry = self.history_entry(self.browse);
+			line[..entry.len()].copy_from_slice(entry);
+			entry.len()
+		};
+		self.buf = line;
+		self.len = len;
+		out.write_str(self.line()).ok();
+	}
+}
+
+impl Default for LineEditor {
+	fn default() -> LineEditor {
+		LineEditor::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn feed(editor: &mut LineEditor, bytes: &[u8], out: &mut String) -> Vec<Input> {
+		bytes.iter().map(|b| editor.feed(*b, out)).filter(|i| *i != Input::Pending).collect()
+	}
+
+	#[test]
+	fn editing() {
+		let mut editor = LineEditor::new();
+		let mut out = String::new();
+		assert_eq!(feed(&mut editor, b"lex\x7f\x08ed\x01 on\r\n", &mut out), vec![Input::Line]);
+		assert_eq!(editor.line(), "led on");
+		assert_eq!(out, "lex\x08 \x08\x08 \x08ed on\r\n");
+	}
+
+	#[test]
+	fn line_endings() {
+		let mut editor = LineEditor::new();
+		let mut out = String::new();
+		// \r\n is one line, \n alone ends one as well
+		assert_eq!(feed(&mut editor, b"a\r\n", &mut out), vec![Input::Line]);
+		editor.clear();
+		assert_eq!(feed(&mut editor, b"b\n\n", &mut out), vec![Input::Line, Input::Line]);
+	}
+
+	#[test]
+	fn interrupt_and_complete() {
+		let mut editor = LineEditor::new();
+		let mut out = String::new();
+		assert_eq!(feed(&mut editor, b"abc\x03", &mut out), vec![Input::Interrupt]);
+		assert_eq!(editor.line(), "");
+		assert_eq!(out, "abc^C\r\n");
+		assert_eq!(feed(&mut editor, b"he\t", &mut out), vec![Input::Complete]);
+		editor.insert("lp\x07 ", &mut out);
+		assert_eq!(editor.line(), "help ");
+	}
+
+	#[test]
+	fn line_length_is_limited() {
+		let mut editor = LineEditor::new();
+		let mut out = String::new();
+		feed(&mut editor, &[b'x'; LINE_LEN + 5], &mut out);
+		assert_eq!(editor.line().len(), LINE_LEN);
+		editor.insert("y", &mut out);
+		assert_eq!(editor.line().len(), LINE_LEN);
+	}
+
+	#[test]
+	fn history() {
+		let mut editor = LineEditor::new();
+		let mut out = String::new();
+		for line in ["one", "two", "two", "three"].iter() {
+			feed(&mut editor, line.as_bytes(), &mut out);
+			feed(&mut editor, b"\r", &mut out);
+			editor.clear();
+		}
+		let up = b"\x1b[A";
+		let down = b"\x1b[B";
+		feed(&mut editor, up, &mut out);
+		assert_eq!(editor.line(), "three");
+		// repeated lines are stored once
+		feed(&mut editor, up, &mut out);
+		assert_eq!(editor.line(), "two");
+		feed(&mut editor, up, &mut out);
+		feed(&mut editor, up, &mut out);
+		assert_eq!(editor.line(), "one");
+		feed(&mut editor, down, &mut out);
+		assert_eq!(editor.line(), "two");
+		out.clear();
+		feed(&mut editor, down, &mut out);
+		feed(&mut editor, down, &mut out);
+		assert_eq!(editor.line(), "");
+		assert_eq!(out, "\x08\x08\x08\x1b[Kthree\x08\x08\x08\x08\x08\x1b[K");
+	}
+
+	#[test]
+	fn history_keeps_the_latest_entries() {
+		let mut editor = LineEditor::new();
+		let mut out = String::new();
+		for i in 0..HISTORY_LEN + 2 {
+			feed(&mut editor, format!("{}\r", i).as_bytes(), &mut out);
+			editor.clear();
+		}
+		for _ in 0..HISTORY_LEN + 2 {
+			feed(&mut editor, b"\x1b[A", &mut out);
+		}
+		assert_eq!(editor.line(), "2");
+	}
+}
@@ -0,0 +1,233 @@
+// interactive command shell, usually running on uart0
+//
+// the application feeds received bytes into the shell together with the list of
+// commands it wants to offer. besides the generic commands in `commands` it can
+// implement `Command` for anything of its own.
+
+use core::fmt::{self, Write};
+
+mod editor;
+pub mod commands;
+
+pub use self::editor::{Input, LineEditor, LINE_LEN};
+
+pub const MAX_ARGS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+	// wrong number of arguments or unknown sub command, prints the help text
+	Usage,
+	BadArgument,
+	Failed(&'static str)
+}
+
+impl fmt::Display for CommandError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			CommandError::Usage => write!(f, "usage"),
+			CommandError::BadArgument => write!(f, "invalid argument"),
+			CommandError::Failed(msg) => write!(f, "{}", msg)
+		}
+	}
+}
+
+pub trait Command {
+	fn name(&self) -> &'static str;
+
+	fn help(&self) -> &'static str;
+
+	// `args` does not include the command name
+	fn run(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError>;
+}
+
+// parse decimal or 0x prefixed hexadecimal numbers
+pub fn parse_number(s: &str) -> Result<u32, CommandError> {
+	let res = if s.starts_with("0x") || s.starts_with("0X") {
+		u32::from_str_radix(&s[2..], 16)
+	} else {
+		s.parse()
+	};
+	res.map_err(|_| CommandError::BadArgument)
+}
+
+pub struct Shell {
+	editor: LineEditor,
+	prompt: &'static str
+}
+
+impl Shell {
+	pub fn new(prompt: &'static str) -> Shell {
+		Shell {
+			editor: LineEditor::new(),
+			prompt: prompt
+		}
+	}
+
+	pub fn prompt(&self, out: &mut dyn Write) {
+		out.write_str(self.prompt).ok();
+	}
+
+	pub fn feed(&mut self, byte: u8, commands: &mut [&mut dyn Command], out: &mut dyn Write) {
+		match self.editor.feed(byte, out) {
+			Input::Pending => {},
+			Input::Interrupt => self.prompt(out),
+			Input::Line => {
+				execute(self.editor.line(), commands, out);
+				self.editor.clear();
+				self.prompt(out);
+			},
+			Input::Complete => self.complete(commands, out)
+		}
+	}
+
+	// complete the command name, arguments are not completed
+	fn complete(&mut self, commands: &mut [&mut dyn Command], out: &mut dyn Write) {
+		let line = self.editor.line();
+		let typed = line.len();
+		if line.contains(' ') {
+			return;
+		}
+
+		let names = || core::iter::once("help")
+			.chain(commands.iter().map(|c| c.name()))
+			.filter(move |n| n.starts_with(line));
+		let count = names().count();
+		let first = match names().next() {
+			Some(n) => n,
+			None => return
+		};
+
+		// command names are static, so they can be used after the line changes
+		if count == 1 {
+			self.editor.insert(&first[typed..], out);
+			self.editor.insert(" ", out);
+			return;
+		}
+
+		// several candidates, list them and complete the common prefix
+		let common = names().fold(first.len(), |len, n| {
+			first.bytes().zip(n.bytes()).take(len).take_while(|(a, b)| a == b).count()
+		});
+		out.write_str("\r\n").ok();
+		for n in names() {
+			write!(out, "{}  ", n).ok();
+		}
+		out.write_str("\r\n").ok();
+		self.prompt(out);
+		out.write_str(line).ok();
+		self.editor.insert(&first[typed..common], out);
+	}
+}
+
+pub fn execute(line: &str, commands: &mut [&mut dyn Command], out: &mut dyn Write) {
+	let mut args = [""; MAX_ARGS];
+	let mut count = 0;
+	for arg in line.split_whitespace() {
+		if count == MAX_ARGS {
+			writeln!(out, "too many arguments\r").ok();
+			return;
+		}
+		args[count] = arg;
+		count += 1;
+	}
+	if count == 0 {
+		return;
+	}
+
+	if args[0] == "help" {
+		for c in commands.iter() {
+			writeln!(out, "{:8} {}\r", c.name(), c.help()).ok();
+		}
+		return;
+	}
+
+	match commands.iter_mut().find(|c| c.name() == args[0]) {
+		Some(c) => match c.run(&args[1..count], out) {
+			Ok(()) => {},
+			Err(CommandError::Usage) => { writeln!(out, "usage: {}\r", c.help()).ok(); },
+			Err(e) => { writeln!(out, "error: {}\r", e).ok(); }
+		},
+		None => { writeln!(out, "unknown command: {}\r", args[0]).ok(); }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct Echo {
+		name: &'static str
+	}
+
+	impl Command for Echo {
+		fn name(&self) -> &'static str {
+			self.name
+		}
+
+		fn help(&self) -> &'static str {
+			"echo <text>"
+		}
+
+		fn run(&mut self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+			match args {
+				[] => Err(CommandError::Usage),
+				["fail"] => Err(CommandError::Failed("failed")),
+				_ => {
+					writeln!(out, "{}\r", args.join("|")).ok();
+					Ok(())
+				}
+			}
+		}
+	}
+
+	fn run_line(line: &str) -> String {
+		let mut echo = Echo { name: "echo" };
+		let mut out = String::new();
+		execute(line, &mut [&mut echo], &mut out);
+		out
+	}
+
+	#[test]
+	fn numbers() {
+		assert_eq!(parse_number("42"), Ok(42));
+		assert_eq!(parse_number("0x2A"), Ok(42));
+		assert_eq!(parse_number("0XffffFFFF"), Ok(0xFFFF_FFFF));
+		assert_eq!(parse_number("0x100000000"), Err(CommandError::BadArgument));
+		assert_eq!(parse_number("0x"), Err(CommandError::BadArgument));
+		assert_eq!(parse_number("-1"), Err(CommandError::BadArgument));
+		assert_eq!(parse_number("12a"), Err(CommandError::BadArgument));
+	}
+
+	#[test]
+	fn arguments() {
+		assert_eq!(run_line("  echo  a b\tc "), "a|b|c\r\n");
+		assert_eq!(run_line(""), "");
+		assert_eq!(run_line("echo 1 2 3 4 5 6 7"), "1|2|3|4|5|6|7\r\n");
+		assert_eq!(run_line("echo 1 2 3 4 5 6 7 8"), "too many arguments\r\n");
+	}
+
+	#[test]
+	fn errors() {
+		assert_eq!(run_line("echo"), "usage: echo <text>\r\n");
+		assert_eq!(run_line("echo fail"), "error: failed\r\n");
+		assert_eq!(run_line("ech"), "unknown command: ech\r\n");
+		assert_eq!(run_line("help"), "echo     echo <text>\r\n");
+	}
+
+	#[test]
+	fn completion() {
+		let mut shell = Shell::new("> ");
+		let mut led = Echo { name: "led" };
+		let mut lcd = Echo { name: "lcd" };
+		let mut out = String::new();
+		for b in b"l\t".iter() {
+			shell.feed(*b, &mut [&mut led, &mut lcd], &mut out);
+		}
+		assert_eq!(out, "l\r\nled  lcd  \r\n> l");
+		out.clear();
+		for b in b"e\tx\r".iter() {
+			shell.feed(*b, &mut [&mut led, &mut lcd], &mut out);
+		}
+		assert_eq!(out, "ed x\r\nx\r\n> ");
+	}
+}
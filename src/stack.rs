@@ -0,0 +1,131 @@
+// stack overflow protection and stack usage
+//
+// the stack grows down from `_stack_start` towards the statics in the ram. the lowest
+// GUARD_SIZE bytes above `_stack_limit` (the end of the statics, see build.rs) are
+// made inaccessible by an mpu region, so an overflow faults instead of silently
+// overwriting .bss. the mpu stays off in the hard fault handler, which therefore can
+// still push its frame into the guard and record the crash.
+//
+// applications route the faults here:
+//
+//     #[exception]
+//     fn MemoryManagement() { board::stack::memory_fault() }
+//
+//     #[exception]
+//     fn HardFault(ef: &ExceptionFrame) -> ! { board::stack::hard_fault(ef) }
+
+use core::ptr;
+
+use atsamx7x_hal::mpu::{Mpu, ProtectedMemoryRegion, MpuRegionSize, MpuSubregions, MpuAccessPolicy, MpuMemoryAttributes};
+use cortex_m::peripheral::MPU;
+use cortex_m_rt::ExceptionFrame;
+
+use crate::bootinfo::{CrashKind, CrashRecord};
+
+pub const GUARD_SIZE: usize = 256;
+
+// the unused part of the stack is filled with this pattern by `paint`
+const PAINT: u32 = 0x57AC_57AC;
+// painting stops this far below the stack pointer of the caller
+const PAINT_MARGIN: usize = 64;
+
+const SCB_SHCSR: *mut u32 = 0xE000_ED24 as *mut u32;
+const SCB_CFSR: *const u32 = 0xE000_ED28 as *const u32;
+const SCB_MMFAR: *const u32 = 0xE000_ED34 as *const u32;
+const SHCSR_MEMFAULTENA: u32 = 1 << 16;
+const CFSR_MSTKERR: u32 = 1 << 4;
+const CFSR_MMARVALID: u32 = 1 << 7;
+
+extern "C" {
+	static _stack_start: u32;
+	static _stack_limit: u32;
+}
+
+fn top() -> usize {
+	unsafe { &_stack_start as *const u32 as usize }
+}
+
+// first address of the guard region, aligned to its size for the mpu
+pub fn guard_start() -> usize {
+	let limit = unsafe { &_stack_limit as *const u32 as usize };
+	(limit + GUARD_SIZE - 1) & !(GUARD_SIZE - 1)
+}
+
+// lowest address the stack may use
+pub fn bottom() -> usize {
+	guard_start() + GUARD_SIZE
+}
+
+pub fn size() -> usize {
+	top() - bottom()
+}
+
+// fill the unused stack with a pattern, call early in main, the high water mark
+// only covers what is used after this call
+pub fn paint() {
+	let sp = cortex_m::register::msp::read() as usize;
+	let mut p = bottom();
+	while p + PAINT_MARGIN < sp {
+		unsafe { ptr::write_volatile(p as *mut u32, PAINT) };
+		p += 4;
+	}
+}
+
+// most bytes of stack used since `paint`
+pub fn high_water_mark() -> usize {
+	let mut p = bottom();
+	while p < top() && unsafe { ptr::read_volatile(p as *const u32) } == PAINT {
+		p += 4;
+	}
+	top() - p
+}
+
+// protect the guard region and enable the memory management fault. regions added
+// to the mpu later take precedence, so add the guard last.
+pub fn enable_guard(mpu: &mut MPU) -> Result<(), ()> {
+	mpu.add_region(ProtectedMemoryRegion {
+		base_address: guard_start() as *const u32,
+		size: MpuRegionSize::REGION256_B,
+		subregions: MpuSubregions::default(),
+		executable: false,
+		permissions: MpuAccessPolicy::NoAccess,
+		attributes: MpuMemoryAttributes::StronglyOrdered
+	}).map_err(|_| ())?;
+	mpu.enable();
+	unsafe {
+		let shcsr = ptr::read_volatile(SCB_SHCSR);
+		ptr::write_volatile(SCB_SHCSR, shcsr | SHCSR_MEMFAULTENA);
+	}
+	Ok(())
+}
+
+// whether the current memory management fault was caused by the stack running into
+// the guard, either while pushing an exception frame or by an instruction
+pub fn is_stack_overflow() -> bool {
+	let cfsr = unsafe { ptr::read_volatile(SCB_CFSR) };
+	if cfsr & CFSR_MSTKERR != 0 {
+		return true;
+	}
+	let address = unsafe { ptr::read_volatile(SCB_MMFAR) } as usize;
+	cfsr & CFSR_MMARVALID != 0 && address >= guard_start() && address < bottom()
+}
+
+fn crash(pc: u32) -> ! {
+	let kind = if is_stack_overflow() { CrashKind::StackOverflow } else { CrashKind::HardFault };
+	CrashRecord {
+		kind: kind,
+		pc: pc,
+		info: unsafe { ptr::read_volatile(SCB_CFSR) }
+	}.store();
+	cortex_m::peripheral::SCB::sys_reset()
+}
+
+// memory management fault handler, records the crash and resets
+pub fn memory_fault() -> ! {
+	crash(0)
+}
+
+// hard fault handler, faults in the memory management handler end up here as well
+pub fn hard_fault(ef: &ExceptionFrame) -> ! {
+	crash(ef.pc)
+}
@@ -0,0 +1,163 @@
+// tightly coupled memories
+//
+// the cortex-m7 accesses the itcm and dtcm without wait states and without going
+// through the caches. their size is taken from the sram and set by two gpnvm bits,
+// the linker layout has to match it (`tcm_size` in board.rs).
+//
+// code is placed in the itcm with `#[link_section = ".itcm"]` or the `itcm!` macro,
+// which also works for interrupt handlers, statics with
+// `#[link_section = ".dtcm.data"]` or `#[link_section = ".dtcm.bss"]`. all of them
+// are only valid after `init` has been called. with `stack_in_dtcm` in board.rs the
+// main stack is moved to the end of the dtcm, which relies on the core enabling the
+// tcm out of reset once the gpnvm bits select a size.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::flash::{Flash, FlashError, GPNVM_TCM_HIGH, GPNVM_TCM_LOW};
+use crate::layout::BOARD;
+
+const SCB_ITCMCR: *mut u32 = 0xE000_EF90 as *mut u32;
+const SCB_DTCMCR: *mut u32 = 0xE000_EF94 as *mut u32;
+const TCMCR_EN: u32 = 1 << 0;
+const TCMCR_RMW: u32 = 1 << 1;
+const TCMCR_RETEN: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcmSize {
+	Disabled,
+	Kib32,
+	Kib64,
+	Kib128
+}
+
+impl TcmSize {
+	pub fn bytes(self) -> usize {
+		match self {
+			TcmSize::Disabled => 0,
+			TcmSize::Kib32 => 0x8000,
+			TcmSize::Kib64 => 0x1_0000,
+			TcmSize::Kib128 => 0x2_0000
+		}
+	}
+
+	pub fn from_bytes(bytes: usize) -> Option<TcmSize> {
+		[TcmSize::Disabled, TcmSize::Kib32, TcmSize::Kib64, TcmSize::Kib128].iter()
+			.cloned()
+			.find(|s| s.bytes() == bytes)
+	}
+
+	fn from_gpnvm(gpnvm: u32) -> TcmSize {
+		match (gpnvm >> GPNVM_TCM_LOW) & 0x3 {
+			0 => TcmSize::Disabled,
+			1 => TcmSize::Kib32,
+			2 => TcmSize::Kib64,
+			_ => TcmSize::Kib128
+		}
+	}
+
+	fn config(self) -> u32 {
+		match self {
+			TcmSize::Disabled => 0,
+			TcmSize::Kib32 => 1,
+			TcmSize::Kib64 => 2,
+			TcmSize::Kib128 => 3
+		}
+	}
+}
+
+// size the firmware has been linked for
+pub fn linked_size() -> TcmSize {
+	TcmSize::from_bytes(BOARD.tcm_size).unwrap()
+}
+
+// size currently selected by the gpnvm bits
+pub fn configured_size(flash: &mut Flash) -> Result<TcmSize, FlashError> {
+	flash.gpnvm().map(TcmSize::from_gpnvm)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcmConfig {
+	// the gpnvm bits already select the size
+	Unchanged,
+	// the bits have been written, the new size is used after the next reset
+	ResetRequired
+}
+
+// select the tcm size in the gpnvm bits. the bits are read first and only written if
+// they differ. a firmware linked for a different size than the one that is active
+// will crash, so only the size returned by `linked_size` should be configured unless
+// the next image is known to use another.
+pub fn configure(flash: &mut Flash, size: TcmSize) -> Result<TcmConfig, FlashError> {
+	if configured_size(flash)? == size {
+		return Ok(TcmConfig::Unchanged);
+	}
+	let config = size.config();
+	flash.set_gpnvm(GPNVM_TCM_LOW, config & 0x1 != 0)?;
+	flash.set_gpnvm(GPNVM_TCM_HIGH, config & 0x2 != 0)?;
+	Ok(TcmConfig::ResetRequired)
+}
+
+extern "C" {
+	static mut _sitcm: u32;
+	static mut _eitcm: u32;
+	static _siitcm: u32;
+	static mut _sdtcm_data: u32;
+	static mut _edtcm_data: u32;
+	static _sidtcm_data: u32;
+	static mut _sdtcm_bss: u32;
+	static mut _edtcm_bss: u32;
+}
+
+static INITIALISED: AtomicBool = AtomicBool::new(false);
+
+// enable the tcm in the core and load the itcm code and dtcm statics, returns
+// without doing anything when called again. has to run before anything placed in
+// the tcm is used, first thing in main. not from `#[pre_init]`, the flag that makes
+// it safe to call again lives in .bss, which is zeroed later.
+pub unsafe fn init() {
+	if INITIALISED.swap(true, Ordering::SeqCst) {
+		return;
+	}
+	if BOARD.tcm_size != 0 {
+		for reg in [SCB_ITCMCR, SCB_DTCMCR].iter() {
+			let value = ptr::read_volatile(*reg);
+			ptr::write_volatile(*reg, (value | TCMCR_EN) & !(TCMCR_RMW | TCMCR_RETEN));
+		}
+		cortex_m::asm::dsb();
+		cortex_m::asm::isb();
+	}
+
+	r0::init_data(&mut _sitcm, &mut _eitcm, &_siitcm);
+	r0::init_data(&mut _sdtcm_data, &mut _edtcm_data, &_sidtcm_data);
+	r0::zero_bss(&mut _sdtcm_bss, &mut _edtcm_bss);
+	// the copied code must not be executed from stale pipeline or cache contents
+	cortex_m::asm::dsb();
+	cortex_m::asm::isb();
+}
+
+pub fn is_initialised() -> bool {
+	INITIALISED.load(Ordering::SeqCst)
+}
+
+// place a function in the itcm, e.g.
+//
+//     itcm! {
+//         #[interrupt]
+//         fn UART0() { ... }
+//     }
+#[macro_export]
+macro_rules! itcm {
+	($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+		#[link_section = ".itcm"]
+		#[inline(never)]
+		$(#[$attr])*
+		$vis fn $($rest)*
+	};
+	($(#[$attr:meta])* $vis:vis unsafe fn $($rest:tt)*) => {
+		#[link_section = ".itcm"]
+		#[inline(never)]
+		$(#[$attr])*
+		$vis unsafe fn $($rest)*
+	};
+}
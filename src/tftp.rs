@@ -0,0 +1,465 @@
+// tftp client (rfc 1350 + tsize option from rfc 2349) that downloads a file
+// straight into a memory buffer, usually a region of the sdram
+
+use crate::crc::Crc32;
+use crate::net::{Endpoint, Ipv4Addr, UdpSocket};
+
+pub const TFTP_PORT: u16 = 69;
+
+const BLOCK_SIZE: usize = 512;
+
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERR_DISK_FULL: u16 = 3;
+const ERR_UNKNOWN_TID: u16 = 5;
+
+#[derive(Debug)]
+pub enum TftpError<E> {
+	Socket(E),
+	Timeout,
+	// error code sent by the server
+	Remote(u16),
+	FilenameTooLong,
+	BufferTooSmall,
+	Protocol,
+	CrcMismatch { expected: u32, actual: u32 }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+	pub received: usize,
+	// only known if the server supports the tsize option
+	pub total: Option<usize>
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Download {
+	pub len: usize,
+	pub crc: u32
+}
+
+pub struct TftpClient<S> {
+	socket: S,
+	server: Endpoint,
+	timeout_ms: u32,
+	retries: u8
+}
+
+impl<S: UdpSocket> TftpClient<S> {
+	pub fn new(socket: S, server: Ipv4Addr) -> TftpClient<S> {
+		TftpClient {
+			socket: socket,
+			server: Endpoint::new(server, TFTP_PORT),
+			timeout_ms: 1000,
+			retries: 5
+		}
+	}
+
+	pub fn port(mut self, port: u16) -> Self {
+		self.server.port = port;
+		self
+	}
+
+	pub fn timeout_ms(mut self, timeout_ms: u32) -> Self {
+		self.timeout_ms = timeout_ms;
+		self
+	}
+
+	pub fn retries(mut self, retries: u8) -> Self {
+		self.retries = retries;
+		self
+	}
+
+	pub fn free(self) -> S {
+		self.socket
+	}
+
+	// download `filename` into `dest`, `progress` is called after every received block
+	pub fn get<F>(&mut self, filename: &str, dest: &mut [u8], expected_crc: Option<u32>, mut progress: F) -> Result<Download, TftpError<S::Error>>
+		where F: FnMut(Progress)
+	{
+		let mut request = [0u8; BLOCK_SIZE];
+		let request_len = build_request(&mut request, filename)?;
+
+		let mut packet = [0u8; BLOCK_SIZE + 4];
+		let mut ack = [0u8; 4];
+		// until the first answer arrives the request is repeated on timeout, afterwards the last ack
+		let mut remote: Option<Endpoint> = None;
+
+		let mut crc = Crc32::new();
+		let mut received: usize = 0;
+		let mut total: Option<usize> = None;
+		let mut expected_block: u16 = 1;
+		let mut tries = 0;
+
+		self.socket.send_to(&request[..request_len], self.server).map_err(TftpError::Socket)?;
+
+		loop {
+			let (len, from) = match self.socket.recv_from(&mut packet, self.timeout_ms).map_err(TftpError::Socket)? {
+				Some(r) => r,
+				None => {
+					tries += 1;
+					if tries > self.retries {
+						return Err(TftpError::Timeout);
+					}
+					match remote {
+						Some(peer) => self.socket.send_to(&ack, peer),
+						None => self.socket.send_to(&request[..request_len], self.server)
+					}.map_err(TftpError::Socket)?;
+					continue;
+				}
+			};
+
+			if from.addr != self.server.addr {
+				continue;
+			}
+			// the server answers from a new port (transfer id) which is fixed for the whole transfer
+			match remote {
+				None => remote = Some(from),
+				Some(r) if r != from => {
+					let mut err = [0u8; 32];
+					let n = build_error(&mut err, ERR_UNKNOWN_TID, "unknown transfer id");
+					self.socket.send_to(&err[..n], from).map_err(TftpError::Socket)?;
+					continue;
+				},
+				_ => {}
+			}
+			let peer = from;
+
+			if len < 4 {
+				return Err(TftpError::Protocol);
+			}
+			let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+			let arg = u16::from_be_bytes([packet[2], packet[3]]);
+
+			match opcode {
+				OP_OACK => {
+					total = parse_tsize(&packet[2..len]);
+					if let Some(t) = total {
+						if t > dest.len() {
+							self.abort(peer, ERR_DISK_FULL, "file too large")?;
+							return Err(TftpError::BufferTooSmall);
+						}
+					}
+					build_ack(&mut ack, 0);
+				},
+				OP_DATA => {
+					let data = &packet[4..len];
+					if arg == expected_block {
+						if received + data.len() > dest.len() {
+							self.abort(peer, ERR_DISK_FULL, "file too large")?;
+							return Err(TftpError::BufferTooSmall);
+						}
+						dest[received..received + data.len()].copy_from_slice(data);
+						crc.update(data);
+						received += data.len();
+						progress(Progress { received: received, total: total });
+
+						build_ack(&mut ack, arg);
+						if data.len() < BLOCK_SIZE {
+							self.socket.send_to(&ack, peer).map_err(TftpError::Socket)?;
+							break;
+						}
+						expected_block = expected_block.wrapping_add(1);
+					} else if received == 0 || arg != expected_block.wrapping_sub(1) {
+						// neither the expected nor a duplicate of the previous block
+						return Err(TftpError::Protocol);
+					}
+				},
+				OP_ERROR => {
+					return Err(TftpError::Remote(arg));
+				},
+				_ => {
+					return Err(TftpError::Protocol);
+				}
+			}
+
+			self.socket.send_to(&ack, peer).map_err(TftpError::Socket)?;
+			tries = 0;
+		}
+
+		let download = Download {
+			len: received,
+			crc: crc.finish()
+		};
+		if let Some(expected) = expected_crc {
+			if expected != download.crc {
+				return Err(TftpError::CrcMismatch { expected: expected, actual: download.crc });
+			}
+		}
+		Ok(download)
+	}
+
+	fn abort(&mut self, remote: Endpoint, code: u16, msg: &str) -> Result<(), TftpError<S::Error>> {
+		let mut err = [0u8; 32];
+		let n = build_error(&mut err, code, msg);
+		self.socket.send_to(&err[..n], remote).map_err(TftpError::Socket)
+	}
+}
+
+fn build_request<E>(buf: &mut [u8], filename: &str) -> Result<usize, TftpError<E>> {
+	let options: &[&[u8]] = &[b"octet", b"tsize", b"0"];
+	let needed = 2 + filename.len() + 1 + options.iter().map(|o| o.len() + 1).sum::<usize>();
+	if filename.is_empty() || needed > buf.len() {
+		return Err(TftpError::FilenameTooLong);
+	}
+
+	buf[..2].copy_from_slice(&OP_RRQ.to_be_bytes());
+	let mut pos = 2;
+	for part in core::iter::once(filename.as_bytes()).chain(options.iter().cloned()) {
+		buf[pos..pos + part.len()].copy_from_slice(part);
+		pos += part.len();
+		buf[pos] = 0;
+		pos += 1;
+	}
+	Ok(pos)
+}
+
+fn build_ack(buf: &mut [u8; 4], block: u16) {
+	buf[..2].copy_from_slice(&OP_ACK.to_be_bytes());
+	buf[2..].copy_from_slice(&block.to_be_bytes());
+}
+
+fn build_error(buf: &mut [u8], code: u16, msg: &str) -> usize {
+	let msg = &msg.as_bytes()[..core::cmp::min(msg.len(), buf.len() - 5)];
+	buf[..2].copy_from_slice(&OP_ERROR.to_be_bytes());
+	buf[2..4].copy_from_slice(&code.to_be_bytes());
+	buf[4..4 + msg.len()].copy_from_slice(msg);
+	buf[4 + msg.len()] = 0;
+	5 + msg.len()
+}
+
+// options are sent as a list of zero terminated name/value pairs
+fn parse_tsize(options: &[u8]) -> Option<usize> {
+	let mut parts = options.split(|b| *b == 0);
+	while let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+		if name.eq_ignore_ascii_case(b"tsize") {
+			return core::str::from_utf8(value).ok()?.parse().ok();
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::crc::crc32;
+	use std::collections::VecDeque;
+	use std::vec::Vec;
+
+	const SERVER: Ipv4Addr = Ipv4Addr([10, 0, 0, 1]);
+	// transfer id the server answers from
+	const TID: u16 = 4711;
+
+	// tftp server stand-in, answers every packet the client sends right away
+	struct Server {
+		file: Vec<u8>,
+		tsize: bool,
+		// answers that get lost, counted from 0
+		drop: Vec<usize>,
+		// send every data block twice
+		duplicate: bool,
+		// replaces the first answer
+		first: Option<Vec<u8>>,
+		answers: usize,
+		queue: VecDeque<Vec<u8>>,
+		sent: Vec<Vec<u8>>
+	}
+
+	impl Server {
+		fn new(file: Vec<u8>) -> Server {
+			Server {
+				file: file,
+				tsize: true,
+				drop: Vec::new(),
+				duplicate: false,
+				first: None,
+				answers: 0,
+				queue: VecDeque::new(),
+				sent: Vec::new()
+			}
+		}
+
+		fn data(&self, block: u16) -> Vec<u8> {
+			let start = core::cmp::min((block as usize - 1) * BLOCK_SIZE, self.file.len());
+			let end = core::cmp::min(start + BLOCK_SIZE, self.file.len());
+			let mut packet = Vec::new();
+			packet.extend_from_slice(&OP_DATA.to_be_bytes());
+			packet.extend_from_slice(&block.to_be_bytes());
+			packet.extend_from_slice(&self.file[start..end]);
+			packet
+		}
+
+		fn answer(&mut self, packet: Vec<u8>) {
+			let n = self.answers;
+			self.answers += 1;
+			let packet = match (n, self.first.take()) {
+				(0, Some(first)) => first,
+				_ => packet
+			};
+			if !self.drop.contains(&n) {
+				if self.duplicate && packet[1] as u16 == OP_DATA {
+					self.queue.push_back(packet.clone());
+				}
+				self.queue.push_back(packet);
+			}
+		}
+	}
+
+	impl UdpSocket for Server {
+		type Error = ();
+
+		fn send_to(&mut self, data: &[u8], remote: Endpoint) -> Result<(), ()> {
+			assert_eq!(remote.addr, SERVER);
+			self.sent.push(data.to_vec());
+			let opcode = u16::from_be_bytes([data[0], data[1]]);
+			let arg = u16::from_be_bytes([data[2], data[3]]);
+			match opcode {
+				OP_RRQ if self.tsize => {
+					let mut oack = Vec::new();
+					oack.extend_from_slice(&OP_OACK.to_be_bytes());
+					oack.extend_from_slice(b"tsize\0");
+					oack.extend_from_slice(format!("{}\0", self.file.len()).as_bytes());
+					self.answer(oack);
+				},
+				OP_RRQ => {
+					let data = self.data(1);
+					self.answer(data);
+				},
+				// nothing follows the ack of the last block
+				OP_ACK if (arg as usize) * BLOCK_SIZE <= self.file.len() => {
+					let data = self.data(arg + 1);
+					self.answer(data);
+				},
+				_ => {}
+			}
+			Ok(())
+		}
+
+		fn recv_from(&mut self, buf: &mut [u8], _timeout_ms: u32) -> Result<Option<(usize, Endpoint)>, ()> {
+			Ok(self.queue.pop_front().map(|p| {
+				buf[..p.len()].copy_from_slice(&p);
+				(p.len(), Endpoint::new(SERVER, TID))
+			}))
+		}
+	}
+
+	fn file(len: usize) -> Vec<u8> {
+		(0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+	}
+
+	#[test]
+	fn download_with_tsize() {
+		let data = file(3 * BLOCK_SIZE + 100);
+		let mut client = TftpClient::new(Server::new(data.clone()), SERVER);
+		let mut dest = [0u8; 4096];
+		let mut last = None;
+		let download = client.get("image.bin", &mut dest, Some(crc32(&data)), |p| last = Some(p)).unwrap();
+		assert_eq!(download.len, data.len());
+		assert_eq!(&dest[..data.len()], &data[..]);
+		let last = last.unwrap();
+		assert_eq!((last.received, last.total), (data.len(), Some(data.len())));
+
+		// the request carries the filename and the options
+		let server = client.free();
+		assert_eq!(&server.sent[0][..], &b"\x00\x01image.bin\0octet\0tsize\00\0"[..]);
+	}
+
+	#[test]
+	fn download_block_multiple_without_tsize() {
+		// a file of whole blocks ends with an empty block
+		let data = file(2 * BLOCK_SIZE);
+		let mut server = Server::new(data.clone());
+		server.tsize = false;
+		let mut client = TftpClient::new(server, SERVER);
+		let mut dest = [0u8; 4096];
+		let download = client.get("a", &mut dest, None, |_| {}).unwrap();
+		assert_eq!(download.len, data.len());
+		assert_eq!(download.crc, crc32(&data));
+		let server = client.free();
+		assert_eq!(&server.sent.last().unwrap()[..], &[0, 4, 0, 3][..]);
+	}
+
+	#[test]
+	fn retries_lost_packets() {
+		let data = file(BLOCK_SIZE + 1);
+		let mut server = Server::new(data.clone());
+		// the oack and the second data block get lost once
+		server.drop = vec![0, 2];
+		let mut client = TftpClient::new(server, SERVER);
+		let mut dest = [0u8; 4096];
+		let download = client.get("a", &mut dest, None, |_| {}).unwrap();
+		assert_eq!(&dest[..download.len], &data[..]);
+	}
+
+	#[test]
+	fn gives_up_after_retries() {
+		let mut server = Server::new(file(10));
+		server.drop = (0..10).collect();
+		let mut client = TftpClient::new(server, SERVER).retries(2);
+		let mut dest = [0u8; 64];
+		match client.get("a", &mut dest, None, |_| {}) {
+			Err(TftpError::Timeout) => {},
+			r => panic!("{:?}", r)
+		}
+	}
+
+	#[test]
+	fn ignores_duplicate_blocks() {
+		let data = file(3 * BLOCK_SIZE + 5);
+		let mut server = Server::new(data.clone());
+		server.duplicate = true;
+		let mut client = TftpClient::new(server, SERVER);
+		let mut dest = [0u8; 4096];
+		let download = client.get("a", &mut dest, None, |_| {}).unwrap();
+		assert_eq!(&dest[..download.len], &data[..]);
+	}
+
+	#[test]
+	fn rejects_block_zero_before_the_first_block() {
+		let mut server = Server::new(file(10));
+		server.tsize = false;
+		server.first = Some(vec![0, 3, 0, 0, 1, 2, 3]);
+		let mut client = TftpClient::new(server, SERVER);
+		let mut dest = [0u8; 64];
+		match client.get("a", &mut dest, None, |_| {}) {
+			Err(TftpError::Protocol) => {},
+			r => panic!("{:?}", r)
+		}
+	}
+
+	#[test]
+	fn file_too_large() {
+		let mut client = TftpClient::new(Server::new(file(1000)), SERVER);
+		let mut dest = [0u8; 512];
+		match client.get("a", &mut dest, None, |_| {}) {
+			Err(TftpError::BufferTooSmall) => {},
+			r => panic!("{:?}", r)
+		}
+		// the server is told why
+		let server = client.free();
+		assert_eq!(&server.sent.last().unwrap()[..4], &[0, 5, 0, 3][..]);
+	}
+
+	#[test]
+	fn remote_error_and_crc_mismatch() {
+		let mut server = Server::new(file(10));
+		server.first = Some(vec![0, 5, 0, 1, b'n', b'o', 0]);
+		let mut client = TftpClient::new(server, SERVER);
+		let mut dest = [0u8; 64];
+		match client.get("a", &mut dest, None, |_| {}) {
+			Err(TftpError::Remote(1)) => {},
+			r => panic!("{:?}", r)
+		}
+
+		let mut client = TftpClient::new(Server::new(file(10)), SERVER);
+		match client.get("a", &mut dest, Some(0), |_| {}) {
+			Err(TftpError::CrcMismatch { expected: 0, .. }) => {},
+			r => panic!("{:?}", r)
+		}
+	}
+}
@@ -0,0 +1,243 @@
+// the buffers of `BufferedSerial`, apart from the uart registers
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Error, LINE_SIZE, RING_SIZE};
+
+// single producer, single consumer byte queue. `head` is only advanced by the
+// producer, `tail` only by the consumer, both count up and wrap.
+pub struct Ring {
+	buf: UnsafeCell<[u8; RING_SIZE]>,
+	head: AtomicUsize,
+	tail: AtomicUsize
+}
+
+unsafe impl Sync for Ring {}
+
+impl Ring {
+	pub const fn new() -> Ring {
+		Ring {
+			buf: UnsafeCell::new([0; RING_SIZE]),
+			head: AtomicUsize::new(0),
+			tail: AtomicUsize::new(0)
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.head.load(Ordering::Acquire).wrapping_sub(self.tail.load(Ordering::Acquire))
+	}
+
+	pub fn push(&self, byte: u8) -> bool {
+		let head = self.head.load(Ordering::Relaxed);
+		if head.wrapping_sub(self.tail.load(Ordering::Acquire)) == RING_SIZE {
+			return false;
+		}
+		unsafe { (*self.buf.get())[head % RING_SIZE] = byte; }
+		self.head.store(head.wrapping_add(1), Ordering::Release);
+		true
+	}
+
+	pub fn pop(&self) -> Option<u8> {
+		let tail = self.tail.load(Ordering::Relaxed);
+		if self.head.load(Ordering::Acquire) == tail {
+			return None;
+		}
+		let byte = unsafe { (*self.buf.get())[tail % RING_SIZE] };
+		self.tail.store(tail.wrapping_add(1), Ordering::Release);
+		Some(byte)
+	}
+
+	// only while the producer does not run
+	pub fn clear(&self) {
+		self.tail.store(self.head.load(Ordering::Acquire), Ordering::Release);
+	}
+}
+
+// collects the received bytes into lines
+pub struct LineBuffer {
+	line: [u8; LINE_SIZE],
+	len: usize,
+	// the last line ended with \r, skip the \n of a \r\n
+	skip_lf: bool,
+	// the last call returned a line, start a new one
+	line_done: bool,
+	// the current line was too long, skip it up to its terminator
+	discarding: bool
+}
+
+impl LineBuffer {
+	pub fn new() -> LineBuffer {
+		LineBuffer {
+			line: [0; LINE_SIZE],
+			len: 0,
+			skip_lf: false,
+			line_done: false,
+			discarding: false
+		}
+	}
+
+	// the next complete line from `rx` without its terminator (\r, \n or \r\n)
+	pub fn read_line(&mut self, rx: &Ring) -> nb::Result<&[u8], Error> {
+		if self.line_done {
+			self.len = 0;
+			self.line_done = false;
+		}
+		while let Some(byte) = rx.pop() {
+			if byte == b'\n' && self.skip_lf {
+				self.skip_lf = false;
+				continue;
+			}
+			self.skip_lf = byte == b'\r';
+			if byte == b'\r' || byte == b'\n' {
+				if self.discarding {
+					self.discarding = false;
+					continue;
+				}
+				self.line_done = true;
+				return Ok(&self.line[..self.len]);
+			}
+			if self.discarding {
+				continue;
+			}
+			if self.len == LINE_SIZE {
+				self.len = 0;
+				self.discarding = true;
+				return Err(nb::Error::Other(Error::LineTooLong));
+			}
+			self.line[self.len] = byte;
+			self.len += 1;
+		}
+		Err(nb::Error::WouldBlock)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn push(ring: &Ring, data: &[u8]) {
+		for b in data {
+			assert!(ring.push(*b));
+		}
+	}
+
+	#[test]
+	fn ring_full_and_empty() {
+		let ring = Ring::new();
+		assert_eq!(ring.len(), 0);
+		assert_eq!(ring.pop(), None);
+
+		for i in 0..RING_SIZE {
+			assert!(ring.push(i as u8));
+		}
+		assert_eq!(ring.len(), RING_SIZE);
+		assert!(!ring.push(0xFF));
+
+		assert_eq!(ring.pop(), Some(0));
+		assert!(ring.push(0xFF));
+		assert!(!ring.push(0xFF));
+		for i in 1..RING_SIZE {
+			assert_eq!(ring.pop(), Some(i as u8));
+		}
+		assert_eq!(ring.pop(), Some(0xFF));
+		assert_eq!(ring.pop(), None);
+		assert_eq!(ring.len(), 0);
+
+		push(&ring, b"abc");
+		ring.clear();
+		assert_eq!(ring.len(), 0);
+		assert_eq!(ring.pop(), None);
+	}
+
+	#[test]
+	fn ring_wraparound() {
+		let ring = Ring::new();
+		// the counters wrap around while bytes are queued
+		ring.head.store(usize::max_value() - 2, Ordering::Relaxed);
+		ring.tail.store(usize::max_value() - 2, Ordering::Relaxed);
+		for i in 0..RING_SIZE {
+			assert!(ring.push(i as u8));
+		}
+		assert!(!ring.push(0xFF));
+		assert_eq!(ring.len(), RING_SIZE);
+		for i in 0..RING_SIZE {
+			assert_eq!(ring.pop(), Some(i as u8));
+		}
+		assert_eq!(ring.pop(), None);
+		assert_eq!(ring.head.load(Ordering::Relaxed), RING_SIZE - 3);
+	}
+
+	#[test]
+	fn lines_across_calls() {
+		let ring = Ring::new();
+		let mut line = LineBuffer::new();
+		assert_eq!(line.read_line(&ring), Err(nb::Error::WouldBlock));
+
+		push(&ring, b"he");
+		assert_eq!(line.read_line(&ring), Err(nb::Error::WouldBlock));
+		push(&ring, b"llo\nworld\n");
+		assert_eq!(line.read_line(&ring), Ok(&b"hello"[..]));
+		assert_eq!(line.read_line(&ring), Ok(&b"world"[..]));
+		assert_eq!(line.read_line(&ring), Err(nb::Error::WouldBlock));
+
+		// empty lines are lines
+		push(&ring, b"\n\n");
+		assert_eq!(line.read_line(&ring), Ok(&b""[..]));
+		assert_eq!(line.read_line(&ring), Ok(&b""[..]));
+	}
+
+	#[test]
+	fn crlf_folding() {
+		let ring = Ring::new();
+		let mut line = LineBuffer::new();
+		push(&ring, b"one\r\ntwo\r");
+		assert_eq!(line.read_line(&ring), Ok(&b"one"[..]));
+		assert_eq!(line.read_line(&ring), Ok(&b"two"[..]));
+		assert_eq!(line.read_line(&ring), Err(nb::Error::WouldBlock));
+
+		// the \n of the \r\n arrives with the next call
+		push(&ring, b"\nthree\n");
+		assert_eq!(line.read_line(&ring), Ok(&b"three"[..]));
+
+		// \n\r and \r\r are two terminators
+		push(&ring, b"\n\r\r\r");
+		assert_eq!(line.read_line(&ring), Ok(&b""[..]));
+		assert_eq!(line.read_line(&ring), Ok(&b""[..]));
+		assert_eq!(line.read_line(&ring), Ok(&b""[..]));
+		assert_eq!(line.read_line(&ring), Ok(&b""[..]));
+		assert_eq!(line.read_line(&ring), Err(nb::Error::WouldBlock));
+	}
+
+	#[test]
+	fn line_of_line_size() {
+		let ring = Ring::new();
+		let mut line = LineBuffer::new();
+		let data = [b'x'; LINE_SIZE];
+		push(&ring, &data);
+		assert_eq!(line.read_line(&ring), Err(nb::Error::WouldBlock));
+		push(&ring, b"\n");
+		assert_eq!(line.read_line(&ring), Ok(&data[..]));
+	}
+
+	#[test]
+	fn line_too_long() {
+		let ring = Ring::new();
+		let mut line = LineBuffer::new();
+		push(&ring, &[b'x'; LINE_SIZE + 1]);
+		assert_eq!(line.read_line(&ring), Err(nb::Error::Other(Error::LineTooLong)));
+		assert_eq!(line.read_line(&ring), Err(nb::Error::WouldBlock));
+
+		// the rest of the line is discarded up to its terminator
+		push(&ring, &[b'y'; 2 * LINE_SIZE]);
+		assert_eq!(line.read_line(&ring), Err(nb::Error::WouldBlock));
+		push(&ring, b"yyy\r\nnext\n");
+		assert_eq!(line.read_line(&ring), Ok(&b"next"[..]));
+
+		// the error is reported once per line
+		push(&ring, &[b'z'; 3 * LINE_SIZE]);
+		push(&ring, b"\nok\n");
+		assert_eq!(line.read_line(&ring), Err(nb::Error::Other(Error::LineTooLong)));
+		assert_eq!(line.read_line(&ring), Ok(&b"ok"[..]));
+	}
+}
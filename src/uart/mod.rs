@@ -0,0 +1,314 @@
+// interrupt driven uart0 with ring buffers
+//
+// `BufferedSerial` takes UART0 and its pins and configures the uart for 8n1. received
+// bytes are moved into the rx ring by the UART0 interrupt, written bytes wait in the tx
+// ring and the interrupt feeds them to the transmitter, so `writeln!` only blocks while
+// the tx ring is full. the interrupt handler has to call `handle_interrupt`:
+//
+//     #[interrupt]
+//     fn UART0() {
+//         board::uart::handle_interrupt();
+//     }
+//
+// uart0 has no receiver timeout, so the xdmac could not tell when a short reception
+// has ended, which is why this is interrupt driven. each ring has one producer and
+// one consumer (the interrupt and the owner of `BufferedSerial`) and needs no locks.
+//
+// bytes lost in hardware (overrun) or because the rx ring was full are counted in
+// the statistics and reported once by the next read.
+//
+// the rings and the line assembly do not touch the uart, they are in `buffer`.
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use atsame70q21::{Interrupt, PMC, UART0};
+use atsamx7x_hal::gpio::{pioa, PeripheralCntr, PeriphA};
+use cortex_m::peripheral::NVIC;
+
+mod buffer;
+
+use self::buffer::{LineBuffer, Ring};
+
+const PMC_BASE: usize = 0x400E_0600;
+const PMC_PCER0: *mut u32 = (PMC_BASE + 0x10) as *mut u32;
+const UART0_PID: u32 = 7;
+
+const UART0_BASE: usize = 0x400E_0800;
+const UART_CR: *mut u32 = UART0_BASE as *mut u32;
+const UART_MR: *mut u32 = (UART0_BASE + 0x04) as *mut u32;
+const UART_IER: *mut u32 = (UART0_BASE + 0x08) as *mut u32;
+const UART_IDR: *mut u32 = (UART0_BASE + 0x0C) as *mut u32;
+const UART_IMR: *const u32 = (UART0_BASE + 0x10) as *const u32;
+const UART_SR: *const u32 = (UART0_BASE + 0x14) as *const u32;
+const UART_RHR: *const u32 = (UART0_BASE + 0x18) as *const u32;
+const UART_THR: *mut u32 = (UART0_BASE + 0x1C) as *mut u32;
+const UART_BRGR: *mut u32 = (UART0_BASE + 0x20) as *mut u32;
+
+const UART_CR_RSTRX: u32 = 1 << 2;
+const UART_CR_RSTTX: u32 = 1 << 3;
+const UART_CR_RXEN: u32 = 1 << 4;
+const UART_CR_RXDIS: u32 = 1 << 5;
+const UART_CR_TXEN: u32 = 1 << 6;
+const UART_CR_TXDIS: u32 = 1 << 7;
+const UART_CR_RSTSTA: u32 = 1 << 8;
+// no parity, the baud rate generator runs from the peripheral clock (mck)
+const UART_MR_PAR_NO: u32 = 4 << 9;
+const UART_SR_RXRDY: u32 = 1 << 0;
+const UART_SR_TXRDY: u32 = 1 << 1;
+const UART_SR_OVRE: u32 = 1 << 5;
+const UART_SR_FRAME: u32 = 1 << 6;
+const UART_SR_PARE: u32 = 1 << 7;
+const UART_SR_TXEMPTY: u32 = 1 << 9;
+const UART_SR_ERRORS: u32 = UART_SR_OVRE | UART_SR_FRAME | UART_SR_PARE;
+
+// size of each ring, a power of two
+pub const RING_SIZE: usize = 512;
+// longest line `read_line` can return
+pub const LINE_SIZE: usize = 128;
+
+static RX: Ring = Ring::new();
+static TX: Ring = Ring::new();
+
+static OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static FRAMING_ERRORS: AtomicU32 = AtomicU32::new(0);
+static PARITY_ERRORS: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+// errors since the last read, as uart status bits plus DROPPED_FLAG
+static PENDING_ERRORS: AtomicU32 = AtomicU32::new(0);
+const DROPPED_FLAG: u32 = 1 << 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+	// received bytes were lost, in hardware or because the rx ring was full
+	Overrun,
+	Framing,
+	Parity,
+	// a line did not fit into LINE_SIZE bytes, it has been discarded
+	LineTooLong
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SerialStats {
+	// bytes the receiver lost because the interrupt came too late
+	pub overruns: u32,
+	pub framing_errors: u32,
+	pub parity_errors: u32,
+	// bytes dropped because the rx ring was full
+	pub dropped: u32
+}
+
+impl fmt::Display for SerialStats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "overruns {}, framing errors {}, parity errors {}, dropped {}",
+			self.overruns, self.framing_errors, self.parity_errors, self.dropped)
+	}
+}
+
+// to be called from the UART0 interrupt handler
+pub fn handle_interrupt() {
+	let mut sr = unsafe { ptr::read_volatile(UART_SR) };
+
+	if sr & UART_SR_ERRORS != 0 {
+		if sr & UART_SR_OVRE != 0 {
+			OVERRUNS.fetch_add(1, Ordering::Relaxed);
+		}
+		if sr & UART_SR_FRAME != 0 {
+			FRAMING_ERRORS.fetch_add(1, Ordering::Relaxed);
+		}
+		if sr & UART_SR_PARE != 0 {
+			PARITY_ERRORS.fetch_add(1, Ordering::Relaxed);
+		}
+		PENDING_ERRORS.fetch_or(sr & UART_SR_ERRORS, Ordering::Relaxed);
+		unsafe { ptr::write_volatile(UART_CR, UART_CR_RSTSTA); }
+	}
+
+	while sr & UART_SR_RXRDY != 0 {
+		let byte = unsafe { ptr::read_volatile(UART_RHR) } as u8;
+		if !RX.push(byte) {
+			DROPPED.fetch_add(1, Ordering::Relaxed);
+			PENDING_ERRORS.fetch_or(DROPPED_FLAG, Ordering::Relaxed);
+		}
+		sr = unsafe { ptr::read_volatile(UART_SR) };
+	}
+
+	let imr = unsafe { ptr::read_volatile(UART_IMR) };
+	if imr & UART_SR_TXRDY != 0 && sr & UART_SR_TXRDY != 0 {
+		match TX.pop() {
+			Some(byte) => unsafe { ptr::write_volatile(UART_THR, byte as u32) },
+			// nothing left, `write` enables the interrupt again
+			None => unsafe { ptr::write_volatile(UART_IDR, UART_SR_TXRDY) }
+		}
+	}
+}
+
+pub fn stats() -> SerialStats {
+	SerialStats {
+		overruns: OVERRUNS.load(Ordering::Relaxed),
+		framing_errors: FRAMING_ERRORS.load(Ordering::Relaxed),
+		parity_errors: PARITY_ERRORS.load(Ordering::Relaxed),
+		dropped: DROPPED.load(Ordering::Relaxed)
+	}
+}
+
+pub fn reset_stats() {
+	OVERRUNS.store(0, Ordering::Relaxed);
+	FRAMING_ERRORS.store(0, Ordering::Relaxed);
+	PARITY_ERRORS.store(0, Ordering::Relaxed);
+	DROPPED.store(0, Ordering::Relaxed);
+}
+
+// utxd0 and urxd0
+pub type Uart0Pins = (pioa::PA10<PeripheralCntr<PeriphA>>, pioa::PA9<PeripheralCntr<PeriphA>>);
+
+// move one byte from the tx ring to the transmitter if it is ready. the UART0
+// interrupt is kept out, so the ring still has a single consumer at a time.
+fn feed_transmitter() {
+	cortex_m::interrupt::free(|_| {
+		if unsafe { ptr::read_volatile(UART_SR) } & UART_SR_TXRDY != 0 {
+			if let Some(byte) = TX.pop() {
+				unsafe { ptr::write_volatile(UART_THR, byte as u32); }
+			}
+		}
+	});
+}
+
+pub struct BufferedSerial {
+	// owned so nothing else uses them, the registers are accessed directly
+	uart: UART0,
+	pins: Uart0Pins,
+	line: LineBuffer
+}
+
+impl BufferedSerial {
+	// `mck_hz` is the master clock the clocks were switched to, e.g.
+	// `ClockPreset::Max300MHz.mck_hz()`
+	pub fn new(uart: UART0, pins: Uart0Pins, baudrate: u32, mck_hz: u32, _pmc: &mut PMC) -> BufferedSerial {
+		assert!(baudrate != 0 && baudrate <= mck_hz / 16, "baud rate {} is out of range", baudrate);
+		let divider = core::cmp::min((mck_hz + baudrate * 8) / (baudrate * 16), 0xFFFF);
+		cortex_m::interrupt::free(|_| {
+			RX.clear();
+			TX.clear();
+			PENDING_ERRORS.store(0, Ordering::Relaxed);
+			unsafe {
+				ptr::write_volatile(PMC_PCER0, 1 << UART0_PID);
+				ptr::write_volatile(UART_CR, UART_CR_RSTRX | UART_CR_RSTTX | UART_CR_RXDIS | UART_CR_TXDIS);
+				ptr::write_volatile(UART_MR, UART_MR_PAR_NO);
+				ptr::write_volatile(UART_BRGR, divider);
+				ptr::write_volatile(UART_CR, UART_CR_RSTSTA | UART_CR_RXEN | UART_CR_TXEN);
+				ptr::write_volatile(UART_IER, UART_SR_RXRDY | UART_SR_ERRORS);
+				NVIC::unmask(Interrupt::UART0);
+			}
+		});
+		BufferedSerial {
+			uart: uart,
+			pins: pins,
+			line: LineBuffer::new()
+		}
+	}
+
+	// stop the interrupt and send what is left in the tx ring. the bytes are fed to
+	// the transmitter here, so this returns after at most RING_SIZE characters even
+	// with interrupts disabled.
+	pub fn free(self) -> (UART0, Uart0Pins) {
+		NVIC::mask(Interrupt::UART0);
+		unsafe { ptr::write_volatile(UART_IDR, UART_SR_RXRDY | UART_SR_TXRDY | UART_SR_ERRORS); }
+		while TX.len() != 0 || unsafe { ptr::read_volatile(UART_SR) } & UART_SR_TXEMPTY == 0 {
+			feed_transmitter();
+		}
+		(self.uart, self.pins)
+	}
+
+	// bytes waiting in the rx ring
+	pub fn available(&self) -> usize {
+		RX.len()
+	}
+
+	// bytes that can be written without blocking
+	pub fn tx_free(&self) -> usize {
+		RING_SIZE - TX.len()
+	}
+
+	pub fn stats(&self) -> SerialStats {
+		stats()
+	}
+
+	// the next complete line without its terminator (\r, \n or \r\n). bytes are
+	// collected across calls, so this can be polled from the main loop.
+	pub fn read_line(&mut self) -> nb::Result<&[u8], Error> {
+		self.line.read_line(&RX)
+	}
+
+	// write as much of `data` as fits into the tx ring, returns the bytes taken
+	pub fn write_bytes(&mut self, data: &[u8]) -> usize {
+		let n = data.iter().take_while(|b| TX.push(**b)).count();
+		if n > 0 {
+			unsafe { ptr::write_volatile(UART_IER, UART_SR_TXRDY); }
+		}
+		n
+	}
+
+	// errors since the last read, the most severe first
+	fn take_error(&mut self) -> Option<Error> {
+		let pending = PENDING_ERRORS.swap(0, Ordering::Relaxed);
+		if pending & (UART_SR_OVRE | DROPPED_FLAG) != 0 {
+			Some(Error::Overrun)
+		} else if pending & UART_SR_FRAME != 0 {
+			Some(Error::Framing)
+		} else if pending & UART_SR_PARE != 0 {
+			Some(Error::Parity)
+		} else {
+			None
+		}
+	}
+}
+
+impl embedded_hal::serial::Read<u8> for BufferedSerial {
+	type Error = Error;
+
+	// an error is reported once, the received bytes stay available
+	fn read(&mut self) -> nb::Result<u8, Error> {
+		if let Some(e) = self.take_error() {
+			return Err(nb::Error::Other(e));
+		}
+		RX.pop().ok_or(nb::Error::WouldBlock)
+	}
+}
+
+impl embedded_hal::serial::Write<u8> for BufferedSerial {
+	type Error = Error;
+
+	fn write(&mut self, byte: u8) -> nb::Result<(), Error> {
+		match self.write_bytes(&[byte]) {
+			1 => Ok(()),
+			_ => Err(nb::Error::WouldBlock)
+		}
+	}
+
+	// done once the last byte has left the transmitter
+	fn flush(&mut self) -> nb::Result<(), Error> {
+		if TX.len() == 0 && unsafe { ptr::read_volatile(UART_SR) } & UART_SR_TXEMPTY != 0 {
+			Ok(())
+		} else {
+			Err(nb::Error::WouldBlock)
+		}
+	}
+}
+
+// blocks while the tx ring is full. the ring is drained here as well, so this also
+// returns when the UART0 interrupt can not be served, e.g. in a panic handler or an
+// interrupt of higher priority, the wait is bounded by the baud rate.
+impl fmt::Write for BufferedSerial {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let mut data = s.as_bytes();
+		while !data.is_empty() {
+			let n = self.write_bytes(data);
+			if n == 0 {
+				feed_transmitter();
+			}
+			data = &data[n..];
+		}
+		Ok(())
+	}
+}
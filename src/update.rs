@@ -0,0 +1,119 @@
+// in-application firmware update
+//
+// a new image is staged in sdram (e.g. downloaded with tftp), verified and then
+// programmed into the slot that is currently not running. the payload is written
+// first and the header page last, so an interrupted update leaves the slot invalid
+// and the bootloader keeps starting the old image.
+
+use core::convert::Infallible;
+
+use crate::flash::{Flash, FlashError, PAGE_SIZE};
+use crate::image::{ImageError, ImageHeader, Slot, HEADER_SIZE, SLOT_SIZE};
+use crate::net::UdpSocket;
+use crate::tftp::{Progress, TftpClient, TftpError};
+use crate::crc::crc32;
+
+// `E` is the error of the download socket, `install` alone never downloads
+#[derive(Debug)]
+pub enum UpdateError<E = Infallible> {
+	Download(TftpError<E>),
+	Image(ImageError),
+	Flash(FlashError),
+	// data read back from flash does not match the staged image
+	Verify
+}
+
+impl UpdateError {
+	// the same error for a caller that also downloads
+	pub fn widen<E>(self) -> UpdateError<E> {
+		match self {
+			// `install` does not download
+			UpdateError::Download(_) => unreachable!(),
+			UpdateError::Image(e) => UpdateError::Image(e),
+			UpdateError::Flash(e) => UpdateError::Flash(e),
+			UpdateError::Verify => UpdateError::Verify
+		}
+	}
+}
+
+impl<E> From<ImageError> for UpdateError<E> {
+	fn from(e: ImageError) -> Self {
+		UpdateError::Image(e)
+	}
+}
+
+impl<E> From<FlashError> for UpdateError<E> {
+	fn from(e: FlashError) -> Self {
+		UpdateError::Flash(e)
+	}
+}
+
+// slot of the running application, derived from the vector table location
+pub fn active_slot() -> Option<Slot> {
+	let vtor = unsafe { (*cortex_m::peripheral::SCB::ptr()).vtor.read() } as usize;
+	Slot::containing(vtor)
+}
+
+// slot an update has to be built for and will be written to
+pub fn target_slot() -> Slot {
+	match active_slot() {
+		Some(slot) => slot.other(),
+		// running without bootloader from the start of the flash
+		None => Slot::B
+	}
+}
+
+pub struct Updater {
+	flash: Flash
+}
+
+impl Updater {
+	pub fn new(flash: Flash) -> Updater {
+		Updater {
+			flash: flash
+		}
+	}
+
+	pub fn free(self) -> Flash {
+		self.flash
+	}
+
+	// download an image into `staging` and install it into the inactive slot
+	pub fn update_from_tftp<S, F>(&mut self, client: &mut TftpClient<S>, filename: &str, staging: &mut [u8], progress: F) -> Result<(Slot, ImageHeader), UpdateError<S::Error>>
+		where S: UdpSocket, F: FnMut(Progress)
+	{
+		let download = client.get(filename, staging, None, progress).map_err(UpdateError::Download)?;
+		self.install(&staging[..download.len]).map_err(UpdateError::widen)
+	}
+
+	// verify and program a complete image (header + payload) into the inactive slot
+	pub fn install(&mut self, image: &[u8]) -> Result<(Slot, ImageHeader), UpdateError> {
+		let slot = target_slot();
+		let header = slot.check(image)?;
+		let payload = &image[HEADER_SIZE..HEADER_SIZE + header.length as usize];
+
+		let offset = slot.offset();
+		self.flash.erase(offset, SLOT_SIZE)?;
+		self.flash.write(offset + HEADER_SIZE, payload)?;
+		if crc32(self.flash.read(offset + HEADER_SIZE, payload.len())) != header.crc {
+			return Err(UpdateError::Verify);
+		}
+
+		let mut page = [0xFFu8; PAGE_SIZE];
+		header.write_to(&mut page);
+		self.flash.write_page(offset / PAGE_SIZE, &page)?;
+		if ImageHeader::parse(self.flash.read(offset, HEADER_SIZE)) != Ok(header) {
+			return Err(UpdateError::Verify);
+		}
+
+		Ok((slot, header))
+	}
+
+	// make an installed image unbootable again, e.g. after it failed a self test
+	pub fn invalidate(&mut self, slot: Slot) -> Result<(), FlashError> {
+		if Some(slot) == active_slot() {
+			return Err(FlashError::Locked);
+		}
+		self.flash.erase(slot.offset(), 8 * PAGE_SIZE)
+	}
+}
@@ -0,0 +1,452 @@
+// usb device firmware upgrade (dfu 1.1) state machine
+//
+// like `scsi` this module does not touch any hardware: `Dfu` follows the states of
+// the dfu specification for the class requests it is handed by the transport
+// (`dfu_class`) and moves the data through a `DfuTarget`. requests that are not
+// allowed in the current state stall and put the machine into the error state.
+//
+// downloads are processed when the host asks for the status after each block, so the
+// target may take a while to program it (the status request is answered late).
+
+// largest download or upload block, limited by the control buffer of usb-device
+pub const MAX_TRANSFER_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DfuState {
+	AppIdle = 0,
+	AppDetach = 1,
+	Idle = 2,
+	DownloadSync = 3,
+	DownloadBusy = 4,
+	DownloadIdle = 5,
+	ManifestSync = 6,
+	Manifest = 7,
+	ManifestWaitReset = 8,
+	UploadIdle = 9,
+	Error = 10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DfuStatus {
+	Ok = 0x00,
+	// the file is not targeted for this device
+	ErrTarget = 0x01,
+	// the file fails a vendor specific verification
+	ErrFile = 0x02,
+	ErrWrite = 0x03,
+	ErrErase = 0x04,
+	ErrCheckErased = 0x05,
+	ErrProg = 0x06,
+	ErrVerify = 0x07,
+	// the download does not fit the target
+	ErrAddress = 0x08,
+	// the download ended before the image was complete
+	ErrNotDone = 0x09,
+	ErrFirmware = 0x0A,
+	ErrVendor = 0x0B,
+	ErrUsbReset = 0x0C,
+	ErrPowerOnReset = 0x0D,
+	ErrUnknown = 0x0E,
+	// a request was not allowed in the current state
+	ErrStalledPacket = 0x0F
+}
+
+// where downloaded data goes and uploaded data comes from
+pub trait DfuTarget {
+	// largest download in bytes
+	fn capacity(&self) -> usize;
+
+	// a download starts, e.g. invalidate the current contents
+	fn begin(&mut self) -> Result<(), DfuStatus>;
+
+	// blocks arrive in order, `offset` is the length of everything received before
+	fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), DfuStatus>;
+
+	// the download of `len` bytes is complete, check and activate it
+	fn manifest(&mut self, len: usize) -> Result<(), DfuStatus>;
+
+	// copy the data at `offset` for an upload, returns fewer bytes than `buf` holds at
+	// the end of the data
+	fn read(&mut self, offset: usize, buf: &mut [u8]) -> usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuMode {
+	// the application runs, the host can only request a detach to dfu mode
+	Runtime,
+	Dfu
+}
+
+// the request is not allowed in the current state, the transport stalls it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stalled;
+
+pub struct Dfu<T: DfuTarget> {
+	target: T,
+	// mode after a usb reset outside of a detach or manifestation
+	initial_mode: DfuMode,
+	state: DfuState,
+	status: DfuStatus,
+	// bytes downloaded or uploaded so far
+	offset: usize,
+	block: [u8; MAX_TRANSFER_SIZE],
+	block_len: usize,
+	reset_requested: bool
+}
+
+impl<T: DfuTarget> Dfu<T> {
+	pub fn new(target: T, mode: DfuMode) -> Dfu<T> {
+		Dfu {
+			target: target,
+			initial_mode: mode,
+			state: match mode {
+				DfuMode::Runtime => DfuState::AppIdle,
+				DfuMode::Dfu => DfuState::Idle
+			},
+			status: DfuStatus::Ok,
+			offset: 0,
+			block: [0; MAX_TRANSFER_SIZE],
+			block_len: 0,
+			reset_requested: false
+		}
+	}
+
+	pub fn target(&self) -> &T {
+		&self.target
+	}
+
+	pub fn target_mut(&mut self) -> &mut T {
+		&mut self.target
+	}
+
+	pub fn state(&self) -> DfuState {
+		self.state
+	}
+
+	pub fn status(&self) -> DfuStatus {
+		self.status
+	}
+
+	pub fn mode(&self) -> DfuMode {
+		match self.state {
+			DfuState::AppIdle | DfuState::AppDetach => DfuMode::Runtime,
+			_ => DfuMode::Dfu
+		}
+	}
+
+	// a new image has been manifested and the host has reset the bus, the
+	// application should reset the chip to start it
+	pub fn reset_requested(&self) -> bool {
+		self.reset_requested
+	}
+
+	fn stall(&mut self) -> Stalled {
+		// the runtime mode has no error state
+		if self.mode() == DfuMode::Dfu {
+			self.fail(DfuStatus::ErrStalledPacket);
+		}
+		Stalled
+	}
+
+	fn fail(&mut self, status: DfuStatus) {
+		self.state = DfuState::Error;
+		self.status = status;
+	}
+
+	pub fn detach(&mut self) -> Result<(), Stalled> {
+		match self.state {
+			DfuState::AppIdle => {
+				self.state = DfuState::AppDetach;
+				Ok(())
+			},
+			_ => Err(self.stall())
+		}
+	}
+
+	// a download block, an empty one ends the download
+	pub fn download(&mut self, data: &[u8]) -> Result<(), Stalled> {
+		if data.len() > MAX_TRANSFER_SIZE {
+			return Err(self.stall());
+		}
+		match self.state {
+			DfuState::Idle if !data.is_empty() => {
+				self.offset = 0;
+				if let Err(status) = self.target.begin() {
+					self.fail(status);
+					return Ok(());
+				}
+			},
+			DfuState::DownloadIdle => {},
+			_ => return Err(self.stall())
+		}
+
+		if data.is_empty() {
+			self.state = DfuState::ManifestSync;
+		} else {
+			self.block[..data.len()].copy_from_slice(data);
+			self.block_len = data.len();
+			self.state = DfuState::DownloadSync;
+		}
+		Ok(())
+	}
+
+	// fill `buf` with the next upload block, a short block ends the upload
+	pub fn upload(&mut self, buf: &mut [u8]) -> Result<usize, Stalled> {
+		match self.state {
+			DfuState::Idle => self.offset = 0,
+			DfuState::UploadIdle => {},
+			_ => return Err(self.stall())
+		}
+		let len = self.target.read(self.offset, buf);
+		self.offset += len;
+		self.state = if len < buf.len() { DfuState::Idle } else { DfuState::UploadIdle };
+		Ok(len)
+	}
+
+	// the status response: status, poll timeout (ms, 24 bit), state, string index.
+	// pending download blocks and the manifestation are processed here.
+	pub fn get_status(&mut self) -> [u8; 6] {
+		match self.state {
+			DfuState::DownloadSync => {
+				let len = self.block_len;
+				let result = if self.offset + len > self.target.capacity() {
+					Err(DfuStatus::ErrAddress)
+				} else {
+					self.target.write(self.offset, &self.block[..len])
+				};
+				match result {
+					Ok(()) => {
+						self.offset += len;
+						self.state = DfuState::DownloadIdle;
+					},
+					Err(status) => self.fail(status)
+				}
+			},
+			DfuState::ManifestSync => match self.target.manifest(self.offset) {
+				Ok(()) => self.state = DfuState::Manifest,
+				Err(status) => self.fail(status)
+			},
+			_ => {}
+		}
+
+		let response = [self.status as u8, 0, 0, 0, self.state as u8, 0];
+		// not manifestation tolerant, the host has to reset the device afterwards
+		if self.state == DfuState::Manifest {
+			self.state = DfuState::ManifestWaitReset;
+		}
+		response
+	}
+
+	pub fn get_state(&self) -> u8 {
+		self.state as u8
+	}
+
+	pub fn clear_status(&mut self) -> Result<(), Stalled> {
+		match self.state {
+			DfuState::Error => {
+				self.state = DfuState::Idle;
+				self.status = DfuStatus::Ok;
+				Ok(())
+			},
+			_ => Err(self.stall())
+		}
+	}
+
+	pub fn abort(&mut self) -> Result<(), Stalled> {
+		match self.state {
+			DfuState::Idle | DfuState::DownloadIdle | DfuState::UploadIdle => {
+				self.state = DfuState::Idle;
+				Ok(())
+			},
+			_ => Err(self.stall())
+		}
+	}
+
+	// usb bus reset: enter dfu mode after a detach, otherwise go back to the mode the
+	// device started in
+	pub fn usb_reset(&mut self) {
+		match self.state {
+			DfuState::AppDetach => self.state = DfuState::Idle,
+			DfuState::ManifestWaitReset => self.reset_requested = true,
+			_ => {
+				self.state = match self.initial_mode {
+					DfuMode::Runtime => DfuState::AppIdle,
+					DfuMode::Dfu => DfuState::Idle
+				};
+				self.status = DfuStatus::Ok;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct Memory {
+		data: Vec<u8>,
+		capacity: usize,
+		begun: usize,
+		manifested: Option<usize>
+	}
+
+	impl Memory {
+		fn new(capacity: usize) -> Memory {
+			Memory {
+				data: Vec::new(),
+				capacity: capacity,
+				begun: 0,
+				manifested: None
+			}
+		}
+	}
+
+	impl DfuTarget for Memory {
+		fn capacity(&self) -> usize {
+			self.capacity
+		}
+
+		fn begin(&mut self) -> Result<(), DfuStatus> {
+			self.begun += 1;
+			self.data.clear();
+			Ok(())
+		}
+
+		fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), DfuStatus> {
+			assert_eq!(offset, self.data.len());
+			self.data.extend_from_slice(data);
+			Ok(())
+		}
+
+		fn manifest(&mut self, len: usize) -> Result<(), DfuStatus> {
+			self.manifested = Some(len);
+			Ok(())
+		}
+
+		fn read(&mut self, offset: usize, buf: &mut [u8]) -> usize {
+			let rest = &self.data[core::cmp::min(offset, self.data.len())..];
+			let len = core::cmp::min(rest.len(), buf.len());
+			buf[..len].copy_from_slice(&rest[..len]);
+			len
+		}
+	}
+
+	fn status(status: DfuStatus, state: DfuState) -> [u8; 6] {
+		[status as u8, 0, 0, 0, state as u8, 0]
+	}
+
+	#[test]
+	fn download_and_manifest() {
+		let mut dfu = Dfu::new(Memory::new(1024), DfuMode::Dfu);
+		assert_eq!(dfu.download(&[1; MAX_TRANSFER_SIZE]), Ok(()));
+		assert_eq!(dfu.state(), DfuState::DownloadSync);
+		// blocks are written when the host asks for the status
+		assert!(dfu.target().data.is_empty());
+		assert_eq!(dfu.get_status(), status(DfuStatus::Ok, DfuState::DownloadIdle));
+		assert_eq!(dfu.download(&[2; 100]), Ok(()));
+		assert_eq!(dfu.get_status(), status(DfuStatus::Ok, DfuState::DownloadIdle));
+		assert_eq!(dfu.target().data.len(), MAX_TRANSFER_SIZE + 100);
+		assert_eq!(dfu.target().data[MAX_TRANSFER_SIZE], 2);
+
+		assert_eq!(dfu.download(&[]), Ok(()));
+		assert_eq!(dfu.state(), DfuState::ManifestSync);
+		assert_eq!(dfu.get_status(), status(DfuStatus::Ok, DfuState::Manifest));
+		assert_eq!(dfu.target().manifested, Some(MAX_TRANSFER_SIZE + 100));
+		assert_eq!(dfu.target().begun, 1);
+		assert_eq!(dfu.get_status(), status(DfuStatus::Ok, DfuState::ManifestWaitReset));
+		assert!(!dfu.reset_requested());
+
+		dfu.usb_reset();
+		assert!(dfu.reset_requested());
+		assert_eq!(dfu.state(), DfuState::ManifestWaitReset);
+	}
+
+	#[test]
+	fn upload() {
+		let mut dfu = Dfu::new(Memory::new(1024), DfuMode::Dfu);
+		dfu.target_mut().data = (0..300).map(|i| i as u8).collect();
+		let mut uploaded = Vec::new();
+		let mut buf = [0; MAX_TRANSFER_SIZE];
+		assert_eq!(dfu.upload(&mut buf), Ok(MAX_TRANSFER_SIZE));
+		assert_eq!(dfu.state(), DfuState::UploadIdle);
+		uploaded.extend_from_slice(&buf);
+		// the short block ends the upload
+		assert_eq!(dfu.upload(&mut buf), Ok(300 - MAX_TRANSFER_SIZE));
+		assert_eq!(dfu.state(), DfuState::Idle);
+		uploaded.extend_from_slice(&buf[..300 - MAX_TRANSFER_SIZE]);
+		assert_eq!(uploaded, dfu.target().data);
+
+		// the next upload starts over
+		assert_eq!(dfu.upload(&mut buf[..10]), Ok(10));
+		assert_eq!(buf[..10], dfu.target().data[..10]);
+		assert_eq!(dfu.abort(), Ok(()));
+		assert_eq!(dfu.state(), DfuState::Idle);
+	}
+
+	#[test]
+	fn illegal_requests_stall() {
+		let mut dfu = Dfu::new(Memory::new(1024), DfuMode::Dfu);
+		// an empty download without any data before it
+		assert_eq!(dfu.download(&[]), Err(Stalled));
+		assert_eq!(dfu.state(), DfuState::Error);
+		assert_eq!(dfu.get_status(), status(DfuStatus::ErrStalledPacket, DfuState::Error));
+		// nothing but clearing the status is allowed in the error state
+		assert_eq!(dfu.download(&[1]), Err(Stalled));
+		assert_eq!(dfu.abort(), Err(Stalled));
+		assert_eq!(dfu.clear_status(), Ok(()));
+		assert_eq!(dfu.get_status(), status(DfuStatus::Ok, DfuState::Idle));
+		assert_eq!(dfu.clear_status(), Err(Stalled));
+		assert_eq!(dfu.clear_status(), Ok(()));
+
+		assert_eq!(dfu.download(&[1; MAX_TRANSFER_SIZE + 1]), Err(Stalled));
+		assert_eq!(dfu.clear_status(), Ok(()));
+		assert_eq!(dfu.download(&[1]), Ok(()));
+		assert_eq!(dfu.upload(&mut [0; 16]), Err(Stalled));
+		assert_eq!(dfu.state(), DfuState::Error);
+		assert_eq!(dfu.clear_status(), Ok(()));
+		assert_eq!(dfu.detach(), Err(Stalled));
+		assert_eq!(dfu.status(), DfuStatus::ErrStalledPacket);
+	}
+
+	#[test]
+	fn detach() {
+		let mut dfu = Dfu::new(Memory::new(1024), DfuMode::Runtime);
+		assert_eq!(dfu.mode(), DfuMode::Runtime);
+		// the runtime mode has no error state
+		assert_eq!(dfu.download(&[1]), Err(Stalled));
+		assert_eq!(dfu.state(), DfuState::AppIdle);
+
+		assert_eq!(dfu.detach(), Ok(()));
+		assert_eq!(dfu.state(), DfuState::AppDetach);
+		dfu.usb_reset();
+		assert_eq!(dfu.state(), DfuState::Idle);
+		assert_eq!(dfu.mode(), DfuMode::Dfu);
+		assert!(!dfu.reset_requested());
+
+		// without a new image the next reset goes back to the application
+		dfu.usb_reset();
+		assert_eq!(dfu.state(), DfuState::AppIdle);
+	}
+
+	#[test]
+	fn download_larger_than_the_target() {
+		let mut dfu = Dfu::new(Memory::new(300), DfuMode::Dfu);
+		assert_eq!(dfu.download(&[1; MAX_TRANSFER_SIZE]), Ok(()));
+		assert_eq!(dfu.get_status(), status(DfuStatus::Ok, DfuState::DownloadIdle));
+		assert_eq!(dfu.download(&[2; 100]), Ok(()));
+		assert_eq!(dfu.get_status(), status(DfuStatus::ErrAddress, DfuState::Error));
+		assert_eq!(dfu.target().data.len(), MAX_TRANSFER_SIZE);
+		assert_eq!(dfu.target().manifested, None);
+
+		// a new download starts from the beginning
+		assert_eq!(dfu.clear_status(), Ok(()));
+		assert_eq!(dfu.download(&[3; 300]), Err(Stalled));
+		assert_eq!(dfu.clear_status(), Ok(()));
+		assert_eq!(dfu.download(&[3; 200]), Ok(()));
+		assert_eq!(dfu.get_status(), status(DfuStatus::Ok, DfuState::DownloadIdle));
+		assert_eq!(dfu.target().begun, 2);
+		assert_eq!(dfu.target().data, vec![3; 200]);
+	}
+}
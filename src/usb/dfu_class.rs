@@ -0,0 +1,246 @@
+// usb device firmware upgrade class, flashing with `dfu-util`
+//
+// `DfuClass` passes the dfu class requests on the control endpoint to the `Dfu`
+// state machine, it has no endpoints of its own. in runtime mode the interface only
+// accepts a detach, the host then resets the bus and the device enumerates again in
+// dfu mode (the configuration descriptor follows the current mode). the device is not
+// manifestation tolerant: after a download the host resets the bus once more and
+// `reset_requested` tells the application to reset the chip into the new image.
+//
+// `SlotTarget` writes the download into the inactive application slot. the file is
+// an image as for the tftp update (header page + payload), the payload is programmed
+// as it arrives and the header page is held back until the manifestation has checked
+// the crc, so an interrupted download leaves the slot invalid.
+
+use usb_device::class_prelude::*;
+
+use super::dfu::{Dfu, DfuMode, DfuStatus, DfuTarget, MAX_TRANSFER_SIZE};
+use crate::crc::crc32;
+use crate::flash::{Flash, PAGE_SIZE};
+use crate::image::{ImageHeader, Slot, HEADER_SIZE, SLOT_SIZE};
+use crate::update::target_slot;
+
+const CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_RUNTIME: u8 = 0x01;
+const PROTOCOL_DFU: u8 = 0x02;
+
+const DESCRIPTOR_DFU_FUNCTIONAL: u8 = 0x21;
+const ATTRIBUTE_CAN_DOWNLOAD: u8 = 0x01;
+const ATTRIBUTE_CAN_UPLOAD: u8 = 0x02;
+// ms the device waits for the bus reset after a detach
+const DETACH_TIMEOUT: u16 = 1000;
+const DFU_VERSION: u16 = 0x0110;
+
+const REQUEST_DETACH: u8 = 0;
+const REQUEST_DOWNLOAD: u8 = 1;
+const REQUEST_UPLOAD: u8 = 2;
+const REQUEST_GET_STATUS: u8 = 3;
+const REQUEST_CLEAR_STATUS: u8 = 4;
+const REQUEST_GET_STATE: u8 = 5;
+const REQUEST_ABORT: u8 = 6;
+
+pub struct DfuClass<T: DfuTarget> {
+	interface: InterfaceNumber,
+	dfu: Dfu<T>
+}
+
+impl<T: DfuTarget> DfuClass<T> {
+	pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>, target: T, mode: DfuMode) -> DfuClass<T> {
+		DfuClass {
+			interface: alloc.interface(),
+			dfu: Dfu::new(target, mode)
+		}
+	}
+
+	pub fn dfu(&self) -> &Dfu<T> {
+		&self.dfu
+	}
+
+	pub fn dfu_mut(&mut self) -> &mut Dfu<T> {
+		&mut self.dfu
+	}
+
+	// a new image has been manifested and the host has reset the bus
+	pub fn reset_requested(&self) -> bool {
+		self.dfu.reset_requested()
+	}
+}
+
+impl<B: UsbBus, T: DfuTarget> UsbClass<B> for DfuClass<T> {
+	fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+		let protocol = match self.dfu.mode() {
+			DfuMode::Runtime => PROTOCOL_RUNTIME,
+			DfuMode::Dfu => PROTOCOL_DFU
+		};
+		writer.interface(self.interface, CLASS_APPLICATION_SPECIFIC, SUBCLASS_DFU, protocol)?;
+		let timeout = DETACH_TIMEOUT.to_le_bytes();
+		let transfer = (MAX_TRANSFER_SIZE as u16).to_le_bytes();
+		let version = DFU_VERSION.to_le_bytes();
+		writer.write(DESCRIPTOR_DFU_FUNCTIONAL, &[
+			ATTRIBUTE_CAN_DOWNLOAD | ATTRIBUTE_CAN_UPLOAD,
+			timeout[0], timeout[1],
+			transfer[0], transfer[1],
+			version[0], version[1]
+		])
+	}
+
+	fn reset(&mut self) {
+		self.dfu.usb_reset();
+	}
+
+	fn control_in(&mut self, xfer: ControlIn<B>) {
+		let req = *xfer.request();
+		if req.request_type != control::RequestType::Class
+			|| req.recipient != control::Recipient::Interface
+			|| req.index != u8::from(self.interface) as u16 {
+			return;
+		}
+		match req.request {
+			REQUEST_UPLOAD => {
+				let mut buf = [0u8; MAX_TRANSFER_SIZE];
+				let len = core::cmp::min(req.length as usize, MAX_TRANSFER_SIZE);
+				match self.dfu.upload(&mut buf[..len]) {
+					Ok(n) => xfer.accept_with(&buf[..n]).ok(),
+					Err(_) => xfer.reject().ok()
+				}
+			},
+			REQUEST_GET_STATUS => xfer.accept_with(&self.dfu.get_status()).ok(),
+			REQUEST_GET_STATE => xfer.accept_with(&[self.dfu.get_state()]).ok(),
+			_ => xfer.reject().ok()
+		};
+	}
+
+	fn control_out(&mut self, xfer: ControlOut<B>) {
+		let req = *xfer.request();
+		if req.request_type != control::RequestType::Class
+			|| req.recipient != control::Recipient::Interface
+			|| req.index != u8::from(self.interface) as u16 {
+			return;
+		}
+		let result = match req.request {
+			REQUEST_DETACH => self.dfu.detach(),
+			REQUEST_DOWNLOAD => self.dfu.download(xfer.data()),
+			REQUEST_CLEAR_STATUS => self.dfu.clear_status(),
+			REQUEST_ABORT => self.dfu.abort(),
+			_ => {
+				xfer.reject().ok();
+				return;
+			}
+		};
+		match result {
+			Ok(()) => xfer.accept().ok(),
+			Err(_) => xfer.reject().ok()
+		};
+	}
+}
+
+// the inactive application slot as download target, see the top of the file.
+// uploads read back the image currently in that slot.
+pub struct SlotTarget<'a> {
+	flash: &'a mut Flash,
+	slot: Slot,
+	header: [u8; HEADER_SIZE],
+	// the page being received
+	page: [u8; PAGE_SIZE]
+}
+
+impl<'a> SlotTarget<'a> {
+	pub fn new(flash: &'a mut Flash) -> SlotTarget<'a> {
+		SlotTarget {
+			flash: flash,
+			slot: target_slot(),
+			header: [0xFF; HEADER_SIZE],
+			page: [0xFF; PAGE_SIZE]
+		}
+	}
+
+	pub fn slot(&self) -> Slot {
+		self.slot
+	}
+
+	// program the page with the index `index` of the image, the first one (the header)
+	// is kept until the manifestation. every 8th page starts a new erase unit.
+	fn store_page(&mut self, index: usize) -> Result<(), DfuStatus> {
+		if index == 0 {
+			self.header.copy_from_slice(&self.page);
+		} else {
+			let page = self.slot.offset() / PAGE_SIZE + index;
+			if index % 8 == 0 {
+				self.flash.erase_pages(page, 8).map_err(|_| DfuStatus::ErrErase)?;
+			}
+			self.flash.write_page(page, &self.page).map_err(|_| DfuStatus::ErrProg)?;
+		}
+		self.page = [0xFF; PAGE_SIZE];
+		Ok(())
+	}
+}
+
+impl<'a> DfuTarget for SlotTarget<'a> {
+	fn capacity(&self) -> usize {
+		SLOT_SIZE
+	}
+
+	// erasing the first unit removes the header of the old image
+	fn begin(&mut self) -> Result<(), DfuStatus> {
+		self.header = [0xFF; HEADER_SIZE];
+		self.page = [0xFF; PAGE_SIZE];
+		self.flash.erase_pages(self.slot.offset() / PAGE_SIZE, 8).map_err(|_| DfuStatus::ErrErase)
+	}
+
+	fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), DfuStatus> {
+		let mut offset = offset;
+		let mut data = data;
+		while !data.is_empty() {
+			let start = offset % PAGE_SIZE;
+			let n = core::cmp::min(PAGE_SIZE - start, data.len());
+			self.page[start..start + n].copy_from_slice(&data[..n]);
+			offset += n;
+			data = &data[n..];
+			if offset % PAGE_SIZE == 0 {
+				self.store_page(offset / PAGE_SIZE - 1)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn manifest(&mut self, len: usize) -> Result<(), DfuStatus> {
+		if len % PAGE_SIZE != 0 {
+			self.store_page(len / PAGE_SIZE)?;
+		}
+		if len < HEADER_SIZE {
+			return Err(DfuStatus::ErrNotDone);
+		}
+
+		let header = ImageHeader::parse(&self.header).map_err(|_| DfuStatus::ErrFile)?;
+		let end = HEADER_SIZE + header.length as usize;
+		if header.entry != self.slot.entry() || end > SLOT_SIZE {
+			return Err(DfuStatus::ErrTarget);
+		}
+		if len < end {
+			return Err(DfuStatus::ErrNotDone);
+		}
+		let offset = self.slot.offset();
+		if crc32(self.flash.read(offset + HEADER_SIZE, header.length as usize)) != header.crc {
+			return Err(DfuStatus::ErrVerify);
+		}
+
+		self.flash.write_page(offset / PAGE_SIZE, &self.header).map_err(|_| DfuStatus::ErrProg)?;
+		if ImageHeader::parse(self.flash.read(offset, HEADER_SIZE)) != Ok(header) {
+			return Err(DfuStatus::ErrVerify);
+		}
+		Ok(())
+	}
+
+	fn read(&mut self, offset: usize, buf: &mut [u8]) -> usize {
+		// the payload crc has been checked when the image was installed
+		let contents = self.slot.contents();
+		let len = match ImageHeader::parse(contents) {
+			Ok(header) => core::cmp::min(HEADER_SIZE + header.length as usize, SLOT_SIZE),
+			Err(_) => 0
+		};
+		let n = core::cmp::min(buf.len(), len.saturating_sub(offset));
+		buf[..n].copy_from_slice(&contents[offset..offset + n]);
+		n
+	}
+}
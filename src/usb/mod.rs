@@ -0,0 +1,457 @@
+// usb device support for the high speed usb port (usbhs) through usb-device
+//
+// `UsbBus` implements the usb-device bus for the usbhs controller in device mode.
+// the device runs at full speed: usb-device only produces full speed descriptors
+// (no device qualifier, 64 byte bulk packets), which high speed hosts would reject.
+// the clock comes from the upll, so the clock settings need `usb` enabled.
+//
+// usb-device is polled, `UsbDevice::poll` can be called from the main loop or from
+// the USBHS interrupt. an endpoint with received data raises its interrupt only
+// until the data has been reported, it is enabled again when the class reads it,
+// so a class that can not take data right now does not keep the interrupt pending.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use atsame70q21::{PMC, USBHS};
+use usb_device::bus::PollResult;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+pub mod serial;
+pub mod scsi;
+pub mod msc;
+pub mod dfu;
+pub mod dfu_class;
+
+pub use self::serial::UsbSerial;
+pub use self::msc::MassStorage;
+pub use self::dfu_class::DfuClass;
+
+// pid.codes test vid and pids, replace them for anything leaving the lab
+pub const USB_VID: u16 = 0x1209;
+pub const USB_PID_SERIAL: u16 = 0x0001;
+pub const USB_PID_MASS_STORAGE: u16 = 0x0002;
+pub const USB_PID_DFU: u16 = 0x0003;
+
+pub const ENDPOINT_COUNT: usize = 10;
+const CONTROL_MAX_PACKET_SIZE: u16 = 64;
+const MAX_PACKET_SIZE: u16 = 1024;
+// dual port ram holding the endpoint banks
+const DPRAM_SIZE: usize = 4096;
+
+const PMC_BASE: usize = 0x400E_0600;
+const PMC_SCER: *mut u32 = PMC_BASE as *mut u32;
+const PMC_PCER1: *mut u32 = (PMC_BASE + 0x100) as *mut u32;
+const PMC_USB: *mut u32 = (PMC_BASE + 0x38) as *mut u32;
+const PMC_SR: *const u32 = (PMC_BASE + 0x68) as *const u32;
+
+const PMC_SCER_USBCLK: u32 = 1 << 5;
+const PMC_USB_USBS_UPLL: u32 = 1 << 0;
+// 480 MHz / 10 = 48 MHz
+const PMC_USB_USBDIV: u32 = 9 << 8;
+const PMC_SR_LOCKU: u32 = 1 << 6;
+const USBHS_PID: u32 = 34;
+
+const USBHS_BASE: usize = 0x4003_8000;
+const DEVCTRL: usize = 0x000;
+const DEVISR: usize = 0x004;
+const DEVICR: usize = 0x008;
+const DEVIMR: usize = 0x010;
+const DEVIDR: usize = 0x014;
+const DEVIER: usize = 0x018;
+const DEVEPT: usize = 0x01C;
+const DEVEPTCFG: usize = 0x100;
+const DEVEPTISR: usize = 0x130;
+const DEVEPTICR: usize = 0x160;
+const DEVEPTIMR: usize = 0x1C0;
+const DEVEPTIER: usize = 0x1F0;
+const DEVEPTIDR: usize = 0x220;
+const CTRL: usize = 0x800;
+const SR: usize = 0x804;
+
+const DEVCTRL_UADD_MASK: u32 = 0x7F;
+const DEVCTRL_ADDEN: u32 = 1 << 7;
+const DEVCTRL_DETACH: u32 = 1 << 8;
+const DEVCTRL_SPDCONF_MASK: u32 = 0x3 << 10;
+const DEVCTRL_SPDCONF_FORCED_FS: u32 = 0x3 << 10;
+
+// DEVISR and the matching bits of the other device interrupt registers
+const DEV_SUSP: u32 = 1 << 0;
+const DEV_EORST: u32 = 1 << 3;
+const DEV_WAKEUP: u32 = 1 << 4;
+const DEV_PEP_SHIFT: u32 = 12;
+
+const DEVEPTCFG_ALLOC: u32 = 1 << 1;
+const DEVEPTCFG_EPBK_SHIFT: u32 = 2;
+const DEVEPTCFG_EPSIZE_SHIFT: u32 = 4;
+const DEVEPTCFG_EPDIR_IN: u32 = 1 << 8;
+const DEVEPTCFG_EPTYPE_SHIFT: u32 = 11;
+const DEVEPT_EPRST_SHIFT: u32 = 16;
+
+// DEVEPTISR and the matching bits of the other endpoint interrupt registers
+const EPT_TXINI: u32 = 1 << 0;
+const EPT_RXOUTI: u32 = 1 << 1;
+const EPT_RXSTPI: u32 = 1 << 2;
+const EPT_FIFOCON: u32 = 1 << 14;
+const EPT_RSTDT: u32 = 1 << 18;
+const EPT_STALLRQ: u32 = 1 << 19;
+const EPTISR_BYCT_SHIFT: u32 = 20;
+const EPTISR_BYCT_MASK: u32 = 0x7FF;
+
+const CTRL_VBUSHWC: u32 = 1 << 8;
+const CTRL_USBE: u32 = 1 << 15;
+const CTRL_UIMOD_DEVICE: u32 = 1 << 25;
+const SR_CLKUSABLE: u32 = 1 << 14;
+
+// each endpoint's banks are accessed through a 32 KiB window
+const FIFO_BASE: usize = 0xA010_0000;
+const FIFO_WINDOW: usize = 0x8000;
+
+fn reg(offset: usize) -> *mut u32 {
+	(USBHS_BASE + offset) as *mut u32
+}
+
+fn read_reg(offset: usize) -> u32 {
+	unsafe { ptr::read_volatile(reg(offset)) }
+}
+
+fn write_reg(offset: usize, value: u32) {
+	unsafe { ptr::write_volatile(reg(offset), value) }
+}
+
+fn modify_reg<F: FnOnce(u32) -> u32>(offset: usize, f: F) {
+	write_reg(offset, f(read_reg(offset)))
+}
+
+fn fifo(index: usize) -> *mut u8 {
+	(FIFO_BASE + index * FIFO_WINDOW) as *mut u8
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Endpoint {
+	ep_type: EndpointType,
+	dir: UsbDirection,
+	// rounded up to the next size the controller supports
+	size: u16,
+	banks: u8
+}
+
+impl Endpoint {
+	fn config(&self) -> u32 {
+		// 8 bytes is size 0, the size is a power of two
+		let epsize = self.size.trailing_zeros() - 3;
+		let eptype = match self.ep_type {
+			EndpointType::Control => 0,
+			EndpointType::Isochronous => 1,
+			EndpointType::Bulk => 2,
+			EndpointType::Interrupt => 3
+		};
+		let dir = match self.dir {
+			UsbDirection::In if self.ep_type != EndpointType::Control => DEVEPTCFG_EPDIR_IN,
+			_ => 0
+		};
+		DEVEPTCFG_ALLOC
+			| ((self.banks as u32 - 1) << DEVEPTCFG_EPBK_SHIFT)
+			| (epsize << DEVEPTCFG_EPSIZE_SHIFT)
+			| dir
+			| (eptype << DEVEPTCFG_EPTYPE_SHIFT)
+	}
+}
+
+pub struct UsbBus {
+	_usbhs: USBHS,
+	endpoints: [Option<Endpoint>; ENDPOINT_COUNT],
+	// in endpoints with a packet written but not yet reported as sent
+	in_flight: AtomicU16
+}
+
+// the registers are only accessed through `&self` by the usb device, which is used
+// from one context (the main loop or the usb interrupt), the shared state is atomic
+unsafe impl Sync for UsbBus {}
+
+impl UsbBus {
+	// the upll has to be running, see `ClockSettings::usb`
+	pub fn new(usbhs: USBHS, _pmc: &mut PMC) -> UsbBus {
+		unsafe {
+			assert!(ptr::read_volatile(PMC_SR) & PMC_SR_LOCKU != 0, "usb needs the upll, enable usb in the clock settings");
+			ptr::write_volatile(PMC_USB, PMC_USB_USBS_UPLL | PMC_USB_USBDIV);
+			ptr::write_volatile(PMC_SCER, PMC_SCER_USBCLK);
+			ptr::write_volatile(PMC_PCER1, 1 << (USBHS_PID - 32));
+		}
+		UsbBus {
+			_usbhs: usbhs,
+			endpoints: [None; ENDPOINT_COUNT],
+			in_flight: AtomicU16::new(0)
+		}
+	}
+
+	fn endpoint(&self, ep_addr: EndpointAddress) -> Result<Endpoint> {
+		let ep = self.endpoints.get(ep_addr.index())
+			.and_then(|ep| *ep)
+			.ok_or(UsbError::InvalidEndpoint)?;
+		if ep.ep_type != EndpointType::Control && ep.dir != ep_addr.direction() {
+			return Err(UsbError::InvalidEndpoint);
+		}
+		Ok(ep)
+	}
+
+	fn dpram_used(&self) -> usize {
+		self.endpoints.iter()
+			.flatten()
+			.map(|ep| ep.size as usize * ep.banks as usize)
+			.sum()
+	}
+
+	// (re)configure all allocated endpoints, they are lost on a bus reset. the
+	// controller places the banks in the order of the endpoint numbers.
+	fn configure_endpoints(&self) {
+		for (i, ep) in self.endpoints.iter().enumerate() {
+			let ep = match ep {
+				Some(ep) => ep,
+				None => continue
+			};
+			modify_reg(DEVEPT, |v| v | (1 << (i as u32 + DEVEPT_EPRST_SHIFT)));
+			modify_reg(DEVEPT, |v| (v & !(1 << (i as u32 + DEVEPT_EPRST_SHIFT))) | (1 << i));
+			write_reg(DEVEPTCFG + 4 * i, ep.config());
+
+			let irq = match (ep.ep_type, ep.dir) {
+				(EndpointType::Control, _) => EPT_RXSTPI | EPT_RXOUTI,
+				(_, UsbDirection::Out) => EPT_RXOUTI,
+				(_, UsbDirection::In) => 0
+			};
+			write_reg(DEVEPTIER + 4 * i, irq);
+			write_reg(DEVIER, 1 << (DEV_PEP_SHIFT + i as u32));
+		}
+		self.in_flight.store(0, Ordering::SeqCst);
+	}
+}
+
+impl usb_device::bus::UsbBus for UsbBus {
+	fn alloc_ep(
+		&mut self,
+		ep_dir: UsbDirection,
+		ep_addr: Option<EndpointAddress>,
+		ep_type: EndpointType,
+		max_packet_size: u16,
+		_interval: u8
+	) -> Result<EndpointAddress> {
+		if ep_type == EndpointType::Control {
+			// usb-device allocates both directions of endpoint 0
+			if ep_addr.map(|a| a.index()).unwrap_or(0) != 0 || max_packet_size > CONTROL_MAX_PACKET_SIZE {
+				return Err(UsbError::Unsupported);
+			}
+			if self.endpoints[0].is_none() {
+				self.endpoints[0] = Some(Endpoint {
+					ep_type: ep_type,
+					dir: UsbDirection::Out,
+					size: core::cmp::max(max_packet_size, 8).next_power_of_two(),
+					banks: 1
+				});
+			}
+			return Ok(EndpointAddress::from_parts(0, ep_dir));
+		}
+
+		if max_packet_size > MAX_PACKET_SIZE {
+			return Err(UsbError::Unsupported);
+		}
+		// endpoints are unidirectional, every class endpoint gets its own number
+		let index = match ep_addr {
+			Some(addr) => {
+				let index = addr.index();
+				if index == 0 || index >= ENDPOINT_COUNT || self.endpoints[index].is_some() {
+					return Err(UsbError::InvalidEndpoint);
+				}
+				index
+			},
+			None => (1..ENDPOINT_COUNT)
+				.find(|i| self.endpoints[*i].is_none())
+				.ok_or(UsbError::EndpointOverflow)?
+		};
+
+		let ep = Endpoint {
+			ep_type: ep_type,
+			dir: ep_dir,
+			size: core::cmp::max(max_packet_size, 8).next_power_of_two(),
+			banks: 2
+		};
+		if self.dpram_used() + ep.size as usize * ep.banks as usize > DPRAM_SIZE {
+			return Err(UsbError::EndpointMemoryOverflow);
+		}
+		self.endpoints[index] = Some(ep);
+		Ok(EndpointAddress::from_parts(index, ep_dir))
+	}
+
+	fn enable(&mut self) {
+		// FRZCLK is cleared as well, the clock runs
+		write_reg(CTRL, CTRL_UIMOD_DEVICE | CTRL_VBUSHWC | CTRL_USBE);
+		while read_reg(SR) & SR_CLKUSABLE == 0 {}
+		modify_reg(DEVCTRL, |v| (v & !DEVCTRL_SPDCONF_MASK) | DEVCTRL_SPDCONF_FORCED_FS);
+
+		self.configure_endpoints();
+		write_reg(DEVICR, DEV_EORST | DEV_SUSP | DEV_WAKEUP);
+		write_reg(DEVIER, DEV_EORST | DEV_SUSP);
+		// attach, the host sees the device from now on
+		modify_reg(DEVCTRL, |v| v & !DEVCTRL_DETACH);
+	}
+
+	fn reset(&self) {
+		modify_reg(DEVCTRL, |v| v & !(DEVCTRL_UADD_MASK | DEVCTRL_ADDEN));
+		self.configure_endpoints();
+	}
+
+	// called after the status stage, the address is written before it is enabled
+	fn set_device_address(&self, addr: u8) {
+		modify_reg(DEVCTRL, |v| (v & !(DEVCTRL_UADD_MASK | DEVCTRL_ADDEN)) | (addr as u32 & DEVCTRL_UADD_MASK));
+		modify_reg(DEVCTRL, |v| v | DEVCTRL_ADDEN);
+	}
+
+	fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+		let ep = self.endpoint(ep_addr)?;
+		if !ep_addr.is_in() {
+			return Err(UsbError::InvalidEndpoint);
+		}
+		if buf.len() > ep.size as usize {
+			return Err(UsbError::BufferOverflow);
+		}
+		let i = ep_addr.index();
+		if read_reg(DEVEPTISR + 4 * i) & EPT_TXINI == 0 {
+			return Err(UsbError::WouldBlock);
+		}
+
+		let fifo = fifo(i);
+		for (n, b) in buf.iter().enumerate() {
+			unsafe { ptr::write_volatile(fifo.add(n), *b) };
+		}
+		write_reg(DEVEPTICR + 4 * i, EPT_TXINI);
+		if ep.ep_type != EndpointType::Control {
+			// hand the bank to the controller
+			write_reg(DEVEPTIDR + 4 * i, EPT_FIFOCON);
+		}
+		self.in_flight.fetch_or(1 << i, Ordering::SeqCst);
+		write_reg(DEVEPTIER + 4 * i, EPT_TXINI);
+		Ok(buf.len())
+	}
+
+	fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+		let ep = self.endpoint(ep_addr)?;
+		if ep_addr.is_in() {
+			return Err(UsbError::InvalidEndpoint);
+		}
+		let i = ep_addr.index();
+		let isr = read_reg(DEVEPTISR + 4 * i);
+		let flag = if ep.ep_type == EndpointType::Control && isr & EPT_RXSTPI != 0 {
+			EPT_RXSTPI
+		} else if isr & EPT_RXOUTI != 0 {
+			EPT_RXOUTI
+		} else {
+			return Err(UsbError::WouldBlock);
+		};
+
+		let len = ((isr >> EPTISR_BYCT_SHIFT) & EPTISR_BYCT_MASK) as usize;
+		if len > buf.len() {
+			return Err(UsbError::BufferOverflow);
+		}
+		let fifo = fifo(i);
+		for (n, b) in buf[..len].iter_mut().enumerate() {
+			*b = unsafe { ptr::read_volatile(fifo.add(n)) };
+		}
+
+		write_reg(DEVEPTICR + 4 * i, flag);
+		if ep.ep_type != EndpointType::Control {
+			// free the bank for the next packet
+			write_reg(DEVEPTIDR + 4 * i, EPT_FIFOCON);
+		}
+		write_reg(DEVEPTIER + 4 * i, flag);
+		Ok(len)
+	}
+
+	fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+		let i = ep_addr.index();
+		if self.endpoint(ep_addr).is_err() {
+			return;
+		}
+		if stalled {
+			write_reg(DEVEPTIER + 4 * i, EPT_STALLRQ);
+		} else {
+			write_reg(DEVEPTIDR + 4 * i, EPT_STALLRQ);
+			write_reg(DEVEPTIER + 4 * i, EPT_RSTDT);
+		}
+	}
+
+	fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+		let i = ep_addr.index();
+		self.endpoint(ep_addr).is_ok() && read_reg(DEVEPTIMR + 4 * i) & EPT_STALLRQ != 0
+	}
+
+	fn suspend(&self) {
+		write_reg(DEVIDR, DEV_SUSP);
+		write_reg(DEVICR, DEV_WAKEUP);
+		write_reg(DEVIER, DEV_WAKEUP);
+	}
+
+	fn resume(&self) {
+		write_reg(DEVIDR, DEV_WAKEUP);
+		write_reg(DEVICR, DEV_SUSP);
+		write_reg(DEVIER, DEV_SUSP);
+	}
+
+	fn poll(&self) -> PollResult {
+		let isr = read_reg(DEVISR);
+		let imr = read_reg(DEVIMR);
+
+		if isr & DEV_EORST != 0 {
+			write_reg(DEVICR, DEV_EORST | DEV_SUSP | DEV_WAKEUP);
+			write_reg(DEVIDR, DEV_WAKEUP);
+			write_reg(DEVIER, DEV_SUSP);
+			return PollResult::Reset;
+		}
+		// wake up is flagged on every bus activity, it only counts while suspended
+		if isr & imr & DEV_WAKEUP != 0 {
+			write_reg(DEVICR, DEV_WAKEUP);
+			return PollResult::Resume;
+		}
+		// no bus activity for 3 ms, e.g. the cable has been unplugged
+		if isr & imr & DEV_SUSP != 0 {
+			write_reg(DEVICR, DEV_SUSP);
+			return PollResult::Suspend;
+		}
+
+		let mut ep_out = 0u16;
+		let mut ep_in_complete = 0u16;
+		let mut ep_setup = 0u16;
+		let in_flight = self.in_flight.load(Ordering::SeqCst);
+		for (i, ep) in self.endpoints.iter().enumerate() {
+			let ep = match ep {
+				Some(ep) => ep,
+				None => continue
+			};
+			let isr = read_reg(DEVEPTISR + 4 * i);
+			let control = ep.ep_type == EndpointType::Control;
+
+			if control && isr & EPT_RXSTPI != 0 {
+				ep_setup |= 1 << i;
+				write_reg(DEVEPTIDR + 4 * i, EPT_RXSTPI);
+			}
+			if (control || ep.dir == UsbDirection::Out) && isr & EPT_RXOUTI != 0 {
+				ep_out |= 1 << i;
+				write_reg(DEVEPTIDR + 4 * i, EPT_RXOUTI);
+			}
+			if in_flight & (1 << i) != 0 && isr & EPT_TXINI != 0 {
+				ep_in_complete |= 1 << i;
+				self.in_flight.fetch_and(!(1 << i), Ordering::SeqCst);
+				write_reg(DEVEPTIDR + 4 * i, EPT_TXINI);
+			}
+		}
+
+		if ep_out | ep_in_complete | ep_setup != 0 {
+			PollResult::Data {
+				ep_out: ep_out,
+				ep_in_complete: ep_in_complete,
+				ep_setup: ep_setup
+			}
+		} else {
+			PollResult::None
+		}
+	}
+}
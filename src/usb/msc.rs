@@ -0,0 +1,822 @@
+// usb mass storage class, bulk-only transport
+//
+// the host sends a command block wrapper (cbw) on the bulk out endpoint, data
+// follows in the direction of the command and a command status wrapper (csw) on the
+// bulk in endpoint closes it. the scsi commands inside are handled by `Scsi`, the
+// blocks are moved one at a time through a 512 byte buffer. only one packet is in
+// flight on the in endpoint, its completion triggers the next one.
+//
+// `Transport` implements the protocol on top of the `Endpoints` trait, `MassStorage`
+// only connects it to the usb-device endpoints.
+
+use embedded_storage::nor_flash::NorFlash;
+use usb_device::class_prelude::*;
+
+use super::scsi::{BlockStorage, Phase, Scsi, Sense, BLOCK_SIZE};
+use crate::flash::{FlashError, ERASE_SIZE};
+
+const CLASS_MASS_STORAGE: u8 = 0x08;
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+const REQUEST_RESET: u8 = 0xFF;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LEN: usize = 31;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_LEN: usize = 13;
+const CBW_DIR_IN: u8 = 0x80;
+
+// full speed bulk packets
+const MAX_PACKET_SIZE: usize = 64;
+
+const STATUS_PASSED: u8 = 0x00;
+const STATUS_FAILED: u8 = 0x01;
+const STATUS_PHASE_ERROR: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+	// waiting for a cbw
+	Command,
+	// sending buf[pos..len]
+	DataIn,
+	// sending buf[pos..], then the next block
+	ReadBlocks { lba: u32, remaining: u32 },
+	// receiving into buf[pos..]
+	WriteBlocks { lba: u32, remaining: u32 },
+	// the csw waits to be written
+	Status,
+	// the csw has been written
+	StatusSent
+}
+
+// a command block wrapper
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cbw {
+	tag: u32,
+	// bytes the host expects in the data phase
+	expected: u32,
+	dir_in: bool,
+	cb: [u8; 16],
+	cb_len: usize
+}
+
+impl Cbw {
+	// `None` if the packet is not a valid and meaningful cbw
+	fn parse(packet: &[u8]) -> Option<Cbw> {
+		let valid = packet.len() == CBW_LEN
+			&& u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]) == CBW_SIGNATURE
+			&& (1..=16).contains(&(packet[14] & 0x1F));
+		if !valid {
+			return None;
+		}
+		let cb_len = packet[14] as usize & 0x1F;
+		let mut cb = [0u8; 16];
+		cb[..cb_len].copy_from_slice(&packet[15..15 + cb_len]);
+		Some(Cbw {
+			tag: u32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]),
+			expected: u32::from_le_bytes([packet[8], packet[9], packet[10], packet[11]]),
+			dir_in: packet[12] & CBW_DIR_IN != 0,
+			cb: cb,
+			cb_len: cb_len
+		})
+	}
+
+	fn command(&self) -> &[u8] {
+		&self.cb[..self.cb_len]
+	}
+}
+
+// how a command continues after the scsi layer has accepted or rejected it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataPhase {
+	// no data, the csw follows with this status
+	Status(u8),
+	// send the first n bytes of the response
+	DataIn(usize),
+	ReadBlocks { lba: u32, count: u32 },
+	WriteBlocks { lba: u32, count: u32 }
+}
+
+// the thirteen cases of the bulk-only spec: data the device wants to move in the
+// other direction or beyond what the host expects is a phase error. responses longer
+// than the host expects are cut like for a short allocation length.
+fn data_phase(phase: Result<Phase, Sense>, expected: u32, dir_in: bool) -> DataPhase {
+	let fits = |count: u32| count as u64 * BLOCK_SIZE as u64 <= expected as u64;
+	match phase {
+		Ok(Phase::DataIn(_)) | Ok(Phase::ReadBlocks { .. }) if expected == 0 || !dir_in => DataPhase::Status(STATUS_PHASE_ERROR),
+		Ok(Phase::DataIn(len)) => DataPhase::DataIn(core::cmp::min(len, expected as usize)),
+		Ok(Phase::ReadBlocks { count, .. }) if !fits(count) => DataPhase::Status(STATUS_PHASE_ERROR),
+		Ok(Phase::ReadBlocks { lba, count }) => DataPhase::ReadBlocks { lba: lba, count: count },
+		Ok(Phase::WriteBlocks { count: 0, .. }) => DataPhase::Status(STATUS_PASSED),
+		Ok(Phase::WriteBlocks { count, .. }) if expected == 0 || dir_in || !fits(count) => DataPhase::Status(STATUS_PHASE_ERROR),
+		Ok(Phase::WriteBlocks { lba, count }) => DataPhase::WriteBlocks { lba: lba, count: count },
+		Ok(Phase::NoData) => DataPhase::Status(STATUS_PASSED),
+		Err(_) => DataPhase::Status(STATUS_FAILED)
+	}
+}
+
+// the bulk endpoints as seen by `Transport`
+trait Endpoints {
+	fn packet_size(&self) -> usize;
+
+	// `None` while the previous packet has not been sent
+	fn write(&mut self, packet: &[u8]) -> Option<usize>;
+
+	fn stall_in(&mut self);
+
+	fn stall_out(&mut self);
+}
+
+struct BulkEndpoints<'e, 'a, B: UsbBus> {
+	read_ep: &'e EndpointOut<'a, B>,
+	write_ep: &'e EndpointIn<'a, B>
+}
+
+impl<'e, 'a, B: UsbBus> Endpoints for BulkEndpoints<'e, 'a, B> {
+	fn packet_size(&self) -> usize {
+		self.write_ep.max_packet_size() as usize
+	}
+
+	fn write(&mut self, packet: &[u8]) -> Option<usize> {
+		self.write_ep.write(packet).ok()
+	}
+
+	fn stall_in(&mut self) {
+		self.write_ep.stall();
+	}
+
+	fn stall_out(&mut self) {
+		self.read_ep.stall();
+	}
+}
+
+// the bulk-only state machine, apart from the usb device so it runs on the host
+struct Transport<S: BlockStorage> {
+	scsi: Scsi<S>,
+	state: State,
+	// a packet has been written and is not yet sent
+	busy: bool,
+	buf: [u8; BLOCK_SIZE],
+	pos: usize,
+	len: usize,
+	tag: u32,
+	// bytes the host expects in the data phase and bytes moved so far
+	expected: u32,
+	transferred: u32,
+	status: u8
+}
+
+impl<S: BlockStorage> Transport<S> {
+	fn new(scsi: Scsi<S>) -> Transport<S> {
+		Transport {
+			scsi: scsi,
+			state: State::Command,
+			busy: false,
+			buf: [0; BLOCK_SIZE],
+			pos: 0,
+			len: 0,
+			tag: 0,
+			expected: 0,
+			transferred: 0,
+			status: STATUS_PASSED
+		}
+	}
+
+	fn reset(&mut self) {
+		self.state = State::Command;
+		self.busy = false;
+	}
+
+	fn start_command<E: Endpoints>(&mut self, ep: &mut E, packet: &[u8]) {
+		let cbw = match Cbw::parse(packet) {
+			Some(cbw) => cbw,
+			None => {
+				// invalid cbw, the host has to do a reset recovery
+				ep.stall_out();
+				ep.stall_in();
+				return;
+			}
+		};
+		self.tag = cbw.tag;
+		self.expected = cbw.expected;
+		self.transferred = 0;
+		self.status = STATUS_PASSED;
+
+		let phase = self.scsi.command(cbw.command(), &mut self.buf);
+		match data_phase(phase, cbw.expected, cbw.dir_in) {
+			DataPhase::Status(status) => self.finish(ep, status, cbw.dir_in),
+			DataPhase::DataIn(len) => {
+				self.pos = 0;
+				self.len = len;
+				self.state = State::DataIn;
+			},
+			DataPhase::ReadBlocks { lba, count } => {
+				// start with an empty buffer, the first block is read when it is sent
+				self.pos = BLOCK_SIZE;
+				self.state = State::ReadBlocks { lba: lba, remaining: count };
+			},
+			DataPhase::WriteBlocks { lba, count } => {
+				self.pos = 0;
+				self.state = State::WriteBlocks { lba: lba, remaining: count };
+			}
+		}
+		self.flush(ep);
+	}
+
+	// end the data phase and send the csw. when the host expects more data than
+	// was transferred the endpoint of the data direction is stalled.
+	fn finish<E: Endpoints>(&mut self, ep: &mut E, status: u8, dir_in: bool) {
+		self.status = status;
+		if self.transferred < self.expected {
+			// a short packet already ends the transfer for the host
+			let short = self.transferred % ep.packet_size() as u32 != 0;
+			if dir_in && !short {
+				ep.stall_in();
+			} else if !dir_in {
+				ep.stall_out();
+			}
+		}
+		self.state = State::Status;
+	}
+
+	// write the next packet on the in endpoint if there is one
+	fn flush<E: Endpoints>(&mut self, ep: &mut E) {
+		if self.busy {
+			return;
+		}
+		match self.state {
+			State::ReadBlocks { lba, remaining } if self.pos == BLOCK_SIZE => {
+				if remaining == 0 {
+					self.finish(ep, STATUS_PASSED, true);
+					return self.flush(ep);
+				}
+				if self.scsi.read_block(lba, &mut self.buf).is_err() {
+					self.finish(ep, STATUS_FAILED, true);
+					return self.flush(ep);
+				}
+				self.pos = 0;
+				self.len = BLOCK_SIZE;
+				self.state = State::ReadBlocks { lba: lba + 1, remaining: remaining - 1 };
+				self.write_data(ep);
+			},
+			State::ReadBlocks { .. } => self.write_data(ep),
+			State::DataIn if self.pos < self.len => self.write_data(ep),
+			State::DataIn => {
+				self.finish(ep, STATUS_PASSED, true);
+				self.flush(ep);
+			},
+			State::Status => {
+				let residue = self.expected.saturating_sub(self.transferred);
+				let mut csw = [0u8; CSW_LEN];
+				csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+				csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+				csw[8..12].copy_from_slice(&residue.to_le_bytes());
+				csw[12] = self.status;
+				if ep.write(&csw).is_some() {
+					self.busy = true;
+					self.state = State::StatusSent;
+				}
+			},
+			_ => {}
+		}
+	}
+
+	fn write_data<E: Endpoints>(&mut self, ep: &mut E) {
+		let end = core::cmp::min(self.pos + ep.packet_size(), self.len);
+		if let Some(n) = ep.write(&self.buf[self.pos..end]) {
+			self.busy = true;
+			self.pos += n;
+			self.transferred += n as u32;
+		}
+	}
+
+	// a packet has arrived on the out endpoint
+	fn receive<E: Endpoints>(&mut self, ep: &mut E, packet: &[u8]) {
+		match self.state {
+			// the csw has arrived when the host sends the next command
+			State::Command | State::StatusSent => {
+				self.state = State::Command;
+				self.start_command(ep, packet);
+			},
+			State::WriteBlocks { lba, remaining } => {
+				let n = core::cmp::min(packet.len(), BLOCK_SIZE - self.pos);
+				self.buf[self.pos..self.pos + n].copy_from_slice(&packet[..n]);
+				self.pos += n;
+				self.transferred += n as u32;
+				if self.pos < BLOCK_SIZE {
+					return;
+				}
+
+				if self.scsi.write_block(lba, &self.buf).is_err() {
+					self.finish(ep, STATUS_FAILED, false);
+				} else if remaining == 1 {
+					self.finish(ep, STATUS_PASSED, false);
+				} else {
+					self.pos = 0;
+					self.state = State::WriteBlocks { lba: lba + 1, remaining: remaining - 1 };
+				}
+				self.flush(ep);
+			},
+			// unexpected data, e.g. after a failed write
+			_ => {}
+		}
+	}
+
+	// the packet written last has been sent
+	fn in_complete<E: Endpoints>(&mut self, ep: &mut E) {
+		self.busy = false;
+		if self.state == State::StatusSent {
+			self.state = State::Command;
+		}
+		self.flush(ep);
+	}
+}
+
+pub struct MassStorage<'a, B: UsbBus, S: BlockStorage> {
+	interface: InterfaceNumber,
+	read_ep: EndpointOut<'a, B>,
+	write_ep: EndpointIn<'a, B>,
+	transport: Transport<S>
+}
+
+impl<'a, B: UsbBus, S: BlockStorage> MassStorage<'a, B, S> {
+	// `max_packet_size` is 8, 16, 32 or 64
+	pub fn new(alloc: &'a UsbBusAllocator<B>, scsi: Scsi<S>, max_packet_size: u16) -> MassStorage<'a, B, S> {
+		assert!(max_packet_size as usize <= MAX_PACKET_SIZE);
+		MassStorage {
+			interface: alloc.interface(),
+			read_ep: alloc.bulk(max_packet_size),
+			write_ep: alloc.bulk(max_packet_size),
+			transport: Transport::new(scsi)
+		}
+	}
+
+	pub fn scsi(&self) -> &Scsi<S> {
+		&self.transport.scsi
+	}
+
+	pub fn scsi_mut(&mut self) -> &mut Scsi<S> {
+		&mut self.transport.scsi
+	}
+}
+
+impl<'a, B: UsbBus, S: BlockStorage> UsbClass<B> for MassStorage<'a, B, S> {
+	fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+		writer.interface(self.interface, CLASS_MASS_STORAGE, SUBCLASS_SCSI, PROTOCOL_BULK_ONLY)?;
+		writer.endpoint(&self.read_ep)?;
+		writer.endpoint(&self.write_ep)?;
+		Ok(())
+	}
+
+	fn reset(&mut self) {
+		self.transport.reset();
+	}
+
+	fn control_in(&mut self, xfer: ControlIn<B>) {
+		let req = *xfer.request();
+		if req.request_type != control::RequestType::Class
+			|| req.recipient != control::Recipient::Interface
+			|| req.index != u8::from(self.interface) as u16 {
+			return;
+		}
+		match req.request {
+			// a single logical unit
+			REQUEST_GET_MAX_LUN => xfer.accept_with(&[0]).ok(),
+			_ => xfer.reject().ok()
+		};
+	}
+
+	fn control_out(&mut self, xfer: ControlOut<B>) {
+		let req = *xfer.request();
+		if req.request_type != control::RequestType::Class
+			|| req.recipient != control::Recipient::Interface
+			|| req.index != u8::from(self.interface) as u16 {
+			return;
+		}
+		match req.request {
+			// reset recovery, the host clears the stalled endpoints afterwards
+			REQUEST_RESET => {
+				self.transport.reset();
+				xfer.accept().ok()
+			},
+			_ => xfer.reject().ok()
+		};
+	}
+
+	fn endpoint_out(&mut self, addr: EndpointAddress) {
+		if addr != self.read_ep.address() {
+			return;
+		}
+		let mut packet = [0u8; MAX_PACKET_SIZE];
+		if let Ok(n) = self.read_ep.read(&mut packet) {
+			let mut ep = BulkEndpoints { read_ep: &self.read_ep, write_ep: &self.write_ep };
+			self.transport.receive(&mut ep, &packet[..n]);
+		}
+	}
+
+	fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+		if addr == self.write_ep.address() {
+			let mut ep = BulkEndpoints { read_ep: &self.read_ep, write_ep: &self.write_ep };
+			self.transport.in_complete(&mut ep);
+		}
+	}
+
+	fn poll(&mut self) {
+		let mut ep = BulkEndpoints { read_ep: &self.read_ep, write_ep: &self.write_ep };
+		self.transport.flush(&mut ep);
+	}
+}
+
+// a region of a nor flash, normally the internal `Flash`, as block storage. a block
+// write rewrites the 4 KiB unit containing it, so this is slow and meant for small,
+// rarely written stores, e.g. configuration files next to the settings.
+pub struct FlashDisk<'a, F: NorFlash<Error = FlashError>> {
+	flash: &'a mut F,
+	offset: usize,
+	len: usize,
+	unit: [u8; ERASE_SIZE]
+}
+
+impl<'a, F: NorFlash<Error = FlashError>> FlashDisk<'a, F> {
+	// `offset` and `len` are relative to the flash start and aligned to ERASE_SIZE
+	pub fn new(flash: &'a mut F, offset: usize, len: usize) -> FlashDisk<'a, F> {
+		assert!(ERASE_SIZE % F::ERASE_SIZE == 0 && ERASE_SIZE % F::WRITE_SIZE == 0);
+		assert!(offset % ERASE_SIZE == 0 && len % ERASE_SIZE == 0 && offset + len <= flash.capacity());
+		FlashDisk {
+			flash: flash,
+			offset: offset,
+			len: len,
+			unit: [0xFF; ERASE_SIZE]
+		}
+	}
+}
+
+impl<'a, F: NorFlash<Error = FlashError>> BlockStorage for FlashDisk<'a, F> {
+	type Error = FlashError;
+
+	fn block_count(&self) -> u32 {
+		(self.len / BLOCK_SIZE) as u32
+	}
+
+	fn read_block(&mut self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FlashError> {
+		if lba >= self.block_count() {
+			return Err(FlashError::OutOfBounds);
+		}
+		self.flash.read((self.offset + lba as usize * BLOCK_SIZE) as u32, buf)
+	}
+
+	fn write_block(&mut self, lba: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), FlashError> {
+		if lba >= self.block_count() {
+			return Err(FlashError::OutOfBounds);
+		}
+		let address = self.offset + lba as usize * BLOCK_SIZE;
+		let unit = address - address % ERASE_SIZE;
+		let start = address - unit;
+		self.flash.read(unit as u32, &mut self.unit)?;
+		if self.unit[start..start + BLOCK_SIZE] == data[..] {
+			return Ok(());
+		}
+
+		self.unit[start..start + BLOCK_SIZE].copy_from_slice(data);
+		self.flash.erase(unit as u32, (unit + ERASE_SIZE) as u32)?;
+		self.flash.write(unit as u32, &self.unit)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use embedded_storage::nor_flash::ReadNorFlash;
+	use crate::usb::scsi::tests::MemDisk;
+
+	const READ_10: u8 = 0x28;
+	const WRITE_10: u8 = 0x2A;
+	const INQUIRY: u8 = 0x12;
+	const TEST_UNIT_READY: u8 = 0x00;
+
+	// the in endpoint keeps what was written until the test completes it
+	struct Bulk {
+		sent: Vec<Vec<u8>>,
+		busy: bool,
+		stalled_in: bool,
+		stalled_out: bool
+	}
+
+	impl Endpoints for Bulk {
+		fn packet_size(&self) -> usize {
+			MAX_PACKET_SIZE
+		}
+
+		fn write(&mut self, packet: &[u8]) -> Option<usize> {
+			if self.busy {
+				return None;
+			}
+			assert!(packet.len() <= MAX_PACKET_SIZE);
+			self.sent.push(packet.to_vec());
+			self.busy = true;
+			Some(packet.len())
+		}
+
+		fn stall_in(&mut self) {
+			self.stalled_in = true;
+		}
+
+		fn stall_out(&mut self) {
+			self.stalled_out = true;
+		}
+	}
+
+	fn transport() -> (Transport<MemDisk>, Bulk) {
+		let mut disk = MemDisk { blocks: [[0; BLOCK_SIZE]; 4] };
+		for (lba, block) in disk.blocks.iter_mut().enumerate() {
+			for (i, b) in block.iter_mut().enumerate() {
+				*b = (lba * 0x40 + i) as u8;
+			}
+		}
+		let bulk = Bulk {
+			sent: Vec::new(),
+			busy: false,
+			stalled_in: false,
+			stalled_out: false
+		};
+		(Transport::new(Scsi::new(disk)), bulk)
+	}
+
+	fn cbw(tag: u32, expected: u32, dir_in: bool, cb: &[u8]) -> [u8; CBW_LEN] {
+		let mut cbw = [0u8; CBW_LEN];
+		cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+		cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+		cbw[8..12].copy_from_slice(&expected.to_le_bytes());
+		cbw[12] = if dir_in { CBW_DIR_IN } else { 0 };
+		cbw[14] = cb.len() as u8;
+		cbw[15..15 + cb.len()].copy_from_slice(cb);
+		cbw
+	}
+
+	fn csw(tag: u32, residue: u32, status: u8) -> Vec<u8> {
+		let mut csw = CSW_SIGNATURE.to_le_bytes().to_vec();
+		csw.extend_from_slice(&tag.to_le_bytes());
+		csw.extend_from_slice(&residue.to_le_bytes());
+		csw.push(status);
+		csw
+	}
+
+	fn rw10(opcode: u8, lba: u32, count: u16) -> [u8; 10] {
+		let lba = lba.to_be_bytes();
+		let count = count.to_be_bytes();
+		[opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0]
+	}
+
+	// complete every packet the transport writes, returns the data and the csw
+	fn drain(t: &mut Transport<MemDisk>, ep: &mut Bulk) -> (Vec<u8>, Vec<u8>) {
+		while ep.busy {
+			ep.busy = false;
+			t.in_complete(ep);
+		}
+		assert_eq!(t.state, State::Command);
+		let csw = ep.sent.pop().unwrap();
+		(ep.sent.drain(..).flatten().collect(), csw)
+	}
+
+	#[test]
+	fn cbw_parse() {
+		let packet = cbw(0x1234_5678, 512, true, &rw10(READ_10, 1, 1));
+		let parsed = Cbw::parse(&packet).unwrap();
+		assert_eq!(parsed.tag, 0x1234_5678);
+		assert_eq!(parsed.expected, 512);
+		assert!(parsed.dir_in);
+		assert_eq!(parsed.command(), &rw10(READ_10, 1, 1)[..]);
+
+		assert_eq!(Cbw::parse(&packet[..CBW_LEN - 1]), None);
+		let mut bad = packet;
+		bad[0] ^= 1;
+		assert_eq!(Cbw::parse(&bad), None);
+		bad = packet;
+		bad[14] = 0;
+		assert_eq!(Cbw::parse(&bad), None);
+		bad[14] = 17;
+		assert_eq!(Cbw::parse(&bad), None);
+	}
+
+	#[test]
+	fn data_phase_cases() {
+		let read = |count| Ok(Phase::ReadBlocks { lba: 0, count: count });
+		let write = |count| Ok(Phase::WriteBlocks { lba: 0, count: count });
+		let phase_error = DataPhase::Status(STATUS_PHASE_ERROR);
+
+		// hn = dn, hi > dn, ho > dn
+		assert_eq!(data_phase(Ok(Phase::NoData), 0, false), DataPhase::Status(STATUS_PASSED));
+		assert_eq!(data_phase(Ok(Phase::NoData), 512, true), DataPhase::Status(STATUS_PASSED));
+		assert_eq!(data_phase(Ok(Phase::NoData), 512, false), DataPhase::Status(STATUS_PASSED));
+		assert_eq!(data_phase(Err(Sense::INVALID_COMMAND), 36, true), DataPhase::Status(STATUS_FAILED));
+
+		// hn < di, hn < do
+		assert_eq!(data_phase(Ok(Phase::DataIn(36)), 0, false), phase_error);
+		assert_eq!(data_phase(read(1), 0, false), phase_error);
+		assert_eq!(data_phase(write(1), 0, false), phase_error);
+
+		// hi > di, hi = di, hi < di
+		assert_eq!(data_phase(read(1), 1024, true), DataPhase::ReadBlocks { lba: 0, count: 1 });
+		assert_eq!(data_phase(read(2), 1024, true), DataPhase::ReadBlocks { lba: 0, count: 2 });
+		assert_eq!(data_phase(read(3), 1024, true), phase_error);
+		assert_eq!(data_phase(Ok(Phase::DataIn(36)), 255, true), DataPhase::DataIn(36));
+		// cut like a short allocation length
+		assert_eq!(data_phase(Ok(Phase::DataIn(36)), 18, true), DataPhase::DataIn(18));
+
+		// hi <> do, ho <> di
+		assert_eq!(data_phase(write(1), 512, true), phase_error);
+		assert_eq!(data_phase(read(1), 512, false), phase_error);
+		assert_eq!(data_phase(Ok(Phase::DataIn(8)), 8, false), phase_error);
+
+		// ho > do, ho = do, ho < do
+		assert_eq!(data_phase(write(1), 1024, false), DataPhase::WriteBlocks { lba: 0, count: 1 });
+		assert_eq!(data_phase(write(2), 1024, false), DataPhase::WriteBlocks { lba: 0, count: 2 });
+		assert_eq!(data_phase(write(3), 1024, false), phase_error);
+
+		// a write of no blocks has no data phase
+		assert_eq!(data_phase(write(0), 0, false), DataPhase::Status(STATUS_PASSED));
+	}
+
+	#[test]
+	fn read_blocks() {
+		let (mut t, mut ep) = transport();
+		t.receive(&mut ep, &cbw(7, 1024, true, &rw10(READ_10, 1, 2)));
+		let (data, status) = drain(&mut t, &mut ep);
+		assert_eq!(&data[..BLOCK_SIZE], &t.scsi.storage().blocks[1][..]);
+		assert_eq!(&data[BLOCK_SIZE..], &t.scsi.storage().blocks[2][..]);
+		assert_eq!(status, csw(7, 0, STATUS_PASSED));
+		assert!(!ep.stalled_in && !ep.stalled_out);
+
+		// the next command
+		t.receive(&mut ep, &cbw(8, 0, false, &[TEST_UNIT_READY, 0, 0, 0, 0, 0]));
+		assert_eq!(drain(&mut t, &mut ep), (Vec::new(), csw(8, 0, STATUS_PASSED)));
+	}
+
+	#[test]
+	fn read_blocks_host_expects_more() {
+		let (mut t, mut ep) = transport();
+		t.receive(&mut ep, &cbw(1, 1536, true, &rw10(READ_10, 3, 1)));
+		let (data, status) = drain(&mut t, &mut ep);
+		assert_eq!(&data[..], &t.scsi.storage().blocks[3][..]);
+		// the data ended on a full packet, the stall ends the transfer
+		assert!(ep.stalled_in);
+		assert_eq!(status, csw(1, 1024, STATUS_PASSED));
+	}
+
+	#[test]
+	fn read_blocks_host_expects_less() {
+		let (mut t, mut ep) = transport();
+		t.receive(&mut ep, &cbw(2, 512, true, &rw10(READ_10, 0, 2)));
+		let (data, status) = drain(&mut t, &mut ep);
+		assert!(data.is_empty());
+		assert!(ep.stalled_in);
+		assert_eq!(status, csw(2, 512, STATUS_PHASE_ERROR));
+	}
+
+	#[test]
+	fn data_in_cut_to_expected() {
+		let (mut t, mut ep) = transport();
+		t.receive(&mut ep, &cbw(3, 18, true, &[INQUIRY, 0, 0, 0, 36, 0]));
+		let (data, status) = drain(&mut t, &mut ep);
+		assert_eq!(data.len(), 18);
+		assert_eq!(status, csw(3, 0, STATUS_PASSED));
+
+		// hi > di, the short packet ends the transfer without a stall
+		t.receive(&mut ep, &cbw(4, 255, true, &[INQUIRY, 0, 0, 0, 36, 0]));
+		let (data, status) = drain(&mut t, &mut ep);
+		assert_eq!(data.len(), 36);
+		assert!(!ep.stalled_in);
+		assert_eq!(status, csw(4, 255 - 36, STATUS_PASSED));
+	}
+
+	#[test]
+	fn write_blocks() {
+		let (mut t, mut ep) = transport();
+		t.receive(&mut ep, &cbw(5, 1024, false, &rw10(WRITE_10, 2, 2)));
+		assert!(ep.sent.is_empty());
+
+		let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| (i * 7) as u8).collect();
+		for packet in data.chunks(MAX_PACKET_SIZE) {
+			// the csw only follows the last block
+			assert!(ep.sent.is_empty());
+			t.receive(&mut ep, packet);
+		}
+		assert_eq!(drain(&mut t, &mut ep), (Vec::new(), csw(5, 0, STATUS_PASSED)));
+		assert_eq!(&t.scsi.storage().blocks[2][..], &data[..BLOCK_SIZE]);
+		assert_eq!(&t.scsi.storage().blocks[3][..], &data[BLOCK_SIZE..]);
+		assert_eq!(t.scsi.storage().blocks[1][0], 0x40);
+		assert!(!ep.stalled_in && !ep.stalled_out);
+	}
+
+	#[test]
+	fn write_blocks_host_sends_more() {
+		let (mut t, mut ep) = transport();
+		t.receive(&mut ep, &cbw(6, 2048, false, &rw10(WRITE_10, 0, 1)));
+		for packet in [0x5Au8; BLOCK_SIZE].chunks(MAX_PACKET_SIZE) {
+			t.receive(&mut ep, packet);
+		}
+		assert!(ep.stalled_out);
+		assert_eq!(drain(&mut t, &mut ep), (Vec::new(), csw(6, 1536, STATUS_PASSED)));
+		assert_eq!(&t.scsi.storage().blocks[0][..], &[0x5A; BLOCK_SIZE][..]);
+	}
+
+	#[test]
+	fn write_blocks_in_direction() {
+		let (mut t, mut ep) = transport();
+		t.receive(&mut ep, &cbw(9, 512, true, &rw10(WRITE_10, 0, 1)));
+		assert!(ep.stalled_in);
+		assert_eq!(drain(&mut t, &mut ep), (Vec::new(), csw(9, 512, STATUS_PHASE_ERROR)));
+		assert_eq!(t.scsi.storage().blocks[0][1], 1);
+	}
+
+	#[test]
+	fn invalid_cbw() {
+		let (mut t, mut ep) = transport();
+		let mut packet = cbw(10, 0, false, &[TEST_UNIT_READY, 0, 0, 0, 0, 0]);
+		packet[3] = 0;
+		t.receive(&mut ep, &packet);
+		assert!(ep.stalled_in && ep.stalled_out);
+		assert!(ep.sent.is_empty());
+	}
+
+	// nor flash in ram, programming only clears bits
+	struct RamFlash {
+		data: Vec<u8>,
+		erased: Vec<(u32, u32)>
+	}
+
+	impl ReadNorFlash for RamFlash {
+		type Error = FlashError;
+
+		const READ_SIZE: usize = 1;
+
+		fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+			bytes.copy_from_slice(&self.data[offset as usize..offset as usize + bytes.len()]);
+			Ok(())
+		}
+
+		fn capacity(&self) -> usize {
+			self.data.len()
+		}
+	}
+
+	impl NorFlash for RamFlash {
+		const WRITE_SIZE: usize = 16;
+
+		const ERASE_SIZE: usize = ERASE_SIZE;
+
+		fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+			assert!(from as usize % ERASE_SIZE == 0 && to as usize % ERASE_SIZE == 0);
+			self.erased.push((from, to));
+			for b in self.data[from as usize..to as usize].iter_mut() {
+				*b = 0xFF;
+			}
+			Ok(())
+		}
+
+		fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+			for (b, new) in self.data[offset as usize..].iter_mut().zip(bytes) {
+				*b &= *new;
+			}
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn flash_disk_rewrites_unit() {
+		let original: Vec<u8> = (0..4 * ERASE_SIZE).map(|i| (i / 16) as u8).collect();
+		let mut flash = RamFlash { data: original.clone(), erased: Vec::new() };
+		{
+			let mut disk = FlashDisk::new(&mut flash, ERASE_SIZE, 2 * ERASE_SIZE);
+			assert_eq!(disk.block_count(), 16);
+
+			let mut buf = [0u8; BLOCK_SIZE];
+			disk.read_block(9, &mut buf).unwrap();
+			let address = ERASE_SIZE + 9 * BLOCK_SIZE;
+			assert_eq!(&buf[..], &original[address..address + BLOCK_SIZE]);
+
+			// unchanged data is not written
+			disk.write_block(9, &buf).unwrap();
+			disk.write_block(9, &[0xA5; BLOCK_SIZE]).unwrap();
+			disk.read_block(9, &mut buf).unwrap();
+			assert_eq!(&buf[..], &[0xA5; BLOCK_SIZE][..]);
+
+			assert_eq!(disk.read_block(16, &mut buf), Err(FlashError::OutOfBounds));
+			assert_eq!(disk.write_block(16, &buf), Err(FlashError::OutOfBounds));
+		}
+
+		// one erase of the unit holding the block, the rest of it is written back
+		let unit = 2 * ERASE_SIZE;
+		assert_eq!(flash.erased, vec![(unit as u32, (unit + ERASE_SIZE) as u32)]);
+		let block = ERASE_SIZE + 9 * BLOCK_SIZE;
+		for (i, (b, o)) in flash.data.iter().zip(original.iter()).enumerate() {
+			if i >= block && i < block + BLOCK_SIZE {
+				assert_eq!(*b, 0xA5);
+			} else {
+				assert_eq!(b, o, "byte {:#x}", i);
+			}
+		}
+	}
+}
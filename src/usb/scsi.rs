@@ -0,0 +1,387 @@
+// scsi block commands for the usb mass storage class
+//
+// this module does not touch any hardware, it turns command blocks into responses
+// and block transfers on a `BlockStorage`, the transport (`msc`) moves the data. the
+// command set is what the common hosts use with a removable direct access device.
+
+pub const BLOCK_SIZE: usize = 512;
+
+// storage made of 512 byte blocks
+pub trait BlockStorage {
+	type Error;
+
+	fn block_count(&self) -> u32;
+
+	fn read_block(&mut self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Self::Error>;
+
+	fn write_block(&mut self, lba: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), Self::Error>;
+
+	fn is_writable(&self) -> bool {
+		true
+	}
+
+	// called for synchronize cache, write back anything buffered
+	fn flush(&mut self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const VERIFY_10: u8 = 0x2F;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5A;
+
+// sense key, additional sense code and qualifier reported by request sense
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sense {
+	pub key: u8,
+	pub asc: u8,
+	pub ascq: u8
+}
+
+impl Sense {
+	pub const NONE: Sense = Sense { key: 0x00, asc: 0x00, ascq: 0x00 };
+	pub const MEDIUM_NOT_PRESENT: Sense = Sense { key: 0x02, asc: 0x3A, ascq: 0x00 };
+	pub const READ_ERROR: Sense = Sense { key: 0x03, asc: 0x11, ascq: 0x00 };
+	pub const WRITE_ERROR: Sense = Sense { key: 0x03, asc: 0x0C, ascq: 0x00 };
+	pub const INVALID_COMMAND: Sense = Sense { key: 0x05, asc: 0x20, ascq: 0x00 };
+	pub const LBA_OUT_OF_RANGE: Sense = Sense { key: 0x05, asc: 0x21, ascq: 0x00 };
+	pub const INVALID_FIELD: Sense = Sense { key: 0x05, asc: 0x24, ascq: 0x00 };
+	pub const WRITE_PROTECTED: Sense = Sense { key: 0x07, asc: 0x27, ascq: 0x00 };
+}
+
+// what the transport has to do after a command has been accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+	NoData,
+	// send the first n bytes of the response buffer
+	DataIn(usize),
+	// send `count` blocks starting at `lba`, fetched with `read_block`
+	ReadBlocks { lba: u32, count: u32 },
+	// receive `count` blocks starting at `lba`, stored with `write_block`
+	WriteBlocks { lba: u32, count: u32 }
+}
+
+pub struct Scsi<S: BlockStorage> {
+	storage: S,
+	sense: Sense,
+	ejected: bool,
+	vendor: &'static str,
+	product: &'static str
+}
+
+impl<S: BlockStorage> Scsi<S> {
+	pub fn new(storage: S) -> Scsi<S> {
+		Scsi {
+			storage: storage,
+			sense: Sense::NONE,
+			ejected: false,
+			vendor: "HD",
+			product: "Board disk"
+		}
+	}
+
+	// inquiry strings, cut to 8 and 16 characters
+	pub fn identity(mut self, vendor: &'static str, product: &'static str) -> Self {
+		self.vendor = vendor;
+		self.product = product;
+		self
+	}
+
+	pub fn storage(&self) -> &S {
+		&self.storage
+	}
+
+	pub fn storage_mut(&mut self) -> &mut S {
+		&mut self.storage
+	}
+
+	pub fn free(self) -> S {
+		self.storage
+	}
+
+	// the host has ejected the medium, e.g. after unmounting it. the board can use
+	// the storage without the host writing to it at the same time.
+	pub fn is_ejected(&self) -> bool {
+		self.ejected
+	}
+
+	// make the medium available to the host again
+	pub fn insert(&mut self) {
+		self.ejected = false;
+	}
+
+	pub fn sense(&self) -> Sense {
+		self.sense
+	}
+
+	// execute the command block `cb`, responses are written to `response`, which has
+	// to hold at least 36 bytes. on errors the sense is set for request sense.
+	pub fn command(&mut self, cb: &[u8], response: &mut [u8]) -> Result<Phase, Sense> {
+		let result = self.execute(cb, response);
+		match result {
+			// request sense clears the sense itself after reporting it
+			Ok(_) if cb[0] == REQUEST_SENSE => {},
+			Ok(_) => self.sense = Sense::NONE,
+			Err(sense) => self.sense = sense
+		}
+		result
+	}
+
+	fn execute(&mut self, cb: &[u8], response: &mut [u8]) -> Result<Phase, Sense> {
+		let opcode = *cb.first().ok_or(Sense::INVALID_COMMAND)?;
+		let byte = |i: usize| cb.get(i).cloned().unwrap_or(0);
+		let blocks = self.storage.block_count();
+		let write_protect = if self.storage.is_writable() { 0x00 } else { 0x80 };
+
+		match opcode {
+			TEST_UNIT_READY => {
+				self.check_ready()?;
+				Ok(Phase::NoData)
+			},
+			REQUEST_SENSE => {
+				let len = 18;
+				clear(&mut response[..len]);
+				response[0] = 0x70;
+				response[2] = self.sense.key;
+				response[7] = len as u8 - 8;
+				response[12] = self.sense.asc;
+				response[13] = self.sense.ascq;
+				self.sense = Sense::NONE;
+				Ok(Phase::DataIn(len))
+			},
+			INQUIRY => {
+				// vital product data pages are not supported
+				if byte(1) & 0x01 != 0 {
+					return Err(Sense::INVALID_FIELD);
+				}
+				let len = 36;
+				clear(&mut response[..len]);
+				// removable direct access device, spc-2
+				response[1] = 0x80;
+				response[2] = 0x04;
+				response[3] = 0x02;
+				response[4] = len as u8 - 5;
+				padded(&mut response[8..16], self.vendor);
+				padded(&mut response[16..32], self.product);
+				padded(&mut response[32..36], "1.0");
+				Ok(Phase::DataIn(len))
+			},
+			MODE_SENSE_6 => {
+				clear(&mut response[..4]);
+				response[0] = 3;
+				response[2] = write_protect;
+				Ok(Phase::DataIn(4))
+			},
+			MODE_SENSE_10 => {
+				clear(&mut response[..8]);
+				response[1] = 6;
+				response[3] = write_protect;
+				Ok(Phase::DataIn(8))
+			},
+			START_STOP_UNIT => {
+				// load/eject bit, the start bit selects which
+				if byte(4) & 0x02 != 0 {
+					self.ejected = byte(4) & 0x01 == 0;
+				}
+				Ok(Phase::NoData)
+			},
+			PREVENT_ALLOW_MEDIUM_REMOVAL | VERIFY_10 => Ok(Phase::NoData),
+			SYNCHRONIZE_CACHE_10 => {
+				self.check_ready()?;
+				self.storage.flush().map_err(|_| Sense::WRITE_ERROR)?;
+				Ok(Phase::NoData)
+			},
+			READ_FORMAT_CAPACITIES => {
+				self.check_ready()?;
+				clear(&mut response[..12]);
+				response[3] = 8;
+				response[4..8].copy_from_slice(&blocks.to_be_bytes());
+				// formatted media
+				response[8] = 0x02;
+				response[9..12].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+				Ok(Phase::DataIn(12))
+			},
+			READ_CAPACITY_10 => {
+				self.check_ready()?;
+				response[0..4].copy_from_slice(&blocks.saturating_sub(1).to_be_bytes());
+				response[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+				Ok(Phase::DataIn(8))
+			},
+			READ_10 | WRITE_10 => {
+				self.check_ready()?;
+				let lba = u32::from_be_bytes([byte(2), byte(3), byte(4), byte(5)]);
+				let count = u16::from_be_bytes([byte(7), byte(8)]) as u32;
+				if lba as u64 + count as u64 > blocks as u64 {
+					return Err(Sense::LBA_OUT_OF_RANGE);
+				}
+				if opcode == READ_10 {
+					Ok(Phase::ReadBlocks { lba: lba, count: count })
+				} else if write_protect != 0 {
+					Err(Sense::WRITE_PROTECTED)
+				} else {
+					Ok(Phase::WriteBlocks { lba: lba, count: count })
+				}
+			},
+			_ => Err(Sense::INVALID_COMMAND)
+		}
+	}
+
+	fn check_ready(&self) -> Result<(), Sense> {
+		if self.ejected {
+			Err(Sense::MEDIUM_NOT_PRESENT)
+		} else {
+			Ok(())
+		}
+	}
+
+	// one block of a `ReadBlocks` phase
+	pub fn read_block(&mut self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Sense> {
+		let result = self.storage.read_block(lba, buf).map_err(|_| Sense::READ_ERROR);
+		if let Err(sense) = result {
+			self.sense = sense;
+		}
+		result
+	}
+
+	// one block of a `WriteBlocks` phase
+	pub fn write_block(&mut self, lba: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), Sense> {
+		let result = self.storage.write_block(lba, data).map_err(|_| Sense::WRITE_ERROR);
+		if let Err(sense) = result {
+			self.sense = sense;
+		}
+		result
+	}
+}
+
+fn clear(buf: &mut [u8]) {
+	for b in buf.iter_mut() {
+		*b = 0;
+	}
+}
+
+// copy `s` into `buf` padded with spaces, as inquiry strings are
+fn padded(buf: &mut [u8], s: &str) {
+	for (i, b) in buf.iter_mut().enumerate() {
+		*b = s.as_bytes().get(i).cloned().unwrap_or(b' ');
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	pub struct MemDisk {
+		pub blocks: [[u8; BLOCK_SIZE]; 4]
+	}
+
+	impl BlockStorage for MemDisk {
+		type Error = ();
+
+		fn block_count(&self) -> u32 {
+			self.blocks.len() as u32
+		}
+
+		fn read_block(&mut self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), ()> {
+			buf.copy_from_slice(&self.blocks[lba as usize]);
+			Ok(())
+		}
+
+		fn write_block(&mut self, lba: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), ()> {
+			self.blocks[lba as usize].copy_from_slice(data);
+			Ok(())
+		}
+	}
+
+	fn scsi() -> Scsi<MemDisk> {
+		Scsi::new(MemDisk { blocks: [[0; BLOCK_SIZE]; 4] }).identity("HD", "Test disk")
+	}
+
+	fn rw10(opcode: u8, lba: u32, count: u16) -> [u8; 10] {
+		let lba = lba.to_be_bytes();
+		let count = count.to_be_bytes();
+		[opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0]
+	}
+
+	#[test]
+	fn inquiry() {
+		let mut s = scsi();
+		let mut response = [0u8; 64];
+		assert_eq!(s.command(&[INQUIRY, 0, 0, 0, 36, 0], &mut response), Ok(Phase::DataIn(36)));
+		assert_eq!(response[0], 0x00);
+		assert_eq!(response[1], 0x80);
+		assert_eq!(&response[8..16], b"HD      ");
+		assert_eq!(&response[16..32], b"Test disk       ");
+
+		// vital product data pages are rejected
+		assert_eq!(s.command(&[INQUIRY, 1, 0x80, 0, 36, 0], &mut response), Err(Sense::INVALID_FIELD));
+	}
+
+	#[test]
+	fn read_capacity() {
+		let mut s = scsi();
+		let mut response = [0u8; 64];
+		assert_eq!(s.command(&[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], &mut response), Ok(Phase::DataIn(8)));
+		assert_eq!(&response[0..4], &3u32.to_be_bytes());
+		assert_eq!(&response[4..8], &512u32.to_be_bytes());
+	}
+
+	#[test]
+	fn request_sense_after_error() {
+		let mut s = scsi();
+		let mut response = [0u8; 64];
+		assert_eq!(s.command(&[0xFF, 0, 0, 0, 0, 0], &mut response), Err(Sense::INVALID_COMMAND));
+
+		assert_eq!(s.command(&[REQUEST_SENSE, 0, 0, 0, 18, 0], &mut response), Ok(Phase::DataIn(18)));
+		assert_eq!(response[0], 0x70);
+		assert_eq!(response[2], Sense::INVALID_COMMAND.key);
+		assert_eq!(response[12], Sense::INVALID_COMMAND.asc);
+
+		// reported once
+		s.command(&[REQUEST_SENSE, 0, 0, 0, 18, 0], &mut response).unwrap();
+		assert_eq!(response[2], 0);
+		assert_eq!(s.sense(), Sense::NONE);
+	}
+
+	#[test]
+	fn read_write_bounds() {
+		let mut s = scsi();
+		let mut response = [0u8; 64];
+		assert_eq!(s.command(&rw10(READ_10, 2, 2), &mut response), Ok(Phase::ReadBlocks { lba: 2, count: 2 }));
+		assert_eq!(s.command(&rw10(WRITE_10, 0, 4), &mut response), Ok(Phase::WriteBlocks { lba: 0, count: 4 }));
+		assert_eq!(s.command(&rw10(READ_10, 3, 2), &mut response), Err(Sense::LBA_OUT_OF_RANGE));
+		assert_eq!(s.command(&rw10(WRITE_10, 4, 1), &mut response), Err(Sense::LBA_OUT_OF_RANGE));
+		assert_eq!(s.command(&rw10(READ_10, u32::max_value(), 2), &mut response), Err(Sense::LBA_OUT_OF_RANGE));
+
+		let block = [0xA5; BLOCK_SIZE];
+		s.write_block(1, &block).unwrap();
+		let mut buf = [0; BLOCK_SIZE];
+		s.read_block(1, &mut buf).unwrap();
+		assert_eq!(&buf[..], &block[..]);
+	}
+
+	#[test]
+	fn start_stop_eject() {
+		let mut s = scsi();
+		let mut response = [0u8; 64];
+		// eject
+		assert_eq!(s.command(&[START_STOP_UNIT, 0, 0, 0, 0x02, 0], &mut response), Ok(Phase::NoData));
+		assert!(s.is_ejected());
+		assert_eq!(s.command(&[TEST_UNIT_READY, 0, 0, 0, 0, 0], &mut response), Err(Sense::MEDIUM_NOT_PRESENT));
+		assert_eq!(s.command(&rw10(READ_10, 0, 1), &mut response), Err(Sense::MEDIUM_NOT_PRESENT));
+
+		// load
+		assert_eq!(s.command(&[START_STOP_UNIT, 0, 0, 0, 0x03, 0], &mut response), Ok(Phase::NoData));
+		assert!(!s.is_ejected());
+		assert_eq!(s.command(&[TEST_UNIT_READY, 0, 0, 0, 0, 0], &mut response), Ok(Phase::NoData));
+	}
+}
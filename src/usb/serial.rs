@@ -0,0 +1,120 @@
+// cdc-acm serial port over usb, a drop-in replacement for the uart0 console
+//
+// `UsbSerial` owns the usb device and the serial class, so reading and writing poll
+// the device themselves. output is dropped while no terminal has the port open (dtr
+// not set, e.g. the cable is unplugged), so logging never blocks without a host.
+// while a terminal is connected writing blocks until the host has taken the data.
+
+use core::fmt;
+
+use usb_device::class_prelude::UsbBusAllocator;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+use usb_device::UsbError;
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+use super::{UsbBus, USB_VID, USB_PID_SERIAL};
+
+pub struct UsbSerial<'a> {
+	device: UsbDevice<'a, UsbBus>,
+	port: SerialPort<'a, UsbBus>
+}
+
+impl<'a> UsbSerial<'a> {
+	// `serial_number` is reported to the host, see `ident::BoardId::serial`
+	pub fn new(bus: &'a UsbBusAllocator<UsbBus>, serial_number: &'static str) -> UsbSerial<'a> {
+		let port = SerialPort::new(bus);
+		let device = UsbDeviceBuilder::new(bus, UsbVidPid(USB_VID, USB_PID_SERIAL))
+			.manufacturer("HD Embedded")
+			.product("Board console")
+			.serial_number(serial_number)
+			.device_class(USB_CLASS_CDC)
+			.max_packet_size_0(64)
+			.build();
+		UsbSerial {
+			device: device,
+			port: port
+		}
+	}
+
+	// handle pending usb events, has to be called regularly (at least every few
+	// milliseconds while enumerating) or from the USBHS interrupt
+	pub fn poll(&mut self) -> bool {
+		self.device.poll(&mut [&mut self.port])
+	}
+
+	pub fn state(&self) -> UsbDeviceState {
+		self.device.state()
+	}
+
+	// the host has configured the device and a terminal has opened the port
+	pub fn is_connected(&self) -> bool {
+		self.device.state() == UsbDeviceState::Configured && self.port.dtr()
+	}
+
+	// baud rate the host has set, it has no effect on the transfer speed
+	pub fn baud_rate(&self) -> u32 {
+		self.port.line_coding().data_rate()
+	}
+}
+
+impl<'a> embedded_hal::serial::Read<u8> for UsbSerial<'a> {
+	type Error = UsbError;
+
+	fn read(&mut self) -> nb::Result<u8, UsbError> {
+		self.poll();
+		let mut byte = [0u8];
+		match self.port.read(&mut byte) {
+			Ok(1) => Ok(byte[0]),
+			Ok(_) | Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
+			Err(e) => Err(nb::Error::Other(e))
+		}
+	}
+}
+
+impl<'a> embedded_hal::serial::Write<u8> for UsbSerial<'a> {
+	type Error = UsbError;
+
+	// the byte is dropped without a connected terminal
+	fn write(&mut self, byte: u8) -> nb::Result<(), UsbError> {
+		self.poll();
+		if !self.is_connected() {
+			return Ok(());
+		}
+		match self.port.write(&[byte]) {
+			Ok(1) => Ok(()),
+			Ok(_) | Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
+			Err(e) => Err(nb::Error::Other(e))
+		}
+	}
+
+	fn flush(&mut self) -> nb::Result<(), UsbError> {
+		self.poll();
+		if !self.is_connected() {
+			return Ok(());
+		}
+		match self.port.flush() {
+			Ok(()) => Ok(()),
+			Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
+			Err(e) => Err(nb::Error::Other(e))
+		}
+	}
+}
+
+impl<'a> fmt::Write for UsbSerial<'a> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let mut data = s.as_bytes();
+		while !data.is_empty() {
+			self.poll();
+			// the terminal may go away while waiting for it
+			if !self.is_connected() {
+				return Ok(());
+			}
+			match self.port.write(data) {
+				Ok(n) => data = &data[n..],
+				Err(UsbError::WouldBlock) => {},
+				Err(_) => return Err(fmt::Error)
+			}
+		}
+		Ok(())
+	}
+}
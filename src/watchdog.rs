@@ -0,0 +1,193 @@
+// watchdog handling
+//
+// the watchdog mode register can only be written once after reset, so `start`
+// configures it for good (examples that disable it can not be mixed with this).
+//
+// without early warning the watchdog resets the chip when it is not fed in time.
+// with early warning it raises the WDT interrupt instead, whose handler calls
+// `expired` to dump whatever state is useful before the chip is reset. the
+// reinforced watchdog runs with a slightly longer timeout as a safety net in case
+// the interrupt can not be served.
+//
+// the supervisor feeds the watchdog only after every registered task checked in,
+// so a single stuck task is enough to trigger it.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use atsame70q21::{RSTC, RSWDT, WDT};
+
+use crate::bootinfo::{CrashKind, CrashRecord};
+use crate::gpbr;
+
+// both watchdogs count the slow clock divided by 128
+const WDT_HZ: u32 = 32768 / 128;
+const WDT_MAX_COUNT: u32 = 0xFFF;
+
+const WDT_CR_KEY: u32 = 0xA5 << 24;
+const WDT_CR_WDRSTT: u32 = 1 << 0;
+const WDT_MR_WDFIEN: u32 = 1 << 12;
+const WDT_MR_WDRSTEN: u32 = 1 << 13;
+const WDT_MR_WDDIS: u32 = 1 << 15;
+const WDT_MR_WDDBGHLT: u32 = 1 << 28;
+const WDT_MR_WDIDLEHLT: u32 = 1 << 29;
+
+const RSWDT_CR_KEY: u32 = 0xC4 << 24;
+const RSWDT_MR_ALLONES: u32 = 0xFFF << 16;
+
+const RSTC_SR_RSTTYP_SHIFT: u32 = 8;
+const RSTC_RSTTYP_WATCHDOG: u32 = 2;
+
+const WATCHDOG_MARKER: u32 = 0x3D09_0001;
+
+// a little more than one second between early warning and hard reset
+const RSWDT_GRACE_COUNT: u32 = 300;
+
+static REGISTERED: AtomicU32 = AtomicU32::new(0);
+static CHECKED_IN: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogConfig {
+	pub timeout_ms: u32,
+	pub early_warning: bool,
+	// keep counting while the core sleeps, otherwise a sleeping board never resets
+	pub run_in_idle: bool
+}
+
+impl Default for WatchdogConfig {
+	fn default() -> WatchdogConfig {
+		WatchdogConfig {
+			timeout_ms: 4000,
+			early_warning: false,
+			run_in_idle: true
+		}
+	}
+}
+
+impl WatchdogConfig {
+	pub fn timeout_ms(mut self, timeout_ms: u32) -> Self {
+		self.timeout_ms = timeout_ms;
+		self
+	}
+
+	pub fn early_warning(mut self) -> Self {
+		self.early_warning = true;
+		self
+	}
+
+	pub fn halt_in_idle(mut self) -> Self {
+		self.run_in_idle = false;
+		self
+	}
+}
+
+fn timeout_count(timeout_ms: u32) -> u32 {
+	let count = (timeout_ms as u64 * WDT_HZ as u64 / 1000) as u32;
+	core::cmp::max(core::cmp::min(count, WDT_MAX_COUNT), 1)
+}
+
+pub struct Watchdog {
+	_wdt: WDT,
+	_rswdt: RSWDT
+}
+
+impl Watchdog {
+	// timeouts above 16 s are clipped
+	pub fn start(wdt: WDT, rswdt: RSWDT, config: WatchdogConfig) -> Watchdog {
+		let count = timeout_count(config.timeout_ms);
+		let mut halt = WDT_MR_WDDBGHLT;
+		if !config.run_in_idle {
+			halt |= WDT_MR_WDIDLEHLT;
+		}
+		// feeding is allowed at any time (delta value = counter value)
+		let mode = count | (count << 16) | halt;
+
+		if config.early_warning {
+			wdt.wdt_mr.write(|w| unsafe { w.bits(mode | WDT_MR_WDFIEN) });
+			let count = core::cmp::min(count + RSWDT_GRACE_COUNT, WDT_MAX_COUNT);
+			rswdt.rswdt_mr.write(|w| unsafe { w.bits(count | RSWDT_MR_ALLONES | halt | WDT_MR_WDRSTEN) });
+		} else {
+			wdt.wdt_mr.write(|w| unsafe { w.bits(mode | WDT_MR_WDRSTEN) });
+			rswdt.rswdt_mr.write(|w| unsafe { w.bits(WDT_MR_WDDIS | RSWDT_MR_ALLONES) });
+		}
+
+		Watchdog {
+			_wdt: wdt,
+			_rswdt: rswdt
+		}
+	}
+
+	pub fn feed(&mut self) {
+		feed();
+	}
+}
+
+// restart both watchdogs, also usable where the `Watchdog` is not reachable
+pub fn feed() {
+	unsafe {
+		(*WDT::ptr()).wdt_cr.write(|w| w.bits(WDT_CR_KEY | WDT_CR_WDRSTT));
+		(*RSWDT::ptr()).rswdt_cr.write(|w| w.bits(RSWDT_CR_KEY | WDT_CR_WDRSTT));
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskId(u8);
+
+impl TaskId {
+	pub fn index(self) -> u8 {
+		self.0
+	}
+}
+
+// register a task with the supervisor, returns None if all 32 slots are in use
+pub fn register_task() -> Option<TaskId> {
+	let mut registered = REGISTERED.load(Ordering::Relaxed);
+	loop {
+		let index = (!registered).trailing_zeros();
+		if index >= 32 {
+			return None;
+		}
+		match REGISTERED.compare_exchange(registered, registered | (1 << index), Ordering::AcqRel, Ordering::Relaxed) {
+			Ok(_) => return Some(TaskId(index as u8)),
+			Err(current) => registered = current
+		}
+	}
+}
+
+// mark the task as alive, the watchdog is fed once all registered tasks did so
+pub fn check_in(task: TaskId) {
+	let registered = REGISTERED.load(Ordering::Acquire);
+	let checked = CHECKED_IN.fetch_or(1 << task.0, Ordering::AcqRel) | (1 << task.0);
+	if checked & registered == registered {
+		CHECKED_IN.store(0, Ordering::Release);
+		feed();
+	}
+}
+
+// bit mask of the tasks that did not check in since the watchdog was fed last
+pub fn missing_tasks() -> u32 {
+	REGISTERED.load(Ordering::Acquire) & !CHECKED_IN.load(Ordering::Acquire)
+}
+
+// to be called from the WDT interrupt handler when early warning is enabled.
+// `dump` gets the mask of tasks that did not check in, afterwards the chip is reset.
+// `pc` ends up in the crash record reported on the next boot.
+pub fn expired<F: FnOnce(u32)>(pc: u32, dump: F) -> ! {
+	let missing = missing_tasks();
+	gpbr::write(gpbr::WATCHDOG_MARKER, WATCHDOG_MARKER);
+	CrashRecord {
+		kind: CrashKind::Watchdog(missing as u16),
+		pc: pc,
+		info: missing
+	}.store();
+	dump(missing);
+	cortex_m::peripheral::SCB::sys_reset()
+}
+
+// whether the last reset was caused by the watchdog, either directly or through
+// the early warning handler. clears the early warning marker.
+pub fn caused_last_reset(rstc: &RSTC) -> bool {
+	let rsttyp = (rstc.rstc_sr.read().bits() >> RSTC_SR_RSTTYP_SHIFT) & 0x7;
+	let marker = gpbr::read(gpbr::WATCHDOG_MARKER) == WATCHDOG_MARKER;
+	gpbr::write(gpbr::WATCHDOG_MARKER, 0);
+	rsttyp == RSTC_RSTTYP_WATCHDOG || marker
+}
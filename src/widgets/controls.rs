@@ -0,0 +1,623 @@
+use core::fmt::Write;
+
+use crate::bitmap::PixelTarget;
+
+use super::{draw_span, draw_text_in, fill_rect, Align, Rect, Style, TextBuf, Widget, DEFAULT_STYLE};
+use super::font::CELL_HEIGHT;
+
+// most samples a plot keeps, one per pixel column of the lcd
+pub const PLOT_POINTS: usize = 320;
+
+// `value` with `decimals` fractional digits followed by `unit`
+fn format_fixed(buf: &mut TextBuf, value: i32, decimals: u8, unit: &str) {
+	buf.clear();
+	let sign = if value < 0 { "-" } else { "" };
+	// i32::MIN has no positive i32 counterpart
+	let magnitude = (value as i64).abs() as u64;
+	let (whole, fraction) = match 10u64.checked_pow(decimals as u32) {
+		Some(divisor) => (magnitude / divisor, magnitude % divisor),
+		// more decimals than any i32 has digits
+		None => (0, magnitude)
+	};
+	if decimals == 0 {
+		let _ = write!(buf, "{}{}{}", sign, whole, unit);
+	} else {
+		let _ = write!(buf, "{}{}.{:0width$}{}", sign, whole, fraction, unit, width = decimals as usize);
+	}
+}
+
+// position of `value` between `min` and `max` in 1/256
+fn fraction(value: i32, min: i32, max: i32) -> i32 {
+	if max <= min {
+		return 0;
+	}
+	let v = core::cmp::min(core::cmp::max(value, min), max);
+	((v - min) as i64 * 256 / (max - min) as i64) as i32
+}
+
+pub struct Label {
+	rect: Rect,
+	text: TextBuf,
+	align: Align,
+	style: Style,
+	dirty: bool
+}
+
+impl Label {
+	pub fn new(rect: Rect, text: &str) -> Label {
+		Label {
+			rect: rect,
+			text: TextBuf::from(text),
+			align: Align::Left,
+			style: Style::default(),
+			dirty: true
+		}
+	}
+
+	pub fn align(mut self, align: Align) -> Self {
+		self.align = align;
+		self
+	}
+
+	pub fn style(mut self, style: Style) -> Self {
+		self.style = style;
+		self
+	}
+
+	pub fn set_text(&mut self, text: &str) {
+		let text = TextBuf::from(text);
+		if text != self.text {
+			self.text = text;
+			self.dirty = true;
+		}
+	}
+}
+
+impl Widget for Label {
+	fn rect(&self) -> Rect {
+		self.rect
+	}
+
+	fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	fn invalidate(&mut self) {
+		self.dirty = true;
+	}
+
+	fn draw(&mut self, target: &mut dyn PixelTarget) {
+		draw_text_in(target, self.rect, self.text.as_bytes(), self.align, &self.style);
+		self.dirty = false;
+	}
+}
+
+// fixed point number, e.g. a value of 2345 with 2 decimals is shown as "23.45"
+pub struct NumberField {
+	rect: Rect,
+	value: i32,
+	decimals: u8,
+	unit: &'static str,
+	style: Style,
+	dirty: bool
+}
+
+impl NumberField {
+	pub fn new(rect: Rect, decimals: u8, unit: &'static str) -> NumberField {
+		NumberField {
+			rect: rect,
+			value: 0,
+			decimals: decimals,
+			unit: unit,
+			style: Style::default(),
+			dirty: true
+		}
+	}
+
+	pub fn style(mut self, style: Style) -> Self {
+		self.style = style;
+		self
+	}
+
+	pub fn value(&self) -> i32 {
+		self.value
+	}
+
+	pub fn set(&mut self, value: i32) {
+		if value != self.value {
+			self.value = value;
+			self.dirty = true;
+		}
+	}
+}
+
+impl Widget for NumberField {
+	fn rect(&self) -> Rect {
+		self.rect
+	}
+
+	fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	fn invalidate(&mut self) {
+		self.dirty = true;
+	}
+
+	fn draw(&mut self, target: &mut dyn PixelTarget) {
+		let mut text = TextBuf::new();
+		format_fixed(&mut text, self.value, self.decimals, self.unit);
+		draw_text_in(target, self.rect, text.as_bytes(), Align::Right, &self.style);
+		self.dirty = false;
+	}
+}
+
+// horizontal bar with a one pixel frame in the foreground color
+pub struct ProgressBar {
+	rect: Rect,
+	value: u32,
+	max: u32,
+	style: Style,
+	dirty: bool
+}
+
+impl ProgressBar {
+	pub fn new(rect: Rect, max: u32) -> ProgressBar {
+		ProgressBar {
+			rect: rect,
+			value: 0,
+			max: max,
+			style: Style::default(),
+			dirty: true
+		}
+	}
+
+	pub fn style(mut self, style: Style) -> Self {
+		self.style = style;
+		self
+	}
+
+	pub fn set(&mut self, value: u32) {
+		let value = core::cmp::min(value, self.max);
+		if value != self.value {
+			self.value = value;
+			self.dirty = true;
+		}
+	}
+}
+
+impl Widget for ProgressBar {
+	fn rect(&self) -> Rect {
+		self.rect
+	}
+
+	fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	fn invalidate(&mut self) {
+		self.dirty = true;
+	}
+
+	fn draw(&mut self, target: &mut dyn PixelTarget) {
+		let r = self.rect;
+		if r.width < 2 || r.height < 2 {
+			return;
+		}
+		let inner = r.inset(1);
+		let filled = if self.max == 0 { 0 } else { (inner.width as u64 * self.value as u64 / self.max as u64) as u16 };
+		let style = self.style;
+		for y in r.y..r.y + r.height {
+			let edge = y == r.y || y == r.y + r.height - 1;
+			draw_span(target, r.x, y, r.width, |x| {
+				if edge || x == 0 || x == r.width - 1 {
+					style.foreground
+				} else if x - 1 < filled {
+					style.accent
+				} else {
+					style.background
+				}
+			});
+		}
+		self.dirty = false;
+	}
+}
+
+// sine of `a` in 1/256 of a half turn, as 2.14 fixed point
+fn sin_q14(a: i32) -> i32 {
+	// sin(0°), sin(5.625°), .. sin(90°)
+	const TABLE: [i32; 17] = [
+		0, 1606, 3196, 4756, 6270, 7723, 9102, 10394, 11585,
+		12665, 13623, 14449, 15137, 15679, 16069, 16305, 16384
+	];
+	let q = if a <= 128 { a } else { 256 - a };
+	let i = (q / 8) as usize;
+	if i == 16 {
+		return TABLE[16];
+	}
+	TABLE[i] + (TABLE[i + 1] - TABLE[i]) * (q % 8) / 8
+}
+
+fn cos_q14(a: i32) -> i32 {
+	if a <= 128 { sin_q14(128 - a) } else { -sin_q14(a - 128) }
+}
+
+// half ring filled from the left according to the value, with the value shown below
+pub struct Gauge {
+	rect: Rect,
+	value: i32,
+	min: i32,
+	max: i32,
+	decimals: u8,
+	unit: &'static str,
+	style: Style,
+	dirty: bool
+}
+
+impl Gauge {
+	pub fn new(rect: Rect, min: i32, max: i32) -> Gauge {
+		Gauge {
+			rect: rect,
+			value: min,
+			min: min,
+			max: max,
+			decimals: 0,
+			unit: "",
+			style: Style::default(),
+			dirty: true
+		}
+	}
+
+	// how the value is printed, see `NumberField`
+	pub fn format(mut self, decimals: u8, unit: &'static str) -> Self {
+		self.decimals = decimals;
+		self.unit = unit;
+		self
+	}
+
+	pub fn style(mut self, style: Style) -> Self {
+		self.style = style;
+		self
+	}
+
+	pub fn set(&mut self, value: i32) {
+		if value != self.value {
+			self.value = value;
+			self.dirty = true;
+		}
+	}
+}
+
+impl Widget for Gauge {
+	fn rect(&self) -> Rect {
+		self.rect
+	}
+
+	fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	fn invalidate(&mut self) {
+		self.dirty = true;
+	}
+
+	fn draw(&mut self, target: &mut dyn PixelTarget) {
+		let r = self.rect;
+		let text_height = CELL_HEIGHT * self.style.scale as u16;
+		let (arc, text) = r.split_bottom(text_height);
+		let outer = core::cmp::min(arc.width / 2, arc.height) as i32;
+		let inner = outer * 2 / 3;
+		let cx = (arc.x + arc.width / 2) as i32;
+		let cy = (arc.y + arc.height) as i32;
+
+		// a pixel is filled if it lies counter clockwise of the direction of the value
+		let f = fraction(self.value, self.min, self.max);
+		let ux = -cos_q14(f);
+		let uy = sin_q14(f);
+		let style = self.style;
+		for y in arc.y..arc.y + arc.height {
+			let dy = cy - y as i32;
+			draw_span(target, arc.x, y, arc.width, |x| {
+				let dx = (arc.x + x) as i32 - cx;
+				let d2 = dx * dx + dy * dy;
+				if d2 < inner * inner || d2 > outer * outer {
+					style.background
+				} else if ux * dy - uy * dx >= 0 {
+					style.accent
+				} else {
+					style.foreground
+				}
+			});
+		}
+
+		let mut buf = TextBuf::new();
+		format_fixed(&mut buf, self.value, self.decimals, self.unit);
+		draw_text_in(target, text, buf.as_bytes(), Align::Center, &self.style);
+		self.dirty = false;
+	}
+}
+
+// scrolling line plot, the newest sample is drawn at the right edge
+pub struct Plot {
+	rect: Rect,
+	min: i16,
+	max: i16,
+	samples: [i16; PLOT_POINTS],
+	len: usize,
+	next: usize,
+	style: Style,
+	dirty: bool
+}
+
+impl Plot {
+	pub fn new(rect: Rect, min: i16, max: i16) -> Plot {
+		Plot {
+			rect: rect,
+			min: min,
+			max: max,
+			samples: [0; PLOT_POINTS],
+			len: 0,
+			next: 0,
+			style: Style::default(),
+			dirty: true
+		}
+	}
+
+	pub fn style(mut self, style: Style) -> Self {
+		self.style = style;
+		self
+	}
+
+	pub fn push(&mut self, value: i16) {
+		self.samples[self.next] = value;
+		self.next = (self.next + 1) % PLOT_POINTS;
+		self.len = core::cmp::min(self.len + 1, PLOT_POINTS);
+		self.dirty = true;
+	}
+
+	pub fn clear(&mut self) {
+		self.len = 0;
+		self.dirty = true;
+	}
+
+	// row of `value` relative to the top of the plot
+	fn row_of(&self, value: i16) -> i32 {
+		let h = self.rect.height as i32 - 1;
+		h - fraction(value as i32, self.min as i32, self.max as i32) * h / 256
+	}
+}
+
+impl Widget for Plot {
+	fn rect(&self) -> Rect {
+		self.rect
+	}
+
+	fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	fn invalidate(&mut self) {
+		self.dirty = true;
+	}
+
+	fn draw(&mut self, target: &mut dyn PixelTarget) {
+		let r = self.rect;
+		let visible = core::cmp::min(self.len, r.width as usize);
+		let first_column = r.width as usize - visible;
+		let oldest = (self.next + PLOT_POINTS - visible) % PLOT_POINTS;
+
+		// rows of the visible samples, a column connects a sample to its predecessor
+		let mut rows = [(0i32, 0i32); PLOT_POINTS];
+		let mut prev = None;
+		for (i, span) in rows[..visible].iter_mut().enumerate() {
+			let row = self.row_of(self.samples[(oldest + i) % PLOT_POINTS]);
+			let p = prev.unwrap_or(row);
+			*span = (core::cmp::min(p, row), core::cmp::max(p, row));
+			prev = Some(row);
+		}
+
+		let style = self.style;
+		for y in 0..r.height {
+			let row = y as i32;
+			draw_span(target, r.x, r.y + y, r.width, |x| {
+				let x = x as usize;
+				if x >= first_column && row >= rows[x - first_column].0 && row <= rows[x - first_column].1 {
+					style.accent
+				} else {
+					style.background
+				}
+			});
+		}
+		self.dirty = false;
+	}
+}
+
+// one line of text at the left and one at the right, e.g. a title and the time
+pub struct StatusBar {
+	rect: Rect,
+	left: TextBuf,
+	right: TextBuf,
+	style: Style,
+	dirty: bool
+}
+
+impl StatusBar {
+	pub fn new(rect: Rect) -> StatusBar {
+		let style = Style { background: DEFAULT_STYLE.accent, ..DEFAULT_STYLE };
+		StatusBar {
+			rect: rect,
+			left: TextBuf::new(),
+			right: TextBuf::new(),
+			style: style,
+			dirty: true
+		}
+	}
+
+	pub fn style(mut self, style: Style) -> Self {
+		self.style = style;
+		self
+	}
+
+	pub fn set_left(&mut self, text: &str) {
+		let text = TextBuf::from(text);
+		if text != self.left {
+			self.left = text;
+			self.dirty = true;
+		}
+	}
+
+	pub fn set_right(&mut self, text: &str) {
+		let text = TextBuf::from(text);
+		if text != self.right {
+			self.right = text;
+			self.dirty = true;
+		}
+	}
+}
+
+impl Widget for StatusBar {
+	fn rect(&self) -> Rect {
+		self.rect
+	}
+
+	fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	fn invalidate(&mut self) {
+		self.dirty = true;
+	}
+
+	fn draw(&mut self, target: &mut dyn PixelTarget) {
+		let r = self.rect;
+		fill_rect(target, r, self.style.background);
+		let (left, right) = r.split_left(r.width / 2);
+		draw_text_in(target, left.inset(2), self.left.as_bytes(), Align::Left, &self.style);
+		draw_text_in(target, right.inset(2), self.right.as_bytes(), Align::Right, &self.style);
+		self.dirty = false;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::tests::{art, STYLE};
+	use crate::bitmap::Framebuffer;
+
+	fn fixed(value: i32, decimals: u8, unit: &str) -> String {
+		let mut buf = TextBuf::new();
+		format_fixed(&mut buf, value, decimals, unit);
+		String::from_utf8(buf.as_bytes().to_vec()).unwrap()
+	}
+
+	#[test]
+	fn fixed_point() {
+		assert_eq!(fixed(2345, 2, " V"), "23.45 V");
+		assert_eq!(fixed(-5, 1, ""), "-0.5");
+		assert_eq!(fixed(-1200, 3, "A"), "-1.200A");
+		assert_eq!(fixed(7, 0, "%"), "7%");
+		assert_eq!(fixed(i32::MIN, 0, ""), "-2147483648");
+		assert_eq!(fixed(i32::MIN, 3, ""), "-2147483.648");
+		assert_eq!(fixed(i32::MAX, 10, ""), "0.2147483647");
+		assert_eq!(fixed(123, 12, ""), "0.000000000123");
+		assert_eq!(fixed(-5, 30, ""), format!("-0.{}5", "0".repeat(29)));
+		// cut off at the capacity of the buffer
+		assert_eq!(fixed(1, 255, ""), format!("0.{}", "0".repeat(TextBuf::CAPACITY - 2)));
+	}
+
+	#[test]
+	fn progress_bar() {
+		let mut pixels = vec![0u16; 7 * 4];
+		let mut fb = Framebuffer::new(&mut pixels, 7, 4);
+		let mut bar = ProgressBar::new(Rect::new(0, 0, 6, 4), 4).style(STYLE);
+		bar.set(2);
+		bar.draw(&mut fb);
+		assert_eq!(art(&fb), vec![
+			"###### ",
+			"#++..# ",
+			"#++..# ",
+			"###### "
+		]);
+		bar.set(9);
+		assert!(bar.is_dirty());
+		bar.draw(&mut fb);
+		assert_eq!(art(&fb)[1], "#++++# ");
+		bar.set(4);
+		assert!(!bar.is_dirty());
+	}
+
+	#[test]
+	fn plot() {
+		let mut pixels = vec![0u16; 4 * 3];
+		let mut fb = Framebuffer::new(&mut pixels, 4, 3);
+		let mut plot = Plot::new(Rect::new(0, 0, 4, 3), 0, 2).style(STYLE);
+		plot.draw(&mut fb);
+		assert_eq!(art(&fb), vec!["....", "....", "...."]);
+		for v in [0, 2, 1].iter() {
+			plot.push(*v);
+		}
+		plot.draw(&mut fb);
+		assert_eq!(art(&fb), vec![
+			"..++",
+			"..++",
+			".++."
+		]);
+		// older samples scroll out to the left
+		for v in [0, 0, 3].iter() {
+			plot.push(*v);
+		}
+		plot.draw(&mut fb);
+		assert_eq!(art(&fb), vec![
+			"...+",
+			"++.+",
+			".+++"
+		]);
+	}
+
+	#[test]
+	fn gauge() {
+		let text_rows = CELL_HEIGHT as usize;
+		let mut pixels = vec![0u16; 20 * (10 + text_rows)];
+		let mut fb = Framebuffer::new(&mut pixels, 20, 10 + CELL_HEIGHT);
+		let mut gauge = Gauge::new(Rect::new(0, 0, 20, 10 + CELL_HEIGHT), 0, 100).style(STYLE);
+		gauge.draw(&mut fb);
+		let arc = art(&fb)[..10].concat();
+		assert!(arc.contains('#') && !arc.contains('+'));
+
+		gauge.set(150);
+		gauge.draw(&mut fb);
+		let arc = art(&fb)[..10].concat();
+		assert!(arc.contains('+') && !arc.contains('#'));
+
+		// half way the left half is filled, up to the center column
+		gauge.set(50);
+		gauge.draw(&mut fb);
+		for row in art(&fb)[..10].iter() {
+			assert!(!row[..11].contains('#') && !row[11..].contains('+'), "{}", row);
+		}
+		// the value is printed below the arc
+		assert!(art(&fb)[10..].concat().contains('#'));
+	}
+
+	#[test]
+	fn redraw_only_on_change() {
+		let mut pixels = vec![0u16; 40 * 8];
+		let mut fb = Framebuffer::new(&mut pixels, 40, 8);
+		let mut label = Label::new(Rect::new(0, 0, 40, 8), "on").style(STYLE);
+		let mut number = NumberField::new(Rect::new(0, 0, 40, 8), 1, "V").style(STYLE);
+		let mut status = StatusBar::new(Rect::new(0, 0, 40, 8));
+		label.draw(&mut fb);
+		number.draw(&mut fb);
+		status.draw(&mut fb);
+
+		label.set_text("on");
+		number.set(0);
+		status.set_left("");
+		assert!(!label.is_dirty() && !number.is_dirty() && !status.is_dirty());
+		label.set_text("off");
+		number.set(15);
+		status.set_right("12:00");
+		assert!(label.is_dirty() && number.is_dirty() && status.is_dirty());
+		assert_eq!(number.value(), 15);
+	}
+}
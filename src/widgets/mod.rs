@@ -0,0 +1,599 @@
+// retained mode widgets for the lcd
+//
+// a screen is a set of widgets, each owning a rectangle of the display. setters only
+// mark a widget dirty when its value changes, `Screen::render` then redraws the dirty
+// ones. widgets draw to any `bitmap::PixelTarget`, so a screen can be rendered to the
+// lcd directly or into a `bitmap::Framebuffer`.
+
+use core::fmt;
+
+use crate::bitmap::PixelTarget;
+use crate::lcd::rgb565;
+
+pub mod font;
+mod controls;
+
+pub use self::controls::{Label, NumberField, ProgressBar, Gauge, Plot, StatusBar, PLOT_POINTS};
+
+use self::font::{glyph, CELL_HEIGHT, CELL_WIDTH, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+	pub x: u16,
+	pub y: u16,
+	pub width: u16,
+	pub height: u16
+}
+
+impl Rect {
+	pub const fn new(x: u16, y: u16, width: u16, height: u16) -> Rect {
+		Rect {
+			x: x,
+			y: y,
+			width: width,
+			height: height
+		}
+	}
+
+	// shrink by `margin` on every side
+	pub fn inset(&self, margin: u16) -> Rect {
+		let m = core::cmp::min(margin, core::cmp::min(self.width, self.height) / 2);
+		Rect::new(self.x + m, self.y + m, self.width - 2 * m, self.height - 2 * m)
+	}
+
+	// cut `height` pixels off the top, returns the top part and the rest
+	pub fn split_top(&self, height: u16) -> (Rect, Rect) {
+		let h = core::cmp::min(height, self.height);
+		(Rect::new(self.x, self.y, self.width, h), Rect::new(self.x, self.y + h, self.width, self.height - h))
+	}
+
+	// cut `height` pixels off the bottom, returns the rest and the bottom part
+	pub fn split_bottom(&self, height: u16) -> (Rect, Rect) {
+		let h = core::cmp::min(height, self.height);
+		(Rect::new(self.x, self.y, self.width, self.height - h), Rect::new(self.x, self.y + self.height - h, self.width, h))
+	}
+
+	// cut `width` pixels off the left, returns the left part and the rest
+	pub fn split_left(&self, width: u16) -> (Rect, Rect) {
+		let w = core::cmp::min(width, self.width);
+		(Rect::new(self.x, self.y, w, self.height), Rect::new(self.x + w, self.y, self.width - w, self.height))
+	}
+
+	// row `index` of `count` rows of equal height separated by `gap` pixels,
+	// without any rows the result is empty
+	pub fn row(&self, index: u16, count: u16, gap: u16) -> Rect {
+		if count == 0 {
+			return Rect::new(self.x, self.y, self.width, 0);
+		}
+		let h = self.height.saturating_sub(gap.saturating_mul(count - 1)) / count;
+		Rect::new(self.x, self.y + index * (h + gap), self.width, h)
+	}
+
+	// column `index` of `count` columns of equal width separated by `gap` pixels,
+	// without any columns the result is empty
+	pub fn column(&self, index: u16, count: u16, gap: u16) -> Rect {
+		if count == 0 {
+			return Rect::new(self.x, self.y, 0, self.height);
+		}
+		let w = self.width.saturating_sub(gap.saturating_mul(count - 1)) / count;
+		Rect::new(self.x + index * (w + gap), self.y, w, self.height)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+	pub foreground: u16,
+	pub background: u16,
+	// filled part of bars and gauges, plot lines
+	pub accent: u16,
+	// text size as multiple of the 6x8 font cell
+	pub scale: u8
+}
+
+pub const DEFAULT_STYLE: Style = Style {
+	foreground: rgb565(0xFF, 0xFF, 0xFF),
+	background: rgb565(0x00, 0x00, 0x00),
+	accent: rgb565(0x00, 0xA0, 0xFF),
+	scale: 1
+};
+
+impl Default for Style {
+	fn default() -> Style {
+		DEFAULT_STYLE
+	}
+}
+
+pub trait Widget {
+	fn rect(&self) -> Rect;
+
+	fn is_dirty(&self) -> bool;
+
+	// force a redraw on the next render
+	fn invalidate(&mut self);
+
+	// draw the whole rectangle of the widget and clear the dirty flag
+	fn draw(&mut self, target: &mut dyn PixelTarget);
+}
+
+pub struct Screen {
+	background: u16,
+	cleared: bool
+}
+
+impl Screen {
+	pub fn new(background: u16) -> Screen {
+		Screen {
+			background: background,
+			cleared: false
+		}
+	}
+
+	// clear the target and redraw every widget on the next render, e.g. after
+	// something else has drawn to the display
+	pub fn invalidate(&mut self) {
+		self.cleared = false;
+	}
+
+	// draw the widgets that changed since the last render, returns how many were drawn
+	pub fn render(&mut self, target: &mut dyn PixelTarget, widgets: &mut [&mut dyn Widget]) -> usize {
+		if !self.cleared {
+			let (width, height) = (target.width(), target.height());
+			fill_rect(target, Rect::new(0, 0, width, height), self.background);
+			for w in widgets.iter_mut() {
+				w.invalidate();
+			}
+			self.cleared = true;
+		}
+
+		let mut drawn = 0;
+		for w in widgets.iter_mut().filter(|w| w.is_dirty()) {
+			w.draw(target);
+			drawn += 1;
+		}
+		drawn
+	}
+}
+
+// fixed size text for widgets, longer text is cut off
+#[derive(Clone, Copy)]
+pub struct TextBuf {
+	buf: [u8; TextBuf::CAPACITY],
+	len: usize
+}
+
+impl TextBuf {
+	pub const CAPACITY: usize = 40;
+
+	pub fn new() -> TextBuf {
+		TextBuf {
+			buf: [0; TextBuf::CAPACITY],
+			len: 0
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.len = 0;
+	}
+
+	pub fn push_str(&mut self, text: &str) {
+		for c in text.bytes() {
+			if self.len == TextBuf::CAPACITY {
+				break;
+			}
+			self.buf[self.len] = c;
+			self.len += 1;
+		}
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.buf[..self.len]
+	}
+}
+
+impl Default for TextBuf {
+	fn default() -> TextBuf {
+		TextBuf::new()
+	}
+}
+
+impl<'a> From<&'a str> for TextBuf {
+	fn from(text: &'a str) -> TextBuf {
+		let mut buf = TextBuf::new();
+		buf.push_str(text);
+		buf
+	}
+}
+
+impl PartialEq for TextBuf {
+	fn eq(&self, other: &TextBuf) -> bool {
+		self.as_bytes() == other.as_bytes()
+	}
+}
+
+impl fmt::Write for TextBuf {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		self.push_str(s);
+		Ok(())
+	}
+}
+
+// draw a horizontal span of `width` pixels, `color` gives the color of every pixel
+// relative to `x`, the span is clipped to the target
+pub fn draw_span<F: FnMut(u16) -> u16>(target: &mut dyn PixelTarget, x: u16, y: u16, width: u16, mut color: F) {
+	if y >= target.height() || x >= target.width() {
+		return;
+	}
+	let end = core::cmp::min(x as u32 + width as u32, target.width() as u32) as u16;
+	let mut line = [0u16; 64];
+	let mut pos = x;
+	while pos < end {
+		let count = core::cmp::min(end - pos, line.len() as u16);
+		for (i, p) in line[..count as usize].iter_mut().enumerate() {
+			*p = color(pos - x + i as u16);
+		}
+		target.write_row(pos, y, &line[..count as usize]);
+		pos += count;
+	}
+}
+
+pub fn fill_rect(target: &mut dyn PixelTarget, rect: Rect, color: u16) {
+	for y in rect.y..rect.y + rect.height {
+		draw_span(target, rect.x, y, rect.width, |_| color);
+	}
+}
+
+// size of `text` in pixels when drawn at `scale`
+pub fn text_size(text: &[u8], scale: u8) -> (u16, u16) {
+	(text.len() as u16 * CELL_WIDTH * scale as u16, CELL_HEIGHT * scale as u16)
+}
+
+// draw one line of text with its top left corner at `x`, `y`, the cells are filled
+// with `background`
+pub fn draw_text(target: &mut dyn PixelTarget, x: u16, y: u16, text: &[u8], style: &Style) {
+	let scale = style.scale as u16;
+	let (width, height) = text_size(text, style.scale);
+	for row in 0..height {
+		let glyph_row = row / scale;
+		draw_span(target, x, y + row, width, |px| {
+			let column = px / scale;
+			let c = text[(column / CELL_WIDTH) as usize];
+			let glyph_column = column % CELL_WIDTH;
+			let set = glyph_column < GLYPH_WIDTH && glyph_row < GLYPH_HEIGHT
+				&& glyph(c)[glyph_column as usize] & (1 << glyph_row) != 0;
+			if set { style.foreground } else { style.background }
+		});
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+	Left,
+	Center,
+	Right
+}
+
+// fill `rect` with the background and draw `text` vertically centered in it
+pub fn draw_text_in(target: &mut dyn PixelTarget, rect: Rect, text: &[u8], align: Align, style: &Style) {
+	fill_rect(target, rect, style.background);
+	// drop characters that do not fit
+	let max = (rect.width / (CELL_WIDTH * style.scale as u16)) as usize;
+	let text = &text[..core::cmp::min(text.len(), max)];
+	let (width, height) = text_size(text, style.scale);
+	if height > rect.height {
+		return;
+	}
+	let x = match align {
+		Align::Left => rect.x,
+		Align::Center => rect.x + (rect.width - width) / 2,
+		Align::Right => rect.x + rect.width - width
+	};
+	draw_text(target, x, rect.y + (rect.height - height) / 2, text, style);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::bitmap::{draw_qoi, qoi_size, Framebuffer, Placement};
+
+	// reference renderings, run the tests with UPDATE_REFERENCE=1 to rewrite them
+	const REFERENCE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/widgets/reference");
+
+	pub const STYLE: Style = Style {
+		foreground: 1,
+		background: 2,
+		accent: 3,
+		scale: 1
+	};
+
+	// the target as text, one line per row: foreground '#', background '.',
+	// accent '+' and untouched pixels ' '
+	pub fn art(fb: &Framebuffer) -> Vec<String> {
+		fb.pixels().chunks(fb.width() as usize).map(|row| {
+			row.iter().map(|p| match p {
+				0 => ' ',
+				1 => '#',
+				2 => '.',
+				3 => '+',
+				_ => '?'
+			}).collect()
+		}).collect()
+	}
+
+	fn rgb888(p: u16) -> [u8; 3] {
+		let (r, g, b) = (p >> 11, (p >> 5) & 0x3F, p & 0x1F);
+		[(r << 3 | r >> 2) as u8, (g << 2 | g >> 4) as u8, (b << 3 | b >> 2) as u8]
+	}
+
+	// qoi image of the frame buffer using runs, index and rgb chunks only. the
+	// decoder cuts the 8 bit channels back to rgb565, so the round trip is exact
+	fn encode_qoi(fb: &Framebuffer) -> Vec<u8> {
+		let mut out = b"qoif".to_vec();
+		out.extend_from_slice(&(fb.width() as u32).to_be_bytes());
+		out.extend_from_slice(&(fb.height() as u32).to_be_bytes());
+		out.extend_from_slice(&[3, 0]);
+		let mut index = [[0u8; 3]; 64];
+		let mut prev = [0u8; 3];
+		let mut run = 0;
+		for p in fb.pixels().iter().map(|p| rgb888(*p)) {
+			if p == prev {
+				run += 1;
+				if run == 62 {
+					out.push(0xC0 | (run - 1));
+					run = 0;
+				}
+				continue;
+			}
+			if run > 0 {
+				out.push(0xC0 | (run - 1));
+				run = 0;
+			}
+			let hash = (p[0] as usize * 3 + p[1] as usize * 5 + p[2] as usize * 7 + 255 * 11) % 64;
+			if index[hash] == p {
+				out.push(hash as u8);
+			} else {
+				index[hash] = p;
+				out.push(0xFE);
+				out.extend_from_slice(&p);
+			}
+			prev = p;
+		}
+		if run > 0 {
+			out.push(0xC0 | (run - 1));
+		}
+		out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+		out
+	}
+
+	// compare the frame buffer with the reference image `name`, on a mismatch the
+	// rendering is saved next to the reference for a look
+	pub fn assert_reference(name: &str, fb: &Framebuffer) {
+		let path = format!("{}/{}.qoi", REFERENCE_DIR, name);
+		if std::env::var_os("UPDATE_REFERENCE").is_some() {
+			std::fs::write(&path, encode_qoi(fb)).unwrap();
+			return;
+		}
+		let reference = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+		let size = (fb.width() as u32, fb.height() as u32);
+		assert_eq!(qoi_size(&reference), Ok(size), "{}: size", path);
+
+		let mut pixels = vec![0u16; fb.pixels().len()];
+		let mut expected = Framebuffer::new(&mut pixels, fb.width(), fb.height());
+		draw_qoi(&reference, &mut expected, Placement::default()).unwrap();
+		let differ: Vec<usize> = (0..pixels.len()).filter(|i| fb.pixels()[*i] != pixels[*i]).collect();
+		if let Some(first) = differ.first() {
+			let actual = format!("{}/{}.actual.qoi", REFERENCE_DIR, name);
+			std::fs::write(&actual, encode_qoi(fb)).unwrap();
+			panic!("{}: {} pixels differ, the first at {}, {}; see {}", path, differ.len(),
+				first % fb.width() as usize, first / fb.width() as usize, actual);
+		}
+	}
+
+	// render the widgets of `screen` into `fb` and diff the result against `name`,
+	// returns how many widgets were drawn
+	pub fn assert_screen(name: &str, screen: &mut Screen, fb: &mut Framebuffer, widgets: &mut [&mut dyn Widget]) -> usize {
+		let drawn = screen.render(fb, widgets);
+		assert_reference(name, fb);
+		drawn
+	}
+
+	#[test]
+	fn rect_layout() {
+		let r = Rect::new(10, 20, 100, 50);
+		assert_eq!(r.inset(5), Rect::new(15, 25, 90, 40));
+		assert_eq!(r.inset(40), Rect::new(35, 45, 50, 0));
+		assert_eq!(r.split_top(60), (r, Rect::new(10, 70, 100, 0)));
+		assert_eq!(r.split_bottom(10), (Rect::new(10, 20, 100, 40), Rect::new(10, 60, 100, 10)));
+		assert_eq!(r.split_left(30), (Rect::new(10, 20, 30, 50), Rect::new(40, 20, 70, 50)));
+		assert_eq!(r.row(2, 3, 4), Rect::new(10, 20 + 2 * 18, 100, 14));
+		assert_eq!(r.column(1, 3, 5), Rect::new(10 + 35, 20, 30, 50));
+		assert_eq!(r.row(0, 0, 4), Rect::new(10, 20, 100, 0));
+		assert_eq!(r.column(0, 0, 4), Rect::new(10, 20, 0, 50));
+		// gaps wider than the rect
+		assert_eq!(r.row(0, 3, u16::MAX), Rect::new(10, 20, 100, 0));
+		assert_eq!(r.column(0, 300, 1000), Rect::new(10, 20, 0, 50));
+	}
+
+	#[test]
+	fn text() {
+		let mut pixels = vec![0u16; 13 * 9];
+		let mut fb = Framebuffer::new(&mut pixels, 13, 9);
+		draw_text(&mut fb, 1, 0, b"!T", &STYLE);
+		assert_eq!(art(&fb), vec![
+			" ..#...#####.",
+			" ..#.....#...",
+			" ..#.....#...",
+			" ..#.....#...",
+			" ..#.....#...",
+			" ........#...",
+			" ..#.....#...",
+			" ............",
+			"             "
+		]);
+	}
+
+	#[test]
+	fn scaled_text_is_clipped() {
+		let mut pixels = vec![0u16; 8 * 8];
+		let mut fb = Framebuffer::new(&mut pixels, 8, 8);
+		draw_text(&mut fb, 2, 0, b"-", &Style { scale: 2, ..STYLE });
+		// the bar is the fourth glyph row
+		let mut expected = vec!["  ......"; 8];
+		expected[6] = "  ######";
+		expected[7] = "  ######";
+		assert_eq!(art(&fb), expected);
+	}
+
+	#[test]
+	fn text_in_rect() {
+		let mut pixels = vec![0u16; 16 * 10];
+		let mut fb = Framebuffer::new(&mut pixels, 16, 10);
+		draw_text_in(&mut fb, Rect::new(1, 0, 14, 10), b"---", Align::Right, &STYLE);
+		// only two characters fit, right aligned and vertically centered
+		let mut expected = vec![" .............. "; 10];
+		expected[4] = " ..#####.#####. ";
+		assert_eq!(art(&fb), expected);
+
+		draw_text_in(&mut fb, Rect::new(1, 0, 14, 10), b"-", Align::Center, &STYLE);
+		expected[4] = " ....#####..... ";
+		assert_eq!(art(&fb), expected);
+
+		// text higher than the rect is not drawn
+		draw_text_in(&mut fb, Rect::new(1, 0, 14, 7), b"-", Align::Left, &STYLE);
+		expected[4] = " .............. ";
+		assert_eq!(art(&fb), expected);
+	}
+
+	struct Counter {
+		rect: Rect,
+		dirty: bool,
+		draws: usize
+	}
+
+	impl Widget for Counter {
+		fn rect(&self) -> Rect {
+			self.rect
+		}
+
+		fn is_dirty(&self) -> bool {
+			self.dirty
+		}
+
+		fn invalidate(&mut self) {
+			self.dirty = true;
+		}
+
+		fn draw(&mut self, target: &mut dyn PixelTarget) {
+			fill_rect(target, self.rect, 1);
+			self.draws += 1;
+			self.dirty = false;
+		}
+	}
+
+	#[test]
+	fn screen_renders_dirty_widgets() {
+		let mut pixels = vec![0u16; 4 * 2];
+		let mut fb = Framebuffer::new(&mut pixels, 4, 2);
+		let mut screen = Screen::new(2);
+		let mut a = Counter { rect: Rect::new(0, 0, 1, 1), dirty: false, draws: 0 };
+		let mut b = Counter { rect: Rect::new(3, 1, 1, 1), dirty: false, draws: 0 };
+
+		// the first render clears the target and draws everything
+		assert_eq!(screen.render(&mut fb, &mut [&mut a, &mut b]), 2);
+		assert_eq!(art(&fb), vec!["#...", "...#"]);
+		assert_eq!(screen.render(&mut fb, &mut [&mut a, &mut b]), 0);
+		b.invalidate();
+		assert_eq!(screen.render(&mut fb, &mut [&mut a, &mut b]), 1);
+		screen.invalidate();
+		assert_eq!(screen.render(&mut fb, &mut [&mut a, &mut b]), 2);
+		assert_eq!((a.draws, b.draws), (2, 3));
+	}
+
+	struct Dashboard {
+		status: StatusBar,
+		gauge: Gauge,
+		labels: [Label; 3],
+		voltage: NumberField,
+		current: NumberField,
+		temperature: NumberField,
+		progress: ProgressBar,
+		plot: Plot
+	}
+
+	impl Dashboard {
+		// the lcd split into a status bar, three columns and a plot at the bottom
+		fn new() -> Dashboard {
+			let big = Style { scale: 2, ..DEFAULT_STYLE };
+			let (status, body) = Rect::new(0, 0, 480, 272).split_top(16);
+			let (top, plot) = body.inset(8).split_bottom(96);
+			let top = Rect::new(top.x, top.y, top.width, top.height - 8);
+			let (left, middle, right) = (top.column(0, 3, 8), top.column(1, 3, 8), top.column(2, 3, 8));
+
+			let mut status = StatusBar::new(status);
+			status.set_left("board status");
+			status.set_right("12:34:56");
+			let values = |i| middle.row(i, 3, 4).split_left(64);
+			let mut dashboard = Dashboard {
+				status: status,
+				gauge: Gauge::new(left, 0, 1000).format(1, "%").style(big),
+				labels: [
+					Label::new(values(0).0, "supply"),
+					Label::new(values(1).0, "load"),
+					Label::new(values(2).0, "temp")
+				],
+				voltage: NumberField::new(values(0).1, 2, " V").style(big),
+				current: NumberField::new(values(1).1, 3, " A").style(big),
+				temperature: NumberField::new(values(2).1, 1, " C").style(big),
+				progress: ProgressBar::new(right.row(1, 3, 4), 100),
+				plot: Plot::new(plot, -100, 100)
+			};
+			dashboard.gauge.set(650);
+			dashboard.voltage.set(330);
+			dashboard.current.set(1250);
+			dashboard.temperature.set(-52);
+			dashboard.progress.set(37);
+			for i in 0..400 {
+				dashboard.plot.push(wave(i));
+			}
+			dashboard
+		}
+
+		fn widgets(&mut self) -> [&mut dyn Widget; 10] {
+			let [a, b, c] = &mut self.labels;
+			[&mut self.status, &mut self.gauge, a, b, c, &mut self.voltage, &mut self.current,
+				&mut self.temperature, &mut self.progress, &mut self.plot]
+		}
+	}
+
+	// triangle wave with a ripple, exact in integers so the reference is stable
+	fn wave(i: i32) -> i16 {
+		let t = i % 160;
+		let triangle = if t < 80 { t * 2 - 80 } else { 240 - t * 2 };
+		(triangle + (i * 37 % 11) - 5) as i16
+	}
+
+	#[test]
+	fn dashboard() {
+		let mut pixels = vec![0u16; 480 * 272];
+		let mut fb = Framebuffer::new(&mut pixels, 480, 272);
+		let mut screen = Screen::new(DEFAULT_STYLE.background);
+		let mut dashboard = Dashboard::new();
+		assert_eq!(assert_screen("dashboard", &mut screen, &mut fb, &mut dashboard.widgets()), 10);
+
+		// only the widgets that changed are redrawn on top of the last frame
+		dashboard.status.set_right("12:35:00");
+		dashboard.gauge.set(1000);
+		dashboard.voltage.set(329);
+		dashboard.temperature.set(-52);
+		dashboard.progress.set(100);
+		for i in 400..440 {
+			dashboard.plot.push(wave(i));
+		}
+		assert_eq!(assert_screen("dashboard_updated", &mut screen, &mut fb, &mut dashboard.widgets()), 5);
+
+		// and end up the same as a full redraw
+		let mut full = vec![0u16; 480 * 272];
+		let mut full_fb = Framebuffer::new(&mut full, 480, 272);
+		Screen::new(DEFAULT_STYLE.background).render(&mut full_fb, &mut dashboard.widgets());
+		assert!(full_fb.pixels() == fb.pixels());
+	}
+}
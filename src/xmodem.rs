@@ -0,0 +1,644 @@
+// xmodem-crc / xmodem-1k and ymodem (single file) receiver
+//
+// works on anything implementing the embedded-hal serial traits, e.g. the uart0
+// `Serial` used in the examples, and writes the received file into a `Sink`
+// which is implemented for plain buffers (sdram regions) and the internal flash.
+
+use core::cmp::min;
+use embedded_hal::serial::{Read, Write};
+use embedded_hal::blocking::delay::DelayUs;
+
+use crate::crc::crc16_xmodem;
+use crate::flash::{Flash, FlashError, PAGE_SIZE};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+// sent instead of NAK to request crc mode
+const POLL_CRC: u8 = b'C';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+	Xmodem,
+	Ymodem
+}
+
+#[derive(Debug)]
+pub enum ModemError<E> {
+	Serial,
+	Sink(E),
+	Cancelled,
+	TooManyErrors,
+	// block out of sequence, the transfer can not be recovered
+	Sequence,
+	// ymodem batch without any file
+	NoFile
+}
+
+pub trait Sink {
+	type Error;
+
+	// data arrives in order, `offset` is the number of bytes written before
+	fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+	fn flush(&mut self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
+
+impl Sink for [u8] {
+	type Error = ();
+
+	fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), ()> {
+		if offset + data.len() > self.len() {
+			return Err(());
+		}
+		self[offset..offset + data.len()].copy_from_slice(data);
+		Ok(())
+	}
+}
+
+// writes into an internal flash region, erased on creation
+pub struct FlashSink<'a> {
+	flash: &'a mut Flash,
+	offset: usize,
+	len: usize,
+	page: [u8; PAGE_SIZE],
+	fill: usize,
+	written: usize
+}
+
+impl<'a> FlashSink<'a> {
+	// `offset` and `len` have to be aligned to 8 pages
+	pub fn new(flash: &'a mut Flash, offset: usize, len: usize) -> Result<FlashSink<'a>, FlashError> {
+		flash.erase(offset, len)?;
+		Ok(FlashSink {
+			flash: flash,
+			offset: offset,
+			len: len,
+			page: [0xFF; PAGE_SIZE],
+			fill: 0,
+			written: 0
+		})
+	}
+}
+
+impl<'a> Sink for FlashSink<'a> {
+	type Error = FlashError;
+
+	fn write(&mut self, _offset: usize, mut data: &[u8]) -> Result<(), FlashError> {
+		while !data.is_empty() {
+			if self.written + PAGE_SIZE > self.len {
+				return Err(FlashError::OutOfBounds);
+			}
+			let n = min(PAGE_SIZE - self.fill, data.len());
+			self.page[self.fill..self.fill + n].copy_from_slice(&data[..n]);
+			self.fill += n;
+			data = &data[n..];
+			if self.fill == PAGE_SIZE {
+				self.flush()?;
+			}
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<(), FlashError> {
+		if self.fill > 0 {
+			self.flash.write_page((self.offset + self.written) / PAGE_SIZE, &self.page[..self.fill])?;
+			self.written += PAGE_SIZE;
+			self.fill = 0;
+			self.page = [0xFF; PAGE_SIZE];
+		}
+		Ok(())
+	}
+}
+
+pub struct Received {
+	// xmodem has no length information, so this includes the padding of the last block
+	pub len: usize,
+	name: [u8; 64],
+	name_len: usize
+}
+
+impl Received {
+	pub fn name(&self) -> &str {
+		core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+	}
+}
+
+enum Packet {
+	Data(u8, usize),
+	Eot,
+	Cancel,
+	Timeout,
+	Bad
+}
+
+pub struct Modem<'a, S, D> {
+	serial: &'a mut S,
+	delay: &'a mut D,
+	timeout_ms: u32,
+	retries: u8
+}
+
+impl<'a, S, D> Modem<'a, S, D>
+	where S: Read<u8> + Write<u8>, D: DelayUs<u32>
+{
+	pub fn new(serial: &'a mut S, delay: &'a mut D) -> Modem<'a, S, D> {
+		Modem {
+			serial: serial,
+			delay: delay,
+			timeout_ms: 3000,
+			retries: 10
+		}
+	}
+
+	pub fn timeout_ms(mut self, timeout_ms: u32) -> Self {
+		self.timeout_ms = timeout_ms;
+		self
+	}
+
+	pub fn retries(mut self, retries: u8) -> Self {
+		self.retries = retries;
+		self
+	}
+
+	pub fn receive<K>(&mut self, protocol: Protocol, sink: &mut K) -> Result<Received, ModemError<K::Error>>
+		where K: Sink + ?Sized
+	{
+		let mut buf = [0u8; 1024];
+		let mut received = Received {
+			len: 0,
+			name: [0; 64],
+			name_len: 0
+		};
+		let mut size: Option<usize> = None;
+
+		if protocol == Protocol::Ymodem {
+			let len = self.header_block(&mut buf)?;
+			let header = &buf[..len];
+			let name = header.split(|b| *b == 0).next().unwrap_or(&[]);
+			if name.is_empty() {
+				self.send(ACK)?;
+				return Err(ModemError::NoFile);
+			}
+			received.name_len = min(name.len(), received.name.len());
+			received.name[..received.name_len].copy_from_slice(&name[..received.name_len]);
+			size = header.split(|b| *b == 0).nth(1)
+				.and_then(|s| s.split(|b| *b == b' ').next())
+				.and_then(|s| core::str::from_utf8(s).ok())
+				.and_then(|s| s.parse().ok());
+			self.send(ACK)?;
+		}
+
+		let mut expected: u8 = 1;
+		let mut errors = 0;
+		let mut started = false;
+		let mut eot_seen = false;
+		self.send(POLL_CRC)?;
+
+		loop {
+			match self.read_packet(&mut buf) {
+				Packet::Data(block, len) if block == expected => {
+					started = true;
+					let len = match size {
+						Some(size) => min(len, size.saturating_sub(received.len)),
+						None => len
+					};
+					if let Err(e) = sink.write(received.len, &buf[..len]) {
+						self.cancel()?;
+						return Err(ModemError::Sink(e));
+					}
+					received.len += len;
+					expected = expected.wrapping_add(1);
+					errors = 0;
+					self.send(ACK)?;
+				},
+				// before the first data block only a ymodem header can be repeated
+				Packet::Data(block, _) if block == expected.wrapping_sub(1) && (started || protocol == Protocol::Ymodem) => {
+					// our ack got lost, the sender repeated the last block
+					self.send(ACK)?;
+				},
+				Packet::Data(..) => {
+					self.cancel()?;
+					return Err(ModemError::Sequence);
+				},
+				Packet::Eot => {
+					// ymodem senders expect the first eot to be answered with a nak
+					if protocol == Protocol::Ymodem && !eot_seen {
+						eot_seen = true;
+						self.send(NAK)?;
+						continue;
+					}
+					self.send(ACK)?;
+					break;
+				},
+				Packet::Cancel => {
+					return Err(ModemError::Cancelled);
+				},
+				Packet::Timeout | Packet::Bad => {
+					errors += 1;
+					if errors > self.retries {
+						self.cancel()?;
+						return Err(ModemError::TooManyErrors);
+					}
+					self.purge();
+					self.send(if started { NAK } else { POLL_CRC })?;
+				}
+			}
+		}
+		sink.flush().map_err(ModemError::Sink)?;
+
+		if protocol == Protocol::Ymodem {
+			// only one file per batch is accepted, the next header has to be the empty one
+			let len = self.header_block(&mut buf)?;
+			if len == 0 || buf[0] != 0 {
+				self.cancel()?;
+			} else {
+				self.send(ACK)?;
+			}
+		}
+
+		Ok(received)
+	}
+
+	// wait for a ymodem header (block 0) and return its length
+	fn header_block<E>(&mut self, buf: &mut [u8; 1024]) -> Result<usize, ModemError<E>> {
+		let mut errors = 0;
+		self.send(POLL_CRC)?;
+		loop {
+			match self.read_packet(buf) {
+				Packet::Data(0, len) => return Ok(len),
+				Packet::Data(..) | Packet::Eot => {
+					self.cancel()?;
+					return Err(ModemError::Sequence);
+				},
+				Packet::Cancel => return Err(ModemError::Cancelled),
+				Packet::Timeout | Packet::Bad => {
+					errors += 1;
+					if errors > self.retries {
+						self.cancel()?;
+						return Err(ModemError::TooManyErrors);
+					}
+					self.purge();
+					self.send(POLL_CRC)?;
+				}
+			}
+		}
+	}
+
+	fn read_packet(&mut self, buf: &mut [u8; 1024]) -> Packet {
+		let len = match self.read_byte(self.timeout_ms) {
+			None => return Packet::Timeout,
+			Some(SOH) => 128,
+			Some(STX) => 1024,
+			Some(EOT) => return Packet::Eot,
+			Some(CAN) => {
+				// a single can may be line noise
+				return match self.read_byte(1000) {
+					Some(CAN) => Packet::Cancel,
+					_ => Packet::Bad
+				};
+			},
+			Some(_) => return Packet::Bad
+		};
+
+		let block = match self.read_byte(1000) { Some(b) => b, None => return Packet::Bad };
+		let inverse = match self.read_byte(1000) { Some(b) => b, None => return Packet::Bad };
+		for b in buf[..len].iter_mut() {
+			*b = match self.read_byte(1000) { Some(b) => b, None => return Packet::Bad };
+		}
+		let hi = match self.read_byte(1000) { Some(b) => b, None => return Packet::Bad };
+		let lo = match self.read_byte(1000) { Some(b) => b, None => return Packet::Bad };
+
+		if block != !inverse || crc16_xmodem(&buf[..len]) != u16::from_be_bytes([hi, lo]) {
+			return Packet::Bad;
+		}
+		Packet::Data(block, len)
+	}
+
+	fn read_byte(&mut self, timeout_ms: u32) -> Option<u8> {
+		let mut waited_us: u32 = 0;
+		loop {
+			match self.serial.read() {
+				Ok(b) => return Some(b),
+				// framing and overrun errors show up as crc errors later
+				Err(_) => {}
+			}
+			if waited_us >= timeout_ms.saturating_mul(1000) {
+				return None;
+			}
+			self.delay.delay_us(50);
+			waited_us = waited_us.saturating_add(50);
+		}
+	}
+
+	// drop everything until the line is quiet
+	fn purge(&mut self) {
+		while self.read_byte(100).is_some() {}
+	}
+
+	fn send<E>(&mut self, byte: u8) -> Result<(), ModemError<E>> {
+		nb::block!(self.serial.write(byte)).map_err(|_| ModemError::Serial)
+	}
+
+	fn cancel<E>(&mut self) -> Result<(), ModemError<E>> {
+		for _ in 0..3 {
+			self.send(CAN)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::VecDeque;
+
+	#[derive(PartialEq)]
+	enum Phase {
+		Header,
+		Data,
+		Eot,
+		EndHeader,
+		Done
+	}
+
+	// the sending side, answers every byte the receiver writes
+	struct Sender {
+		header: Option<Vec<u8>>,
+		blocks: Vec<Vec<u8>>,
+		next: usize,
+		phase: Phase,
+		// number of the first data block
+		first: u8,
+		// blocks sent corrupted once
+		corrupt: Vec<usize>,
+		// blocks whose ack gets lost once
+		lost_ack: Vec<usize>,
+		cancel: bool,
+		// polls answered with WouldBlock before the first byte
+		stall: usize,
+		rx: VecDeque<u8>,
+		sent: Vec<u8>
+	}
+
+	impl Sender {
+		fn new(data: &[u8], block_size: usize) -> Sender {
+			let blocks = data.chunks(block_size).map(|c| {
+				let mut block = c.to_vec();
+				block.resize(block_size, 0x1A);
+				block
+			}).collect();
+			Sender {
+				header: None,
+				blocks: blocks,
+				next: 0,
+				phase: Phase::Data,
+				first: 1,
+				corrupt: Vec::new(),
+				lost_ack: Vec::new(),
+				cancel: false,
+				stall: 0,
+				rx: VecDeque::new(),
+				sent: Vec::new()
+			}
+		}
+
+		fn ymodem(mut self, name: &str, size: usize) -> Sender {
+			let mut header = format!("{}\0{} 0 0", name, size).into_bytes();
+			header.resize(128, 0);
+			self.header = Some(header);
+			self.phase = Phase::Header;
+			self
+		}
+
+		fn packet(&mut self, block: u8, data: &[u8], corrupt: bool) {
+			self.rx.push_back(if data.len() == 1024 { STX } else { SOH });
+			self.rx.push_back(block);
+			self.rx.push_back(!block);
+			let crc = crc16_xmodem(data);
+			self.rx.extend(data.iter());
+			if corrupt {
+				let n = self.rx.len() - 1;
+				self.rx[n] ^= 0xFF;
+			}
+			self.rx.extend(crc.to_be_bytes().iter());
+		}
+
+		fn send_block(&mut self) {
+			let corrupt = match self.corrupt.iter().position(|b| *b == self.next) {
+				Some(i) => { self.corrupt.remove(i); true },
+				None => false
+			};
+			let data = self.blocks[self.next].clone();
+			self.packet(self.first.wrapping_add(self.next as u8), &data, corrupt);
+		}
+
+		fn on_byte(&mut self, byte: u8) {
+			self.sent.push(byte);
+			if self.cancel {
+				self.rx.extend([CAN, CAN].iter());
+				return;
+			}
+			match self.phase {
+				Phase::Header => match byte {
+					POLL_CRC | NAK => {
+						let header = self.header.clone().unwrap();
+						self.packet(0, &header, false);
+					},
+					ACK => self.phase = Phase::Data,
+					_ => {}
+				},
+				Phase::Data => match byte {
+					POLL_CRC | NAK => self.send_block(),
+					ACK => {
+						match self.lost_ack.iter().position(|b| *b == self.next) {
+							Some(i) => { self.lost_ack.remove(i); },
+							None => self.next += 1
+						}
+						if self.next == self.blocks.len() {
+							self.phase = Phase::Eot;
+							self.rx.push_back(EOT);
+						} else {
+							self.send_block();
+						}
+					},
+					_ => {}
+				},
+				Phase::Eot => match byte {
+					NAK => self.rx.push_back(EOT),
+					ACK => {
+						self.phase = if self.header.is_some() { Phase::EndHeader } else { Phase::Done };
+					},
+					_ => {}
+				},
+				Phase::EndHeader => match byte {
+					POLL_CRC => self.packet(0, &[0; 128], false),
+					ACK => self.phase = Phase::Done,
+					_ => {}
+				},
+				Phase::Done => {}
+			}
+		}
+	}
+
+	impl Read<u8> for Sender {
+		type Error = ();
+
+		fn read(&mut self) -> nb::Result<u8, ()> {
+			if self.stall > 0 {
+				self.stall -= 1;
+				return Err(nb::Error::WouldBlock);
+			}
+			self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+		}
+	}
+
+	impl Write<u8> for Sender {
+		type Error = ();
+
+		fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+			self.on_byte(byte);
+			Ok(())
+		}
+
+		fn flush(&mut self) -> nb::Result<(), ()> {
+			Ok(())
+		}
+	}
+
+	struct NoDelay;
+
+	impl DelayUs<u32> for NoDelay {
+		fn delay_us(&mut self, _us: u32) {}
+	}
+
+	fn data(len: usize) -> Vec<u8> {
+		(0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+	}
+
+	#[test]
+	fn xmodem_crc() {
+		let file = data(300);
+		let mut sender = Sender::new(&file, 128);
+		let mut buf = [0u8; 1024];
+		let received = Modem::new(&mut sender, &mut NoDelay).receive(Protocol::Xmodem, &mut buf[..]).unwrap();
+		assert_eq!(received.len, 384);
+		assert_eq!(&buf[..300], &file[..]);
+		assert!(buf[300..384].iter().all(|b| *b == 0x1A));
+		assert!(sender.phase == Phase::Done);
+		assert_eq!(sender.sent[0], POLL_CRC);
+	}
+
+	#[test]
+	fn xmodem_1k_retries_bad_crc() {
+		let file = data(3000);
+		let mut sender = Sender::new(&file, 1024);
+		sender.corrupt = vec![0, 1];
+		let mut buf = [0u8; 4096];
+		let received = Modem::new(&mut sender, &mut NoDelay).receive(Protocol::Xmodem, &mut buf[..]).unwrap();
+		assert_eq!(received.len, 3072);
+		assert_eq!(&buf[..3000], &file[..]);
+		// the first block is requested again with C, later ones with a nak
+		assert_eq!(&sender.sent[..4], &[POLL_CRC, POLL_CRC, ACK, NAK]);
+	}
+
+	#[test]
+	fn duplicate_block_is_acked_once() {
+		let file = data(512);
+		let mut sender = Sender::new(&file, 128);
+		sender.lost_ack = vec![1];
+		let mut buf = [0u8; 512];
+		let received = Modem::new(&mut sender, &mut NoDelay).receive(Protocol::Xmodem, &mut buf[..]).unwrap();
+		assert_eq!(received.len, 512);
+		assert_eq!(&buf[..], &file[..]);
+	}
+
+	#[test]
+	fn ymodem_name_and_size() {
+		let file = data(1500);
+		let mut sender = Sender::new(&file, 1024).ymodem("app.bin", 1500);
+		let mut buf = [0u8; 2048];
+		let received = Modem::new(&mut sender, &mut NoDelay).receive(Protocol::Ymodem, &mut buf[..]).unwrap();
+		assert_eq!(received.name(), "app.bin");
+		// the padding of the last block is cut off
+		assert_eq!(received.len, 1500);
+		assert_eq!(&buf[..1500], &file[..]);
+		assert!(buf[1500..].iter().all(|b| *b == 0));
+		assert!(sender.phase == Phase::Done);
+		assert!(!sender.sent.contains(&CAN));
+	}
+
+	#[test]
+	fn sink_too_small_cancels() {
+		let file = data(512);
+		let mut sender = Sender::new(&file, 128);
+		let mut buf = [0u8; 256];
+		match Modem::new(&mut sender, &mut NoDelay).receive(Protocol::Xmodem, &mut buf[..]) {
+			Err(ModemError::Sink(())) => {},
+			_ => panic!("expected a sink error")
+		}
+		assert!(sender.sent.ends_with(&[CAN, CAN, CAN]));
+	}
+
+	#[test]
+	fn sender_cancels() {
+		let mut sender = Sender::new(&data(128), 128);
+		sender.cancel = true;
+		let mut buf = [0u8; 128];
+		match Modem::new(&mut sender, &mut NoDelay).receive(Protocol::Xmodem, &mut buf[..]) {
+			Err(ModemError::Cancelled) => {},
+			_ => panic!("expected a cancel")
+		}
+	}
+
+	#[test]
+	fn block_out_of_sequence() {
+		let mut sender = Sender::new(&data(256), 128);
+		sender.first = 2;
+		let mut buf = [0u8; 256];
+		match Modem::new(&mut sender, &mut NoDelay).receive(Protocol::Xmodem, &mut buf[..]) {
+			Err(ModemError::Sequence) => {},
+			_ => panic!("expected a sequence error")
+		}
+		assert!(sender.sent.ends_with(&[CAN, CAN, CAN]));
+	}
+
+	#[test]
+	fn xmodem_block_zero_is_out_of_sequence() {
+		let mut sender = Sender::new(&data(256), 128);
+		sender.first = 0;
+		let mut buf = [0u8; 256];
+		match Modem::new(&mut sender, &mut NoDelay).receive(Protocol::Xmodem, &mut buf[..]) {
+			Err(ModemError::Sequence) => {},
+			_ => panic!("expected a sequence error")
+		}
+		assert!(!sender.sent.contains(&ACK));
+	}
+
+	#[test]
+	fn silent_line_times_out() {
+		let mut sender = Sender::new(&[], 128);
+		sender.phase = Phase::Done;
+		let mut buf = [0u8; 128];
+		match Modem::new(&mut sender, &mut NoDelay).timeout_ms(10).retries(2).receive(Protocol::Xmodem, &mut buf[..]) {
+			Err(ModemError::TooManyErrors) => {},
+			_ => panic!("expected a timeout")
+		}
+		assert_eq!(&sender.sent[..], &[POLL_CRC, POLL_CRC, POLL_CRC, CAN, CAN, CAN]);
+	}
+
+	#[test]
+	fn long_timeout_does_not_overflow() {
+		let file = data(128);
+		let mut sender = Sender::new(&file, 128);
+		sender.stall = 100;
+		let mut buf = [0u8; 128];
+		let received = Modem::new(&mut sender, &mut NoDelay).timeout_ms(u32::MAX).receive(Protocol::Xmodem, &mut buf[..]).unwrap();
+		assert_eq!(received.len, 128);
+	}
+}
@@ -0,0 +1,35 @@
+/* Code and data in the tightly coupled memories. Functions are placed in the itcm
+   with #[link_section = ".itcm"], statics in the dtcm with
+   #[link_section = ".dtcm.data"] or #[link_section = ".dtcm.bss"] (zeroed).
+   The sections are initialised by `tcm::init`. Without tcm (see board.rs) they are
+   placed in the ram instead. */
+SECTIONS
+{
+  .itcm : ALIGN(4)
+  {
+    _sitcm = .;
+    *(.itcm .itcm.*);
+    . = ALIGN(4);
+    _eitcm = .;
+  } > ITCM AT > FLASH
+
+  _siitcm = LOADADDR(.itcm);
+
+  .dtcm.data : ALIGN(4)
+  {
+    _sdtcm_data = .;
+    *(.dtcm.data .dtcm.data.*);
+    . = ALIGN(4);
+    _edtcm_data = .;
+  } > DTCM AT > FLASH
+
+  _sidtcm_data = LOADADDR(.dtcm.data);
+
+  .dtcm.bss (NOLOAD) : ALIGN(4)
+  {
+    _sdtcm_bss = .;
+    *(.dtcm.bss .dtcm.bss.*);
+    . = ALIGN(4);
+    _edtcm_bss = .;
+  } > DTCM
+} INSERT AFTER .bss;